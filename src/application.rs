@@ -1,4 +1,5 @@
 use std::hash::Hasher;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use gfx::buffer::{Arg, BufferLayout, BufferSource, BufferType, BufferUsage};
 use gfx::glue::GlContext;
@@ -8,23 +9,45 @@ use gfx::texture::{FilterMode, TextureAccess, TextureFormat, TextureParams, Text
 use gfx::{Bindings, QuadContext};
 use gfx::pass::PassAction;
 use gfx::uniform::{UniformBlockLayout, UniformDesc, UniformsSource, UniformType};
+use log::{error, info};
 use microui::atlas::{ATLAS, ATLAS_FONT, ATLAS_HEIGHT, ATLAS_TEXTURE, ATLAS_WHITE, ATLAS_WIDTH};
 use microui::{Color, Command, FontId, Rect, WidgetOption};
 use winit::dpi::{PhysicalPosition, PhysicalSize};
-use winit::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
+use winit::event::{ElementState, Event, MouseButton, VirtualKeyCode, WindowEvent};
 use winit::event::VirtualKeyCode::P;
 use winit::event_loop::EventLoop;
 use winit::platform::run_return::EventLoopExtRunReturn;
 use winit::window::{Window, WindowBuilder};
-use crate::arm::cpu::Cpu;
+use crate::arm::cpu::{Arch, Cpu};
+use crate::arm::disassembler::Disassembler;
+use crate::arm::memory::Memory;
+use crate::arm::trace::TraceFormat;
+use crate::audio::AudioOutput;
 
-use crate::core::config::BootMode;
+use crate::core::config::{AccuracyConfig, AccuracyPreset, BootMode, ClockScale, ScreenOrder};
+use crate::core::debugger::gdb::GdbStub;
+use crate::core::debugger::StepCondition;
+use crate::core::hardware::cartridge::save::SaveFormat;
+use crate::core::hardware::firmware::Language;
 use crate::core::hardware::input::InputEvent;
+use crate::core::video::vram::VramBank;
 use crate::core::video::Screen;
-use crate::core::System;
-use crate::framehelper::FrameHelper;
+use crate::core::{OwnedSystem, StopReason, System};
+use crate::framehelper::{FrameHelper, Rewind, DS_REFRESH_RATE};
+use crate::gamepad::{GamepadInput, Gamepads};
+use crate::geometry::{Layout, Rotation, ScreenGeometry, Viewport};
+use crate::input_map::{Action, InputMap};
+use crate::layer_viewer::{LayerViewer, IMAGE_SIZE};
+use crate::memory_viewer::MemoryViewer;
+use crate::recorder::Recorder;
 use crate::renderer::Renderer;
-use crate::util::Shared;
+use crate::util::{clear_unimplemented_hits, diff_states, paths, unimplemented_hits};
+
+/// Quick save slots F6 cycles through
+const STATE_SLOTS: u8 = 4;
+
+/// Kept in the config directory as `true` or `false`
+const FOCUS_PAUSE_NAME: &str = "pause-on-focus-loss";
 
 #[repr(C)]
 struct Vec2 {
@@ -38,35 +61,54 @@ struct Vertex {
     uv: Vec2,
 }
 
-#[rustfmt::skip]
-const NORMAL_VERTICES: [Vertex; 6] = [
-    Vertex { pos: Vec2 { x: -1.0, y: -1.0 }, uv: Vec2 { x: 0., y: 1. } },
-    Vertex { pos: Vec2 { x: 1.0, y: -1.0 }, uv: Vec2 { x: 1., y: 1. } },
-    Vertex { pos: Vec2 { x: 1.0, y: 1.0 }, uv: Vec2 { x: 1., y: 0. } },
-    Vertex { pos: Vec2 { x: -1.0, y: -1.0 }, uv: Vec2 { x: 0., y: 1. } },
-    Vertex { pos: Vec2 { x: 1.0, y: 1.0 }, uv: Vec2 { x: 1., y: 0. } },
-    Vertex { pos: Vec2 { x: -1.0, y: 1.0 }, uv: Vec2 { x: 0., y: 0. } },
-];
-#[rustfmt::skip]
-const DEBUGGER_VERTICES: [Vertex; 6] = [
-    Vertex { pos: Vec2 { x: -1.0, y: -1.0 }, uv: Vec2 { x: 0., y: 1. } },
-    Vertex { pos: Vec2 { x: 0.0, y: -1.0 }, uv: Vec2 { x: 1., y: 1. } },
-    Vertex { pos: Vec2 { x: 0.0, y: 1.0 }, uv: Vec2 { x: 1., y: 0. } },
-    Vertex { pos: Vec2 { x: -1.0, y: -1.0 }, uv: Vec2 { x: 0., y: 1. } },
-    Vertex { pos: Vec2 { x: 0.0, y: 1.0 }, uv: Vec2 { x: 1., y: 0. } },
-    Vertex { pos: Vec2 { x: -1.0, y: 1.0 }, uv: Vec2 { x: 0., y: 0. } },
-];
+impl From<[f32; 4]> for Vertex {
+    fn from([x, y, u, v]: [f32; 4]) -> Self {
+        Self {
+            pos: Vec2 { x, y },
+            uv: Vec2 { x: u, y: v },
+        }
+    }
+}
 
 pub struct Application {
-    system: Shared<System>,
+    system: OwnedSystem,
     ctx: QuadContext,
     gl: GlContext,
     window: Window,
     pipeline: Pipeline,
     bindings: Bindings,
     framehelper: FrameHelper,
+    rewind: Rewind,
+    /// The rewind key is held, frames step back instead of forward
+    rewinding: bool,
+    geometry: ScreenGeometry,
+    cursor: PhysicalPosition<f64>,
     last: u64,
     in_debugger: bool,
+    paused: bool,
+    /// Pause while the window doesn't have focus
+    pause_on_focus_loss: bool,
+    /// Whether the current pause came from losing focus, so regaining it doesn't undo a manual pause
+    focus_paused: bool,
+    editing_nickname: bool,
+    memory_viewer: MemoryViewer,
+    disassembler: Disassembler,
+    layer_viewer: LayerViewer,
+    /// Quad and texture the layer viewer is drawn with, in place of the screens
+    viewer_bindings: Bindings,
+    /// Quick save slot used by F5 and F7, cycled with F6
+    state_slot: u8,
+    /// `None` without an output device
+    audio: Option<AudioOutput>,
+    /// Samples of the last frame, shared by the audio output and the recorder
+    audio_buffer: Vec<i16>,
+    recorder: Option<Recorder>,
+    /// Remote debugging servers, empty unless started with `--gdb`
+    gdb: Vec<GdbStub>,
+    input_map: InputMap,
+    gamepads: Gamepads,
+    /// Action the debugger is waiting for a key or button press for
+    remapping: Option<Action>,
     microui: microui::Context,
     renderer: Renderer,
 }
@@ -84,7 +126,12 @@ impl Application {
 
         let mut ctx = QuadContext::new(gl.glow());
 
-        let vertex_buffer = ctx.new_buffer(BufferType::VertexBuffer, BufferUsage::Immutable, BufferSource::slice(&NORMAL_VERTICES));
+        let geometry = ScreenGeometry {
+            viewport: Viewport { x: 0.0, y: 0.0, width: 512.0, height: 768.0 },
+            ..Default::default()
+        };
+        let vertices = geometry.vertices(512.0, 768.0).map(Vertex::from);
+        let vertex_buffer = ctx.new_buffer(BufferType::VertexBuffer, BufferUsage::Immutable, BufferSource::slice(&vertices));
 
         let screen = ctx.new_texture(
             TextureAccess::RenderTarget,
@@ -103,6 +150,24 @@ impl Application {
             images: vec![screen],
         };
 
+        let viewer_vertices = [[-1.0, 1.0, 0.0, 0.0]; 6].map(Vertex::from);
+        let viewer_buffer = ctx.new_buffer(BufferType::VertexBuffer, BufferUsage::Immutable, BufferSource::slice(&viewer_vertices));
+        let viewer_image = ctx.new_texture(
+            TextureAccess::RenderTarget,
+            None,
+            TextureParams {
+                format: TextureFormat::RGBA8,
+                filter: FilterMode::Nearest,
+                width: IMAGE_SIZE as _,
+                height: IMAGE_SIZE as _,
+                ..Default::default()
+            },
+        );
+        let viewer_bindings = Bindings {
+            vertex_buffers: vec![viewer_buffer],
+            images: vec![viewer_image],
+        };
+
         let shader = ctx
             .new_shader(
                 ShaderSource {
@@ -124,16 +189,39 @@ impl Application {
 
         let renderer = Renderer::new(&mut ctx);
 
+        let system = System::new();
+        let audio = AudioOutput::new(system.audio_sample_rate());
+
         Self {
-            system: System::new(),
+            system,
             ctx,
             gl,
             window,
             pipeline,
             bindings,
             framehelper: FrameHelper::new(),
+            rewind: Rewind::new(),
+            rewinding: false,
+            geometry,
+            cursor: PhysicalPosition::new(0.0, 0.0),
             last: 0,
             in_debugger: false,
+            paused: false,
+            pause_on_focus_loss: load_pause_on_focus_loss(),
+            focus_paused: false,
+            editing_nickname: false,
+            memory_viewer: MemoryViewer::default(),
+            disassembler: Disassembler::new(),
+            layer_viewer: LayerViewer::default(),
+            viewer_bindings,
+            state_slot: 1,
+            audio,
+            audio_buffer: vec![0; 4096 * 2],
+            recorder: None,
+            gdb: Vec::new(),
+            input_map: InputMap::load(),
+            gamepads: Gamepads::new(),
+            remapping: None,
             microui: microui::Context::new(Renderer::get_char_width, Renderer::get_font_height),
             renderer,
         }
@@ -141,8 +229,44 @@ impl Application {
 
     pub fn boot_game(&mut self, path: &str) {
         self.system.set_game_path(path);
+        let firmware = paths::firmware("firmware.bin");
+        if firmware.exists() {
+            self.system.set_firmware_path(firmware.to_str());
+        }
         self.system.set_boot_mode(BootMode::Direct);
         self.system.reset();
+        self.rewind.clear();
+    }
+
+    pub fn set_local_wifi(&mut self, enabled: bool) {
+        self.system.set_local_wifi(enabled);
+    }
+
+    pub fn set_hle_bios(&mut self, enabled: bool) {
+        self.system.set_hle_bios(enabled);
+    }
+
+    pub fn set_trace_format(&mut self, format: TraceFormat) {
+        self.system.set_trace_format(format);
+    }
+
+    pub fn set_clocks(&mut self, arm9: ClockScale, arm7: ClockScale) {
+        self.system.set_arm9_clock(arm9);
+        self.system.set_arm7_clock(arm7);
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recorder = Recorder::start(&self.system);
+    }
+
+    /// Serves the arm9 on `port` and the arm7 on the port after it
+    pub fn start_gdb(&mut self, port: u16) {
+        for (arch, port) in [(Arch::ARMv5, port), (Arch::ARMv4, port.wrapping_add(1))] {
+            match GdbStub::bind(arch, port) {
+                Ok(stub) => self.gdb.push(stub),
+                Err(e) => error!("Application: no gdb stub for the {arch:?}: {e}"),
+            }
+        }
     }
 
     pub fn run(&mut self, event_loop: &mut EventLoop<()>) {
@@ -150,38 +274,162 @@ impl Application {
         let _ = event_loop.run_return(|event, _, flow| match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => flow.set_exit(),
-                WindowEvent::Resized(new) => self.ctx.resize(new.width as _, new.height as _),
+                WindowEvent::Resized(new) => {
+                    self.ctx.resize(new.width as _, new.height as _);
+                    self.update_vertices(new.width, new.height);
+                }
+                WindowEvent::Focused(focused) => self.update_focus(focused),
+                WindowEvent::CursorMoved { position, .. } => {
+                    self.cursor = position;
+                    if self.system.input.pending().touch_down() {
+                        self.update_touch(true);
+                    }
+                }
+                WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+                    self.update_touch(matches!(state, ElementState::Pressed));
+                }
+                WindowEvent::ReceivedCharacter(c) if self.editing_nickname => {
+                    let mut settings = self.system.user_settings();
+                    match c {
+                        '\u{8}' => {
+                            settings.nickname.pop();
+                        }
+                        '\r' => self.editing_nickname = false,
+                        c if !c.is_control() && settings.nickname.encode_utf16().count() < 10 => settings.nickname.push(c),
+                        _ => {}
+                    }
+                    self.system.set_user_settings(&settings);
+                    if !self.editing_nickname {
+                        self.system.save_user_settings();
+                    }
+                }
+                WindowEvent::ReceivedCharacter(c) if self.memory_viewer.is_typing() => {
+                    self.memory_viewer.input_char(&mut self.system, c);
+                }
                 WindowEvent::KeyboardInput { input, .. } => {
                     let pressed = matches!(input.state, ElementState::Pressed);
+                    if self.editing_nickname {
+                        return;
+                    }
+                    if self.memory_viewer.is_typing() {
+                        if let Some(code) = input.virtual_keycode.filter(|_| pressed) {
+                            self.memory_viewer.input_key(code);
+                        }
+                        return;
+                    }
+
                     if let Some(code) = input.virtual_keycode {
+                        if let Some(action) = self.remapping.filter(|_| pressed) {
+                            self.input_map.bind_key(code, action);
+                            self.input_map.save();
+                            self.remapping = None;
+                            return;
+                        }
+                        if let Some(action) = self.input_map.key(code) {
+                            return self.handle_action(action, pressed);
+                        }
+
                         match code {
                             VirtualKeyCode::Minus => self.framehelper.set_fast_forward(1.0),
                             VirtualKeyCode::Equals => self.framehelper.set_fast_forward(2.0),
+                            VirtualKeyCode::F1 => {
+                                if pressed {
+                                    self.system.soft_reset();
+                                    self.rewind.clear();
+                                }
+                            }
+                            VirtualKeyCode::F2 => {
+                                // press the combo most games use for their own soft reset
+                                for event in [InputEvent::L, InputEvent::R, InputEvent::Start, InputEvent::Select] {
+                                    self.system.input.handle_input(event, pressed);
+                                }
+                            }
+                            VirtualKeyCode::F3 => {
+                                if pressed {
+                                    let inserted = self.system.slot2_inserted();
+                                    self.system.set_slot2_inserted(!inserted);
+                                }
+                            }
+                            VirtualKeyCode::F12 => {
+                                if pressed {
+                                    self.save_screenshot();
+                                }
+                            }
+                            VirtualKeyCode::S => {
+                                if pressed {
+                                    let order = self.system.screen_order().swap();
+                                    self.system.set_screen_order(order);
+                                }
+                            }
                             VirtualKeyCode::RBracket => {
                                 if pressed {
                                     self.toggle_debugger();
                                     self.center_window();
                                 }
                             },
-                            _ => {
-                                if let Some(event) = Self::convert(code) {
-                                    self.system.input.handle_input(event, pressed);
-                                }
-                            }
+                            _ => {}
                         }
                     }
                 }
                 _ => {}
             },
             Event::MainEventsCleared => {
+                self.poll_gamepads();
+                let layout = (self.geometry.layout, self.geometry.rotation, self.geometry.integer_scale);
                 self.framehelper.run(|| {
-                    self.system.run_frame();
+                    for stub in &mut self.gdb {
+                        stub.poll(&mut self.system);
+                    }
+
+                    if !self.paused && !self.gdb.iter().any(GdbStub::is_halted) {
+                        // a frame still runs after each step back, so the screens show the restored state
+                        if self.rewinding {
+                            self.rewind.step_back(&mut self.system);
+                        }
+                        self.system.run_frame();
+                        if self.system.step_hit() {
+                            self.system.cancel_step();
+                            self.paused = true;
+                        }
+                        if !self.rewinding {
+                            self.rewind.on_frame(&mut self.system);
+                        }
+
+                        let written = self.system.fill_audio(&mut self.audio_buffer);
+                        let samples = &self.audio_buffer[..written * 2];
+                        if let Some(audio) = &mut self.audio {
+                            audio.push(samples);
+                        }
+                        if let Some(recorder) = &mut self.recorder {
+                            recorder.record_frame(&self.system, samples);
+                        }
+                    }
+
                     if self.in_debugger {
                         self.microui.frame(|ui| {
-                            Self::update_debugger(ui, &mut self.system);
+                            Self::update_debugger(
+                                ui,
+                                &mut self.system,
+                                self.state_slot,
+                                &mut self.paused,
+                                &mut self.pause_on_focus_loss,
+                                &mut self.editing_nickname,
+                                &mut self.memory_viewer,
+                                &self.disassembler,
+                                &mut self.layer_viewer,
+                                &mut self.geometry,
+                                &mut self.input_map,
+                                &mut self.remapping,
+                                &mut self.recorder,
+                            );
                         });
                     }
                 });
+
+                if layout != (self.geometry.layout, self.geometry.rotation, self.geometry.integer_scale) {
+                    let size = self.window.inner_size();
+                    self.update_vertices(size.width, size.height);
+                }
             }
             Event::RedrawEventsCleared => {
                 let top = self.system.video_unit.fetch_framebuffer(Screen::Top);
@@ -194,15 +442,24 @@ impl Application {
                     h.finish()
                 };
 
-                if self.last != hash {
+                // the viewer can change while the game is paused, so it's redrawn every time
+                let viewing = self.in_debugger && self.layer_viewer.is_shown();
+                if self.last != hash || viewing {
                     self.last = hash;
                     self.ctx.texture_update_part(self.bindings.images[0], 0, 0, 256, 192, top);
                     self.ctx.texture_update_part(self.bindings.images[0], 0, 192, 256, 192, bot);
 
                     self.ctx.begin_default_pass(Default::default());
                     self.ctx.apply_pipeline(&self.pipeline);
-                    self.ctx.apply_bindings(&self.bindings);
-                    self.ctx.draw(0, 6, 1);
+                    if viewing {
+                        let image = self.layer_viewer.update(&mut self.system);
+                        self.ctx.texture_update_part(self.viewer_bindings.images[0], 0, 0, IMAGE_SIZE as _, IMAGE_SIZE as _, image);
+                        self.ctx.apply_bindings(&self.viewer_bindings);
+                        self.ctx.draw(0, 6, 1);
+                    } else {
+                        self.ctx.apply_bindings(&self.bindings);
+                        self.ctx.draw(0, 12, 1);
+                    }
 
                     if self.in_debugger {
                         self.draw_debugger();
@@ -216,27 +473,81 @@ impl Application {
                 }
 
                 if let Some((fps, ups)) = self.framehelper.inc().fps() {
-                    self.window.set_title(&format!("fps: {fps} ups: {ups}"))
+                    match self.system.stop_reason() {
+                        Some(StopReason::PoweredOff) => self.window.set_title("powered off"),
+                        Some(StopReason::GbaMode) => self.window.set_title("stopped: gba mode is not supported"),
+                        None => self.window.set_title(&self.status_title(fps, ups)),
+                    }
                 }
             }
             _ => {}
         });
+        // a nickname still being typed when the window closes is kept too
+        self.system.save_user_settings();
     }
 
-    const fn convert(key: VirtualKeyCode) -> Option<InputEvent> {
-        Some(match key {
-            VirtualKeyCode::A => InputEvent::A,
-            VirtualKeyCode::B => InputEvent::B,
-            VirtualKeyCode::Tab => InputEvent::Select,
-            VirtualKeyCode::Return => InputEvent::Start,
-            VirtualKeyCode::Right => InputEvent::Right,
-            VirtualKeyCode::Left => InputEvent::Left,
-            VirtualKeyCode::Up => InputEvent::Up,
-            VirtualKeyCode::Down => InputEvent::Down,
-            VirtualKeyCode::E => InputEvent::R,
-            VirtualKeyCode::W => InputEvent::L,
-            _ => return None,
-        })
+    fn handle_action(&mut self, action: Action, pressed: bool) {
+        match action {
+            Action::Button(event) => self.system.input.handle_input(event, pressed),
+            Action::FastForward => self.framehelper.set_fast_forward(if pressed { 2.0 } else { 1.0 }),
+            Action::Rewind => self.rewinding = pressed,
+            Action::SaveState if pressed => self.save_state(),
+            Action::LoadState if pressed => self.load_state(),
+            Action::NextStateSlot if pressed => {
+                self.state_slot = self.state_slot % STATE_SLOTS + 1;
+                info!("Application: selected state slot {}", self.state_slot);
+            }
+            _ => {}
+        }
+    }
+
+    fn poll_gamepads(&mut self) {
+        for (input, pressed) in self.gamepads.poll() {
+            match input {
+                GamepadInput::Button(button) => {
+                    if let Some(action) = self.remapping.filter(|_| pressed) {
+                        self.input_map.bind_button(button, action);
+                        self.input_map.save();
+                        self.remapping = None;
+                    } else if let Some(action) = self.input_map.button(button) {
+                        self.handle_action(action, pressed);
+                    }
+                }
+                GamepadInput::Stick(event) => self.system.input.handle_input(event, pressed),
+            }
+        }
+    }
+
+    fn update_focus(&mut self, focused: bool) {
+        if !self.pause_on_focus_loss {
+            return;
+        }
+
+        if !focused && !self.paused {
+            self.paused = true;
+            self.focus_paused = true;
+        } else if focused && self.focus_paused {
+            self.paused = false;
+            self.focus_paused = false;
+            // don't try to catch up on the frames missed while unfocused
+            self.framehelper.queue_reset();
+        }
+    }
+
+    /// Game title, emulation speed relative to the ds refresh rate, fps, the state slot and the fast forward setting
+    fn status_title(&self, fps: f32, ups: f32) -> String {
+        let speed = ups as f64 / DS_REFRESH_RATE * 100.0;
+        let mut title = format!("{} | {speed:.0}% | fps: {fps} | slot {}", self.system.game_title(), self.state_slot);
+        if self.paused {
+            title.push_str(" | paused");
+        }
+        if self.rewinding {
+            title.push_str(" | rewinding");
+        }
+        if self.framehelper.get_fast_forward() != 1.0 {
+            title.push_str(&format!(" | fast forward x{}", self.framehelper.get_fast_forward()));
+        }
+        title
     }
 
     fn toggle_debugger(&mut self) {
@@ -248,18 +559,44 @@ impl Application {
         }
         self.window.set_inner_size(size);
 
-        let data = if self.in_debugger {
-            &NORMAL_VERTICES
-        } else {
-            &DEBUGGER_VERTICES
-        };
-        self.ctx.buffer_update(self.bindings.vertex_buffers[0], BufferSource::slice(data));
-
         self.in_debugger ^= true;
         self.renderer.clear();
+        self.update_vertices(size.width, size.height);
+    }
+
+    /// Recomputes the screen quads, the emulator gets the left half of the window while debugging
+    fn update_vertices(&mut self, width: u32, height: u32) {
+        let (width, height) = (width as f32, height as f32);
+        self.geometry.viewport = Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: if self.in_debugger { width / 2.0 } else { width },
+            height,
+        };
+
+        let vertices = self.geometry.vertices(width, height).map(Vertex::from);
+        self.ctx.buffer_update(self.bindings.vertex_buffers[0], BufferSource::slice(&vertices));
+
+        // the layer viewer is a square in the top left of the emulator's half
+        let side = self.geometry.viewport.width.min(height);
+        let (right, bottom) = (side / width * 2.0 - 1.0, 1.0 - side / height * 2.0);
+        let corners = [[-1.0, 1.0, 0.0, 0.0], [right, 1.0, 1.0, 0.0], [right, bottom, 1.0, 1.0], [-1.0, bottom, 0.0, 1.0]];
+        let vertices = [0, 1, 2, 0, 2, 3].map(|i| Vertex::from(corners[i]));
+        self.ctx.buffer_update(self.viewer_bindings.vertex_buffers[0], BufferSource::slice(&vertices));
         self.last = 0xdeadbeeef_8008135; // force a redraw
     }
 
+    fn update_touch(&mut self, pressed: bool) {
+        let screen = self.system.video_unit.touch_screen();
+        match self.geometry.map_touch(self.cursor.x as f32, self.cursor.y as f32, screen) {
+            Some((x, y)) if pressed => {
+                self.system.input.set_point(x, y);
+                self.system.input.set_touch(true);
+            }
+            _ => self.system.input.set_touch(false),
+        }
+    }
+
     fn center_window(&self) {
         let monitor_size = self.window.current_monitor().unwrap().size();
         let window_size = self.window.outer_size();
@@ -271,6 +608,47 @@ impl Application {
         self.window.set_outer_position(pos);
     }
 
+    fn state_path(&self) -> std::path::PathBuf {
+        state_path(&self.system, self.state_slot)
+    }
+
+    fn save_state(&mut self) {
+        let path = self.state_path();
+        let state = self.system.save_state();
+        match std::fs::write(&path, state) {
+            Ok(_) => info!("Application: saved state to {}", path.display()),
+            Err(e) => error!("Application: failed to save state to {}: {e}", path.display()),
+        }
+    }
+
+    fn load_state(&mut self) {
+        let path = self.state_path();
+        let result = std::fs::read(&path).map_err(|e| e.to_string()).and_then(|state| self.system.load_state(&state));
+        match result {
+            Ok(_) => {
+                info!("Application: loaded state from {}", path.display());
+                self.rewind.clear();
+            }
+            Err(e) => error!("Application: failed to load state from {}: {e}", path.display()),
+        }
+    }
+
+    /// Writes both screens, stacked like the default layout, to the screenshots directory
+    fn save_screenshot(&self) {
+        let mut pixels = Vec::with_capacity(256 * 192 * 2 * 4);
+        pixels.extend_from_slice(self.system.video_unit.fetch_framebuffer(Screen::Top));
+        pixels.extend_from_slice(self.system.video_unit.fetch_framebuffer(Screen::Bottom));
+
+        let title: String = self.system.game_title().chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        let path = paths::screenshots().join(format!("{title}-{time}.png"));
+
+        match crate::png::write_rgba(&path, 256, 192 * 2, &pixels) {
+            Ok(_) => info!("Application: saved screenshot to {}", path.display()),
+            Err(e) => error!("Application: failed to save screenshot to {}: {e}", path.display()),
+        }
+    }
+
     fn draw_debugger(&mut self) {
         for &cmd in self.microui.commands() {
             match cmd {
@@ -287,17 +665,384 @@ impl Application {
         }
     }
 
-    fn update_debugger(ui: &mut microui::Context, system: &mut System) {
+    fn update_debugger(
+        ui: &mut microui::Context,
+        system: &mut System,
+        state_slot: u8,
+        paused: &mut bool,
+        pause_on_focus_loss: &mut bool,
+        editing_nickname: &mut bool,
+        memory_viewer: &mut MemoryViewer,
+        disassembler: &Disassembler,
+        layer_viewer: &mut LayerViewer,
+        geometry: &mut ScreenGeometry,
+        input_map: &mut InputMap,
+        remapping: &mut Option<Action>,
+        recorder: &mut Option<Recorder>,
+    ) {
         ui.window("main")
             .size(512, 768)
             .options(WidgetOption::NO_TITLE)
             .show(ui, |ui| {
+                render_step_commands(ui, system, paused, pause_on_focus_loss);
+                render_screen_order(ui, system);
+                render_layout(ui, geometry);
+                render_input_map(ui, input_map, remapping);
+                render_accuracy(ui, system);
                 render_cpu(ui, &system.arm7.cpu);
                 render_cpu(ui, &system.arm9.cpu);
+                render_disassembly(ui, system, disassembler, Arch::ARMv4);
+                render_disassembly(ui, system, disassembler, Arch::ARMv5);
+                render_user_settings(ui, system, editing_nickname);
+                memory_viewer.render(ui, system);
+                render_rom_info(ui, system);
+                render_save(ui, system);
+                render_state_diff(ui, system, state_slot);
+                render_recording(ui, system, recorder);
+                render_heatmap(ui, system);
+                render_vram_banks(ui, system);
+                layer_viewer.render(ui, system);
+                render_unimplemented(ui);
             });
     }
 }
 
+/// Pausing while unfocused is on unless it was turned off before
+fn load_pause_on_focus_loss() -> bool {
+    std::fs::read_to_string(paths::config().join(FOCUS_PAUSE_NAME))
+        .ok()
+        .and_then(|enabled| enabled.trim().parse().ok())
+        .unwrap_or(true)
+}
+
+fn save_pause_on_focus_loss(enabled: bool) {
+    if let Err(e) = std::fs::write(paths::config().join(FOCUS_PAUSE_NAME), enabled.to_string()) {
+        error!("Application: failed to save the focus pause setting: {e}");
+    }
+}
+
+/// A checkbox that is reset every frame, so it can double as a button
+pub fn clicked(ui: &mut microui::Context, label: &str) -> bool {
+    let mut state = false;
+    ui.checkbox(label, &mut state);
+    state
+}
+
+fn render_step_commands(ui: &mut microui::Context, system: &mut System, paused: &mut bool, pause_on_focus_loss: &mut bool) {
+    ui.layout_row(&[475 / 5; 5], 0);
+    ui.checkbox("paused", paused);
+
+    let lr = system.arm9.cpu.state.gpr[14] & !1;
+    let commands = [
+        ("until vblank", StepCondition::VBlank),
+        ("until irq", StepCondition::Irq(None)),
+        ("until IF write", StepCondition::MmioWrite(0x04000214)),
+        ("step out (arm9)", StepCondition::BranchTo(lr..lr + 4)),
+    ];
+
+    for (label, condition) in commands {
+        if clicked(ui, label) {
+            system.step_until(&[condition]);
+            *paused = false;
+        }
+    }
+
+    // the step runs with the normal frames and pauses once it's hit
+    ui.layout_row(&[475 / 5, 475 / 5, -1], 0);
+    let was_pausing = *pause_on_focus_loss;
+    ui.checkbox("pause unfocused", pause_on_focus_loss);
+    if *pause_on_focus_loss != was_pausing {
+        save_pause_on_focus_loss(*pause_on_focus_loss);
+    }
+    if system.debugger.is_armed() {
+        if clicked(ui, "cancel step") {
+            system.cancel_step();
+        }
+        ui.label(&format!("stepping until {:?}", system.debugger.conditions()));
+    }
+}
+
+fn render_screen_order(ui: &mut microui::Context, system: &mut System) {
+    ui.layout_row(&[475 / 5; 5], 0);
+    ui.label("screens:");
+    for order in ScreenOrder::ALL {
+        let mut selected = system.screen_order() == order;
+        ui.checkbox(&format!("{order:?}"), &mut selected);
+        if selected && system.screen_order() != order {
+            system.set_screen_order(order);
+        }
+    }
+}
+
+fn render_layout(ui: &mut microui::Context, geometry: &mut ScreenGeometry) {
+    ui.layout_row(&[475 / 5; 5], 0);
+    ui.label("layout:");
+    for layout in Layout::ALL {
+        let mut selected = geometry.layout == layout;
+        ui.checkbox(&format!("{layout:?}"), &mut selected);
+        if selected {
+            geometry.layout = layout;
+        }
+    }
+
+    ui.label("rotation:");
+    for rotation in Rotation::ALL {
+        let mut selected = geometry.rotation == rotation;
+        ui.checkbox(&format!("{rotation:?}"), &mut selected);
+        if selected {
+            geometry.rotation = rotation;
+        }
+    }
+
+    ui.layout_row(&[475 / 5, -1], 0);
+    ui.label("");
+    ui.checkbox("integer scale", &mut geometry.integer_scale);
+}
+
+/// Rebinding waits for the next key or gamepad button press
+fn render_input_map(ui: &mut microui::Context, input_map: &mut InputMap, remapping: &mut Option<Action>) {
+    ui.layout_row(&[475 / 3 * 2, -1], 0);
+    match remapping {
+        Some(action) => ui.label(&format!("Input: press a key or button for {}", action.name())),
+        None => ui.label("Input"),
+    }
+    if clicked(ui, "reset bindings") {
+        *input_map = InputMap::default();
+        input_map.save();
+    }
+
+    ui.layout_row(&[100, 150, 150, -1], 0);
+    for action in Action::all() {
+        let (key, button) = input_map.describe(action);
+        ui.label(&action.name());
+        ui.label(&key);
+        ui.label(&button);
+        if clicked(ui, &format!("rebind {}", action.name())) {
+            *remapping = Some(action);
+        }
+    }
+}
+
+fn render_accuracy(ui: &mut microui::Context, system: &mut System) {
+    let mut accuracy = system.accuracy();
+    let current = accuracy.matching_preset();
+
+    ui.layout_row(&[475 / 5; 4], 0);
+    ui.label("accuracy:");
+    for preset in AccuracyPreset::ALL {
+        let mut selected = current == Some(preset);
+        ui.checkbox(&format!("{preset:?}"), &mut selected);
+        if selected && current != Some(preset) {
+            accuracy = AccuracyConfig::preset(preset);
+        }
+    }
+
+    ui.layout_row(&[475 / 5, 475 / 5, 475 / 5, -1], 0);
+    ui.label("");
+    ui.checkbox("obj cycle limit", &mut accuracy.obj_cycle_limit);
+    ui.checkbox("cartridge timing", &mut accuracy.cartridge_timing);
+    ui.checkbox("math timing", &mut accuracy.math_timing);
+    ui.label("");
+    ui.checkbox("bg enable delay", &mut accuracy.bg_enable_delay);
+    ui.checkbox("cpu timing", &mut accuracy.cpu_timing);
+
+    if accuracy != system.accuracy() {
+        system.set_accuracy(accuracy);
+    }
+}
+
+fn render_user_settings(ui: &mut microui::Context, system: &mut System, editing_nickname: &mut bool) {
+    let mut settings = system.user_settings();
+    let mut changed = false;
+
+    ui.layout_row(&[-1], 0);
+    ui.label("User Settings");
+
+    ui.layout_row(&[475 / 2, -1], 0);
+    ui.label(&format!("nickname: {}{}", settings.nickname, if *editing_nickname { "_" } else { "" }));
+    let was_editing = *editing_nickname;
+    ui.checkbox("edit nickname", editing_nickname);
+    if was_editing && !*editing_nickname {
+        system.save_user_settings();
+    }
+
+    ui.layout_row(&[475 / 4; 4], 0);
+    ui.label(&format!("birthday: {:02}/{:02}", settings.birthday_month, settings.birthday_day));
+    if clicked(ui, "month +") {
+        settings.birthday_month = settings.birthday_month % 12 + 1;
+        changed = true;
+    }
+    if clicked(ui, "day +") {
+        settings.birthday_day = settings.birthday_day % 31 + 1;
+        changed = true;
+    }
+    ui.label("");
+
+    ui.layout_row(&[475 / 8; 8], 0);
+    for color in 0..16 {
+        let mut selected = settings.favorite_color == color;
+        ui.checkbox(&format!("color {color}"), &mut selected);
+        if selected && settings.favorite_color != color {
+            settings.favorite_color = color;
+            changed = true;
+        }
+    }
+
+    ui.layout_row(&[475 / 6; 6], 0);
+    for language in Language::ALL {
+        let mut selected = settings.language == language;
+        ui.checkbox(&format!("{language:?}"), &mut selected);
+        if selected && settings.language != language {
+            settings.language = language;
+            changed = true;
+        }
+    }
+
+    if changed {
+        system.set_user_settings(&settings);
+        system.save_user_settings();
+    }
+}
+
+fn render_rom_info(ui: &mut microui::Context, system: &System) {
+    ui.layout_row(&[475 / 5, -1], 0);
+    ui.label("Rom");
+    ui.label(&format!("{} ({})", system.game_title(), system.rom_id()));
+}
+
+fn render_save(ui: &mut microui::Context, system: &System) {
+    ui.layout_row(&[475 / 3; 3], 0);
+    ui.label("Save");
+    for format in SaveFormat::ALL {
+        if clicked(ui, &format!("export .{}", format.extension())) {
+            system.export_save(format);
+        }
+    }
+
+    if system.save_locked() {
+        ui.layout_row(&[475], 0);
+        ui.label("the save is in use by another instance and won't be written");
+    }
+}
+
+/// Starts and stops dumping frames and audio, stopping finishes the wav
+fn render_recording(ui: &mut microui::Context, system: &mut System, recorder: &mut Option<Recorder>) {
+    ui.layout_row(&[475 / 3, -1], 0);
+    ui.label("Recording");
+    let mut recording = recorder.is_some();
+    ui.checkbox("png frames + wav", &mut recording);
+    if recording != recorder.is_some() {
+        *recorder = if recording { Recorder::start(system) } else { None };
+    }
+}
+
+/// Compares the selected slot with the running system and logs where they differ, for chasing desyncs
+fn render_state_diff(ui: &mut microui::Context, system: &mut System, slot: u8) {
+    ui.layout_row(&[475 / 3, -1], 0);
+    ui.label("Savestate");
+    if clicked(ui, &format!("diff slot {slot} with live")) {
+        let path = state_path(system, slot);
+        let layout = system.state_layout();
+        let result = std::fs::read(&path).map_err(|e| e.to_string()).and_then(|state| diff_states(&state, &system.save_state(), Some(&layout)));
+        match result {
+            Ok(diff) => {
+                for line in diff.to_string().lines() {
+                    info!("Debugger: {line}");
+                }
+            }
+            Err(e) => error!("Debugger: can't diff {}: {e}", path.display()),
+        }
+    }
+}
+
+fn state_path(system: &System, slot: u8) -> std::path::PathBuf {
+    paths::states().join(format!("{}.ss{slot}", system.rom_id()))
+}
+
+fn render_heatmap(ui: &mut microui::Context, system: &mut System) {
+    const SHADES: &[u8] = b" .:-=+*#%@";
+
+    ui.layout_row(&[475 / 3; 3], 0);
+    ui.label("Main Memory Heatmap");
+    let mut enabled = system.heatmap.is_enabled();
+    ui.checkbox("enabled", &mut enabled);
+    if enabled != system.heatmap.is_enabled() {
+        system.set_heatmap_enabled(enabled);
+    }
+    if clicked(ui, "reset") {
+        system.heatmap.clear();
+    }
+
+    // each cell is one 4KB page, shaded on a log scale relative to the busiest page
+    let heatmap = &system.heatmap;
+    let counts = heatmap.reads.iter().zip(heatmap.writes.iter()).map(|(r, w)| r + w).collect::<Vec<_>>();
+    let max = counts.iter().copied().max().unwrap_or(0).max(1) as f32;
+
+    ui.layout_row(&[-1], 200);
+    ui.panel("heatmap").show(ui, |ui| {
+        ui.layout_row(&[80, -1], 0);
+        for (row, chunk) in counts.chunks(32).enumerate() {
+            let line = chunk
+                .iter()
+                .map(|&count| {
+                    let level = ((count as f32).ln_1p() / max.ln_1p() * (SHADES.len() - 1) as f32) as usize;
+                    SHADES[level.min(SHADES.len() - 1)] as char
+                })
+                .collect::<String>();
+            ui.label(&format!("{:08x}", 0x02000000 + row * 32 * 0x1000));
+            ui.label(&line);
+        }
+    });
+}
+
+fn render_vram_banks(ui: &mut microui::Context, system: &System) {
+    let vram = &system.video_unit.vram;
+    let conflicts = vram.conflicts();
+
+    ui.layout_row(&[-1], 0);
+    ui.label("VRAM Banks");
+
+    ui.layout_row(&[40, 120, 200, -1], 0);
+    for bank in VramBank::ALL {
+        let (enable, mst, offset) = vram.bank_control(bank);
+        ui.label(&format!("{bank:?}"));
+        ui.label(&format!("mst {mst} offset {offset}{}", if enable { "" } else { " (off)" }));
+
+        match vram.bank_mapping(bank) {
+            Some(mapping) => ui.label(&format!("{:?} {:05x}..{:05x}", mapping.target, mapping.offset, mapping.offset + mapping.length)),
+            None if enable => ui.label("invalid mst"),
+            None => ui.label("-"),
+        }
+
+        let mut notes = vec![];
+        if vram.bank_mapping(bank).is_some_and(|mapping| !mapping.target.cpu_accessible()) {
+            notes.push("no cpu access".to_string());
+        }
+        for &(a, b) in &conflicts {
+            if a == bank || b == bank {
+                notes.push(format!("overlaps {:?}", if a == bank { b } else { a }));
+            }
+        }
+        ui.label(&notes.join(", "));
+    }
+}
+
+/// Features the running game needed but the emulator doesn't have, with how often each was hit
+fn render_unimplemented(ui: &mut microui::Context) {
+    let hits = unimplemented_hits();
+    ui.layout_row(&[475 / 3 * 2, -1], 0);
+    ui.label(&format!("Unimplemented ({} sites)", hits.len()));
+    if clicked(ui, "clear") {
+        clear_unimplemented_hits();
+    }
+
+    ui.layout_row(&[80, -1], 0);
+    for hit in &hits {
+        ui.label(&hit.count.to_string());
+        ui.label(&hit.message);
+    }
+}
+
 fn render_cpu(ui: &mut microui::Context, cpu: &Cpu) {
     let name = format!("{:?} Registers", cpu.arch);
     ui.layout_row(&[-1], 155);
@@ -323,6 +1068,66 @@ fn render_cpu(ui: &mut microui::Context, cpu: &Cpu) {
     })
 }
 
+/// Instructions around pc with per-cpu run control, ticking a line toggles a breakpoint on it
+fn render_disassembly(ui: &mut microui::Context, system: &mut System, disassembler: &Disassembler, arch: Arch) {
+    const BEFORE: u32 = 4;
+    const LINES: u32 = 12;
+
+    // widgets are told apart by their labels, so each cpu gets its own
+    let name = if arch == Arch::ARMv4 { "arm7" } else { "arm9" };
+    let cpu = system.cpu(arch);
+    ui.layout_row(&[475 / 5; 5], 0);
+    ui.label(&format!("{name}{}", if cpu.breakpoint_hit() { " (breakpoint)" } else { "" }));
+    let mut paused = cpu.is_paused();
+    ui.checkbox(&format!("pause {name}"), &mut paused);
+    cpu.set_paused(paused);
+    if clicked(ui, &format!("run {name}")) {
+        cpu.set_paused(false);
+        cpu.resume();
+    }
+    if clicked(ui, &format!("step {name}")) {
+        system.step_cpu(arch);
+    }
+    if clicked(ui, &format!("clear {name} breakpoints")) {
+        system.cpu(arch).clear_breakpoints();
+    }
+
+    let cpu = system.cpu(arch);
+    let pc = cpu.current_pc();
+    let thumb = cpu.state.cpsr.thumb();
+    let size = if thumb { 2 } else { 4 };
+    let breakpoints = cpu.breakpoints().to_vec();
+
+    let memory = system.get_memory(arch);
+    let mut toggled = None;
+    ui.layout_row(&[-1], 0);
+    for line in 0..LINES {
+        let addr = pc.wrapping_sub(BEFORE * size).wrapping_add(line * size);
+        let text = if thumb {
+            disassembler.thumb(peek(memory, addr, 2) as u16, peek(memory, addr.wrapping_add(2), 2) as u16, addr)
+        } else {
+            disassembler.arm(peek(memory, addr, 4), addr)
+        };
+
+        let mut breakpoint = breakpoints.contains(&addr);
+        ui.checkbox(&format!("{} {addr:08x}  {text}", if addr == pc { ">" } else { " " }), &mut breakpoint);
+        if breakpoint != breakpoints.contains(&addr) {
+            toggled = Some((addr, breakpoint));
+        }
+    }
+
+    match toggled {
+        Some((addr, true)) => system.cpu(arch).add_breakpoint(addr),
+        Some((addr, false)) => system.cpu(arch).remove_breakpoint(addr),
+        None => {}
+    }
+}
+
+/// Little endian read of `size` bytes without side effects
+fn peek(memory: &mut dyn Memory, addr: u32, size: u32) -> u32 {
+    (0..size).fold(0, |val, i| val | (memory.peek_byte(addr.wrapping_add(i)) as u32) << (i * 8))
+}
+
 mod shader {
     use gfx::shader::ShaderMeta;
     use gfx::uniform::{UniformBlockLayout, UniformDesc, UniformType};
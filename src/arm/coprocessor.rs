@@ -1,4 +1,5 @@
 pub trait Coprocessor {
+    fn reset(&mut self);
     fn read(&mut self, cn: u32, cm: u32, cp: u32) -> u32;
     fn write(&mut self, cn: u32, cm: u32, cp: u32, val: u32);
     fn get_exception_base(&self) -> u32;
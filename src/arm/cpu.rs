@@ -1,7 +1,5 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
 use std::mem::swap;
-use std::ops::Not;
+use std::ops::{Not, Range};
 
 use log::{trace, warn};
 
@@ -9,6 +7,8 @@ use crate::arm::coprocessor::Coprocessor;
 use crate::arm::decoder::Decoder;
 use crate::arm::memory::Memory;
 use crate::arm::state::{Bank, Condition, Mode, State, StatusReg, GPR};
+use crate::arm::trace::Tracer;
+use crate::util::{StateReader, StateWriter};
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Arch {
@@ -27,23 +27,48 @@ impl Not for Arch {
     }
 }
 
+/// Runs a swi in place of the bios, gets the swi number from the comment field. Returns false
+/// when it moved pc itself, otherwise execution carries on after the swi
+pub type SwiHandler = fn(&mut Cpu, u8) -> bool;
+
 pub struct Cpu {
     // common stuff
     pub state: State,
     pub arch: Arch,
     pub memory: Box<dyn Memory>,
     pub coprocessor: Box<dyn Coprocessor>,
+    /// Set when there's no bios to take the swi exception
+    swi_handler: Option<SwiHandler>,
     irq: bool,
     halted: bool,
+    /// Held up by hardware it's writing to, like a full geometry fifo. Unlike a halt irqs don't end it
+    stalled: bool,
+    /// Stopped from the debugger, the rest of the system keeps running
+    paused: bool,
+    branch_watch: Vec<Range<u32>>,
+    /// A step condition was met, `run` does nothing until the watch is set again
+    watch_hit: bool,
+    breakpoints: Vec<u32>,
+    breakpoint_hit: bool,
+    /// Lets the instruction under a breakpoint run once after resuming from it
+    skip_breakpoint: bool,
 
     // interpreter stuff
     decoder: Decoder,
     pipeline: [u32; 2],
+    /// Count memory access and internal cycles instead of taking a cycle per instruction
+    timing: bool,
+    /// Cycles left from the last `run`, negative when the last instruction went past it
+    budget: i64,
+    instruction_cycles: u64,
+    /// Addresses right after the last code and data accesses, an access there is sequential
+    next_code: u32,
+    next_data: u32,
     pub instruction: u32,
     condition_table: [[bool; 16]; 16],
 
     #[cfg(feature = "log_state")]
-    debug: BufWriter<File>,
+    tracer: Option<Tracer>,
     // jit stuff
     // todo
 }
@@ -55,14 +80,27 @@ impl Cpu {
             arch,
             memory,
             coprocessor,
+            swi_handler: None,
             irq: false,
             halted: false,
+            stalled: false,
+            paused: false,
+            branch_watch: Vec::new(),
+            watch_hit: false,
+            breakpoints: Vec::new(),
+            breakpoint_hit: false,
+            skip_breakpoint: false,
             decoder: Decoder::new(),
             pipeline: [0; 2],
+            timing: false,
+            budget: 0,
+            instruction_cycles: 0,
+            next_code: 0,
+            next_data: 0,
             instruction: 0,
             condition_table: Condition::table(),
             #[cfg(feature = "log_state")]
-            debug: BufWriter::new(File::create(format!("{arch:?}.log")).unwrap())
+            tracer: None,
         }
     }
 
@@ -73,6 +111,12 @@ impl Cpu {
         self.pipeline.fill(0);
         self.irq = false;
         self.halted = false;
+        self.stalled = false;
+        self.paused = false;
+        self.budget = 0;
+        self.next_code = 0;
+        self.next_data = 0;
+        self.coprocessor.reset();
     }
 
     pub(super) fn illegal_instruction(&mut self, instruction: u32) {
@@ -82,6 +126,31 @@ impl Cpu {
         );
     }
 
+    /// Registers and the pipeline, memory and the coprocessor are saved by the owning core
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.state.save_state(state);
+        state.write_bool("irq", self.irq);
+        state.write_bool("halted", self.halted);
+        state.write_bool("stalled", self.stalled);
+        state.write_slice("pipeline", &self.pipeline);
+        state.write("instruction", self.instruction);
+        state.write("budget", self.budget);
+        state.write("next_code", self.next_code);
+        state.write("next_data", self.next_data);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.state.load_state(state);
+        self.irq = state.read_bool();
+        self.halted = state.read_bool();
+        self.stalled = state.read_bool();
+        state.read_slice(&mut self.pipeline);
+        self.instruction = state.read();
+        self.budget = state.read();
+        self.next_code = state.read();
+        self.next_data = state.read();
+    }
+
     pub const fn is_halted(&self) -> bool {
         self.halted
     }
@@ -90,9 +159,96 @@ impl Cpu {
         self.halted = val;
     }
 
+    pub const fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+
+    pub fn set_stalled(&mut self, stalled: bool) {
+        self.stalled = stalled;
+    }
+
+    pub const fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Keeps `run` from executing anything while the other cpu and the scheduler carry on
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Stops `run` as soon as the pipeline is flushed to an address in any of `ranges`, and clears an earlier hit
+    pub fn set_branch_watch(&mut self, ranges: &[Range<u32>]) {
+        self.branch_watch = ranges.to_vec();
+        self.watch_hit = false;
+    }
+
+    pub const fn watch_hit(&self) -> bool {
+        self.watch_hit
+    }
+
+    /// Stops `run` after the current instruction, for step conditions met by what the instruction did
+    pub fn set_watch_hit(&mut self) {
+        self.watch_hit = true;
+    }
+
+    /// Stops `run` before the instruction at `addr` executes
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.retain(|&breakpoint| breakpoint != addr);
+    }
+
+    pub fn breakpoints(&self) -> &[u32] {
+        &self.breakpoints
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.breakpoint_hit = false;
+    }
+
+    pub const fn breakpoint_hit(&self) -> bool {
+        self.breakpoint_hit
+    }
+
+    /// Continues after a breakpoint without stopping on it again right away
+    pub fn resume(&mut self) {
+        if self.breakpoint_hit {
+            self.breakpoint_hit = false;
+            self.skip_breakpoint = true;
+        }
+    }
+
+    /// Address of the instruction that executes next, pc runs 2 instructions ahead
+    pub fn current_pc(&self) -> u32 {
+        self.state.gpr[15].wrapping_sub(if self.state.cpsr.thumb() { 4 } else { 8 })
+    }
+
+    fn check_breakpoint(&mut self) -> bool {
+        let skip = std::mem::take(&mut self.skip_breakpoint);
+        if !skip && self.breakpoints.contains(&self.current_pc()) {
+            self.breakpoint_hit = true;
+        }
+        self.breakpoint_hit
+    }
+
+    fn check_branch_watch(&mut self) {
+        if self.branch_watch.iter().any(|range| range.contains(&self.state.gpr[15])) {
+            self.watch_hit = true;
+        }
+    }
+
+    /// Runs for `cycles` of this cpu's clock. Without timing every instruction takes a cycle, with it an
+    /// instruction can take longer than what's left and the difference comes off the next run
     pub fn run(&mut self, cycles: u64) {
-        for _ in 0..cycles {
-            if self.halted {
+        self.budget += cycles as i64;
+        while self.budget > 0 {
+            if self.halted || self.stalled || self.paused || self.watch_hit || self.breakpoint_hit {
+                self.budget = 0;
                 return;
             }
 
@@ -100,6 +256,13 @@ impl Cpu {
                 self.handle_interrupt();
             }
 
+            if !self.breakpoints.is_empty() && self.check_breakpoint() {
+                self.budget = 0;
+                return;
+            }
+
+            self.instruction_cycles = 0;
+
             self.instruction = self.pipeline[0];
             self.pipeline[0] = self.pipeline[1];
 
@@ -134,25 +297,51 @@ impl Cpu {
                     self.state.gpr[15] += 4;
                 }
             }
+
+            self.budget -= if self.timing { self.instruction_cycles.max(1) as i64 } else { 1 };
         }
     }
 
-    #[cfg(feature = "log_state")]
-    fn log_state(&mut self) {
-        use std::io::Write;
+    pub fn set_timing(&mut self, enabled: bool) {
+        self.timing = enabled;
+    }
+
+    pub fn set_swi_handler(&mut self, handler: Option<SwiHandler>) {
+        self.swi_handler = handler;
+    }
 
-        let thumb = self.state.cpsr.thumb();
-        let pc = self.state.gpr[15] - if thumb { 4 } else { 8 };
-        let inst = self.instruction;
+    /// Runs the swi handler if there is one, returns false when the swi should go to the bios
+    pub(in crate::arm) fn handle_swi(&mut self, number: u8) -> bool {
+        let Some(handler) = self.swi_handler else {
+            return false;
+        };
 
-        writeln!(self.debug, "{pc:08x}: {inst:08x} | {:x?} cpsr: {:08x}", self.state.gpr, self.state.cpsr.0);
+        if handler(self, number) {
+            self.advance();
+        }
+        true
     }
 
+    /// Replaces the instruction trace output, `None` disables tracing
     #[cfg(feature = "log_state")]
-    fn log_switch_mode(&mut self, old: Bank, new: Bank, old_reg: [u32; 7], new_reg: [u32; 7]) {
-        use std::io::Write;
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
 
-        writeln!(self.debug, "{old:?}->{new:?} | {old_reg:x?}->{new_reg:x?}");
+    #[cfg(feature = "log_state")]
+    fn log_state(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            let thumb = self.state.cpsr.thumb();
+            let pc = self.state.gpr[15] - if thumb { 4 } else { 8 };
+            tracer.log_state(pc, self.instruction, &self.state);
+        }
+    }
+
+    #[cfg(feature = "log_state")]
+    fn log_switch_mode(&mut self, old: Bank, new: Bank, old_reg: [u32; 7], new_reg: [u32; 7]) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.log_switch_mode(old, new, old_reg, new_reg);
+        }
     }
 
     #[cfg(not(feature = "log_state"))]
@@ -197,11 +386,7 @@ impl Cpu {
     pub fn set_gpr(&mut self, reg: GPR, val: u32) {
         self.state.gpr[reg as usize] = val;
         if reg == GPR::PC {
-            if self.state.cpsr.thumb() {
-                self.thumb_flush_pipeline();
-            } else {
-                self.arm_flush_pipeline();
-            }
+            self.flush_pipeline();
         }
     }
 
@@ -217,6 +402,7 @@ impl Cpu {
     pub fn thumb_flush_pipeline(&mut self) {
         assert!(self.state.cpsr.thumb());
         self.state.gpr[15] &= !1;
+        self.check_branch_watch();
         self.pipeline[0] = self.code_read_half(self.state.gpr[15]) as u32;
         self.pipeline[1] = self.code_read_half(self.state.gpr[15] + 2) as u32;
         self.state.gpr[15] += 4;
@@ -225,29 +411,113 @@ impl Cpu {
     pub fn arm_flush_pipeline(&mut self) {
         assert!(!self.state.cpsr.thumb());
         self.state.gpr[15] &= !3;
+        self.check_branch_watch();
         self.pipeline[0] = self.code_read_word(self.state.gpr[15]);
         self.pipeline[1] = self.code_read_word(self.state.gpr[15] + 4);
         self.state.gpr[15] += 8;
     }
 
+    /// Refills the pipeline from pc in the current instruction set, leaving pc 2 instructions ahead
+    pub fn flush_pipeline(&mut self) {
+        if self.state.cpsr.thumb() {
+            self.thumb_flush_pipeline();
+        } else {
+            self.arm_flush_pipeline();
+        }
+    }
+
+    /// Moves on to the next instruction, the pipeline was already refilled by `run`
+    pub fn advance(&mut self) {
+        self.state.gpr[15] += if self.state.cpsr.thumb() { 2 } else { 4 };
+    }
+
+    /// Jumps to `addr` without changing the instruction set
+    pub fn branch_to(&mut self, addr: u32) {
+        self.state.gpr[15] = addr;
+        self.flush_pipeline();
+    }
+
+    /// Jumps to `addr`, switching to thumb when bit 0 is set and to arm otherwise
+    pub fn exchange_to(&mut self, addr: u32) {
+        self.state.cpsr.set_thumb(addr & 0x1 != 0);
+        self.branch_to(addr);
+    }
+
+    /// Jumps to a pc loaded from memory, which only interworks on the arm9
+    pub fn load_pc(&mut self, addr: u32) {
+        if self.arch == Arch::ARMv5 {
+            self.exchange_to(addr);
+        } else {
+            self.branch_to(addr);
+        }
+    }
+
     fn code_read_half(&mut self, addr: u32) -> u16 {
-        // todo: self.memory.read::<u16, { Bus::Code }>(addr)
+        self.add_access_cycles(addr, false, true);
         self.memory.read_half(addr)
     }
 
     fn code_read_word(&mut self, addr: u32) -> u32 {
-        // todo: self.memory.read::<u32, { Bus::Code }>(addr)
+        self.add_access_cycles(addr, true, true);
         self.memory.read_word(addr)
     }
 
+    fn add_access_cycles(&mut self, addr: u32, word: bool, code: bool) {
+        if !self.timing {
+            return;
+        }
+
+        let next = if code { &mut self.next_code } else { &mut self.next_data };
+        let sequential = *next == addr;
+        *next = addr.wrapping_add(if word { 4 } else { 2 });
+        self.instruction_cycles += self.memory.access_cycles(addr, word, sequential, code);
+    }
+
+    /// Cycles an instruction spends without using the bus, like the steps of a multiply
+    pub fn add_internal_cycles(&mut self, cycles: u64) {
+        if self.timing {
+            self.instruction_cycles += cycles;
+        }
+    }
+
+    pub(in crate::arm) fn read_byte(&mut self, addr: u32) -> u8 {
+        self.add_access_cycles(addr, false, false);
+        self.memory.read_byte(addr)
+    }
+
+    pub(in crate::arm) fn read_half(&mut self, addr: u32) -> u16 {
+        self.add_access_cycles(addr, false, false);
+        self.memory.read_half(addr)
+    }
+
+    pub(in crate::arm) fn read_word(&mut self, addr: u32) -> u32 {
+        self.add_access_cycles(addr, true, false);
+        self.memory.read_word(addr)
+    }
+
+    pub(in crate::arm) fn write_byte(&mut self, addr: u32, val: u8) {
+        self.add_access_cycles(addr, false, false);
+        self.memory.write_byte(addr, val)
+    }
+
+    pub(in crate::arm) fn write_half(&mut self, addr: u32, val: u16) {
+        self.add_access_cycles(addr, false, false);
+        self.memory.write_half(addr, val)
+    }
+
+    pub(in crate::arm) fn write_word(&mut self, addr: u32, val: u32) {
+        self.add_access_cycles(addr, true, false);
+        self.memory.write_word(addr, val)
+    }
+
     pub fn read_word_rotate(&mut self, addr: u32) -> u32 {
-        let val = self.memory.read_word(addr);
+        let val = self.read_word(addr);
         let amount = (addr & 0x3) * 8;
         val.rotate_right(amount)
     }
 
     pub fn read_half_rotate(&mut self, addr: u32) -> u32 {
-        let val = self.memory.read_half(addr) as u32;
+        let val = self.read_half(addr) as u32;
         if self.arch == Arch::ARMv4 && addr & 0x1 != 0 {
             return val.rotate_right(8)
         }
@@ -118,7 +118,7 @@ impl Decoder {
     }
 }
 
-fn mask<const BITS: usize>(pattern: &str) -> u32 {
+pub(super) fn mask<const BITS: usize>(pattern: &str) -> u32 {
     let mut res = 0;
 
     for (i, c) in pattern.chars().enumerate() {
@@ -130,7 +130,7 @@ fn mask<const BITS: usize>(pattern: &str) -> u32 {
     res >> (BITS - pattern.len())
 }
 
-fn value<const BITS: usize>(pattern: &str) -> u32 {
+pub(super) fn value<const BITS: usize>(pattern: &str) -> u32 {
     let mut res = 0;
 
     for (i, c) in pattern.chars().enumerate() {
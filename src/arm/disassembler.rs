@@ -0,0 +1,540 @@
+use crate::arm::decoder::{mask, value};
+use crate::arm::interpreter::instructions::*;
+use crate::arm::state::GPR;
+use crate::util::get_field;
+
+/// Formats an arm instruction at the address it was fetched from
+type ArmFormatter = fn(u32, u32) -> String;
+/// Formats a thumb instruction at the address it was fetched from, with the halfword after it
+/// for the two halves of bl
+type ThumbFormatter = fn(u32, u32, u32) -> String;
+
+const CONDITIONS: [&str; 16] = ["eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "", "nv"];
+const REGISTERS: [&str; 16] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc"];
+const SHIFTS: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+
+/// The formatting counterpart of `Decoder`, it registers the same patterns in the same order so
+/// every instruction is shown as what the interpreter executes it as
+pub struct Disassembler {
+    arm_lut: [ArmFormatter; 4096],
+    thumb_lut: [ThumbFormatter; 1024],
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        let mut arm_list: Vec<(u32, u32, ArmFormatter)> = vec![];
+        let mut register_arm = |pattern: &str, formatter: ArmFormatter| arm_list.push((mask::<32>(pattern), value::<32>(pattern), formatter));
+        register_arm("101xxxxxxxxx", arm_branch_link_maybe_exchange);
+        register_arm("000100100001", arm_branch_exchange);
+        register_arm("000101100001", arm_count_leading_zeroes);
+        register_arm("000100100011", arm_branch_link_exchange_register);
+        register_arm("00010x001001", arm_single_data_swap);
+        register_arm("000000xx1001", arm_multiply);
+        register_arm("00010xx00101", arm_saturating_add_subtract);
+        register_arm("00001xxx1001", arm_multiply_long);
+        register_arm("000xxxxx1xx1", arm_halfword_data_transfer);
+        register_arm("00010x000000", arm_status_load);
+        register_arm("00010x100000", arm_status_store);
+        register_arm("00110x10xxxx", arm_status_store);
+        register_arm("100xxxxxxxxx", arm_block_data_transfer);
+        register_arm("01xxxxxxxxxx", arm_single_data_transfer);
+        register_arm("00xxxxxxxxxx", arm_data_processing);
+        register_arm("1110xxxxxxx1", arm_coprocessor_register_transfer);
+        register_arm("1111xxxxxxxx", arm_software_interrupt);
+        register_arm("000101001xx0", arm_signed_multiply_accumulate_long);
+        register_arm("000100101xx0", arm_signed_multiply_word);
+        register_arm("00010xx01xx0", arm_signed_multiply);
+        register_arm("000100100111", arm_breakpoint);
+
+        let mut thumb_list: Vec<(u32, u32, ThumbFormatter)> = vec![];
+        let mut register_thumb = |pattern: &str, formatter: ThumbFormatter| thumb_list.push((mask::<16>(pattern), value::<16>(pattern), formatter));
+        register_thumb("001xxxxxxx", thumb_alu_immediate);
+        register_thumb("11111xxxxx", thumb_branch_link_offset);
+        register_thumb("11110xxxxx", thumb_branch_link_setup);
+        register_thumb("11101xxxxx", thumb_branch_link_exchange_offset);
+        register_thumb("11100xxxxx", thumb_branch);
+        register_thumb("1011x10xxx", thumb_push_pop);
+        register_thumb("010000xxxx", thumb_data_processing_register);
+        register_thumb("010001xxxx", thumb_special_data_processing);
+        register_thumb("010001111x", thumb_branch_link_exchange);
+        register_thumb("010001110x", thumb_branch_exchange);
+        register_thumb("0101xx0xxx", thumb_load_store_register_offset);
+        register_thumb("0101xx1xxx", thumb_load_store_signed);
+        register_thumb("01001xxxxx", thumb_load_pc);
+        register_thumb("1001xxxxxx", thumb_load_store_sp_relative);
+        register_thumb("1000xxxxxx", thumb_load_store_halfword);
+        register_thumb("00011xxxxx", thumb_add_subtract);
+        register_thumb("000xxxxxxx", thumb_shift_immediate);
+        register_thumb("11011111xx", thumb_software_interrupt);
+        register_thumb("1101xxxxxx", thumb_branch_conditional);
+        register_thumb("1100xxxxxx", thumb_load_store_multiple);
+        register_thumb("011xxxxxxx", thumb_load_store_immediate);
+        register_thumb("1010xxxxxx", thumb_add_sp_pc);
+        register_thumb("10110000xx", thumb_adjust_stack_pointer);
+
+        // the most specific pattern wins, like in the decoder
+        arm_list.sort_by(|a, b| a.0.count_ones().cmp(&b.0.count_ones()));
+        thumb_list.sort_by(|a, b| a.0.count_ones().cmp(&b.0.count_ones()));
+
+        let mut disassembler = Self {
+            arm_lut: [arm_undefined; 4096],
+            thumb_lut: [thumb_undefined; 1024],
+        };
+        for (i, entry) in disassembler.arm_lut.iter_mut().enumerate() {
+            for &(mask, value, formatter) in &arm_list {
+                if (i as u32 & mask) == value {
+                    *entry = formatter;
+                }
+            }
+        }
+        for (i, entry) in disassembler.thumb_lut.iter_mut().enumerate() {
+            for &(mask, value, formatter) in &thumb_list {
+                if (i as u32 & mask) == value {
+                    *entry = formatter;
+                }
+            }
+        }
+
+        disassembler
+    }
+
+    pub fn arm(&self, instruction: u32, addr: u32) -> String {
+        let idx = ((instruction >> 16) & 0xff0) | ((instruction >> 4) & 0xf);
+        (self.arm_lut[idx as usize])(instruction, addr)
+    }
+
+    pub fn thumb(&self, instruction: u16, next: u16, addr: u32) -> String {
+        let idx = instruction >> 6;
+        (self.thumb_lut[idx as usize])(instruction as u32, addr, next as u32)
+    }
+}
+
+fn reg(reg: GPR) -> &'static str {
+    REGISTERS[reg as usize]
+}
+
+fn cond(instruction: u32) -> &'static str {
+    CONDITIONS[(instruction >> 28) as usize]
+}
+
+fn suffix(enabled: bool, suffix: &'static str) -> &'static str {
+    if enabled {
+        suffix
+    } else {
+        ""
+    }
+}
+
+fn signed_hex(up: bool, val: u32) -> String {
+    format!("#{}0x{val:x}", suffix(!up, "-"))
+}
+
+/// `{r0-r3, lr}`, consecutive registers are merged into ranges
+fn register_list(rlist: u32) -> String {
+    let mut ranges = vec![];
+    let mut i = 0;
+    while i < 16 {
+        if rlist & (1 << i) == 0 {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < 16 && rlist & (1 << i) != 0 {
+            i += 1;
+        }
+        ranges.push(match i - start {
+            1 => REGISTERS[start].to_string(),
+            2 => format!("{}, {}", REGISTERS[start], REGISTERS[start + 1]),
+            _ => format!("{}-{}", REGISTERS[start], REGISTERS[i - 1]),
+        });
+    }
+    format!("{{{}}}", ranges.join(", "))
+}
+
+/// `rm, lsl #2`, immediate amounts of 0 mean 32 for lsr and asr and rrx for ror
+fn shifted_register(rm: GPR, shift_type: ShiftType, amount: u32) -> String {
+    let shift = shift_type as usize;
+    match (shift, amount) {
+        (0, 0) => reg(rm).to_string(),
+        (1 | 2, 0) => format!("{}, {} #32", reg(rm), SHIFTS[shift]),
+        (3, 0) => format!("{}, rrx", reg(rm)),
+        (shift, amount) => format!("{}, {} #{amount}", reg(rm), SHIFTS[shift]),
+    }
+}
+
+/// `[rn, offset]{!}` when indexing before the access, `[rn], offset` after
+fn address(rn: GPR, offset: Option<String>, pre: bool, writeback: bool) -> String {
+    match (offset, pre) {
+        (None, _) => format!("[{}]", reg(rn)),
+        (Some(offset), true) => format!("[{}, {offset}]{}", reg(rn), suffix(writeback, "!")),
+        (Some(offset), false) => format!("[{}], {offset}", reg(rn)),
+    }
+}
+
+fn arm_undefined(instruction: u32, _: u32) -> String {
+    format!("undefined {instruction:08x}")
+}
+
+fn arm_branch_link_maybe_exchange(instruction: u32, addr: u32) -> String {
+    if instruction >> 28 == 0xf {
+        let ArmBranchLinkExchange { offset } = ArmBranchLinkExchange::decode(instruction);
+        return format!("blx 0x{:08x}", addr.wrapping_add(8).wrapping_add(offset));
+    }
+
+    let ArmBranchLink { link, offset, .. } = ArmBranchLink::decode(instruction);
+    format!("b{}{} 0x{:08x}", suffix(link, "l"), cond(instruction), addr.wrapping_add(8).wrapping_add(offset))
+}
+
+fn arm_branch_exchange(instruction: u32, _: u32) -> String {
+    let ArmBranchExchange { rm } = ArmBranchExchange::decode(instruction);
+    format!("bx{} {}", cond(instruction), reg(rm))
+}
+
+fn arm_count_leading_zeroes(instruction: u32, _: u32) -> String {
+    let ArmCountLeadingZeros { rm, rd } = ArmCountLeadingZeros::decode(instruction);
+    format!("clz{} {}, {}", cond(instruction), reg(rd), reg(rm))
+}
+
+fn arm_branch_link_exchange_register(instruction: u32, _: u32) -> String {
+    let ArmBranchExchange { rm } = ArmBranchExchange::decode(instruction);
+    format!("blx{} {}", cond(instruction), reg(rm))
+}
+
+fn arm_single_data_swap(instruction: u32, _: u32) -> String {
+    let ArmSingleDataSwap { rm, rd, rn, byte } = ArmSingleDataSwap::decode(instruction);
+    format!("swp{}{} {}, {}, [{}]", cond(instruction), suffix(byte, "b"), reg(rd), reg(rm), reg(rn))
+}
+
+fn arm_multiply(instruction: u32, _: u32) -> String {
+    let ArmMultiply { set_flags, accumulate, rm, rs, rn, rd } = ArmMultiply::decode(instruction);
+    let s = suffix(set_flags, "s");
+    if accumulate {
+        format!("mla{}{s} {}, {}, {}, {}", cond(instruction), reg(rd), reg(rm), reg(rs), reg(rn))
+    } else {
+        format!("mul{}{s} {}, {}, {}", cond(instruction), reg(rd), reg(rm), reg(rs))
+    }
+}
+
+fn arm_saturating_add_subtract(instruction: u32, _: u32) -> String {
+    let ArmSaturatingAddSubtract { rm, rd, rn, sub, double_rhs } = ArmSaturatingAddSubtract::decode(instruction);
+    let op = match (double_rhs, sub) {
+        (false, false) => "qadd",
+        (false, true) => "qsub",
+        (true, false) => "qdadd",
+        (true, true) => "qdsub",
+    };
+    format!("{op}{} {}, {}, {}", cond(instruction), reg(rd), reg(rm), reg(rn))
+}
+
+fn arm_multiply_long(instruction: u32, _: u32) -> String {
+    let ArmMultiplyLong { set_flags, accumulate, sign, rm, rs, rdlo, rdhi } = ArmMultiplyLong::decode(instruction);
+    format!(
+        "{}{}{}{} {}, {}, {}, {}",
+        if sign { "s" } else { "u" },
+        if accumulate { "mlal" } else { "mull" },
+        cond(instruction),
+        suffix(set_flags, "s"),
+        reg(rdlo),
+        reg(rdhi),
+        reg(rm),
+        reg(rs)
+    )
+}
+
+fn arm_halfword_data_transfer(instruction: u32, _: u32) -> String {
+    let ArmHalfwordDataTransfer { load, writeback, up, pre, half, sign, rd, rn, rhs } = ArmHalfwordDataTransfer::decode(instruction);
+    let op = match (load, sign, half) {
+        (true, false, _) => "ldrh",
+        (true, true, false) => "ldrsb",
+        (true, true, true) => "ldrsh",
+        (false, false, _) => "strh",
+        (false, true, false) => "ldrd",
+        (false, true, true) => "strd",
+    };
+    let offset = match rhs {
+        ArmHalfwordDataTransferRhs::Imm(0) => None,
+        ArmHalfwordDataTransferRhs::Imm(imm) => Some(signed_hex(up, imm)),
+        ArmHalfwordDataTransferRhs::Reg(rm) => Some(format!("{}{}", suffix(!up, "-"), reg(rm))),
+    };
+    format!("{op}{} {}, {}", cond(instruction), reg(rd), address(rn, offset, pre, writeback))
+}
+
+fn arm_status_load(instruction: u32, _: u32) -> String {
+    let ArmStatusLoad { spsr, rd } = ArmStatusLoad::decode(instruction);
+    format!("mrs{} {}, {}", cond(instruction), reg(rd), if spsr { "spsr" } else { "cpsr" })
+}
+
+fn arm_status_store(instruction: u32, _: u32) -> String {
+    let ArmStatusStore { spsr, mask, rhs } = ArmStatusStore::decode(instruction);
+    let fields = [(0xff000000, 'f'), (0x00ff0000, 's'), (0x0000ff00, 'x'), (0x000000ff, 'c')]
+        .into_iter()
+        .filter(|&(bits, _)| mask & bits != 0)
+        .map(|(_, field)| field)
+        .collect::<String>();
+    let rhs = match rhs {
+        ArmStatusStoreRhs::Imm(imm) => format!("#0x{imm:x}"),
+        ArmStatusStoreRhs::Reg(rm) => reg(rm).to_string(),
+    };
+    format!("msr{} {}_{fields}, {rhs}", cond(instruction), if spsr { "spsr" } else { "cpsr" })
+}
+
+fn arm_block_data_transfer(instruction: u32, _: u32) -> String {
+    let ArmBlockDataTransfer { rlist, load, writeback, psr, up, pre, rn, .. } = ArmBlockDataTransfer::decode(instruction);
+    let mode = match (pre, up) {
+        (false, true) => "ia",
+        (true, true) => "ib",
+        (false, false) => "da",
+        (true, false) => "db",
+    };
+    format!(
+        "{}{}{mode} {}{}, {}{}",
+        if load { "ldm" } else { "stm" },
+        cond(instruction),
+        reg(rn),
+        suffix(writeback, "!"),
+        register_list(rlist),
+        suffix(psr, "^")
+    )
+}
+
+fn arm_single_data_transfer(instruction: u32, _: u32) -> String {
+    let ArmSingleDataTransfer { load, writeback, byte, up, pre, rd, rn, rhs, .. } = ArmSingleDataTransfer::decode(instruction);
+    let offset = match rhs {
+        ArmSingleDataTransferRhs::Imm(0) => None,
+        ArmSingleDataTransferRhs::Imm(imm) => Some(signed_hex(up, imm)),
+        ArmSingleDataTransferRhs::Reg { rm, shift_type, amount } => Some(format!("{}{}", suffix(!up, "-"), shifted_register(rm, shift_type, amount))),
+    };
+    format!(
+        "{}{}{} {}, {}",
+        if load { "ldr" } else { "str" },
+        cond(instruction),
+        suffix(byte, "b"),
+        reg(rd),
+        address(rn, offset, pre, writeback)
+    )
+}
+
+fn arm_data_processing(instruction: u32, _: u32) -> String {
+    const OPCODES: [&str; 16] = ["and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc", "tst", "teq", "cmp", "cmn", "orr", "mov", "bic", "mvn"];
+    let ArmDataProcessing { set_flags, rd, rn, opcode, rhs, .. } = ArmDataProcessing::decode(instruction);
+    let rhs = match rhs {
+        ArmDataProcessingRhs::Imm { rotated, .. } => format!("#0x{rotated:x}"),
+        ArmDataProcessingRhs::Reg { rm, shift_type, amount: ArmDataProcessingAmount::Rs(rs) } => {
+            format!("{}, {} {}", reg(rm), SHIFTS[shift_type as usize], reg(rs))
+        }
+        ArmDataProcessingRhs::Reg { rm, shift_type, amount: ArmDataProcessingAmount::Imm(amount) } => shifted_register(rm, shift_type, amount as u32),
+    };
+
+    let opcode = opcode as usize;
+    let op = OPCODES[opcode];
+    match opcode {
+        // the compares always set the flags
+        8..=11 => format!("{op}{} {}, {rhs}", cond(instruction), reg(rn)),
+        13 | 15 => format!("{op}{}{} {}, {rhs}", cond(instruction), suffix(set_flags, "s"), reg(rd)),
+        _ => format!("{op}{}{} {}, {}, {rhs}", cond(instruction), suffix(set_flags, "s"), reg(rd), reg(rn)),
+    }
+}
+
+fn arm_coprocessor_register_transfer(instruction: u32, _: u32) -> String {
+    let ArmCoprocessorRegisterTransfer { crm, crn, cp, rd, load } = ArmCoprocessorRegisterTransfer::decode(instruction);
+    format!(
+        "{}{} p{}, {}, {}, c{}, c{}, {cp}",
+        if load { "mrc" } else { "mcr" },
+        cond(instruction),
+        get_field::<8, 4>(instruction),
+        get_field::<21, 3>(instruction),
+        reg(rd),
+        crn as u8,
+        crm as u8
+    )
+}
+
+fn arm_software_interrupt(instruction: u32, _: u32) -> String {
+    format!("swi{} 0x{:x}", cond(instruction), instruction & 0xffffff)
+}
+
+fn half_suffix(top: bool) -> &'static str {
+    if top {
+        "t"
+    } else {
+        "b"
+    }
+}
+
+fn arm_signed_multiply_accumulate_long(instruction: u32, _: u32) -> String {
+    let ArmSignedMultiplyAccumulateLong { rm, rs, rn, rd, x, y } = ArmSignedMultiplyAccumulateLong::decode(instruction);
+    format!("smlal{}{}{} {}, {}, {}, {}", half_suffix(x), half_suffix(y), cond(instruction), reg(rn), reg(rd), reg(rm), reg(rs))
+}
+
+fn arm_signed_multiply_word(instruction: u32, _: u32) -> String {
+    let ArmSignedMultiplyWord { rm, rs, rn, rd, accumulate, y } = ArmSignedMultiplyWord::decode(instruction);
+    if accumulate {
+        format!("smlaw{}{} {}, {}, {}, {}", half_suffix(y), cond(instruction), reg(rd), reg(rm), reg(rs), reg(rn))
+    } else {
+        format!("smulw{}{} {}, {}, {}", half_suffix(y), cond(instruction), reg(rd), reg(rm), reg(rs))
+    }
+}
+
+fn arm_signed_multiply(instruction: u32, _: u32) -> String {
+    let ArmSignedMultiply { rm, rs, rn, rd, accumulate, x, y } = ArmSignedMultiply::decode(instruction);
+    let halves = format!("{}{}{}", half_suffix(x), half_suffix(y), cond(instruction));
+    if accumulate {
+        format!("smla{halves} {}, {}, {}, {}", reg(rd), reg(rm), reg(rs), reg(rn))
+    } else {
+        format!("smul{halves} {}, {}, {}", reg(rd), reg(rm), reg(rs))
+    }
+}
+
+fn arm_breakpoint(instruction: u32, _: u32) -> String {
+    format!("bkpt 0x{:x}", ((instruction >> 4) & 0xfff0) | (instruction & 0xf))
+}
+
+fn thumb_undefined(instruction: u32, _: u32, _: u32) -> String {
+    format!("undefined {instruction:04x}")
+}
+
+fn thumb_alu_immediate(instruction: u32, _: u32, _: u32) -> String {
+    const OPCODES: [&str; 4] = ["mov", "cmp", "add", "sub"];
+    let ThumbALUImmediate { imm, rd, opcode } = ThumbALUImmediate::decode(instruction);
+    format!("{} {}, #0x{imm:x}", OPCODES[opcode as usize], reg(rd))
+}
+
+fn thumb_branch_link_offset(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbBranchLinkOffset { offset } = ThumbBranchLinkOffset::decode(instruction);
+    format!("bl lr + 0x{offset:x}")
+}
+
+/// Shows the whole call when the second half follows, which is how compilers emit it
+fn thumb_branch_link_setup(instruction: u32, addr: u32, next: u32) -> String {
+    let ThumbBranchLinkSetup { imm } = ThumbBranchLinkSetup::decode(instruction);
+    let target = addr.wrapping_add(4).wrapping_add(imm).wrapping_add(get_field::<0, 11>(next) << 1);
+    match next >> 11 {
+        0b11111 => format!("bl 0x{target:08x}"),
+        0b11101 => format!("blx 0x{:08x}", target & !3),
+        _ => format!("bl setup lr = pc + 0x{imm:x}"),
+    }
+}
+
+fn thumb_branch_link_exchange_offset(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbBranchLinkExchangeOffset { offset } = ThumbBranchLinkExchangeOffset::decode(instruction);
+    format!("blx lr + 0x{offset:x}")
+}
+
+fn thumb_branch(instruction: u32, addr: u32, _: u32) -> String {
+    let ThumbBranch { offset } = ThumbBranch::decode(instruction);
+    format!("b 0x{:08x}", addr.wrapping_add(4).wrapping_add(offset))
+}
+
+fn thumb_push_pop(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbPushPop { rlist, pclr, pop } = ThumbPushPop::decode(instruction);
+    let extra = match (pclr, pop) {
+        (false, _) => 0,
+        (true, true) => 1 << 15,
+        (true, false) => 1 << 14,
+    };
+    format!("{} {}", if pop { "pop" } else { "push" }, register_list(rlist as u32 | extra))
+}
+
+fn thumb_data_processing_register(instruction: u32, _: u32, _: u32) -> String {
+    const OPCODES: [&str; 16] = ["and", "eor", "lsl", "lsr", "asr", "adc", "sbc", "ror", "tst", "neg", "cmp", "cmn", "orr", "mul", "bic", "mvn"];
+    let ThumbDataProcessingRegister { rd, rs, opcode } = ThumbDataProcessingRegister::decode(instruction);
+    format!("{} {}, {}", OPCODES[opcode as usize], reg(rd), reg(rs))
+}
+
+fn thumb_special_data_processing(instruction: u32, _: u32, _: u32) -> String {
+    const OPCODES: [&str; 3] = ["add", "cmp", "mov"];
+    let ThumbSpecialDataProcessing { rd, rs, opcode } = ThumbSpecialDataProcessing::decode(instruction);
+    format!("{} {}, {}", OPCODES[opcode as usize], reg(rd), reg(rs))
+}
+
+fn thumb_branch_link_exchange(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbBranchLinkExchange { rm } = ThumbBranchLinkExchange::decode(instruction);
+    format!("blx {}", reg(rm))
+}
+
+fn thumb_branch_exchange(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbBranchExchange { rm } = ThumbBranchExchange::decode(instruction);
+    format!("bx {}", reg(rm))
+}
+
+fn thumb_load_store_register_offset(instruction: u32, _: u32, _: u32) -> String {
+    const OPCODES: [&str; 4] = ["str", "strb", "ldr", "ldrb"];
+    let ThumbLoadStoreRegisterOffset { rd, rn, rm, opcode } = ThumbLoadStoreRegisterOffset::decode(instruction);
+    format!("{} {}, [{}, {}]", OPCODES[opcode as usize], reg(rd), reg(rn), reg(rm))
+}
+
+fn thumb_load_store_signed(instruction: u32, _: u32, _: u32) -> String {
+    const OPCODES: [&str; 4] = ["strh", "ldrsb", "ldrh", "ldrsh"];
+    let ThumbLoadStoreSigned { rd, rn, rm, opcode } = ThumbLoadStoreSigned::decode(instruction);
+    format!("{} {}, [{}, {}]", OPCODES[opcode as usize], reg(rd), reg(rn), reg(rm))
+}
+
+/// Shows the address of the literal rather than the offset from pc
+fn thumb_load_pc(instruction: u32, addr: u32, _: u32) -> String {
+    let ThumbLoadPC { imm, rd } = ThumbLoadPC::decode(instruction);
+    format!("ldr {}, [0x{:08x}]", reg(rd), (addr.wrapping_add(4) & !0x2).wrapping_add(imm))
+}
+
+fn thumb_load_store_sp_relative(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbLoadStoreSPRelative { imm, rd, load } = ThumbLoadStoreSPRelative::decode(instruction);
+    format!("{} {}, [sp, #0x{:x}]", if load { "ldr" } else { "str" }, reg(rd), imm << 2)
+}
+
+fn thumb_load_store_halfword(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbLoadStoreHalfword { rd, rn, imm, load } = ThumbLoadStoreHalfword::decode(instruction);
+    format!("{} {}, [{}, #0x{:x}]", if load { "ldrh" } else { "strh" }, reg(rd), reg(rn), imm << 1)
+}
+
+fn thumb_add_subtract(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbAddSubtract { rd, rs, rn, sub, imm } = ThumbAddSubtract::decode(instruction);
+    let rhs = if imm { format!("#{}", rn as u8) } else { reg(rn).to_string() };
+    format!("{} {}, {}, {rhs}", if sub { "sub" } else { "add" }, reg(rd), reg(rs))
+}
+
+fn thumb_shift_immediate(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbShiftImmediate { rd, rs, amount, shift_type } = ThumbShiftImmediate::decode(instruction);
+    let shift = shift_type as usize;
+    // lsr and asr by 0 mean 32
+    let amount = if amount == 0 && shift != 0 { 32 } else { amount };
+    format!("{} {}, {}, #{amount}", SHIFTS[shift], reg(rd), reg(rs))
+}
+
+fn thumb_software_interrupt(instruction: u32, _: u32, _: u32) -> String {
+    format!("swi 0x{:x}", instruction & 0xff)
+}
+
+fn thumb_branch_conditional(instruction: u32, addr: u32, _: u32) -> String {
+    let ThumbBranchConditional { condition, offset } = ThumbBranchConditional::decode(instruction);
+    format!("b{} 0x{:08x}", CONDITIONS[condition as usize], addr.wrapping_add(4).wrapping_add(offset))
+}
+
+fn thumb_load_store_multiple(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbLoadStoreMultiple { rlist, rn, load } = ThumbLoadStoreMultiple::decode(instruction);
+    format!("{} {}!, {}", if load { "ldmia" } else { "stmia" }, reg(rn), register_list(rlist as u32))
+}
+
+fn thumb_load_store_immediate(instruction: u32, _: u32, _: u32) -> String {
+    const OPCODES: [&str; 4] = ["str", "ldr", "strb", "ldrb"];
+    let ThumbLoadStoreImmediate { rd, rn, imm, opcode } = ThumbLoadStoreImmediate::decode(instruction);
+    let opcode = opcode as usize;
+    // word accesses scale the offset
+    let imm = if opcode < 2 { imm << 2 } else { imm };
+    format!("{} {}, [{}, #0x{imm:x}]", OPCODES[opcode], reg(rd), reg(rn))
+}
+
+fn thumb_add_sp_pc(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbAddSPPC { imm, rd, sp } = ThumbAddSPPC::decode(instruction);
+    format!("add {}, {}, #0x{imm:x}", reg(rd), if sp { "sp" } else { "pc" })
+}
+
+fn thumb_adjust_stack_pointer(instruction: u32, _: u32, _: u32) -> String {
+    let ThumbAdjustStackPointer { imm, sub } = ThumbAdjustStackPointer::decode(instruction);
+    format!("{} sp, #0x{imm:x}", if sub { "sub" } else { "add" })
+}
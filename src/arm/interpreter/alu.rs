@@ -1,4 +1,4 @@
-use crate::arm::cpu::Cpu;
+use crate::arm::cpu::{Arch, Cpu};
 use crate::arm::interpreter::instructions::ShiftType;
 
 impl Cpu {
@@ -109,6 +109,34 @@ impl Cpu {
         result
     }
 
+    /// The arm7 leaves a meaningless value from its booth multiplier in carry, which is approximated as cleared.
+    /// The arm9 leaves carry alone
+    pub fn set_multiply_flags(&mut self, negative: bool, zero: bool) {
+        self.state.cpsr.set_n(negative);
+        self.state.cpsr.set_z(zero);
+        if self.arch == Arch::ARMv4 {
+            self.state.cpsr.set_c(false);
+        }
+    }
+
+    /// Internal cycles spent by a multiply with `rs` as the multiplier. The arm7 stops early once the remaining
+    /// bits of `rs` are all zeroes (or all ones for signed multiplies), the arm9 always takes the same time
+    pub fn multiply_cycles(&self, rs: u32, signed: bool, long: bool, accumulate: bool, set_flags: bool) -> u64 {
+        match self.arch {
+            Arch::ARMv4 => {
+                let mut m = 4;
+                for (i, mask) in [0xffffff00, 0xffff0000, 0xff000000].into_iter().enumerate() {
+                    if rs & mask == 0 || (signed && rs & mask == mask) {
+                        m = i as u64 + 1;
+                        break;
+                    }
+                }
+                m + long as u64 + accumulate as u64
+            }
+            Arch::ARMv5 => 1 + long as u64 + if set_flags { 2 } else { 0 },
+        }
+    }
+
     // todo: can this be replaced with overflowing_shl ???
     pub fn alu_lsl(&mut self, val: u32, amt: u32, carry: &mut bool) -> u32 {
         if amt == 0 {
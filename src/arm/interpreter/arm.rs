@@ -1,9 +1,9 @@
 use log::{error, warn};
 
 use crate::arm::cpu::{Arch, Cpu};
-use crate::arm::interpreter::alu::{add_overflow, sub_overflow};
 use crate::arm::interpreter::instructions::*;
 use crate::arm::state::{Bank, Mode, GPR};
+use crate::unimplemented_feature;
 use crate::util::sign_extend;
 
 #[allow(dead_code)]
@@ -18,14 +18,7 @@ impl Cpu {
 
     pub(in crate::arm) fn arm_branch_exchange(&mut self, instruction: u32) {
         let ArmBranchExchange { rm } = ArmBranchExchange::decode(instruction);
-        if self.state.gpr[rm as usize] & 1 != 0 {
-            self.state.cpsr.set_thumb(true);
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !1;
-            self.thumb_flush_pipeline();
-        } else {
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !3;
-            self.arm_flush_pipeline();
-        }
+        self.exchange_to(self.state.gpr[rm as usize]);
     }
 
     fn arm_branch_link(&mut self, instruction: u32) {
@@ -52,8 +45,7 @@ impl Cpu {
                 }
             }
         }
-        self.state.gpr[15] += offset;
-        self.arm_flush_pipeline();
+        self.branch_to(self.state.gpr[15] + offset);
     }
 
     fn arm_branch_link_exchange(&mut self, instruction: u32) {
@@ -64,8 +56,7 @@ impl Cpu {
         let ArmBranchLinkExchange { offset } = ArmBranchLinkExchange::decode(instruction);
         self.state.gpr[14] = self.state.gpr[15] - 4;
         self.state.cpsr.set_thumb(true);
-        self.state.gpr[15] += offset;
-        self.thumb_flush_pipeline();
+        self.branch_to(self.state.gpr[15] + offset);
     }
 
     pub(in crate::arm) fn arm_count_leading_zeroes(&mut self, instruction: u32) {
@@ -75,7 +66,7 @@ impl Cpu {
 
         let ArmCountLeadingZeros { rm, rd } = ArmCountLeadingZeros::decode(instruction);
         self.state.gpr[rd as usize] = self.state.gpr[rm as usize].leading_zeros();
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_branch_link_exchange_register(&mut self, instruction: u32) {
@@ -85,14 +76,7 @@ impl Cpu {
 
         let ArmBranchExchange { rm } = ArmBranchExchange::decode(instruction);
         self.state.gpr[14] = self.state.gpr[15] - 4;
-        if self.state.gpr[rm as usize] & 0x1 != 0 {
-            self.state.cpsr.set_thumb(true);
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !0x1;
-            self.thumb_flush_pipeline();
-        } else {
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !0x3;
-            self.arm_flush_pipeline();
-        }
+        self.exchange_to(self.state.gpr[rm as usize]);
     }
 
     pub(in crate::arm) fn arm_single_data_swap(&mut self, instruction: u32) {
@@ -101,15 +85,15 @@ impl Cpu {
         let data;
 
         if byte {
-            data = self.memory.read_byte(addr) as u32;
-            self.memory.write_byte(addr, self.state.gpr[rm as usize] as u8);
+            data = self.read_byte(addr) as u32;
+            self.write_byte(addr, self.state.gpr[rm as usize] as u8);
         } else {
             data = self.read_word_rotate(addr);
-            self.memory.write_word(addr, self.state.gpr[rm as usize]);
+            self.write_word(addr, self.state.gpr[rm as usize]);
         }
 
         self.state.gpr[rd as usize] = data;
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_multiply(&mut self, instruction: u32) {
@@ -121,18 +105,19 @@ impl Cpu {
             rn,
             rd,
         } = ArmMultiply::decode(instruction);
-        let mut result = self.state.gpr[rm as usize] * self.state.gpr[rs as usize];
+        let mut result = self.state.gpr[rm as usize].wrapping_mul(self.state.gpr[rs as usize]);
+        self.add_internal_cycles(self.multiply_cycles(self.state.gpr[rs as usize], true, false, accumulate, set_flags));
 
         if accumulate {
-            result += self.state.gpr[rn as usize]
+            result = result.wrapping_add(self.state.gpr[rn as usize]);
         }
 
         if set_flags {
-            self.set_nz(result)
+            self.set_multiply_flags(result >> 31 != 0, result == 0);
         }
 
         self.state.gpr[rd as usize] = result;
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_saturating_add_subtract(&mut self, instruction: u32) {
@@ -147,39 +132,38 @@ impl Cpu {
             sub,
             double_rhs,
         } = ArmSaturatingAddSubtract::decode(instruction);
-        let lhs = self.state.gpr[rm as usize];
-        let mut rhs = self.state.gpr[rn as usize];
-
-        if rd == GPR::PC {
-            todo!("handle rd == 15")
-        }
+        let lhs = self.state.gpr[rm as usize] as i32;
+        let mut rhs = self.state.gpr[rn as usize] as i32;
+        let mut saturated = false;
+        let mut saturate = |checked: Option<i32>, clamped: i32| {
+            checked.unwrap_or_else(|| {
+                saturated = true;
+                clamped
+            })
+        };
 
+        // qdadd and qdsub saturate the doubled operand first, both steps can set q
         if double_rhs {
-            let mut result = rhs + rhs;
-            if (rhs ^ result) >> 31 != 0 {
-                self.state.cpsr.set_q(true);
-                result = 0x80000000 - (result >> 31);
-            }
-            rhs = result;
+            rhs = saturate(rhs.checked_mul(2), rhs.saturating_mul(2));
         }
 
-        self.state.gpr[rd as usize] = if sub {
-            let mut result = lhs - rhs;
-            if sub_overflow(lhs, rhs, result) {
-                self.state.cpsr.set_q(true);
-                result = 0x80000000 - (result >> 31);
-            }
-            result
+        let result = if sub {
+            saturate(lhs.checked_sub(rhs), lhs.saturating_sub(rhs))
         } else {
-            let mut result = lhs + rhs;
-            if add_overflow(lhs, rhs, result) {
-                self.state.cpsr.set_q(true);
-                result = 0x80000000 - (result >> 31);
-            }
-            result
-        };
+            saturate(lhs.checked_add(rhs), lhs.saturating_add(rhs))
+        } as u32;
+
+        if saturated {
+            self.state.cpsr.set_q(true);
+        }
 
-        self.state.gpr[15] += 4;
+        // unpredictable on hardware, treat it like any other alu write to pc
+        if rd == GPR::PC {
+            self.branch_to(result);
+        } else {
+            self.state.gpr[rd as usize] = result;
+            self.advance();
+        }
     }
 
     pub(in crate::arm) fn arm_multiply_long(&mut self, instruction: u32) {
@@ -193,28 +177,25 @@ impl Cpu {
             rdhi,
         } = ArmMultiplyLong::decode(instruction);
 
-        const fn sign_extend(x: u32) -> i64 {
-            (x as i64).wrapping_shl(32).wrapping_shr(32)
-        }
-
         let mut result = if sign {
-            sign_extend(self.state.gpr[rm as usize]) * sign_extend(self.state.gpr[rs as usize])
+            (self.state.gpr[rm as usize] as i32 as i64).wrapping_mul(self.state.gpr[rs as usize] as i32 as i64) as u64
         } else {
-            (self.state.gpr[rm as usize] as i64) * (self.state.gpr[rs as usize] as i64)
+            (self.state.gpr[rm as usize] as u64).wrapping_mul(self.state.gpr[rs as usize] as u64)
         };
 
+        self.add_internal_cycles(self.multiply_cycles(self.state.gpr[rs as usize], sign, true, accumulate, set_flags));
+
         if accumulate {
-            result += ((self.state.gpr[rdhi as usize] as i64) << 32) | (self.state.gpr[rdlo as usize] as i64)
+            result = result.wrapping_add(((self.state.gpr[rdhi as usize] as u64) << 32) | (self.state.gpr[rdlo as usize] as u64));
         }
 
         if set_flags {
-            self.state.cpsr.set_n(result >> 63 != 0);
-            self.state.cpsr.set_z(result == 0);
+            self.set_multiply_flags(result >> 63 != 0, result == 0);
         }
 
         self.state.gpr[rdhi as usize] = (result >> 32) as u32;
         self.state.gpr[rdlo as usize] = (result & 0xffffffff) as u32;
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_halfword_data_transfer(&mut self, instruction: u32) {
@@ -231,7 +212,7 @@ impl Cpu {
         } = ArmHalfwordDataTransfer::decode(instruction);
 
         if rd == GPR::PC {
-            error!("Interpreter: handle rd == 15 in arm_halfword_data_transfer")
+            unimplemented_feature!("Interpreter: handle rd == 15 in arm_halfword_data_transfer")
         }
 
         let mut addr = self.state.gpr[rn as usize];
@@ -249,38 +230,38 @@ impl Cpu {
             addr += op2;
         }
 
-        self.state.gpr[15] += 4;
+        self.advance();
 
         match (half, sign) {
             (true, true) => {
                 if load {
-                    self.state.gpr[rd as usize] = sign_extend::<16>(self.memory.read_half(addr) as _);
+                    self.state.gpr[rd as usize] = sign_extend::<16>(self.read_half(addr) as _);
                 } else if self.arch == Arch::ARMv5 {
                     if rd as usize & 1 != 0 {
                         error!("Interpreter: undefined strd exception")
                     }
 
-                    self.memory.write_word(addr, self.state.gpr[rd as usize]);
-                    self.memory.write_word(addr + 4, self.state.gpr[rd as usize + 1]);
+                    self.write_word(addr, self.state.gpr[rd as usize]);
+                    self.write_word(addr + 4, self.state.gpr[rd as usize + 1]);
                 }
             }
             (true, _) => {
                 if load {
                     self.state.gpr[rd as usize] = self.read_half_rotate(addr);
                 } else {
-                    self.memory.write_half(addr, self.state.gpr[rd as usize] as u16);
+                    self.write_half(addr, self.state.gpr[rd as usize] as u16);
                 }
             }
             (_, true) => {
                 if load {
-                    self.state.gpr[rd as usize] = sign_extend::<8>(self.memory.read_byte(addr) as u32);
+                    self.state.gpr[rd as usize] = sign_extend::<8>(self.read_byte(addr) as u32);
                 } else if self.arch == Arch::ARMv5 {
                     if rd as usize & 0x1 != 0 {
                         error!("Interpreter: undefined ldrd exception")
                     }
 
-                    self.state.gpr[rd as usize] = self.memory.read_word(addr);
-                    self.state.gpr[rd as usize + 1] = self.memory.read_word(addr + 4);
+                    self.state.gpr[rd as usize] = self.read_word(addr);
+                    self.state.gpr[rd as usize + 1] = self.read_word(addr + 4);
 
                     do_writeback = rn as usize != (rd as usize + 1);
 
@@ -308,7 +289,7 @@ impl Cpu {
         } else {
             self.state.gpr[rd as usize] = self.state.cpsr.0;
         }
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_status_store_register(&mut self, instruction: u32) {
@@ -318,17 +299,49 @@ impl Cpu {
             ArmStatusStoreRhs::Reg(rm) => self.state.gpr[rm as usize],
         };
 
+        self.write_status_register(spsr, mask, val);
+        self.advance();
+    }
+
+    /// Applies an msr write. Only the flags can be changed from user mode, the thumb bit is never written to the cpsr
+    /// and bits that don't exist on the current architecture stay zero
+    fn write_status_register(&mut self, spsr: bool, mask: u32, val: u32) {
+        let valid = if self.arch == Arch::ARMv5 { 0xf80000ff } else { 0xf00000ff };
+        let privileged = self.state.cpsr.mode() != Mode::User;
+        let mut mask = mask & valid;
+
         if spsr {
+            // user and system mode don't have an spsr
+            if self.state.cpsr.mode().bank() == Bank::USR {
+                return warn!("Interpreter: msr to spsr in mode {:?}", self.state.cpsr.mode());
+            }
+
             let spsr = self.state.spsr_mut();
             spsr.0 = (spsr.0 & !mask) | (val & mask);
         } else {
-            if mask & 0xff != 0 {
-                self.switch_mode((val & 0x1f).into())
+            if !privileged {
+                mask &= 0xff000000;
             }
+            mask &= !0x20;
+
+            let val = if mask & 0xff != 0 { self.write_cpsr_mode(val) } else { val };
             self.state.cpsr.0 = (self.state.cpsr.0 & !mask) | (val & mask);
         }
+    }
 
-        self.state.gpr[15] += 4;
+    /// Switches to the mode in the low bits of an msr value. The arm946e-s and arm7tdmi don't define what
+    /// happens with an invalid mode, so those writes leave the mode bits as they were instead of corrupting the banks
+    fn write_cpsr_mode(&mut self, val: u32) -> u32 {
+        match Mode::from_bits(val) {
+            Some(mode) => {
+                self.switch_mode(mode);
+                val
+            }
+            None => {
+                warn!("Interpreter: msr to invalid mode {:02x} at {:08x}", val & 0x1f, self.state.gpr[15] - 8);
+                (val & !0x1f) | (self.state.cpsr.0 & 0x1f)
+            }
+        }
     }
 
     pub(in crate::arm) fn arm_status_store_immediate(&mut self, instruction: u32) {
@@ -338,17 +351,8 @@ impl Cpu {
             ArmStatusStoreRhs::Reg(_) => unreachable!(),
         };
 
-        if spsr {
-            let spsr = self.state.spsr_mut();
-            spsr.0 = (spsr.0 & !mask) | (val & mask);
-        } else {
-            if mask & 0xff != 0 {
-                self.switch_mode((val & 0x1f).into())
-            }
-            self.state.cpsr.0 = (self.state.cpsr.0 & !mask) | (val & mask)
-        }
-
-        self.state.gpr[15] += 4;
+        self.write_status_register(spsr, mask, val);
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_block_data_transfer(&mut self, instruction: u32) {
@@ -377,8 +381,10 @@ impl Cpu {
                 }
             }
         } else {
+            // an empty list still moves the base by 16 words, only the arm7 transfers r15
             bytes = 0x40;
             if self.arch == Arch::ARMv4 {
+                first = 15;
                 rlist = 1 << 15;
                 r15_in_rlist = true;
             }
@@ -392,7 +398,7 @@ impl Cpu {
             new_base = addr;
         }
 
-        self.state.gpr[15] += 4;
+        self.advance();
 
         if writeback & !load {
             if self.arch == Arch::ARMv4 && first != rn as _ {
@@ -415,9 +421,9 @@ impl Cpu {
             }
 
             if load {
-                self.state.gpr[i] = self.memory.read_word(addr);
+                self.state.gpr[i] = self.read_word(addr);
             } else {
-                self.memory.write_word(addr, self.state.gpr[i]);
+                self.write_word(addr, self.state.gpr[i]);
             }
 
             if !pre {
@@ -441,19 +447,21 @@ impl Cpu {
             }
         }
 
+        // writeback still goes to the user bank, like the transfers
         if user_switch_mode {
             self.switch_mode(old_mode);
-            if load && r15_in_rlist {
-                todo!("Interpreter: handle loading into r15 in user mode")
-            }
         }
 
         if load && r15_in_rlist {
-            if self.arch == Arch::ARMv5 && self.state.gpr[15] & 1 != 0 {
-                self.state.cpsr.set_thumb(true);
-                self.thumb_flush_pipeline();
+            if psr {
+                // ldm with the s bit and r15 is an exception return, the state comes from the spsr
+                // instead of bit 0 of the loaded value
+                let spsr = *self.state.spsr();
+                self.switch_mode(spsr.mode());
+                self.state.cpsr = spsr;
+                self.flush_pipeline();
             } else {
-                self.arm_flush_pipeline();
+                self.load_pc(self.state.gpr[15]);
             }
         }
     }
@@ -489,19 +497,19 @@ impl Cpu {
             addr += op2;
         }
 
-        self.state.gpr[15] += 4;
+        self.advance();
 
         if load {
             if byte {
-                self.state.gpr[rd as usize] = self.memory.read_byte(addr) as u32;
+                self.state.gpr[rd as usize] = self.read_byte(addr) as u32;
             } else {
                 self.state.gpr[rd as usize] = self.read_word_rotate(addr);
             }
         } else {
             if byte {
-                self.memory.write_byte(addr, self.state.gpr[rd as usize] as u8)
+                self.write_byte(addr, self.state.gpr[rd as usize] as u8)
             } else {
-                self.memory.write_word(addr, self.state.gpr[rd as usize])
+                self.write_word(addr, self.state.gpr[rd as usize])
             }
         }
 
@@ -514,14 +522,7 @@ impl Cpu {
         }
 
         if load && rd == GPR::PC {
-            if self.arch == Arch::ARMv5 && self.state.gpr[15] & 1 != 0 {
-                self.state.cpsr.set_thumb(true);
-                self.state.gpr[15] &= !1;
-                self.thumb_flush_pipeline();
-            } else {
-                self.state.gpr[15] &= !3;
-                self.arm_flush_pipeline();
-            }
+            self.load_pc(self.state.gpr[15]);
         }
     }
 
@@ -606,14 +607,10 @@ impl Cpu {
             }
 
             if !matches!(opcode, Opcode::TST | Opcode::TEQ | Opcode::CMP | Opcode::CMN) {
-                if self.state.cpsr.thumb() {
-                    self.thumb_flush_pipeline();
-                } else {
-                    self.arm_flush_pipeline();
-                }
+                self.flush_pipeline();
             }
         } else {
-            self.state.gpr[15] += 4;
+            self.advance();
         }
     }
 
@@ -630,7 +627,7 @@ impl Cpu {
         }
 
         if opcode.rd == GPR::PC {
-            error!("Interpreter: handle rd == 15 in arm_coprocessor_register_transfer");
+            unimplemented_feature!("Interpreter: handle rd == 15 in arm_coprocessor_register_transfer");
         }
 
         if opcode.load {
@@ -640,10 +637,14 @@ impl Cpu {
                 .write(opcode.crn as _, opcode.crm as _, opcode.cp as _, self.state.gpr[opcode.rd as usize]);
         }
 
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
-    pub(in crate::arm) fn arm_software_interrupt(&mut self, _: u32) {
+    pub(in crate::arm) fn arm_software_interrupt(&mut self, instruction: u32) {
+        if self.handle_swi((instruction >> 16) as u8) {
+            return;
+        }
+
         *self.state.spsr_at(Bank::SVC) = self.state.cpsr;
         self.switch_mode(Mode::Supervisor);
 
@@ -660,23 +661,14 @@ impl Cpu {
 
         let ArmSignedMultiplyAccumulateLong { rm, rs, rn, rd, x, y } = ArmSignedMultiplyAccumulateLong::decode(instruction);
         let rdhilo = (((self.state.gpr[rd as usize] as u64) << 32) | (self.state.gpr[rn as usize] as u64)) as i64;
+        let lhs = halfword(self.state.gpr[rm as usize], x) as i64;
+        let rhs = halfword(self.state.gpr[rs as usize], y) as i64;
 
-        let lhs = if x {
-            (self.state.gpr[rm as usize] >> 16) as i16 as i64
-        } else {
-            (self.state.gpr[rm as usize]) as i16 as i64
-        };
-
-        let rhs = if y {
-            (self.state.gpr[rs as usize] >> 16) as i16 as i64
-        } else {
-            (self.state.gpr[rs as usize]) as i16 as i64
-        };
-
-        let result = (lhs * rhs) + rdhilo;
-        self.state.gpr[rn as usize] = (result & 0xffffffff) as u32;
+        // smlalxy wraps around in 64 bits and never touches q
+        let result = (lhs * rhs).wrapping_add(rdhilo);
+        self.state.gpr[rn as usize] = result as u32;
         self.state.gpr[rd as usize] = (result >> 32) as u32;
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_signed_multiply_word(&mut self, instruction: u32) {
@@ -692,24 +684,19 @@ impl Cpu {
             accumulate,
             y,
         } = ArmSignedMultiplyWord::decode(instruction);
-        let result = if y {
-            (((self.state.gpr[rm as usize] as i32) * ((self.state.gpr[rs as usize] >> 16) as i32)) >> 16) as u32
-        } else {
-            (((self.state.gpr[rm as usize] as i32) * (self.state.gpr[rs as usize] as i16 as i32)) >> 16) as u32
-        };
 
-        if accumulate {
-            let operand = self.state.gpr[rn as usize];
-            self.state.gpr[rd as usize] = result + operand;
+        // 32x16 bit product, keeping the top 32 bits of the 48 bit result
+        let lhs = self.state.gpr[rm as usize] as i32 as i64;
+        let rhs = halfword(self.state.gpr[rs as usize], y) as i64;
+        let result = ((lhs * rhs) >> 16) as i32;
 
-            if add_overflow(result, operand, self.state.gpr[rd as usize]) {
-                self.state.cpsr.set_q(true)
-            }
+        self.state.gpr[rd as usize] = if accumulate {
+            self.accumulate_q(result, self.state.gpr[rn as usize] as i32)
         } else {
-            self.state.gpr[rd as usize] = result;
-        }
+            result as u32
+        };
 
-        self.state.gpr[15] += 4;
+        self.advance();
     }
 
     pub(in crate::arm) fn arm_signed_multiply(&mut self, instruction: u32) {
@@ -727,34 +714,37 @@ impl Cpu {
             y,
         } = ArmSignedMultiply::decode(instruction);
 
-        let lhs = if x {
-            (self.state.gpr[rm as usize] >> 16) as i16 as u32
-        } else {
-            self.state.gpr[rm as usize] as i16 as u32
-        };
-        let rhs = if y {
-            (self.state.gpr[rs as usize] >> 16) as i16 as u32
+        // 16x16 bit products always fit in 32 bits, only the accumulate can overflow
+        let result = halfword(self.state.gpr[rm as usize], x) * halfword(self.state.gpr[rs as usize], y);
+
+        self.state.gpr[rd as usize] = if accumulate {
+            self.accumulate_q(result, self.state.gpr[rn as usize] as i32)
         } else {
-            self.state.gpr[rs as usize] as i16 as u32
+            result as u32
         };
 
-        let result = lhs * rhs;
-
-        if accumulate {
-            let operand = self.state.gpr[rn as usize];
-            self.state.gpr[rd as usize] = result + operand;
+        self.advance();
+    }
 
-            if add_overflow(result, operand, self.state.gpr[rd as usize]) {
-                self.state.cpsr.set_q(true);
-            }
-        } else {
-            self.state.gpr[rd as usize] = result;
+    /// Accumulate step of smlaxy and smlawy, sets q on signed overflow but doesn't saturate
+    fn accumulate_q(&mut self, result: i32, operand: i32) -> u32 {
+        let (sum, overflow) = result.overflowing_add(operand);
+        if overflow {
+            self.state.cpsr.set_q(true);
         }
-
-        self.state.gpr[15] += 4;
+        sum as u32
     }
 
     pub(in crate::arm) fn arm_breakpoint(&mut self, _: u32) {
         todo!()
     }
 }
+
+/// Sign extended top or bottom half of a register, as selected by the x/y bits of the dsp multiplies
+const fn halfword(val: u32, top: bool) -> i32 {
+    if top {
+        (val >> 16) as i16 as i32
+    } else {
+        val as i16 as i32
+    }
+}
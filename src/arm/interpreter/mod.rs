@@ -1,4 +1,4 @@
 mod alu;
 mod arm;
-mod instructions;
+pub(super) mod instructions;
 mod thumb;
@@ -18,21 +18,21 @@ impl Cpu {
             ThumbALUImmediateOp::SUB => self.state.gpr[rd as usize] = self.alu_sub(self.state.gpr[rd as usize], imm, true),
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_branch_link_offset(&mut self, instruction: u32) {
         let ThumbBranchLinkOffset { offset } = ThumbBranchLinkOffset::decode(instruction);
         let next_instruction_addr = self.state.gpr[15] - 2;
-        self.state.gpr[15] = (self.state.gpr[14] + offset) & !0x1;
+        let target = self.state.gpr[14] + offset;
         self.state.gpr[14] = next_instruction_addr | 0x1;
-        self.thumb_flush_pipeline();
+        self.branch_to(target);
     }
 
     pub(in crate::arm) fn thumb_branch_link_setup(&mut self, instruction: u32) {
         let ThumbBranchLinkSetup { imm } = ThumbBranchLinkSetup::decode(instruction);
         self.state.gpr[14] = self.state.gpr[15] + imm;
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_branch_link_exchange_offset(&mut self, instruction: u32) {
@@ -42,16 +42,15 @@ impl Cpu {
 
         let ThumbBranchLinkExchangeOffset { offset } = ThumbBranchLinkExchangeOffset::decode(instruction);
         let next_instruction_addr = self.state.gpr[15] - 2;
-        self.state.gpr[15] = (self.state.gpr[14] + offset) & !0x3;
+        let target = self.state.gpr[14] + offset;
         self.state.gpr[14] = next_instruction_addr | 0x1;
         self.state.cpsr.set_thumb(false);
-        self.arm_flush_pipeline();
+        self.branch_to(target);
     }
 
     pub(in crate::arm) fn thumb_branch(&mut self, instruction: u32) {
         let ThumbBranch { offset } = ThumbBranch::decode(instruction);
-        self.state.gpr[15] += offset;
-        self.thumb_flush_pipeline();
+        self.branch_to(self.state.gpr[15] + offset);
     }
 
     pub(in crate::arm) fn thumb_push_pop(&mut self, instruction: u32) {
@@ -61,25 +60,17 @@ impl Cpu {
         if pop {
             for i in 0..8 {
                 if rlist & (1 << i) != 0 {
-                    self.state.gpr[i] = self.memory.read_word(addr);
+                    self.state.gpr[i] = self.read_word(addr);
                     addr += 4;
                 }
             }
 
             if pclr {
-                self.state.gpr[15] = self.memory.read_word(addr);
+                let pc = self.read_word(addr);
                 self.state.gpr[13] = addr + 4;
-
-                if (self.arch == Arch::ARMv4) || (self.state.gpr[15] & 0x1 != 0) {
-                    self.state.gpr[15] &= !0x1;
-                    self.thumb_flush_pipeline();
-                } else {
-                    self.state.cpsr.set_thumb(false);
-                    self.state.gpr[15] &= !0x3;
-                    self.arm_flush_pipeline();
-                }
+                self.load_pc(pc);
             } else {
-                self.state.gpr[15] += 2;
+                self.advance();
                 self.state.gpr[13] = addr;
             }
         } else {
@@ -97,16 +88,16 @@ impl Cpu {
 
             for i in 0..8 {
                 if rlist & (1 << i) != 0 {
-                    self.memory.write_word(addr, self.state.gpr[i]);
+                    self.write_word(addr, self.state.gpr[i]);
                     addr += 4;
                 }
             }
 
             if pclr {
-                self.memory.write_word(addr, self.state.gpr[14]);
+                self.write_word(addr, self.state.gpr[14]);
             }
 
-            self.state.gpr[15] += 2;
+            self.advance();
         }
     }
 
@@ -148,14 +139,16 @@ impl Cpu {
             ThumbOpcode::CMN => self.alu_cmn(self.state.gpr[rd as usize], self.state.gpr[rs as usize]),
             ThumbOpcode::ORR => self.state.gpr[rd as usize] = self.alu_orr(self.state.gpr[rd as usize], self.state.gpr[rs as usize], true),
             ThumbOpcode::MUL => {
-                self.state.gpr[rd as usize] *= self.state.gpr[rs as usize];
-                self.set_nz(self.state.gpr[rd as usize])
+                self.add_internal_cycles(self.multiply_cycles(self.state.gpr[rd as usize], true, false, false, true));
+                let result = self.state.gpr[rd as usize].wrapping_mul(self.state.gpr[rs as usize]);
+                self.state.gpr[rd as usize] = result;
+                self.set_multiply_flags(result >> 31 != 0, result == 0);
             }
             ThumbOpcode::BIC => self.state.gpr[rd as usize] = self.alu_bic(self.state.gpr[rd as usize], self.state.gpr[rs as usize], true),
             ThumbOpcode::MVN => self.state.gpr[rd as usize] = self.alu_mvn(self.state.gpr[rs as usize], true),
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_special_data_processing(&mut self, instruction: u32) {
@@ -166,19 +159,19 @@ impl Cpu {
                 if rd == GPR::PC {
                     self.thumb_flush_pipeline()
                 } else {
-                    self.state.gpr[15] += 2;
+                    self.advance();
                 }
             }
             SpecialOpcode::CMP => {
                 self.alu_cmp(self.state.gpr[rd as usize], self.state.gpr[rs as usize]);
-                self.state.gpr[15] += 2;
+                self.advance();
             }
             SpecialOpcode::MOV => {
                 self.state.gpr[rd as usize] = self.state.gpr[rs as usize];
                 if rd == GPR::PC {
                     self.thumb_flush_pipeline()
                 } else {
-                    self.state.gpr[15] += 2;
+                    self.advance();
                 }
             }
         }
@@ -193,57 +186,43 @@ impl Cpu {
         let next_instruction_addr = self.state.gpr[15] - 2;
         self.state.gpr[14] = next_instruction_addr | 0x1;
 
-        if self.state.gpr[rm as usize] & 0x1 != 0 {
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !0x1;
-            self.thumb_flush_pipeline();
-        } else {
-            self.state.cpsr.set_thumb(false);
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !0x3;
-            self.arm_flush_pipeline();
-        }
+        self.exchange_to(self.state.gpr[rm as usize]);
     }
 
     pub(in crate::arm) fn thumb_branch_exchange(&mut self, instruction: u32) {
         let ThumbBranchExchange { rm } = ThumbBranchExchange::decode(instruction);
-        if self.state.gpr[rm as usize] & 0x1 != 0 {
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !0x1;
-            self.thumb_flush_pipeline();
-        } else {
-            self.state.cpsr.set_thumb(false);
-            self.state.gpr[15] = self.state.gpr[rm as usize] & !0x3;
-            self.arm_flush_pipeline();
-        }
+        self.exchange_to(self.state.gpr[rm as usize]);
     }
 
     pub(in crate::arm) fn thumb_load_store_register_offset(&mut self, instruction: u32) {
         let ThumbLoadStoreRegisterOffset { rd, rn, rm, opcode } = ThumbLoadStoreRegisterOffset::decode(instruction);
         let addr = self.state.gpr[rn as usize] + self.state.gpr[rm as usize];
         match opcode {
-            LoadStoreRegisterOpcode::STR => self.memory.write_word(addr, self.state.gpr[rd as usize]),
-            LoadStoreRegisterOpcode::STRB => self.memory.write_byte(addr, self.state.gpr[rd as usize] as u8),
+            LoadStoreRegisterOpcode::STR => self.write_word(addr, self.state.gpr[rd as usize]),
+            LoadStoreRegisterOpcode::STRB => self.write_byte(addr, self.state.gpr[rd as usize] as u8),
             LoadStoreRegisterOpcode::LDR => self.state.gpr[rd as usize] = self.read_word_rotate(addr),
-            LoadStoreRegisterOpcode::LDRB => self.state.gpr[rd as usize] = self.memory.read_byte(addr) as u32,
+            LoadStoreRegisterOpcode::LDRB => self.state.gpr[rd as usize] = self.read_byte(addr) as u32,
         }
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_load_store_signed(&mut self, instruction: u32) {
         let ThumbLoadStoreSigned { rd, rn, rm, opcode } = ThumbLoadStoreSigned::decode(instruction);
         let addr = self.state.gpr[rn as usize] + self.state.gpr[rm as usize];
         match opcode {
-            LoadStoreSignedOpcode::STRH => self.memory.write_half(addr, self.state.gpr[rd as usize] as u16),
-            LoadStoreSignedOpcode::LDRSB => self.state.gpr[rd as usize] = sign_extend::<8>(self.memory.read_byte(addr) as u32),
-            LoadStoreSignedOpcode::LDRH => self.state.gpr[rd as usize] = self.memory.read_half(addr) as u32,
-            LoadStoreSignedOpcode::LDRSH => self.state.gpr[rd as usize] = sign_extend::<16>(self.memory.read_half(addr) as u32),
+            LoadStoreSignedOpcode::STRH => self.write_half(addr, self.state.gpr[rd as usize] as u16),
+            LoadStoreSignedOpcode::LDRSB => self.state.gpr[rd as usize] = sign_extend::<8>(self.read_byte(addr) as u32),
+            LoadStoreSignedOpcode::LDRH => self.state.gpr[rd as usize] = self.read_half(addr) as u32,
+            LoadStoreSignedOpcode::LDRSH => self.state.gpr[rd as usize] = sign_extend::<16>(self.read_half(addr) as u32),
         }
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_load_pc(&mut self, instruction: u32) {
         let ThumbLoadPC { imm, rd } = ThumbLoadPC::decode(instruction);
         let addr = (self.state.gpr[15] & !0x2) + imm;
-        self.state.gpr[rd as usize] = self.memory.read_word(addr);
-        self.state.gpr[15] += 2;
+        self.state.gpr[rd as usize] = self.read_word(addr);
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_load_store_sp_relative(&mut self, instruction: u32) {
@@ -252,22 +231,22 @@ impl Cpu {
         if load {
             self.state.gpr[rd as usize] = self.read_word_rotate(addr);
         } else {
-            self.memory.write_word(addr, self.state.gpr[rd as usize]);
+            self.write_word(addr, self.state.gpr[rd as usize]);
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_load_store_halfword(&mut self, instruction: u32) {
         let ThumbLoadStoreHalfword { rd, rn, imm, load } = ThumbLoadStoreHalfword::decode(instruction);
         let addr = self.state.gpr[rn as usize] + (imm << 1);
         if load {
-            self.state.gpr[rd as usize] = self.memory.read_half(addr) as u32;
+            self.state.gpr[rd as usize] = self.read_half(addr) as u32;
         } else {
-            self.memory.write_half(addr, self.state.gpr[rd as usize] as u16);
+            self.write_half(addr, self.state.gpr[rd as usize] as u16);
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_add_subtract(&mut self, instruction: u32) {
@@ -281,7 +260,7 @@ impl Cpu {
             self.state.gpr[rd as usize] = self.alu_add(lhs, rhs, true);
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_shift_immediate(&mut self, instruction: u32) {
@@ -298,10 +277,14 @@ impl Cpu {
         }
 
         self.set_nz(self.state.gpr[rd as usize]);
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
-    pub(in crate::arm) fn thumb_software_interrupt(&mut self, _instruction: u32) {
+    pub(in crate::arm) fn thumb_software_interrupt(&mut self, instruction: u32) {
+        if self.handle_swi(instruction as u8) {
+            return;
+        }
+
         self.state.spsr_at(Bank::SVC).0 = self.state.cpsr.0;
         self.switch_mode(Mode::Supervisor);
 
@@ -315,10 +298,9 @@ impl Cpu {
     pub(in crate::arm) fn thumb_branch_conditional(&mut self, instruction: u32) {
         let ThumbBranchConditional { condition, offset } = ThumbBranchConditional::decode(instruction);
         if self.evaluate_cond(condition) {
-            self.state.gpr[15] += offset;
-            self.thumb_flush_pipeline();
+            self.branch_to(self.state.gpr[15] + offset);
         } else {
-            self.state.gpr[15] += 2;
+            self.advance();
         }
     }
 
@@ -327,24 +309,25 @@ impl Cpu {
         let mut addr = self.state.gpr[rn as usize];
 
         if rlist == 0 {
-            self.state.gpr[15] += 2;
+            self.advance();
+            self.state.gpr[rn as usize] = addr + 0x40;
 
             if self.arch == Arch::ARMv4 {
                 if load {
-                    self.state.gpr[15] = self.memory.read_word(addr);
+                    let pc = self.read_word(addr);
+                    self.branch_to(pc);
                 } else {
-                    self.memory.write_word(addr, self.state.gpr[15]);
+                    self.write_word(addr, self.state.gpr[15]);
                 }
             }
 
-            self.state.gpr[rn as usize] = addr + 0x40;
             return;
         }
 
         if load {
             for i in 0..8 {
                 if rlist & (1 << i) != 0 {
-                    self.state.gpr[i] = self.memory.read_word(addr);
+                    self.state.gpr[i] = self.read_word(addr);
                     addr += 4;
                 }
             }
@@ -356,7 +339,7 @@ impl Cpu {
         } else {
             for i in 0..8 {
                 if rlist & (1 << i) != 0 {
-                    self.memory.write_word(addr, self.state.gpr[i]);
+                    self.write_word(addr, self.state.gpr[i]);
                     addr += 4;
                 }
             }
@@ -364,7 +347,7 @@ impl Cpu {
             self.state.gpr[rn as usize] = addr;
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_load_store_immediate(&mut self, instruction: u32) {
@@ -381,11 +364,11 @@ impl Cpu {
                     .write_byte(self.state.gpr[rn as usize] + imm, self.state.gpr[rd as usize] as u8);
             }
             LoadStoreOpcode::LDRB => {
-                self.state.gpr[rd as usize] = self.memory.read_byte(self.state.gpr[rn as usize] + imm) as u32;
+                self.state.gpr[rd as usize] = self.read_byte(self.state.gpr[rn as usize] + imm) as u32;
             }
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_add_sp_pc(&mut self, instruction: u32) {
@@ -395,7 +378,7 @@ impl Cpu {
         } else {
             self.state.gpr[rd as usize] = (self.state.gpr[15] & !0x2) + imm;
         }
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 
     pub(in crate::arm) fn thumb_adjust_stack_pointer(&mut self, instruction: u32) {
@@ -406,6 +389,6 @@ impl Cpu {
             self.state.gpr[13] += imm;
         }
 
-        self.state.gpr[15] += 2;
+        self.advance();
     }
 }
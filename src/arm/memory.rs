@@ -17,6 +17,18 @@ pub trait Memory {
     fn write_half(&mut self, addr: u32, val: u16);
     fn write_word(&mut self, addr: u32, val: u32);
 
+    /// Reads a byte for the debugger without any side effects: mmio and the slots aren't touched and read
+    /// as 0, and main memory accesses don't show up in the heatmap
+    fn peek_byte(&mut self, addr: u32) -> u8;
+
+    /// Writes a byte for the debugger, only to memory that holds plain data. Byte writes to palette ram
+    /// and oam go through, even though the cpu can't make them
+    fn poke_byte(&mut self, addr: u32, val: u8);
+
+    /// Cycles an access at `addr` takes on this cpu's clock, for the cpu timing model. `word` is a 32 bit access,
+    /// `sequential` follows the previous access of the same kind and `code` is an instruction fetch
+    fn access_cycles(&self, addr: u32, word: bool, sequential: bool, code: bool) -> u64;
+
     fn as_any(&mut self) -> &mut dyn Any;
 }
 
@@ -1,6 +1,10 @@
 pub mod coprocessor;
 pub mod cpu;
 pub mod decoder;
+pub mod disassembler;
 mod interpreter;
 pub mod memory;
 pub mod state;
+pub mod trace;
+#[cfg(test)]
+pub(crate) mod tests;
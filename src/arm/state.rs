@@ -1,6 +1,7 @@
 use std::mem::transmute;
 
 use crate::bitfield;
+use crate::util::{StateReader, StateWriter};
 
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, PartialOrd, Default)]
@@ -118,12 +119,26 @@ pub enum Mode {
 }
 
 impl From<u32> for Mode {
+    /// Invalid mode bits can only get here through a raw write to the cpsr, they bank like user mode
     fn from(value: u32) -> Self {
-        unsafe { transmute(value as u8) }
+        Mode::from_bits(value).unwrap_or(Mode::User)
     }
 }
 
 impl Mode {
+    pub const fn from_bits(value: u32) -> Option<Mode> {
+        match value & 0x1f {
+            0x10 => Some(Mode::User),
+            0x11 => Some(Mode::Fiq),
+            0x12 => Some(Mode::Irq),
+            0x13 => Some(Mode::Supervisor),
+            0x17 => Some(Mode::Abort),
+            0x1b => Some(Mode::Undefined),
+            0x1f => Some(Mode::System),
+            _ => None,
+        }
+    }
+
     pub fn bank(self) -> Bank {
         match self {
             Mode::User | Mode::System => Bank::USR,
@@ -178,4 +193,34 @@ impl State {
     pub fn set_spsr(&mut self, bank: Bank) {
         self.spsr = bank as usize;
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_slice("gpr", &self.gpr);
+        for bank in &self.gpr_banked {
+            state.write_slice("gpr_banked", bank);
+        }
+        state.write("cpsr", self.cpsr.0);
+        state.write("spsr_bank", self.spsr as u32);
+        for spsr in &self.spsr_banked {
+            state.write("spsr", spsr.0);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.read_slice(&mut self.gpr);
+        for bank in &mut self.gpr_banked {
+            state.read_slice(bank);
+        }
+        self.cpsr.0 = state.read();
+        // user and system mode have no spsr of their own and point at the cpsr, the usr bank is never used
+        let spsr = state.read::<u32>() as usize;
+        if (Bank::FIQ as usize..=Bank::UND as usize).contains(&spsr) || spsr == Bank::CPSR as usize {
+            self.spsr = spsr;
+        } else {
+            state.fail(format!("invalid spsr bank {spsr}"));
+        }
+        for spsr in &mut self.spsr_banked {
+            spsr.0 = state.read();
+        }
+    }
 }
@@ -0,0 +1,597 @@
+//! Runs short arm and thumb programs on a cpu with flat memory and nothing else around it,
+//! to check single instructions against known register values and flags
+
+use std::any::Any;
+
+use crate::arm::coprocessor::Coprocessor;
+use crate::arm::cpu::{Arch, Cpu};
+use crate::arm::memory::Memory;
+use crate::arm::state::{Bank, Mode};
+use crate::util::{StateReader, StateWriter};
+
+const MEMORY_SIZE: usize = 0x10000;
+
+/// 64K of memory mirrored across the whole address space
+struct FlatMemory {
+    data: Box<[u8]>,
+}
+
+impl FlatMemory {
+    fn index(addr: u32) -> usize {
+        addr as usize & (MEMORY_SIZE - 1)
+    }
+}
+
+impl Memory for FlatMemory {
+    fn reset(&mut self) {
+        self.data.fill(0);
+    }
+
+    fn read_byte(&mut self, addr: u32) -> u8 {
+        self.data[Self::index(addr)]
+    }
+
+    fn read_half(&mut self, addr: u32) -> u16 {
+        u16::from_le_bytes([self.read_byte(addr), self.read_byte(addr + 1)])
+    }
+
+    fn read_word(&mut self, addr: u32) -> u32 {
+        u32::from_le_bytes([self.read_byte(addr), self.read_byte(addr + 1), self.read_byte(addr + 2), self.read_byte(addr + 3)])
+    }
+
+    fn write_byte(&mut self, addr: u32, val: u8) {
+        self.data[Self::index(addr)] = val;
+    }
+
+    fn write_half(&mut self, addr: u32, val: u16) {
+        for (i, byte) in val.to_le_bytes().into_iter().enumerate() {
+            self.write_byte(addr + i as u32, byte);
+        }
+    }
+
+    fn write_word(&mut self, addr: u32, val: u32) {
+        for (i, byte) in val.to_le_bytes().into_iter().enumerate() {
+            self.write_byte(addr + i as u32, byte);
+        }
+    }
+
+    fn peek_byte(&mut self, addr: u32) -> u8 {
+        self.read_byte(addr)
+    }
+
+    fn poke_byte(&mut self, addr: u32, val: u8) {
+        self.write_byte(addr, val)
+    }
+
+    fn access_cycles(&self, _addr: u32, _word: bool, _sequential: bool, _code: bool) -> u64 {
+        1
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct NoCoprocessor;
+
+impl Coprocessor for NoCoprocessor {
+    fn reset(&mut self) {}
+
+    fn read(&mut self, _cn: u32, _cm: u32, _cp: u32) -> u32 {
+        0
+    }
+
+    fn write(&mut self, _cn: u32, _cm: u32, _cp: u32, _val: u32) {}
+
+    fn get_exception_base(&self) -> u32 {
+        0
+    }
+}
+
+/// A cpu in supervisor mode about to run `code` from address 0. Tests elsewhere borrow it for its flat memory
+pub(crate) fn arm_cpu(arch: Arch, code: &[u32]) -> Cpu {
+    let mut cpu = Cpu::new(arch, Box::new(FlatMemory { data: vec![0; MEMORY_SIZE].into_boxed_slice() }), Box::new(NoCoprocessor));
+    cpu.reset();
+    for (i, &instruction) in code.iter().enumerate() {
+        cpu.memory.write_word(i as u32 * 4, instruction);
+    }
+    cpu.state.gpr[15] = 0;
+    cpu.flush_pipeline();
+    cpu
+}
+
+/// Like `arm_cpu` with `code` in thumb
+fn thumb_cpu(arch: Arch, code: &[u16]) -> Cpu {
+    let mut cpu = arm_cpu(arch, &[]);
+    for (i, &instruction) in code.iter().enumerate() {
+        cpu.memory.write_half(i as u32 * 2, instruction);
+    }
+    cpu.state.cpsr.set_thumb(true);
+    cpu.state.gpr[15] = 0;
+    cpu.flush_pipeline();
+    cpu
+}
+
+/// Runs `code` from address 0 after `setup` prepared the registers
+fn run_arm(arch: Arch, code: &[u32], setup: impl FnOnce(&mut Cpu)) -> Cpu {
+    let mut cpu = arm_cpu(arch, code);
+    setup(&mut cpu);
+    cpu.run(code.len() as u64);
+    cpu
+}
+
+#[test]
+fn advance_moves_to_the_next_instruction() {
+    // mov r0, r0
+    let mut cpu = arm_cpu(Arch::ARMv5, &[0xe1a00000; 2]);
+    cpu.run(1);
+    assert_eq!(cpu.current_pc(), 4);
+    assert_eq!(cpu.state.gpr[15], 12);
+
+    // mov r0, r0
+    let mut cpu = thumb_cpu(Arch::ARMv5, &[0x1c00; 2]);
+    cpu.run(1);
+    assert_eq!(cpu.current_pc(), 2);
+    assert_eq!(cpu.state.gpr[15], 6);
+}
+
+#[test]
+fn branches_keep_pc_two_instructions_ahead() {
+    // b 0x10
+    let mut cpu = arm_cpu(Arch::ARMv5, &[0xea000002]);
+    cpu.run(1);
+    assert_eq!(cpu.current_pc(), 0x10);
+    assert_eq!(cpu.state.gpr[15], 0x18);
+
+    // b 0x10
+    let mut cpu = thumb_cpu(Arch::ARMv5, &[0xe006]);
+    cpu.run(1);
+    assert_eq!(cpu.current_pc(), 0x10);
+    assert_eq!(cpu.state.gpr[15], 0x14);
+}
+
+#[test]
+fn exchange_switches_instruction_set_on_bit_0() {
+    // bx r0
+    let mut cpu = arm_cpu(Arch::ARMv4, &[0xe12fff10]);
+    cpu.state.gpr[0] = 0x21;
+    cpu.run(1);
+    assert!(cpu.state.cpsr.thumb());
+    assert_eq!(cpu.current_pc(), 0x20);
+
+    // bx r1
+    let mut cpu = thumb_cpu(Arch::ARMv4, &[0x4708]);
+    cpu.state.gpr[1] = 0x40;
+    cpu.run(1);
+    assert!(!cpu.state.cpsr.thumb());
+    assert_eq!(cpu.current_pc(), 0x40);
+    assert_eq!(cpu.state.gpr[15], 0x48);
+}
+
+#[test]
+fn loaded_pc_only_interworks_on_the_arm9() {
+    // ldr pc, [r0]
+    for (arch, thumb, pc) in [(Arch::ARMv4, false, 0x40), (Arch::ARMv5, true, 0x40)] {
+        let mut cpu = arm_cpu(arch, &[0xe590f000]);
+        cpu.memory.write_word(0x100, 0x41);
+        cpu.state.gpr[0] = 0x100;
+        cpu.run(1);
+        assert_eq!(cpu.state.cpsr.thumb(), thumb, "{arch:?}");
+        assert_eq!(cpu.current_pc(), pc, "{arch:?}");
+    }
+}
+
+#[test]
+fn helpers_refill_the_pipeline() {
+    let mut cpu = arm_cpu(Arch::ARMv5, &[]);
+    cpu.branch_to(0x100);
+    assert_eq!(cpu.state.gpr[15], 0x108);
+
+    cpu.exchange_to(0x201);
+    assert!(cpu.state.cpsr.thumb());
+    assert_eq!(cpu.state.gpr[15], 0x204);
+
+    cpu.advance();
+    assert_eq!(cpu.state.gpr[15], 0x206);
+}
+
+#[test]
+fn saturating_op_writing_pc_branches() {
+    // qadd pc, r0, r1
+    let cpu = run_arm(Arch::ARMv5, &[0xe101f050], |cpu| {
+        cpu.state.gpr[0] = 0x100;
+        cpu.state.gpr[1] = 0x20;
+    });
+    assert_eq!(cpu.current_pc(), 0x120);
+    assert_eq!(cpu.state.gpr[15], 0x128);
+}
+
+#[test]
+fn msr_to_an_invalid_mode_keeps_the_mode() {
+    // msr cpsr_fc, r0
+    let cpu = run_arm(Arch::ARMv5, &[0xe129f000], |cpu| cpu.state.gpr[0] = 0xf0000000 | 0xc0);
+    assert_eq!(cpu.state.cpsr.mode(), Mode::Supervisor);
+    assert_eq!(cpu.state.cpsr.0, 0xf00000d3);
+    assert_eq!(cpu.current_pc(), 4);
+
+    // the registers stay in the supervisor bank
+    let cpu = run_arm(Arch::ARMv4, &[0xe129f000], |cpu| {
+        cpu.state.gpr[0] = 0x0d;
+        cpu.state.gpr[13] = 0x1234;
+    });
+    assert_eq!(cpu.state.cpsr.mode(), Mode::Supervisor);
+    assert_eq!(cpu.state.gpr[13], 0x1234);
+}
+
+#[test]
+fn multiply_flags_leave_carry_to_the_arm9() {
+    // muls r2, r0, r1
+    for (arch, carry) in [(Arch::ARMv4, false), (Arch::ARMv5, true)] {
+        let cpu = run_arm(arch, &[0xe0120190], |cpu| {
+            cpu.state.gpr[0] = 0x10000;
+            cpu.state.gpr[1] = 0x10000;
+            cpu.state.cpsr.set_c(true);
+            cpu.state.cpsr.set_v(true);
+        });
+        assert_eq!(cpu.state.gpr[2], 0, "{arch:?}");
+        assert!(cpu.state.cpsr.z() && !cpu.state.cpsr.n(), "{arch:?}");
+        assert_eq!(cpu.state.cpsr.c(), carry, "{arch:?}");
+        assert!(cpu.state.cpsr.v(), "{arch:?}");
+    }
+}
+
+#[test]
+fn multiplies_wrap() {
+    // mla r3, r0, r1, r2
+    let cpu = run_arm(Arch::ARMv4, &[0xe0232190], |cpu| {
+        cpu.state.gpr[0] = 0xffffffff;
+        cpu.state.gpr[1] = 2;
+        cpu.state.gpr[2] = 3;
+    });
+    assert_eq!(cpu.state.gpr[3], 1);
+
+    // umull r2, r3, r0, r1
+    let cpu = run_arm(Arch::ARMv4, &[0xe0832190], |cpu| {
+        cpu.state.gpr[0] = 0xffffffff;
+        cpu.state.gpr[1] = 0xffffffff;
+    });
+    assert_eq!((cpu.state.gpr[3], cpu.state.gpr[2]), (0xfffffffe, 0x00000001));
+
+    // smull r2, r3, r0, r1
+    let cpu = run_arm(Arch::ARMv4, &[0xe0c32190], |cpu| {
+        cpu.state.gpr[0] = -1i32 as u32;
+        cpu.state.gpr[1] = 2;
+    });
+    assert_eq!((cpu.state.gpr[3], cpu.state.gpr[2]), (0xffffffff, 0xfffffffe));
+
+    // smlals r2, r3, r0, r1
+    let cpu = run_arm(Arch::ARMv5, &[0xe0f32190], |cpu| {
+        cpu.state.gpr[0] = -1i32 as u32;
+        cpu.state.gpr[1] = 1;
+        cpu.state.gpr[2] = 1;
+        cpu.state.gpr[3] = 0;
+    });
+    assert_eq!((cpu.state.gpr[3], cpu.state.gpr[2]), (0, 0));
+    assert!(cpu.state.cpsr.z() && !cpu.state.cpsr.n());
+}
+
+#[test]
+fn arm7_multiplies_terminate_early() {
+    let cpu = arm_cpu(Arch::ARMv4, &[]);
+    assert_eq!(cpu.multiply_cycles(0xff, false, false, false, false), 1);
+    assert_eq!(cpu.multiply_cycles(0x1ff, false, false, false, false), 2);
+    assert_eq!(cpu.multiply_cycles(0x1ffffff, false, false, false, false), 4);
+    assert_eq!(cpu.multiply_cycles(0xffffff00, true, false, false, false), 1);
+    assert_eq!(cpu.multiply_cycles(0xffffff00, false, false, false, false), 4);
+    assert_eq!(cpu.multiply_cycles(0xff0000, true, true, true, false), 5);
+
+    let cpu = arm_cpu(Arch::ARMv5, &[]);
+    assert_eq!(cpu.multiply_cycles(0xff, false, false, false, false), 1);
+    assert_eq!(cpu.multiply_cycles(0xffffffff, false, false, true, false), 1);
+    assert_eq!(cpu.multiply_cycles(0xff, false, true, false, false), 2);
+    assert_eq!(cpu.multiply_cycles(0xff, false, false, false, true), 3);
+}
+
+#[test]
+fn msr_masks_the_thumb_bit_and_reserved_bits() {
+    // msr cpsr_fsxc, r0
+    for (arch, cpsr) in [(Arch::ARMv4, 0xf00000df), (Arch::ARMv5, 0xf80000df)] {
+        let cpu = run_arm(arch, &[0xe12ff000], |cpu| cpu.state.gpr[0] = 0xffffffff);
+        assert_eq!(cpu.state.cpsr.0, cpsr, "{arch:?}");
+        assert!(!cpu.state.cpsr.thumb(), "{arch:?}");
+    }
+}
+
+#[test]
+fn user_mode_msr_only_writes_the_flags() {
+    // msr cpsr_c, r0
+    // msr cpsr_fsxc, r1
+    let cpu = run_arm(Arch::ARMv5, &[0xe121f000, 0xe12ff001], |cpu| {
+        cpu.state.gpr[0] = Mode::User as u32;
+        cpu.state.gpr[1] = 0xf00000d3;
+    });
+    assert_eq!(cpu.state.cpsr.0, 0xf0000010);
+}
+
+#[test]
+fn spsr_round_trips_in_every_exception_mode() {
+    // msr cpsr_c, r0
+    // msr spsr_fsxc, r1
+    // mrs r2, spsr
+    // mrs r3, cpsr
+    let code = [0xe121f000, 0xe16ff001, 0xe14f2000, 0xe10f3000];
+    for mode in [Mode::Fiq, Mode::Irq, Mode::Supervisor, Mode::Abort, Mode::Undefined] {
+        let cpu = run_arm(Arch::ARMv5, &code, |cpu| {
+            cpu.state.gpr[0] = 0xc0 | mode as u32;
+            cpu.state.gpr[1] = 0xffffffff;
+        });
+        // unlike the cpsr the spsr keeps the thumb bit
+        assert_eq!(cpu.state.gpr[2], 0xf80000ff, "{mode:?}");
+        assert_eq!(cpu.state.gpr[3], 0xc0 | mode as u32, "{mode:?}");
+    }
+
+    // without an spsr the write is dropped and mrs reads the cpsr
+    let cpu = run_arm(Arch::ARMv5, &code, |cpu| {
+        cpu.state.gpr[0] = 0xc0 | Mode::System as u32;
+        cpu.state.gpr[1] = 0xffffffff;
+    });
+    assert_eq!(cpu.state.gpr[2], 0xdf);
+}
+
+#[test]
+fn saturating_ops_clamp_and_set_q() {
+    // qadd r2, r0, r1
+    // qsub r2, r0, r1
+    // qdadd r2, r0, r1
+    // qdsub r2, r0, r1
+    let cases = [
+        (0xe1012050, 1, 2, 3, false),
+        (0xe1012050, 0x7fffffff, 1, 0x7fffffff, true),
+        (0xe1012050, 0x80000000, 0xffffffff, 0x80000000, true),
+        (0xe1212050, 0x80000000, 1, 0x80000000, true),
+        (0xe1212050, 0x7fffffff, 0xffffffff, 0x7fffffff, true),
+        (0xe1212050, 5, 7, 0xfffffffe, false),
+        (0xe1412050, 1, 0x10, 0x21, false),
+        (0xe1412050, 0, 0x40000000, 0x7fffffff, true),
+        (0xe1412050, 0x7ffffff0, 0x10, 0x7fffffff, true),
+        (0xe1612050, 0, 0xc0000000, 0x7fffffff, true),
+        (0xe1612050, 0, 0x20000000, 0xc0000000, false),
+    ];
+    for (instruction, rm, rn, result, q) in cases {
+        let cpu = run_arm(Arch::ARMv5, &[instruction], |cpu| {
+            cpu.state.gpr[0] = rm;
+            cpu.state.gpr[1] = rn;
+        });
+        assert_eq!(cpu.state.gpr[2], result, "{instruction:08x} {rm:08x} {rn:08x}");
+        assert_eq!(cpu.state.cpsr.q(), q, "{instruction:08x} {rm:08x} {rn:08x}");
+    }
+}
+
+#[test]
+fn q_is_sticky() {
+    // qadd r2, r0, r1
+    let cpu = run_arm(Arch::ARMv5, &[0xe1012050], |cpu| {
+        cpu.state.gpr[0] = 1;
+        cpu.state.gpr[1] = 2;
+        cpu.state.cpsr.set_q(true);
+    });
+    assert_eq!(cpu.state.gpr[2], 3);
+    assert!(cpu.state.cpsr.q());
+}
+
+#[test]
+fn saturating_ops_are_undefined_on_the_arm7() {
+    // qadd r2, r0, r1
+    let cpu = run_arm(Arch::ARMv4, &[0xe1012050], |cpu| cpu.state.gpr[2] = 0x1234);
+    assert_eq!(cpu.state.cpsr.mode(), Mode::Undefined);
+    assert_eq!(cpu.current_pc(), 0x04);
+    assert_eq!(cpu.state.gpr[2], 0x1234);
+}
+
+#[test]
+fn halfword_multiplies_are_signed() {
+    // smulbb r3, r0, r1
+    // smultb r3, r0, r1
+    // smulbt r3, r0, r1
+    // smultt r3, r0, r1
+    // smlabb r3, r0, r1, r2
+    let cases = [
+        (0xe1630180, 0x8000, 0x8000, 0, 0x40000000, false),
+        (0xe16301a0, 0xffff0000, 0x0005, 0, 0xfffffffb, false),
+        (0xe16301c0, 0x0003, 0x00070000, 0, 21, false),
+        (0xe16301e0, 0x7fff0000, 0x80000000, 0, 0xc0008000, false),
+        (0xe1032180, 0x8000, 0x8000, 1, 0x40000001, false),
+        (0xe1032180, 0x8000, 0x8000, 0x40000000, 0x80000000, true),
+    ];
+    for (instruction, rm, rs, rn, result, q) in cases {
+        let cpu = run_arm(Arch::ARMv5, &[instruction], |cpu| {
+            cpu.state.gpr[0] = rm;
+            cpu.state.gpr[1] = rs;
+            cpu.state.gpr[2] = rn;
+        });
+        assert_eq!(cpu.state.gpr[3], result, "{instruction:08x}");
+        assert_eq!(cpu.state.cpsr.q(), q, "{instruction:08x}");
+    }
+}
+
+#[test]
+fn word_by_halfword_multiplies_keep_the_top_32_bits() {
+    // smulwb r3, r0, r1
+    // smulwt r3, r0, r1
+    // smlawb r3, r0, r1, r2
+    let cases = [
+        (0xe12301a0, 0x40000000, 0x4000, 0, 0x10000000, false),
+        (0xe12301a0, 0x80000000, 0x8000, 0, 0x40000000, false),
+        (0xe12301e0, 0xffffffff, 0x00020000, 0, 0xffffffff, false),
+        (0xe1232180, 0x40000000, 0x4000, 0x10, 0x10000010, false),
+        (0xe1232180, 0x40000000, 0x4000, 0x7fffffff, 0x8fffffff, true),
+    ];
+    for (instruction, rm, rs, rn, result, q) in cases {
+        let cpu = run_arm(Arch::ARMv5, &[instruction], |cpu| {
+            cpu.state.gpr[0] = rm;
+            cpu.state.gpr[1] = rs;
+            cpu.state.gpr[2] = rn;
+        });
+        assert_eq!(cpu.state.gpr[3], result, "{instruction:08x}");
+        assert_eq!(cpu.state.cpsr.q(), q, "{instruction:08x}");
+    }
+}
+
+#[test]
+fn long_halfword_accumulate_wraps_without_q() {
+    // smlalbb r2, r3, r0, r1
+    let cases = [
+        (0xffff, 1, 0, 0, 0xffffffff, 0xffffffff),
+        (1, 1, 0, 0xffffffff, 1, 0),
+        (1, 1, 0x7fffffff, 0xffffffff, 0x80000000, 0),
+    ];
+    for (rm, rs, hi, lo, result_hi, result_lo) in cases {
+        let cpu = run_arm(Arch::ARMv5, &[0xe1432180], |cpu| {
+            cpu.state.gpr[0] = rm;
+            cpu.state.gpr[1] = rs;
+            cpu.state.gpr[2] = lo;
+            cpu.state.gpr[3] = hi;
+        });
+        assert_eq!((cpu.state.gpr[3], cpu.state.gpr[2]), (result_hi, result_lo));
+        assert!(!cpu.state.cpsr.q());
+    }
+}
+
+#[test]
+fn signed_multiplies_do_nothing_on_the_arm7() {
+    // smulbb r3, r0, r1
+    let cpu = run_arm(Arch::ARMv4, &[0xe1630180], |cpu| {
+        cpu.state.gpr[0] = 2;
+        cpu.state.gpr[1] = 3;
+        cpu.state.gpr[3] = 0x1234;
+    });
+    assert_eq!(cpu.state.gpr[3], 0x1234);
+}
+
+#[test]
+fn ldm_with_pc_and_s_bit_returns_from_the_exception() {
+    // ldmia r0, {r1, pc}^
+    for (spsr, thumb) in [(0x6000001f, false), (0x8000003f, true)] {
+        let cpu = run_arm(Arch::ARMv5, &[0xe8d08002], |cpu| {
+            cpu.state.gpr[0] = 0x100;
+            cpu.state.gpr[13] = 0x5555;
+            cpu.state.gpr_banked[Bank::USR as usize][5] = 0xaaaa;
+            cpu.state.spsr_mut().0 = spsr;
+            cpu.memory.write_word(0x100, 0x1234);
+            cpu.memory.write_word(0x104, 0x200);
+        });
+        assert_eq!(cpu.state.cpsr.0, spsr);
+        assert_eq!(cpu.state.cpsr.mode(), Mode::System);
+        assert_eq!(cpu.state.cpsr.thumb(), thumb);
+        assert_eq!(cpu.state.gpr[1], 0x1234);
+        assert_eq!(cpu.current_pc(), 0x200);
+
+        // the system bank is live and the supervisor one was put away
+        assert_eq!(cpu.state.gpr[13], 0xaaaa);
+        assert_eq!(cpu.state.gpr_banked[Bank::SVC as usize][5], 0x5555);
+    }
+}
+
+#[test]
+fn empty_register_lists_only_transfer_pc_on_the_arm7() {
+    // ldmia r0!, {}
+    for (arch, pc) in [(Arch::ARMv4, 0x200), (Arch::ARMv5, 4)] {
+        let cpu = run_arm(arch, &[0xe8b00000], |cpu| {
+            cpu.state.gpr[0] = 0x100;
+            cpu.memory.write_word(0x100, 0x200);
+        });
+        assert_eq!(cpu.current_pc(), pc, "{arch:?}");
+        assert_eq!(cpu.state.gpr[0], 0x140, "{arch:?}");
+    }
+
+    // ldmdb r0!, {}
+    let cpu = run_arm(Arch::ARMv4, &[0xe9300000], |cpu| {
+        cpu.state.gpr[0] = 0x100;
+        cpu.memory.write_word(0xc0, 0x200);
+    });
+    assert_eq!(cpu.current_pc(), 0x200);
+    assert_eq!(cpu.state.gpr[0], 0xc0);
+
+    // stmia r0!, {}
+    for (arch, stored) in [(Arch::ARMv4, 0xc), (Arch::ARMv5, 0)] {
+        let mut cpu = run_arm(arch, &[0xe8a00000], |cpu| cpu.state.gpr[0] = 0x100);
+        assert_eq!(cpu.memory.read_word(0x100), stored, "{arch:?}");
+        assert_eq!(cpu.state.gpr[0], 0x140, "{arch:?}");
+        assert_eq!(cpu.current_pc(), 4, "{arch:?}");
+    }
+}
+
+#[test]
+fn stm_with_s_bit_stores_the_user_bank() {
+    // stmia r0!, {r8-r14}^
+    let mut cpu = run_arm(Arch::ARMv5, &[0xe8e07f00], |cpu| {
+        cpu.switch_mode(Mode::Fiq);
+        cpu.state.gpr[0] = 0x100;
+        for i in 0..7 {
+            cpu.state.gpr[i + 8] = 0xf0 + i as u32;
+            cpu.state.gpr_banked[Bank::USR as usize][i] = 0x10 + i as u32;
+        }
+    });
+    for i in 0..7 {
+        assert_eq!(cpu.memory.read_word(0x100 + i * 4), 0x10 + i, "r{}", i + 8);
+        assert_eq!(cpu.state.gpr[i as usize + 8], 0xf0 + i, "r{}", i + 8);
+    }
+    assert_eq!(cpu.state.gpr[0], 0x11c);
+    assert_eq!(cpu.state.cpsr.mode(), Mode::Fiq);
+}
+
+#[test]
+fn ldm_with_s_bit_loads_the_user_bank() {
+    // ldmia r8!, {r13, r14}^
+    let cpu = run_arm(Arch::ARMv5, &[0xe8f86000], |cpu| {
+        cpu.switch_mode(Mode::Irq);
+        cpu.state.gpr[8] = 0x100;
+        cpu.state.gpr[13] = 0x5555;
+        cpu.state.gpr[14] = 0x6666;
+        cpu.memory.write_word(0x100, 0xaaaa);
+        cpu.memory.write_word(0x104, 0xbbbb);
+    });
+    assert_eq!(cpu.state.gpr_banked[Bank::USR as usize][5], 0xaaaa);
+    assert_eq!(cpu.state.gpr_banked[Bank::USR as usize][6], 0xbbbb);
+    assert_eq!(cpu.state.gpr[13], 0x5555);
+    assert_eq!(cpu.state.gpr[14], 0x6666);
+    assert_eq!(cpu.state.gpr[8], 0x108);
+    assert_eq!(cpu.state.cpsr.mode(), Mode::Irq);
+
+    // in fiq mode the base itself is banked, it's read from the fiq copy and written back to the user one
+    let cpu = run_arm(Arch::ARMv5, &[0xe8f86000], |cpu| {
+        cpu.switch_mode(Mode::Fiq);
+        cpu.state.gpr[8] = 0x300;
+        cpu.state.gpr_banked[Bank::USR as usize][0] = 0x100;
+    });
+    assert_eq!(cpu.state.gpr[8], 0x300);
+    assert_eq!(cpu.state.gpr_banked[Bank::USR as usize][0], 0x308);
+}
+
+/// Saves the registers of `cpu` with the spsr bank index replaced by `spsr` and loads them back
+fn load_with_spsr_bank(cpu: &mut Cpu, spsr: u32) -> Result<(), String> {
+    let mut writer = StateWriter::new();
+    cpu.state.save_state(&mut writer);
+    let mut data = writer.finish();
+    // the index is followed by the 6 banked spsrs
+    let offset = data.len() - 7 * 4;
+    data[offset..offset + 4].copy_from_slice(&spsr.to_le_bytes());
+
+    let mut reader = StateReader::new(&data)?;
+    cpu.state.load_state(&mut reader);
+    reader.finish()
+}
+
+#[test]
+fn states_with_an_invalid_spsr_bank_fail_to_load() {
+    let mut cpu = arm_cpu(Arch::ARMv5, &[]);
+    for bank in [1, 5, 0xff] {
+        assert_eq!(load_with_spsr_bank(&mut cpu, bank), Ok(()));
+    }
+
+    for bank in [0, 6, 0x100] {
+        assert!(load_with_spsr_bank(&mut cpu, bank).is_err(), "bank {bank}");
+        // the bank from the last good load is kept, which is the cpsr
+        assert_eq!(cpu.state.spsr().0, cpu.state.cpsr.0);
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::arm::state::{Bank, State};
+
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub enum TraceFormat {
+    /// `pc: instruction | [gprs] cpsr: cpsr` along with mode switches
+    #[default]
+    Native,
+    /// One line per instruction: `pc instruction r0 .. r15 cpsr`, all as 8 digit hex.
+    /// This is stable and only contains architectural state so it can be diffed against other emulators.
+    Reference,
+}
+
+impl TraceFormat {
+    /// The value of `--trace-format`, `native` or `reference`
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "native" => Ok(TraceFormat::Native),
+            "reference" => Ok(TraceFormat::Reference),
+            _ => Err(format!("unknown trace format: {name}")),
+        }
+    }
+}
+
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+}
+
+impl Tracer {
+    /// Creates a tracer writing to `path`, or to stdout if `path` is `-`
+    pub fn new(path: &str, format: TraceFormat) -> std::io::Result<Self> {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(File::create(path)?)
+        };
+
+        Ok(Self {
+            out: BufWriter::new(out),
+            format,
+        })
+    }
+
+    pub fn log_state(&mut self, pc: u32, instruction: u32, state: &State) {
+        let _ = match self.format {
+            TraceFormat::Native => writeln!(self.out, "{pc:08x}: {instruction:08x} | {:x?} cpsr: {:08x}", state.gpr, state.cpsr.0),
+            TraceFormat::Reference => {
+                let _ = write!(self.out, "{pc:08x} {instruction:08x}");
+                for reg in state.gpr {
+                    let _ = write!(self.out, " {reg:08x}");
+                }
+                writeln!(self.out, " {:08x}", state.cpsr.0)
+            }
+        };
+    }
+
+    pub fn log_switch_mode(&mut self, old: Bank, new: Bank, old_reg: [u32; 7], new_reg: [u32; 7]) {
+        if self.format == TraceFormat::Native {
+            let _ = writeln!(self.out, "{old:?}->{new:?} | {old_reg:x?}->{new_reg:x?}");
+        }
+    }
+}
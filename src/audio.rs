@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+use log::{error, info};
+
+/// Frames the queue holds before the oldest are dropped, about 100ms. Keeps latency bounded while
+/// fast forwarding
+const MAX_QUEUED: usize = 3200;
+
+type Queue = Arc<Mutex<VecDeque<[i16; 2]>>>;
+
+/// Plays the spu output on the default device. Samples are queued after every emulated frame and
+/// resampled to the device rate in the audio callback
+pub struct AudioOutput {
+    queue: Queue,
+    _stream: Stream,
+}
+
+impl AudioOutput {
+    /// `None` when there's no usable output device, emulation carries on without sound
+    pub fn new(source_rate: u32) -> Option<Self> {
+        let device = cpal::default_host().default_output_device()?;
+        let supported = device
+            .default_output_config()
+            .map_err(|e| error!("Audio: no output config: {e}"))
+            .ok()?;
+
+        let queue = Queue::default();
+        let config: StreamConfig = supported.config();
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, &queue, source_rate),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, &queue, source_rate),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, &queue, source_rate),
+            format => {
+                error!("Audio: unsupported sample format {format:?}");
+                return None;
+            }
+        };
+
+        let stream = stream.map_err(|e| error!("Audio: failed to open the output stream: {e}")).ok()?;
+        stream.play().map_err(|e| error!("Audio: failed to start playback: {e}")).ok()?;
+        info!("Audio: playing at {} Hz, {} channels", config.sample_rate.0, config.channels);
+
+        Some(Self { queue, _stream: stream })
+    }
+
+    /// Queues interleaved left/right samples, what `System::fill_audio` produced since the last call
+    pub fn push(&mut self, samples: &[i16]) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples.chunks_exact(2).map(|pair| [pair[0], pair[1]]));
+        if queue.len() > MAX_QUEUED {
+            let excess = queue.len() - MAX_QUEUED;
+            queue.drain(..excess);
+        }
+    }
+}
+
+fn build_stream<T>(device: &cpal::Device, config: &StreamConfig, queue: &Queue, source_rate: u32) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let queue = queue.clone();
+    let channels = config.channels as usize;
+    let step = source_rate as f64 / config.sample_rate.0 as f64;
+    let mut position = 0.0;
+    let mut previous = [0.0f32; 2];
+    let mut current = [0.0f32; 2];
+
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut queue = queue.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                position += step;
+                while position >= 1.0 {
+                    position -= 1.0;
+                    previous = current;
+                    // hold the last sample on underrun instead of snapping to 0, which would click
+                    if let Some(next) = queue.pop_front() {
+                        current = next.map(|val| val as f32 / 32768.0);
+                    }
+                }
+
+                let t = position as f32;
+                let out = [0, 1].map(|i| previous[i] + (current[i] - previous[i]) * t);
+                for (i, sample) in frame.iter_mut().enumerate() {
+                    *sample = T::from_sample(out[i.min(1)]);
+                }
+            }
+        },
+        |e| error!("Audio: stream error: {e}"),
+        None,
+    )
+}
@@ -0,0 +1,81 @@
+//! Compares two instruction traces and reports the first line where they diverge.
+//!
+//! usage: tracediff <expected> <actual>
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process::ExitCode;
+
+const REGISTERS: [&str; 19] = [
+    "pc", "instruction", "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "cpsr",
+];
+
+fn open(path: &str) -> BufReader<File> {
+    match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("failed to open {path}: {e}");
+            std::process::exit(2)
+        }
+    }
+}
+
+fn read_error(path: &str, line: usize, e: std::io::Error) -> ExitCode {
+    eprintln!("failed to read line {line} of {path}: {e}");
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        eprintln!("usage: {} <expected> <actual>", args[0]);
+        return ExitCode::from(2);
+    }
+
+    let mut expected = open(&args[1]).lines();
+    let mut actual = open(&args[2]).lines();
+    let mut previous = String::new();
+
+    for line in 1.. {
+        let (lhs, rhs) = match (expected.next(), actual.next()) {
+            (None, None) => {
+                println!("traces match ({} lines)", line - 1);
+                return ExitCode::SUCCESS;
+            }
+            (Some(lhs), Some(rhs)) => match (lhs, rhs) {
+                (Ok(lhs), Ok(rhs)) => (lhs, rhs),
+                (Err(e), _) => return read_error(&args[1], line, e),
+                (_, Err(e)) => return read_error(&args[2], line, e),
+            },
+            (lhs, _) => {
+                let shorter = if lhs.is_none() { &args[1] } else { &args[2] };
+                println!("{shorter} ends at line {line}");
+                return ExitCode::FAILURE;
+            }
+        };
+
+        if lhs != rhs {
+            println!("traces diverge at line {line}");
+            println!("previous: {previous}");
+            println!("expected: {lhs}");
+            println!("actual:   {rhs}");
+
+            // fields are only meaningful for the reference format
+            let lhs_fields = lhs.split_whitespace().collect::<Vec<_>>();
+            let rhs_fields = rhs.split_whitespace().collect::<Vec<_>>();
+            if lhs_fields.len() == REGISTERS.len() && rhs_fields.len() == REGISTERS.len() {
+                for (i, name) in REGISTERS.iter().enumerate() {
+                    if lhs_fields[i] != rhs_fields[i] {
+                        println!("  {name}: {} != {}", lhs_fields[i], rhs_fields[i]);
+                    }
+                }
+            }
+
+            return ExitCode::FAILURE;
+        }
+
+        previous = lhs;
+    }
+
+    unreachable!()
+}
@@ -3,6 +3,8 @@ use crate::arm::coprocessor::Coprocessor;
 pub struct Arm7Coprocessor;
 
 impl Coprocessor for Arm7Coprocessor {
+    fn reset(&mut self) {}
+
     fn read(&mut self, _cn: u32, _cm: u32, _cp: u32) -> u32 {
         unimplemented!()
     }
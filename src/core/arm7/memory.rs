@@ -3,7 +3,7 @@ use std::any::Any;
 
 use crate::arm::cpu::Arch;
 use crate::arm::memory::{Memory, MmioMemory};
-use crate::core::System;
+use crate::core::{bios, System};
 use crate::util::*;
 
 macro_rules! mmio {
@@ -44,6 +44,12 @@ const MMIO_IPCSYNC: u32 = mmio!(0x04000180);
 const MMIO_IPCFIFOCNT: u32 = mmio!(0x04000184);
 const MMIO_IPCFIFOSEND: u32 = mmio!(0x04000188);
 const MMIO_AUXSPICNT: u32 = mmio!(0x040001a0);
+const MMIO_ROMCTRL: u32 = mmio!(0x040001a4);
+const MMIO_COMMAND_BUFFER0: u32 = mmio!(0x040001a8);
+const MMIO_COMMAND_BUFFER1: u32 = mmio!(0x040001ac);
+const MMIO_SEED0: u32 = mmio!(0x040001b0);
+const MMIO_SEED1: u32 = mmio!(0x040001b4);
+const MMIO_SEED_HIGH: u32 = mmio!(0x040001b8);
 const MMIO_SPICNT: u32 = mmio!(0x040001c0);
 const MMIO_EXMEMSTAT: u32 = mmio!(0x04000204);
 const MMIO_IME: u32 = mmio!(0x04000208);
@@ -58,6 +64,7 @@ const MMIO_SOUNDCNT: u32 = mmio!(0x04000500);
 const MMIO_SOUNDBIAS: u32 = mmio!(0x04000504);
 const MMIO_SOUND_CAPTURE: u32 = mmio!(0x04000508);
 const MMIO_IPCFIFORECV: u32 = mmio!(0x04100000);
+const MMIO_CARTRIDGE_DATA: u32 = mmio!(0x04100010);
 const MMIO_WIFI_START: u32 = mmio!(0x04800000);
 const MMIO_WIFI_END: u32 = mmio!(0x04900000);
 
@@ -65,6 +72,8 @@ pub struct Arm7Memory {
     system: Shared<System>,
     arm7_wram: Box<[u8]>,
     bios: Box<[u8]>,
+    /// Running without bios7.bin, the swis are handled by `bios::software_interrupt`
+    hle_bios: bool,
     rcnt: u16,
     postflg: u8,
     pages: PageTable<14>,
@@ -75,13 +84,18 @@ impl Arm7Memory {
         Self {
             system: system.clone(),
             arm7_wram: vec![0; 0x10000].into_boxed_slice(),
-            bios: std::fs::read("firmware/bios7.bin").unwrap().into_boxed_slice(),
+            bios: Box::default(),
+            hle_bios: false,
             rcnt: 0,
             postflg: 0,
             pages: PageTable::new(),
         }
     }
 
+    pub const fn hle_bios(&self) -> bool {
+        self.hle_bios
+    }
+
     pub fn update_wram_mapping(&mut self) {
         match self.system.wramcnt {
             0x0 => self.pages.map(
@@ -124,9 +138,40 @@ impl Arm7Memory {
         );
     }
 
+    pub fn update_main_memory_mapping(&mut self) {
+        if self.system.heatmap.is_enabled() {
+            self.pages.unmap(0x02000000, 0x03000000, RegionAttributes::ReadWrite);
+        } else {
+            let ptr = self.system.main_memory.as_mut_ptr();
+            self.pages.map(0x02000000, 0x03000000, ptr, 0x3fffff, RegionAttributes::ReadWrite);
+        }
+    }
+
+    fn read_main_memory<T: Copy>(&mut self, addr: u32) -> T {
+        self.system.heatmap.record_read(addr);
+        unsafe { std::ptr::read(self.system.main_memory.as_ptr().add((addr & 0x3fffff) as usize).cast()) }
+    }
+
+    fn write_main_memory<T>(&mut self, addr: u32, val: T) {
+        self.system.heatmap.record_write(addr);
+        unsafe { std::ptr::write(self.system.main_memory.as_mut_ptr().add((addr & 0x3fffff) as usize).cast(), val) }
+    }
+
     fn write_postflg(&mut self, val: u8) {
         self.postflg = val & 1
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write("rcnt", self.rcnt);
+        state.write("postflg", self.postflg);
+        state.write_bytes("arm7_wram", &self.arm7_wram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.rcnt = state.read();
+        self.postflg = state.read();
+        state.read_bytes(&mut self.arm7_wram);
+    }
 }
 
 impl Memory for Arm7Memory {
@@ -135,11 +180,13 @@ impl Memory for Arm7Memory {
         self.rcnt = 0;
         self.postflg = 0;
 
+        let dump = if self.system.config.hle_bios { None } else { bios::load_dump(Arch::ARMv4) };
+        self.hle_bios = dump.is_none();
+        self.bios = dump.unwrap_or_else(|| bios::hle_image(Arch::ARMv4));
+
         let ptr = self.bios.as_mut_ptr();
         self.pages.map(0x00000000, 0x01000000, ptr, 0x3fff, RegionAttributes::Read);
-        let ptr = self.system.main_memory.as_mut_ptr();
-        self.pages.map(0x02000000, 0x03000000, ptr, 0x3fffff, RegionAttributes::ReadWrite);
-
+        self.update_main_memory_mapping();
         self.update_wram_mapping();
     }
 
@@ -150,9 +197,11 @@ impl Memory for Arm7Memory {
         }
 
         match addr >> 24 {
+            0x02 => self.read_main_memory(addr),
             0x04 => self.mmio_read_byte(addr),
             0x06 => self.system.video_unit.vram.arm7_vram.read(addr),
-            0x08 | 0x09 => todo!(),
+            0x08 | 0x09 => (self.system.read_gba_slot(Arch::ARMv4, addr & !1) >> ((addr & 1) * 8)) as u8,
+            0x0a => self.system.read_gba_slot_sram(Arch::ARMv4, addr),
             _ => {
                 warn!("ARM7Memory: handle 8-bit read {addr:08x}");
                 0
@@ -167,15 +216,11 @@ impl Memory for Arm7Memory {
         }
 
         match addr >> 24 {
+            0x02 => self.read_main_memory(addr),
             0x04 => self.mmio_read_half(addr),
             0x06 => self.system.video_unit.vram.arm7_vram.read(addr),
-            0x08 | 0x09 => {
-                if !bit::<7>(self.system.exmemcnt as _) {
-                    0
-                } else {
-                    0xffff
-                }
-            }
+            0x08 | 0x09 => self.system.read_gba_slot(Arch::ARMv4, addr),
+            0x0a => self.system.read_gba_slot_sram(Arch::ARMv4, addr) as u16 * 0x0101,
             _ => {
                 warn!("ARM7Memory: handle 16-bit read {addr:08x}");
                 0
@@ -190,9 +235,16 @@ impl Memory for Arm7Memory {
         }
 
         match addr >> 24 {
+            0x02 => self.read_main_memory(addr),
             0x04 => self.mmio_read_word(addr),
             0x06 => self.system.video_unit.vram.arm7_vram.read(addr),
-            0x08 | 0x09 => todo!(),
+            0x08 | 0x09 => {
+                let lo = self.system.read_gba_slot(Arch::ARMv4, addr) as u32;
+                let hi = self.system.read_gba_slot(Arch::ARMv4, addr + 2) as u32;
+                lo | (hi << 16)
+            }
+            // sram has an 8 bit bus, wider reads repeat the byte
+            0x0a => self.system.read_gba_slot_sram(Arch::ARMv4, addr) as u32 * 0x01010101,
             _ => {
                 warn!("ARM7Memory: handle 32-bit read {addr:08x}");
                 0
@@ -207,6 +259,7 @@ impl Memory for Arm7Memory {
         }
 
         match addr >> 24 {
+            0x02 => self.write_main_memory(addr, val),
             0x04 => self.mmio_write_byte(addr, val),
             0x06 => todo!(),
             _ => warn!("ARM7Memory: handle 8-bit write {addr:08x} = {val:02x}"),
@@ -220,6 +273,7 @@ impl Memory for Arm7Memory {
         }
 
         match addr >> 24 {
+            0x02 => self.write_main_memory(addr, val),
             0x04 => self.mmio_write_half(addr, val),
             0x06 => todo!(),
             _ => warn!("ARM7Memory: handle 16-bit write {addr:08x} = {val:04x}"),
@@ -233,6 +287,7 @@ impl Memory for Arm7Memory {
         }
 
         match addr >> 24 {
+            0x02 => self.write_main_memory(addr, val),
             0x04 => self.mmio_write_word(addr, val),
             0x06 => todo!(),
             0x08 | 0x09 => {}
@@ -240,6 +295,44 @@ impl Memory for Arm7Memory {
         }
     }
 
+    fn peek_byte(&mut self, addr: u32) -> u8 {
+        let ptr = self.pages.read_pointer::<u8>(addr);
+        if !ptr.is_null() {
+            return unsafe { std::ptr::read(ptr) };
+        }
+
+        match addr >> 24 {
+            0x02 => self.system.main_memory[(addr & 0x3fffff) as usize],
+            0x06 => self.system.video_unit.vram.arm7_vram.read(addr),
+            _ => 0,
+        }
+    }
+
+    fn poke_byte(&mut self, addr: u32, val: u8) {
+        let ptr = self.pages.write_pointer::<u8>(addr);
+        if !ptr.is_null() {
+            return unsafe { std::ptr::write(ptr, val) };
+        }
+
+        match addr >> 24 {
+            0x02 => self.system.main_memory[(addr & 0x3fffff) as usize] = val,
+            0x06 => self.system.video_unit.vram.arm7_vram.write(addr, val),
+            _ => {}
+        }
+    }
+
+    fn access_cycles(&self, addr: u32, word: bool, sequential: bool, _code: bool) -> u64 {
+        // n16, s16, n32 and s32 on the 33mhz bus
+        let cycles = match addr >> 24 {
+            0x02 => [8, 1, 9, 2],
+            0x06 => [1, 1, 2, 2],
+            0x08 | 0x09 => return self.system.gba_slot_cycles(Arch::ARMv4, false, word, sequential),
+            0x0a => return self.system.gba_slot_cycles(Arch::ARMv4, true, word, sequential),
+            _ => return 1,
+        };
+        cycles[usize::from(word) * 2 + usize::from(sequential)]
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
@@ -249,6 +342,8 @@ impl MmioMemory for Arm7Memory {
     fn mmio_read<const MASK: u32>(&mut self, addr: u32) -> u32 {
         let mut val = 0;
         match mmio!(addr) {
+            // the cpu without nds slot access reads zeroes from the cartridge registers and can't write them
+            MMIO_AUXSPICNT..=MMIO_SEED_HIGH | MMIO_CARTRIDGE_DATA if self.system.nds_slot_owner() != Arch::ARMv4 => {}
             MMIO_DISPSTAT => handle! { MASK => {
                 0x0000ffff: val |= self.system.video_unit.read_dispstat(Arch::ARMv4),
                 0xffff0000: val |= self.system.video_unit.read_vcount() << 16,
@@ -290,6 +385,7 @@ impl MmioMemory for Arm7Memory {
                 0x0000ffff: val |= self.system.cartridge.read_auxspicnt() as u32,
                 0xffff0000: val |= (self.system.cartridge.read_auxspidata() as u32) << 16
             }},
+            MMIO_ROMCTRL => return self.system.cartridge.read_romctrl(),
             MMIO_SPICNT => handle! { MASK => {
                 0x0000ffff: val |= self.system.spi.read_spicnt() as u32,
                 0xffff0000: val |= (self.system.spi.read_spidata() as u32) << 16,
@@ -310,10 +406,15 @@ impl MmioMemory for Arm7Memory {
             }},
             MMIO_POWCNT1 => return self.system.video_unit.read_powcnt1(),
             MMIO_IPCFIFORECV => return self.system.ipc.read_ipcfiforecv(Arch::ARMv4),
-            MMIO_SPU_CHANNEL_BASE..=MMIO_SPU_CHANNEL_END => { /* todo: spu */ }
+            MMIO_CARTRIDGE_DATA => return self.system.cartridge.read_data(),
+            MMIO_SPU_CHANNEL_BASE..=MMIO_SPU_CHANNEL_END => return self.system.spu.read_channel(addr),
             MMIO_SOUNDCNT => return self.system.spu.read_soundcnt() as u32,
+            MMIO_SOUNDBIAS => return self.system.spu.read_soundbias() as u32,
             MMIO_SOUND_CAPTURE => { /* todo: spu */ }
-            MMIO_WIFI_START..=MMIO_WIFI_END => { /* todo: wifi */ }
+            MMIO_WIFI_START..=MMIO_WIFI_END => handle! { MASK => {
+                0x0000ffff: val |= self.system.wifi.read(addr) as u32,
+                0xffff0000: val |= (self.system.wifi.read(addr + 2) as u32) << 16,
+            }},
             _ => warn!(
                 "ARM7Memory: unmapped {}-bit  read {:08x}",
                 get_access_size(MASK),
@@ -324,7 +425,11 @@ impl MmioMemory for Arm7Memory {
     }
 
     fn mmio_write<const MASK: u32>(&mut self, addr: u32, val: u32) {
+        if self.system.debugger.on_mmio_write(Arch::ARMv4, addr, MASK) {
+            self.system.arm7.cpu.set_watch_hit();
+        }
         match mmio!(addr) {
+            MMIO_AUXSPICNT..=MMIO_SEED_HIGH | MMIO_CARTRIDGE_DATA if self.system.nds_slot_owner() != Arch::ARMv4 => {}
             MMIO_DISPSTAT => handle! { MASK => {
                 0x0000ffff: self.system.video_unit.write_dispstat(Arch::ARMv4, val, MASK),
                 0xffff0000: error!("ARM7Memory: handle vcount write"),
@@ -386,6 +491,15 @@ impl MmioMemory for Arm7Memory {
                 0x0000ffff: self.system.cartridge.write_auxspicnt(val as _, MASK as _),
                 0xffff0000: self.system.cartridge.write_auxspidata((val >> 16) as _)
             }},
+            MMIO_ROMCTRL => self.system.cartridge.write_romctrl(val, MASK),
+            MMIO_COMMAND_BUFFER0 => self.system.cartridge.write_command_buffer(val as _, MASK as _),
+            MMIO_COMMAND_BUFFER1 => self.system.cartridge.write_command_buffer((val as u64) << 32, (MASK as u64) << 32),
+            MMIO_SEED0 => self.system.cartridge.write_seed0(val as _, MASK as _),
+            MMIO_SEED1 => self.system.cartridge.write_seed1(val as _, MASK as _),
+            MMIO_SEED_HIGH => handle! { MASK => {
+                0x0000ffff: self.system.cartridge.write_seed0((val as u64) << 32, (MASK as u64 & 0xffff) << 32),
+                0xffff0000: self.system.cartridge.write_seed1((val as u64 >> 16) << 32, (MASK as u64 >> 16) << 32)
+            }},
             MMIO_SPICNT => handle! { MASK => {
                 0x0000ffff: self.system.spi.write_spicnt(val as _, MASK & 0xffff),
                 0xffff0000: self.system.spi.write_spidata((val >> 16) as _),
@@ -402,11 +516,14 @@ impl MmioMemory for Arm7Memory {
                 0xff00: self.system.write_haltcnt((val >> 8) as u8)
             }},
             MMIO_POWCNT1 => self.system.video_unit.write_powcnt1(val, MASK),
-            MMIO_SPU_CHANNEL_BASE..=MMIO_SPU_CHANNEL_END => { /* todo: spu */ }
+            MMIO_SPU_CHANNEL_BASE..=MMIO_SPU_CHANNEL_END => self.system.spu.write_channel(addr, val, MASK),
             MMIO_SOUNDCNT => self.system.spu.write_soundcnt(val as _, MASK as _),
-            MMIO_SOUNDBIAS => warn!("todo: sound bias"),
+            MMIO_SOUNDBIAS => self.system.spu.write_soundbias(val as _, MASK as _),
             MMIO_SOUND_CAPTURE => { /* todo: spu */ }
-            MMIO_WIFI_START..=MMIO_WIFI_END => { /* todo: wifi */ }
+            MMIO_WIFI_START..=MMIO_WIFI_END => handle! { MASK => {
+                0x0000ffff: self.system.wifi.write(addr, val as u16, MASK as u16),
+                0xffff0000: self.system.wifi.write(addr + 2, (val >> 16) as u16, (MASK >> 16) as u16),
+            }},
             _ => warn!(
                 "ARM7Memory: unmapped {}-bit write {:08x} = {:08x}",
                 get_access_size(MASK),
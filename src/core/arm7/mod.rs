@@ -1,11 +1,11 @@
-use crate::arm::cpu::{Arch, Cpu};
+use crate::arm::cpu::{Arch, Cpu, SwiHandler};
 use crate::arm::memory::Memory;
 use crate::arm::state::{Mode, StatusReg, GPR, Bank};
 use crate::core::arm7::coprocessor::Arm7Coprocessor;
 use crate::core::arm7::memory::Arm7Memory;
 use crate::core::hardware::irq::Irq;
-use crate::core::System;
-use crate::util::Shared;
+use crate::core::{bios, System};
+use crate::util::{Shared, StateReader, StateWriter};
 
 mod coprocessor;
 mod memory;
@@ -23,7 +23,7 @@ impl Arm7 {
         let cpu = Shared::new(Cpu::new(Arch::ARMv4, memory, coprocessor));
         Self {
             system: system.clone(),
-            irq: Shared::new(Irq::new(&cpu)),
+            irq: Shared::new(Irq::new(system, &cpu)),
             cpu,
         }
     }
@@ -31,6 +31,15 @@ impl Arm7 {
     pub fn reset(&mut self) {
         self.cpu.memory.reset();
         self.cpu.reset();
+        self.irq.reset();
+
+        let handler = self.hle_bios().then_some(bios::software_interrupt as SwiHandler);
+        self.cpu.set_swi_handler(handler);
+    }
+
+    /// Whether the swis are handled without bios7.bin, which also means the bios data isn't there
+    pub fn hle_bios(&mut self) -> bool {
+        self.cpu.memory.as_any().downcast_mut::<Arm7Memory>().unwrap().hle_bios()
     }
 
     pub fn run(&mut self, cycles: u64) {
@@ -67,6 +76,20 @@ impl Arm7 {
         self.cpu.arm_flush_pipeline();
     }
 
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        state.section(b"ARM7");
+        self.cpu.save_state(state);
+        self.irq.save_state(state);
+        self.cpu.memory.as_any().downcast_mut::<Arm7Memory>().unwrap().save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"ARM7");
+        self.cpu.load_state(state);
+        self.irq.load_state(state);
+        self.cpu.memory.as_any().downcast_mut::<Arm7Memory>().unwrap().load_state(state);
+    }
+
     pub fn get_memory(&mut self) -> &mut dyn Memory {
         &mut *self.cpu.memory
     }
@@ -74,6 +97,10 @@ impl Arm7 {
         &mut self.irq
     }
 
+    pub fn update_main_memory_mapping(&mut self) {
+        self.cpu.memory.as_any().downcast_mut::<Arm7Memory>().unwrap().update_main_memory_mapping()
+    }
+
     pub fn update_wram_mapping(&mut self) {
         self.cpu.memory.as_any().downcast_mut::<Arm7Memory>().unwrap().update_wram_mapping()
     }
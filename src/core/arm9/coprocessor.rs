@@ -5,16 +5,32 @@ use crate::arm::cpu::Cpu;
 use crate::bitfield;
 use crate::util::Shared;
 
+/// Control register bits that can be written, the rest read as fixed values
+const CONTROL_WRITABLE: u32 = 0x000ff085;
+/// Protection unit off, high exception vectors and tcms disabled
+const CONTROL_RESET: u32 = 0x00002078;
+
 pub struct Arm9Coprocessor {
     cpu: Shared<Cpu>,
     itcm_cnt: Shared<Tcm>,
     dtcm_cnt: Shared<Tcm>,
 
     control: Control,
-    dtcm: [u8; 0x8000],
-    itcm: [u8; 0x4000],
     dtcm_control: TcmControl,
     itcm_control: TcmControl,
+
+    /// Protection unit regions. Games only use them to catch bugs, so they're kept for reads but not enforced
+    regions: [Region; 8],
+    /// One bit per region for data and instruction caching, and for the write buffer
+    data_cacheable: u8,
+    code_cacheable: u8,
+    bufferable: u8,
+    /// 4 bits per region, the 2 bit registers read and write the low half of each
+    data_permissions: u32,
+    code_permissions: u32,
+    data_lockdown: u32,
+    code_lockdown: u32,
+    process_id: u32,
 }
 
 impl Arm9Coprocessor {
@@ -23,22 +39,83 @@ impl Arm9Coprocessor {
             cpu: cpu.clone(),
             itcm_cnt: itcm.clone(),
             dtcm_cnt: dtcm.clone(),
-            control: Control(0),
-            dtcm: [0; 0x8000],
-            itcm: [0; 0x4000],
+            control: Control(CONTROL_RESET),
             dtcm_control: TcmControl(0),
             itcm_control: TcmControl(0),
+            regions: [Region(0); 8],
+            data_cacheable: 0,
+            code_cacheable: 0,
+            bufferable: 0,
+            data_permissions: 0,
+            code_permissions: 0,
+            data_lockdown: 0,
+            code_lockdown: 0,
+            process_id: 0,
         }
     }
+
+    /// Tcm accesses are checked before the page table, so moving or resizing them takes effect on the next access
+    fn update_tcms(&mut self) {
+        self.dtcm_cnt.enable_reads = self.control.dtcm_enable() && !self.control.dtcm_write_only();
+        self.dtcm_cnt.enable_writes = self.control.dtcm_enable();
+        self.itcm_cnt.enable_reads = self.control.itcm_enable() && !self.control.itcm_write_only();
+        self.itcm_cnt.enable_writes = self.control.itcm_enable();
+
+        // the base is aligned to the size, itcm is always at 0
+        let dtcm_size = self.dtcm_control.virtual_size();
+        let dtcm_base = (self.dtcm_control.base() as u64) << 12 & !(dtcm_size - 1);
+        self.dtcm_cnt.base = dtcm_base as u32;
+        self.dtcm_cnt.limit = (dtcm_base + dtcm_size).min(u32::MAX as u64) as u32;
+        self.itcm_cnt.base = 0;
+        self.itcm_cnt.limit = self.itcm_control.virtual_size().min(u32::MAX as u64) as u32;
+    }
+}
+
+/// Packs the low 2 bits of each region's 4 bit permissions for the legacy registers
+fn compress_permissions(val: u32) -> u32 {
+    (0..8).fold(0, |acc, i| acc | ((val >> (i * 4)) & 0x3) << (i * 2))
+}
+
+fn expand_permissions(val: u32) -> u32 {
+    (0..8).fold(0, |acc, i| acc | ((val >> (i * 2)) & 0x3) << (i * 4))
 }
 
 impl Coprocessor for Arm9Coprocessor {
+    fn reset(&mut self) {
+        self.control = Control(CONTROL_RESET);
+        self.dtcm_control = TcmControl(0);
+        self.itcm_control = TcmControl(0);
+        self.regions = [Region(0); 8];
+        self.data_cacheable = 0;
+        self.code_cacheable = 0;
+        self.bufferable = 0;
+        self.data_permissions = 0;
+        self.code_permissions = 0;
+        self.data_lockdown = 0;
+        self.code_lockdown = 0;
+        self.process_id = 0;
+        self.update_tcms();
+    }
+
     fn read(&mut self, cn: u32, cm: u32, cp: u32) -> u32 {
         match (cn << 16) | (cm << 8) | cp {
-            0x000001 => 0x0f0d2112, // chip id
+            0x000000 => 0x41059461, // main id, arm946e-s
+            0x000001 => 0x0f0d2112, // cache type
+            0x000002 => 0x00140180, // tcm size, 32KB itcm and 16KB dtcm
             0x010000 => self.control.0,
+            0x020000 => self.data_cacheable as u32,
+            0x020001 => self.code_cacheable as u32,
+            0x030000 => self.bufferable as u32,
+            0x050000 => compress_permissions(self.data_permissions),
+            0x050001 => compress_permissions(self.code_permissions),
+            0x050002 => self.data_permissions,
+            0x050003 => self.code_permissions,
+            0x060000..=0x060701 if cp <= 1 => self.regions[cm as usize].0,
+            0x090000 => self.data_lockdown,
+            0x090001 => self.code_lockdown,
             0x090100 => self.dtcm_control.0,
             0x090101 => self.itcm_control.0,
+            0x0d0001 | 0x0d0101 => self.process_id,
             _ => {
                 error!("ARM9Coprocessor: handle register read c{cn}, c{cm}, c{cp}");
                 0
@@ -49,54 +126,39 @@ impl Coprocessor for Arm9Coprocessor {
     fn write(&mut self, cn: u32, cm: u32, cp: u32, val: u32) {
         match (cn << 16) | (cm << 8) | cp {
             0x010000 => {
-                self.control.0 = val;
-                self.dtcm_cnt.enable_reads = self.control.dtcm_enable() && !self.control.dtcm_write_only();
-                self.dtcm_cnt.enable_writes = self.control.dtcm_enable();
-                self.itcm_cnt.enable_reads = self.control.itcm_enable() && !self.control.itcm_write_only();
-                self.itcm_cnt.enable_writes = self.control.itcm_enable();
+                self.control.0 = (self.control.0 & !CONTROL_WRITABLE) | (val & CONTROL_WRITABLE);
+                self.update_tcms();
             }
-            0x020000 => {}
-            0x020001 => {}
-            0x030000 => {}
-            0x050002 => {}
-            0x050003 => {}
-            0x060000 => {}
-            0x060100 => {}
-            0x060200 => {}
-            0x060300 => {}
-            0x060400 => {}
-            0x060500 => {}
-            0x060600 => {}
-            0x060700 => {}
-            0x070500 => {}
-            0x070501 => {}
-            0x070600 => {}
-            0x070601 => {}
-            0x070602 => {}
-            0x070a01 => {}
-            0x070a02 => {}
-            0x070a04 => {}
-            0x070e01 => {}
-            0x070e02 => {}
-            0x070004 => self.cpu.update_halted(true),
+            0x020000 => self.data_cacheable = val as u8,
+            0x020001 => self.code_cacheable = val as u8,
+            0x030000 => self.bufferable = val as u8,
+            0x050000 => self.data_permissions = expand_permissions(val),
+            0x050001 => self.code_permissions = expand_permissions(val),
+            0x050002 => self.data_permissions = val,
+            0x050003 => self.code_permissions = val,
+            0x060000..=0x060701 if cp <= 1 => self.regions[cm as usize].0 = val,
+            // caches aren't emulated, so there's nothing to invalidate, clean or prefetch
+            0x070500 | 0x070501 | 0x070600 | 0x070601 | 0x070602 | 0x070a01 | 0x070a02 | 0x070a04 | 0x070d01 | 0x070e01 | 0x070e02 => {}
+            0x070004 | 0x070802 => self.cpu.update_halted(true),
+            0x090000 => self.data_lockdown = val,
+            0x090001 => self.code_lockdown = val,
             0x090100 => {
-                self.dtcm_control.0 = val;
-                self.dtcm_cnt.base = self.dtcm_control.base() << 12;
-                self.dtcm_cnt.limit = self.dtcm_cnt.base + (512 << self.dtcm_control.size());
+                self.dtcm_control.0 = val & 0xfffff03e;
+                self.update_tcms();
                 debug!(
                     "ARM9Coprocessor: dtcm base = {:x}, limit = {:x}",
                     self.dtcm_cnt.base, self.dtcm_cnt.limit
                 )
             }
             0x090101 => {
-                self.itcm_control.0 = val;
-                self.itcm_cnt.base = 0;
-                self.itcm_cnt.limit = 512 << self.itcm_control.size();
+                self.itcm_control.0 = val & 0x3e;
+                self.update_tcms();
                 debug!(
                     "ARM9Coprocessor: itcm base = {:x}, limit = {:x}",
                     self.itcm_cnt.base, self.itcm_cnt.limit
                 )
             }
+            0x0d0001 | 0x0d0101 => self.process_id = val,
             _ => error!("ARM9Coprocessor: handle register write c{cn}, c{cm}, c{cp} = {val:08x}"),
         }
     }
@@ -153,3 +215,20 @@ bitfield! {
         base: u32 => 12 | 31
     }
 }
+
+impl TcmControl {
+    /// The area the tcm is mirrored across, 4KB at least
+    fn virtual_size(&self) -> u64 {
+        512 << self.size().max(3)
+    }
+}
+
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct Region(u32) {
+        enable: bool => 0,
+        size: u32 => 1 | 5,
+        // 6 | 11
+        base: u32 => 12 | 31
+    }
+}
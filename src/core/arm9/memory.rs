@@ -1,11 +1,11 @@
 use std::any::Any;
 
-use log::{error, warn};
+use log::{debug, error, warn};
 
 use crate::arm::coprocessor::Tcm;
 use crate::arm::cpu::Arch;
 use crate::arm::memory::{Memory, MmioMemory};
-use crate::core::System;
+use crate::core::{bios, System};
 use crate::core::video::vram::VramBank;
 use crate::util::*;
 
@@ -49,6 +49,7 @@ const MMIO_PPUA_RESERVED0: u32 = mmio!(0x04000058);
 const MMIO_PPUA_RESERVED1: u32 = mmio!(0x0400005c);
 const MMIO_GPU_DISP3DCNT: u32 = mmio!(0x04000060);
 const MMIO_DISPCAPCNT: u32 = mmio!(0x04000064);
+const MMIO_DISP_MMEM_FIFO: u32 = mmio!(0x04000068);
 const MMIO_PPUA_MASTERBRIGHT: u32 = mmio!(0x0400006c);
 const MMIO_DMA_SOURCE0: u32 = mmio!(0x040000b0);
 const MMIO_DMA_DESTINATION0: u32 = mmio!(0x040000b4);
@@ -76,6 +77,9 @@ const MMIO_AUXSPICNT: u32 = mmio!(0x040001a0);
 const MMIO_ROMCTRL: u32 = mmio!(0x040001a4);
 const MMIO_COMMAND_BUFFER0: u32 = mmio!(0x040001a8);
 const MMIO_COMMAND_BUFFER1: u32 = mmio!(0x040001ac);
+const MMIO_SEED0: u32 = mmio!(0x040001b0);
+const MMIO_SEED1: u32 = mmio!(0x040001b4);
+const MMIO_SEED_HIGH: u32 = mmio!(0x040001b8);
 const MMIO_EXMEMCNT: u32 = mmio!(0x04000204);
 const MMIO_IME: u32 = mmio!(0x04000208);
 const MMIO_IE: u32 = mmio!(0x04000210);
@@ -98,6 +102,29 @@ const MMIO_SQRT_PARAM: u32 = mmio!(0x040002b8);
 const MMIO_SQRT_PARAM2: u32 = mmio!(0x040002bc);
 const MMIO_POSTFLG: u32 = mmio!(0x04000300);
 const MMIO_POWCNT1: u32 = mmio!(0x04000304);
+const MMIO_GPU_EDGE_COLOR_START: u32 = mmio!(0x04000330);
+const MMIO_GPU_EDGE_COLOR_END: u32 = mmio!(0x0400033c);
+const MMIO_GPU_ALPHA_TEST_REF: u32 = mmio!(0x04000340);
+const MMIO_GPU_CLEAR_COLOR: u32 = mmio!(0x04000350);
+const MMIO_GPU_CLEAR_DEPTH: u32 = mmio!(0x04000354);
+const MMIO_GPU_FOG_START: u32 = mmio!(0x04000358);
+const MMIO_GPU_FOG_END: u32 = mmio!(0x0400037c);
+const MMIO_GPU_TOON_TABLE_START: u32 = mmio!(0x04000380);
+const MMIO_GPU_TOON_TABLE_END: u32 = mmio!(0x040003bc);
+const MMIO_GPU_GXFIFO_START: u32 = mmio!(0x04000400);
+const MMIO_GPU_GXFIFO_END: u32 = mmio!(0x0400043c);
+const MMIO_GPU_COMMAND_START: u32 = mmio!(0x04000440);
+const MMIO_GPU_COMMAND_END: u32 = mmio!(0x040005fc);
+const MMIO_GPU_GXSTAT: u32 = mmio!(0x04000600);
+const MMIO_GPU_RAM_COUNT: u32 = mmio!(0x04000604);
+const MMIO_GPU_POS_RESULT_START: u32 = mmio!(0x04000620);
+const MMIO_GPU_POS_RESULT_END: u32 = mmio!(0x0400062c);
+const MMIO_GPU_VEC_RESULT_START: u32 = mmio!(0x04000630);
+const MMIO_GPU_VEC_RESULT_END: u32 = mmio!(0x04000634);
+const MMIO_GPU_CLIPMTX_RESULT_START: u32 = mmio!(0x04000640);
+const MMIO_GPU_CLIPMTX_RESULT_END: u32 = mmio!(0x0400067c);
+const MMIO_GPU_VECMTX_RESULT_START: u32 = mmio!(0x04000680);
+const MMIO_GPU_VECMTX_RESULT_END: u32 = mmio!(0x040006a0);
 const MMIO_PPUB_DISPCNT: u32 = mmio!(0x04001000);
 const MMIO_PPUB_RESERVED0: u32 = mmio!(0x04001004);
 const MMIO_PPUB_BGCNT0: u32 = mmio!(0x04001008);
@@ -130,6 +157,8 @@ pub struct Arm9Memory {
     system: Shared<System>,
     postflg: u8,
     bios: Box<[u8]>,
+    /// Running without bios9.bin, the swis are handled by `bios::software_interrupt`
+    hle_bios: bool,
     dtcm_data: Box<[u8]>,
     itcm_data: Box<[u8]>,
 
@@ -144,7 +173,8 @@ impl Arm9Memory {
         Self {
             system: system.clone(),
             postflg: 0,
-            bios: std::fs::read("firmware/bios9.bin").unwrap().into_boxed_slice(),
+            bios: Box::default(),
+            hle_bios: false,
             dtcm_data: vec![0; 0x4000].into_boxed_slice(),
             itcm_data: vec![0; 0x8000].into_boxed_slice(),
 
@@ -155,6 +185,10 @@ impl Arm9Memory {
         }
     }
 
+    pub const fn hle_bios(&self) -> bool {
+        self.hle_bios
+    }
+
     pub fn update_wram_mapping(&mut self) {
         match self.system.wramcnt {
             0x0 => self.pages.map(
@@ -236,9 +270,52 @@ impl Arm9Memory {
         None
     }
 
+    pub fn update_main_memory_mapping(&mut self) {
+        if self.system.heatmap.is_enabled() {
+            self.pages.unmap(0x02000000, 0x03000000, RegionAttributes::ReadWrite);
+        } else {
+            let ptr = self.system.main_memory.as_mut_ptr();
+            self.pages.map(0x02000000, 0x03000000, ptr, 0x3fffff, RegionAttributes::ReadWrite);
+        }
+    }
+
+    fn read_main_memory<T: Copy>(&mut self, addr: u32) -> T {
+        self.system.heatmap.record_read(addr);
+        unsafe { std::ptr::read(self.system.main_memory.as_ptr().add((addr & 0x3fffff) as usize).cast()) }
+    }
+
+    fn write_main_memory<T>(&mut self, addr: u32, val: T) {
+        self.system.heatmap.record_write(addr);
+        unsafe { std::ptr::write(self.system.main_memory.as_mut_ptr().add((addr & 0x3fffff) as usize).cast(), val) }
+    }
+
+    /// Accesses below main memory that itcm didn't take. Itcm can be moved or disabled through cp15,
+    /// and isn't enabled until the bios or direct boot sets up cp15, anything it doesn't cover has
+    /// nothing connected, so reads give 0 and writes are dropped for every access width
+    fn read_low_region(&self, addr: u32) -> u32 {
+        debug!("ARM9Memory: read from unmapped low address {addr:08x}");
+        0
+    }
+
+    fn write_low_region(&self, addr: u32) {
+        debug!("ARM9Memory: write to unmapped low address {addr:08x}");
+    }
+
     fn write_postflg(&mut self, val: u8) {
         self.postflg = (self.postflg & !0x2) | (val & 0x3)
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write("postflg", self.postflg);
+        state.write_bytes("dtcm_data", &self.dtcm_data);
+        state.write_bytes("itcm_data", &self.itcm_data);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.postflg = state.read();
+        state.read_bytes(&mut self.dtcm_data);
+        state.read_bytes(&mut self.itcm_data);
+    }
 }
 
 impl Memory for Arm9Memory {
@@ -252,12 +329,17 @@ impl Memory for Arm9Memory {
         self.dtcm.mask = self.dtcm_data.len() as u32 - 1;
         self.itcm.mask = self.itcm_data.len() as u32 - 1;
 
+        let dump = if self.system.config.hle_bios { None } else { bios::load_dump(Arch::ARMv5) };
+        self.hle_bios = dump.is_none();
+        // mirrored over the whole 32KB, pages are bigger than the bios
+        let image = dump.unwrap_or_else(|| bios::hle_image(Arch::ARMv5));
+        self.bios = image.repeat(0x8000 / image.len()).into_boxed_slice();
+
         unsafe {
             let ptr = self.bios.as_mut_ptr();
             self.pages.map(0xffff0000, 0xffff8000, ptr, 0x7fff, RegionAttributes::Read);
-            let ptr = self.system.main_memory.as_mut_ptr();
-            self.pages.map(0x02000000, 0x03000000, ptr, 0x3fffff, RegionAttributes::ReadWrite);
         }
+        self.update_main_memory_mapping();
         self.update_wram_mapping();
     }
 
@@ -267,11 +349,14 @@ impl Memory for Arm9Memory {
         }
 
         match addr >> 24 {
+            0x00 | 0x01 => self.read_low_region(addr) as u8,
+            0x02 => self.read_main_memory(addr),
             0x04 => self.mmio_read_byte(addr),
-            0x05 => todo!(),
+            0x05 => self.system.video_unit.read_palette_ram(addr),
             0x06 => self.system.video_unit.vram.read(addr),
-            0x07 => todo!(),
-            0x08 | 0x09 => todo!(),
+            0x07 => self.system.video_unit.read_oam(addr),
+            0x08 | 0x09 => (self.system.read_gba_slot(Arch::ARMv5, addr & !1) >> ((addr & 1) * 8)) as u8,
+            0x0a => self.system.read_gba_slot_sram(Arch::ARMv5, addr),
             _ => {
                 warn!("ARM9Memory: handle 8-bit read {addr:08x}");
                 0
@@ -286,17 +371,14 @@ impl Memory for Arm9Memory {
         }
 
         match addr >> 24 {
+            0x00 | 0x01 => self.read_low_region(addr) as u16,
+            0x02 => self.read_main_memory(addr),
             0x04 => self.mmio_read_half(addr),
-            0x05 => todo!(),
+            0x05 => self.system.video_unit.read_palette_ram(addr),
             0x06 => self.system.video_unit.vram.read(addr),
-            0x07 => todo!(),
-            0x08 | 0x09 => {
-                if bit::<7>(self.system.exmemcnt as _) {
-                    0
-                } else {
-                    0xffff
-                }
-            }
+            0x07 => self.system.video_unit.read_oam(addr),
+            0x08 | 0x09 => self.system.read_gba_slot(Arch::ARMv5, addr),
+            0x0a => self.system.read_gba_slot_sram(Arch::ARMv5, addr) as u16 * 0x0101,
             _ => {
                 warn!("ARM9Memory: handle 16-bit read {addr:08x}");
                 0
@@ -311,13 +393,19 @@ impl Memory for Arm9Memory {
         }
 
         match addr >> 24 {
-            0x00 | 0x01 => 0,
+            0x00 | 0x01 => self.read_low_region(addr),
+            0x02 => self.read_main_memory(addr),
             0x04 => self.mmio_read_word(addr),
-            0x05 => todo!(),
+            0x05 => self.system.video_unit.read_palette_ram(addr),
             0x06 => self.system.video_unit.vram.read(addr),
-            0x07 => todo!(),
-            0x08 | 0x09 => todo!(),
-            0x0a => todo!(),
+            0x07 => self.system.video_unit.read_oam(addr),
+            0x08 | 0x09 => {
+                let lo = self.system.read_gba_slot(Arch::ARMv5, addr) as u32;
+                let hi = self.system.read_gba_slot(Arch::ARMv5, addr + 2) as u32;
+                lo | (hi << 16)
+            }
+            // sram has an 8 bit bus, wider reads repeat the byte
+            0x0a => self.system.read_gba_slot_sram(Arch::ARMv5, addr) as u32 * 0x01010101,
             _ => {
                 warn!("ARM9Memory: handle 32-bit read {addr:08x}");
                 0
@@ -331,6 +419,8 @@ impl Memory for Arm9Memory {
         }
 
         match addr >> 24 {
+            0x00 | 0x01 => self.write_low_region(addr),
+            0x02 => self.write_main_memory(addr, val),
             0x04 => self.mmio_write_byte(addr, val),
             0x06 => self.system.video_unit.vram.write(addr, val),
             _ => warn!("ARM9Memory: handle 8-bit write {addr:08x} = {val:02x}"),
@@ -343,6 +433,8 @@ impl Memory for Arm9Memory {
             return;
         }
         match addr >> 24 {
+            0x00 | 0x01 => self.write_low_region(addr),
+            0x02 => self.write_main_memory(addr, val),
             0x04 => self.mmio_write_half(addr, val),
             0x05 => self.system.video_unit.write_palette_ram(addr, val),
             0x06 => self.system.video_unit.vram.write(addr, val),
@@ -357,7 +449,8 @@ impl Memory for Arm9Memory {
             return;
         }
         match addr >> 24 {
-            0x00 | 0x01 => {}
+            0x00 | 0x01 => self.write_low_region(addr),
+            0x02 => self.write_main_memory(addr, val),
             0x04 => self.mmio_write_word(addr, val),
             0x05 => self.system.video_unit.write_palette_ram(addr, val),
             0x06 => self.system.video_unit.vram.write(addr, val),
@@ -367,6 +460,52 @@ impl Memory for Arm9Memory {
         }
     }
 
+    fn peek_byte(&mut self, addr: u32) -> u8 {
+        if let Some(val) = self.tcm_read::<u8>(addr) {
+            return val;
+        }
+
+        match addr >> 24 {
+            0x02 => self.system.main_memory[(addr & 0x3fffff) as usize],
+            0x05 => self.system.video_unit.read_palette_ram(addr),
+            0x06 => self.system.video_unit.vram.read(addr),
+            0x07 => self.system.video_unit.read_oam(addr),
+            _ => 0,
+        }
+    }
+
+    fn poke_byte(&mut self, addr: u32, val: u8) {
+        if self.tcm_write(addr, val) {
+            return;
+        }
+
+        match addr >> 24 {
+            0x02 => self.system.main_memory[(addr & 0x3fffff) as usize] = val,
+            0x05 => self.system.video_unit.write_palette_ram(addr, val),
+            0x06 => self.system.video_unit.vram.write(addr, val),
+            0x07 => self.system.video_unit.write_oam(addr, val),
+            _ => {}
+        }
+    }
+
+    fn access_cycles(&self, addr: u32, word: bool, sequential: bool, code: bool) -> u64 {
+        let in_tcm = |tcm: &Tcm| tcm.enable_reads && addr >= tcm.base && addr < tcm.limit;
+        if in_tcm(&*self.itcm) || (!code && in_tcm(&*self.dtcm)) {
+            return 1;
+        }
+
+        // the caches aren't emulated, main memory and the bios are treated as always hitting.
+        // everything else goes over the bus, which runs at half the arm9 clock
+        let cycles = match addr >> 24 {
+            0x02 | 0xff => return 1,
+            0x05..=0x07 => [1, 1, 2, 2],
+            0x08 | 0x09 => return self.system.gba_slot_cycles(Arch::ARMv5, false, word, sequential) * 2,
+            0x0a => return self.system.gba_slot_cycles(Arch::ARMv5, true, word, sequential) * 2,
+            _ => return 2,
+        };
+        cycles[usize::from(word) * 2 + usize::from(sequential)] * 2
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
@@ -377,6 +516,8 @@ impl MmioMemory for Arm9Memory {
         let mut val = 0;
 
         match mmio!(addr) {
+            // the cpu without nds slot access reads zeroes from the cartridge registers and can't write them
+            MMIO_AUXSPICNT..=MMIO_SEED_HIGH | MMIO_CARTRIDGE_DATA if self.system.nds_slot_owner() != Arch::ARMv5 => {}
             MMIO_DISPCNT => return self.system.video_unit.ppu_a.read_dispcnt(),
             MMIO_DISPSTAT => handle! { MASK => {
                 0x0000ffff: val |= self.system.video_unit.read_dispstat(Arch::ARMv5),
@@ -394,6 +535,7 @@ impl MmioMemory for Arm9Memory {
                 0x0000ffff: val |= self.system.video_unit.ppu_a.read_winin() as u32,
                 0xffff0000: val |= (self.system.video_unit.ppu_a.read_winout() as u32) << 16
             }},
+            MMIO_GPU_DISP3DCNT => return self.system.video_unit.gpu.read_disp3dcnt(),
             MMIO_DMA_SOURCE0 => return self.system.dma9.read_source(0),
             MMIO_DMA_LENGTH0 => handle! { MASK => {
                 0x0000ffff: val |= self.system.dma9.read_length(0),
@@ -434,6 +576,10 @@ impl MmioMemory for Arm9Memory {
             }},
             MMIO_IPCSYNC => return self.system.ipc.read_ipcsync(Arch::ARMv5),
             MMIO_IPCFIFOCNT => return self.system.ipc.read_ipcfifocnt(Arch::ARMv5) as u32,
+            MMIO_AUXSPICNT => handle! { MASK => {
+                0x0000ffff: val |= self.system.cartridge.read_auxspicnt() as u32,
+                0xffff0000: val |= (self.system.cartridge.read_auxspidata() as u32) << 16
+            }},
             MMIO_ROMCTRL => return self.system.cartridge.read_romctrl(),
             MMIO_EXMEMCNT => return self.system.read_exmemcnt() as u32,
             MMIO_IME => return self.system.arm9.get_irq().read_ime() as u32,
@@ -472,6 +618,12 @@ impl MmioMemory for Arm9Memory {
                 0xff: val |= self.postflg as u32
             }},
             MMIO_POWCNT1 => return self.system.video_unit.read_powcnt1(),
+            MMIO_GPU_GXSTAT => return self.system.video_unit.gpu.read_gxstat(),
+            MMIO_GPU_RAM_COUNT => return self.system.video_unit.gpu.read_ram_count(),
+            MMIO_GPU_POS_RESULT_START..=MMIO_GPU_POS_RESULT_END => return self.system.video_unit.gpu.read_position_result(addr),
+            MMIO_GPU_VEC_RESULT_START..=MMIO_GPU_VEC_RESULT_END => return self.system.video_unit.gpu.read_vector_result(addr),
+            MMIO_GPU_CLIPMTX_RESULT_START..=MMIO_GPU_CLIPMTX_RESULT_END => return self.system.video_unit.gpu.read_clip_matrix(addr),
+            MMIO_GPU_VECMTX_RESULT_START..=MMIO_GPU_VECMTX_RESULT_END => return self.system.video_unit.gpu.read_vector_matrix(addr),
             MMIO_PPUB_DISPCNT => return self.system.video_unit.ppu_b.read_dispcnt(),
             MMIO_PPUB_BGCNT0 => handle! { MASK => {
                 0x0000ffff: val |= self.system.video_unit.ppu_b.read_bgcnt(0) as u32,
@@ -497,7 +649,11 @@ impl MmioMemory for Arm9Memory {
     }
 
     fn mmio_write<const MASK: u32>(&mut self, addr: u32, val: u32) {
+        if self.system.debugger.on_mmio_write(Arch::ARMv5, addr, MASK) {
+            self.system.arm9.cpu.set_watch_hit();
+        }
         match mmio!(addr) {
+            MMIO_AUXSPICNT..=MMIO_SEED_HIGH | MMIO_CARTRIDGE_DATA if self.system.nds_slot_owner() != Arch::ARMv5 => {}
             MMIO_DISPCNT => self.system.video_unit.ppu_a.write_dispcnt(val, MASK),
             MMIO_DISPSTAT => handle! { MASK => {
                 0x0000ffff: self.system.video_unit.write_dispstat(Arch::ARMv5, val, MASK),
@@ -566,8 +722,9 @@ impl MmioMemory for Arm9Memory {
             }},
             MMIO_PPUA_BLDY => self.system.video_unit.ppu_a.write_bldy(val as _, MASK as _),
             MMIO_PPUA_RESERVED0 | MMIO_PPUA_RESERVED1 => {}
-            MMIO_GPU_DISP3DCNT => { /* todo: gpu */ }
+            MMIO_GPU_DISP3DCNT => self.system.video_unit.gpu.write_disp3dcnt(val, MASK),
             MMIO_DISPCAPCNT => self.system.video_unit.write_dispcapcnt(val, MASK),
+            MMIO_DISP_MMEM_FIFO => self.system.video_unit.ppu_a.write_display_fifo(val),
             MMIO_PPUA_MASTERBRIGHT => self.system.video_unit.ppu_a.write_master_bright(val, MASK),
             MMIO_DMA_SOURCE0 => self.system.dma9.write_source(0, val, MASK),
             MMIO_DMA_DESTINATION0 => self.system.dma9.write_destination(0, val, MASK),
@@ -619,11 +776,17 @@ impl MmioMemory for Arm9Memory {
             MMIO_IPCFIFOSEND => self.system.ipc.write_ipcfifosend(Arch::ARMv5, val),
             MMIO_AUXSPICNT => handle! { MASK => {
                 0x0000ffff: self.system.cartridge.write_auxspicnt(val as _, MASK as _),
-                0x00ff0000: error!("handle auxspidata writes")
+                0x00ff0000: self.system.cartridge.write_auxspidata((val >> 16) as _)
             }},
             MMIO_ROMCTRL => self.system.cartridge.write_romctrl(val, MASK),
             MMIO_COMMAND_BUFFER0 => self.system.cartridge.write_command_buffer(val as _, MASK as _),
             MMIO_COMMAND_BUFFER1 => self.system.cartridge.write_command_buffer((val as u64) << 32, (MASK as u64) << 32),
+            MMIO_SEED0 => self.system.cartridge.write_seed0(val as _, MASK as _),
+            MMIO_SEED1 => self.system.cartridge.write_seed1(val as _, MASK as _),
+            MMIO_SEED_HIGH => handle! { MASK => {
+                0x0000ffff: self.system.cartridge.write_seed0((val as u64) << 32, (MASK as u64 & 0xffff) << 32),
+                0xffff0000: self.system.cartridge.write_seed1((val as u64 >> 16) << 32, (MASK as u64 >> 16) << 32)
+            }},
             MMIO_EXMEMCNT => handle! { MASK => {
                 0xffff: self.system.write_exmemcnt(val as _, MASK as _)
             }},
@@ -658,6 +821,15 @@ impl MmioMemory for Arm9Memory {
                 0xff: self.write_postflg(val as u8)
             }},
             MMIO_POWCNT1 => self.system.video_unit.write_powcnt1(val, MASK),
+            MMIO_GPU_EDGE_COLOR_START..=MMIO_GPU_EDGE_COLOR_END => {} // edge marking isn't emulated
+            MMIO_GPU_ALPHA_TEST_REF => self.system.video_unit.gpu.write_alpha_test_ref(val),
+            MMIO_GPU_CLEAR_COLOR => self.system.video_unit.gpu.write_clear_color(val, MASK),
+            MMIO_GPU_CLEAR_DEPTH => self.system.video_unit.gpu.write_clear_depth(val, MASK),
+            MMIO_GPU_FOG_START..=MMIO_GPU_FOG_END => {} // fog isn't emulated
+            MMIO_GPU_TOON_TABLE_START..=MMIO_GPU_TOON_TABLE_END => self.system.video_unit.gpu.write_toon_table(addr, val, MASK),
+            MMIO_GPU_GXFIFO_START..=MMIO_GPU_GXFIFO_END => self.system.video_unit.gpu.write_gxfifo(val),
+            MMIO_GPU_COMMAND_START..=MMIO_GPU_COMMAND_END => self.system.video_unit.gpu.write_command_port(addr, val),
+            MMIO_GPU_GXSTAT => self.system.video_unit.gpu.write_gxstat(val, MASK),
             MMIO_PPUB_DISPCNT => self.system.video_unit.ppu_b.write_dispcnt(val, MASK),
             MMIO_PPUB_RESERVED0 => {}
             MMIO_PPUB_BGCNT0 => handle! { MASK => {
@@ -733,3 +905,51 @@ impl MmioMemory for Arm9Memory {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::core::System;
+
+    const ITCM_ENABLE: u32 = 0x00002078 | 1 << 18;
+    /// 32KB of itcm mirrored over 32KB
+    const ITCM_32K: u32 = 6 << 1;
+
+    fn low_reads(system: &mut System, addr: u32) -> (u8, u16, u32) {
+        let memory = system.arm9.get_memory();
+        (memory.read_byte(addr), memory.read_half(addr), memory.read_word(addr))
+    }
+
+    #[test]
+    fn low_addresses_without_itcm_read_0_for_every_width() {
+        let mut system = System::new();
+        system.arm9.reset();
+        for addr in [0x00000100, 0x01000100] {
+            system.arm9.get_memory().write_word(addr, 0x12345678);
+            system.arm9.get_memory().write_half(addr, 0x1234);
+            system.arm9.get_memory().write_byte(addr, 0x12);
+            assert_eq!(low_reads(&mut system, addr), (0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn itcm_takes_low_addresses_for_every_width_up_to_its_size() {
+        let mut system = System::new();
+        system.arm9.reset();
+        system.arm9.get_coprocessor().write(9, 1, 1, ITCM_32K);
+        system.arm9.get_coprocessor().write(1, 0, 0, ITCM_ENABLE);
+
+        system.arm9.get_memory().write_word(0x100, 0x12345678);
+        assert_eq!(low_reads(&mut system, 0x100), (0x78, 0x5678, 0x12345678));
+        system.arm9.get_memory().write_byte(0x103, 0xab);
+        system.arm9.get_memory().write_half(0x100, 0xcdef);
+        assert_eq!(low_reads(&mut system, 0x100), (0xef, 0xcdef, 0xab34cdef));
+
+        // past the end of the virtual size nothing is connected again
+        system.arm9.get_memory().write_word(0x8100, 0x12345678);
+        assert_eq!(low_reads(&mut system, 0x8100), (0, 0, 0));
+
+        // disabling it hides it again
+        system.arm9.get_coprocessor().write(1, 0, 0, 0x00002078);
+        assert_eq!(low_reads(&mut system, 0x100), (0, 0, 0));
+    }
+}
@@ -1,16 +1,39 @@
 use crate::arm::coprocessor::Coprocessor;
-use crate::arm::cpu::{Arch, Cpu};
+use crate::arm::cpu::{Arch, Cpu, SwiHandler};
 use crate::arm::memory::Memory;
 use crate::arm::state::{Mode, StatusReg, GPR, Bank};
 use crate::core::arm9::coprocessor::Arm9Coprocessor;
 use crate::core::arm9::memory::Arm9Memory;
 use crate::core::hardware::irq::Irq;
-use crate::core::System;
-use crate::util::Shared;
+use crate::core::{bios, System};
+use crate::util::{Shared, StateReader, StateWriter};
 
 mod coprocessor;
 mod memory;
 
+/// Control register, the dtcm and itcm region registers, then the protection unit and cache setup
+const CP15_STATE_REGISTERS: [(u32, u32, u32); 19] = [
+    (1, 0, 0),
+    (9, 1, 0),
+    (9, 1, 1),
+    (2, 0, 0),
+    (2, 0, 1),
+    (3, 0, 0),
+    (5, 0, 2),
+    (5, 0, 3),
+    (6, 0, 0),
+    (6, 1, 0),
+    (6, 2, 0),
+    (6, 3, 0),
+    (6, 4, 0),
+    (6, 5, 0),
+    (6, 6, 0),
+    (6, 7, 0),
+    (9, 0, 0),
+    (9, 0, 1),
+    (13, 0, 1),
+];
+
 pub struct Arm9 {
     system: Shared<System>,
     pub irq: Shared<Irq>,
@@ -26,7 +49,7 @@ impl Arm9 {
         });
         Self {
             system: system.clone(),
-            irq: Shared::new(Irq::new(&cpu)),
+            irq: Shared::new(Irq::new(system, &cpu)),
             cpu,
         }
     }
@@ -34,6 +57,15 @@ impl Arm9 {
     pub fn reset(&mut self) {
         self.cpu.memory.reset();
         self.cpu.reset();
+        self.irq.reset();
+
+        let handler = self.hle_bios().then_some(bios::software_interrupt as SwiHandler);
+        self.cpu.set_swi_handler(handler);
+    }
+
+    /// Whether the swis are handled without bios9.bin, which also means the bios data isn't there
+    pub fn hle_bios(&mut self) -> bool {
+        self.cpu.memory.as_any().downcast_mut::<Arm9Memory>().unwrap().hle_bios()
     }
 
     pub fn run(&mut self, cycles: u64) {
@@ -86,6 +118,28 @@ impl Arm9 {
         self.cpu.arm_flush_pipeline();
     }
 
+    /// cp15 is restored through register writes, so the tcm mappings follow the loaded values
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        state.section(b"ARM9");
+        self.cpu.save_state(state);
+        self.irq.save_state(state);
+        self.cpu.memory.as_any().downcast_mut::<Arm9Memory>().unwrap().save_state(state);
+        for (cn, cm, cp) in CP15_STATE_REGISTERS {
+            state.write("cp15", self.get_coprocessor().read(cn, cm, cp));
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"ARM9");
+        self.cpu.load_state(state);
+        self.irq.load_state(state);
+        self.cpu.memory.as_any().downcast_mut::<Arm9Memory>().unwrap().load_state(state);
+        for (cn, cm, cp) in CP15_STATE_REGISTERS {
+            let val = state.read();
+            self.get_coprocessor().write(cn, cm, cp, val);
+        }
+    }
+
     pub fn get_memory(&mut self) -> &mut dyn Memory {
         &mut *self.cpu.memory
     }
@@ -98,6 +152,10 @@ impl Arm9 {
         &mut self.irq
     }
 
+    pub fn update_main_memory_mapping(&mut self) {
+        self.cpu.memory.as_any().downcast_mut::<Arm9Memory>().unwrap().update_main_memory_mapping()
+    }
+
     pub fn update_wram_mapping(&mut self) {
         self.cpu.memory.as_any().downcast_mut::<Arm9Memory>().unwrap().update_wram_mapping()
     }
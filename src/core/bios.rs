@@ -0,0 +1,562 @@
+use log::{debug, error};
+
+use crate::arm::cpu::{Arch, Cpu};
+use crate::core::hardware::firmware::crc16;
+use crate::unimplemented_feature;
+use crate::util::paths;
+
+pub const ARM7_BIOS_SIZE: usize = 0x4000;
+pub const ARM9_BIOS_SIZE: usize = 0x1000;
+
+/// The hle image runs the irq handler at 0x20 and waits for IntrWait at 0x60
+const IRQ_HANDLER: usize = 0x20;
+const INTR_WAIT_LOOP: u32 = 0x60;
+
+const ARM7_IRQ_HANDLER: [u32; 6] = [
+    0xe92d500f, // stmfd sp!, {r0-r3, r12, lr}
+    0xe3a00301, // mov r0, #0x04000000
+    0xe28fe000, // add lr, pc, #0
+    0xe510f004, // ldr pc, [r0, #-4]
+    0xe8bd500f, // ldmfd sp!, {r0-r3, r12, lr}
+    0xe25ef004, // subs pc, lr, #4
+];
+
+const ARM9_IRQ_HANDLER: [u32; 9] = [
+    0xe92d500f, // stmfd sp!, {r0-r3, r12, lr}
+    0xee190f11, // mrc p15, 0, r0, c9, c1, 0
+    0xe1a00620, // mov r0, r0, lsr #12
+    0xe1a00600, // mov r0, r0, lsl #12
+    0xe2800901, // add r0, r0, #0x4000
+    0xe28fe000, // add lr, pc, #0
+    0xe510f004, // ldr pc, [r0, #-4]
+    0xe8bd500f, // ldmfd sp!, {r0-r3, r12, lr}
+    0xe25ef004, // subs pc, lr, #4
+];
+
+const INTR_WAIT: [u32; 2] = [
+    0xef040000, // swi 0x040000
+    0xe12fff12, // bx r2
+];
+
+const fn base(arch: Arch) -> u32 {
+    match arch {
+        Arch::ARMv4 => 0x00000000,
+        Arch::ARMv5 => 0xffff0000,
+    }
+}
+
+/// `bios7.bin` or `bios9.bin`, `None` when it's missing or isn't a bios dump
+pub fn load_dump(arch: Arch) -> Option<Box<[u8]>> {
+    let (name, size) = match arch {
+        Arch::ARMv4 => ("bios7.bin", ARM7_BIOS_SIZE),
+        Arch::ARMv5 => ("bios9.bin", ARM9_BIOS_SIZE),
+    };
+
+    let path = paths::firmware(name);
+    match std::fs::read(&path) {
+        Ok(data) if data.len() == size => Some(data.into_boxed_slice()),
+        Ok(data) => {
+            error!("BIOS: {} is {:#x} bytes instead of {size:#x}, falling back to the hle bios", path.display(), data.len());
+            None
+        }
+        Err(e) => {
+            error!("BIOS: failed to load {}: {e}, falling back to the hle bios", path.display());
+            None
+        }
+    }
+}
+
+/// A bios with only the exception vectors and the irq handler, which calls the user handler the same
+/// way the real one does. Swis never reach it, they're run by `software_interrupt` instead
+pub fn hle_image(arch: Arch) -> Box<[u8]> {
+    let (size, irq_handler) = match arch {
+        Arch::ARMv4 => (ARM7_BIOS_SIZE, ARM7_IRQ_HANDLER.as_slice()),
+        Arch::ARMv5 => (ARM9_BIOS_SIZE, ARM9_IRQ_HANDLER.as_slice()),
+    };
+
+    // every vector other than irq spins, with swi returning right away in case one gets there
+    let mut words = vec![0xeafffffe; IRQ_HANDLER / 4];
+    words[0x08 / 4] = 0xe1b0f00e; // movs pc, lr
+    words[0x18 / 4] = 0xea000000; // b IRQ_HANDLER
+    words.extend_from_slice(irq_handler);
+    words.resize(INTR_WAIT_LOOP as usize / 4, 0);
+    words.extend_from_slice(&INTR_WAIT);
+
+    let mut image = vec![0; size].into_boxed_slice();
+    for (i, word) in words.into_iter().enumerate() {
+        image[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    image
+}
+
+/// The swis both cpus use, for booting without bios dumps. Swis that need bios code that isn't
+/// there, like SoftReset, are reported as unimplemented and do nothing
+pub fn software_interrupt(cpu: &mut Cpu, number: u8) -> bool {
+    let [r0, r1, r2] = [0, 1, 2].map(|i| cpu.state.gpr[i]);
+    match (cpu.arch, number) {
+        (_, 0x03) => wait_by_loop(cpu, r0),
+        (_, 0x04) => return intr_wait(cpu, r0 != 0, r1),
+        (_, 0x05) => return intr_wait(cpu, true, 1),
+        (_, 0x06) => halt(cpu),
+        (_, 0x09) => div(cpu, r0 as i32, r1 as i32),
+        (_, 0x0b) => cpu_set(cpu, r0, r1, r2),
+        (_, 0x0c) => cpu_fast_set(cpu, r0, r1, r2),
+        (_, 0x0d) => cpu.state.gpr[0] = (r0 as f64).sqrt() as u32,
+        (_, 0x0e) => {
+            let data = read_bytes(cpu, r1, r2 as usize);
+            cpu.state.gpr[0] = crc16(r0 as u16, &data) as u32;
+        }
+        (_, 0x0f) => cpu.state.gpr[0] = 0,
+        (_, 0x10) => bit_unpack(cpu, r0, r1, r2),
+        // the callback versions read through functions passed in r3, the usual ones read straight
+        // from memory so the source is read directly instead
+        (_, 0x11) => {
+            let data = lz77(cpu, r0);
+            write_output(cpu, r1, &data, 1);
+        }
+        (_, 0x12) => {
+            let data = lz77(cpu, r0);
+            write_output(cpu, r1, &data, 2);
+        }
+        (_, 0x13) => {
+            let data = huffman(cpu, r0);
+            write_output(cpu, r1, &data, 4);
+        }
+        (_, 0x14) => {
+            let data = run_length(cpu, r0);
+            write_output(cpu, r1, &data, 1);
+        }
+        (_, 0x15) => {
+            let data = run_length(cpu, r0);
+            write_output(cpu, r1, &data, 2);
+        }
+        (Arch::ARMv4, 0x08) => cpu.memory.write_half(0x04000504, if r0 != 0 { 0x200 } else { 0 }),
+        (Arch::ARMv4, 0x1a) => cpu.state.gpr[0] = sine_table(r0),
+        (Arch::ARMv4, 0x1b) => cpu.state.gpr[0] = pitch_table(r0),
+        (Arch::ARMv4, 0x1c) => cpu.state.gpr[0] = volume_table(r0),
+        (Arch::ARMv4, 0x1f) => cpu.memory.write_byte(0x04000301, r2 as u8),
+        (Arch::ARMv5, 0x16) => {
+            let data = unfilter(cpu, r0, 1);
+            write_output(cpu, r1, &data, 1);
+        }
+        (Arch::ARMv5, 0x18) => {
+            let data = unfilter(cpu, r0, 2);
+            write_output(cpu, r1, &data, 2);
+        }
+        (Arch::ARMv5, 0x1f) => cpu.memory.write_byte(0x04000300, r0 as u8),
+        (arch, number) => unimplemented_feature!("BIOS: handle {arch:?} swi {number:02x}"),
+    }
+
+    true
+}
+
+/// The loop takes 4 cycles a round, which only adds up with cpu timing on
+fn wait_by_loop(cpu: &mut Cpu, count: u32) {
+    if (count as i32) > 0 {
+        cpu.add_internal_cycles(count as u64 * 4);
+        cpu.state.gpr[0] = 0;
+    }
+}
+
+/// Halts until one of `flags` is set in the check flags, which the user irq handler is expected to
+/// set. Waiting happens in a loop in the hle image that runs this again with `discard` cleared and
+/// returns through r2, the bios is allowed to clobber r0-r3
+fn intr_wait(cpu: &mut Cpu, discard: bool, flags: u32) -> bool {
+    let check_flags = match cpu.arch {
+        Arch::ARMv4 => 0x0380fff8,
+        Arch::ARMv5 => (cpu.coprocessor.read(9, 1, 0) & !0xfff) + 0x3ff8,
+    };
+
+    cpu.memory.write_word(0x04000208, 1);
+    let set = cpu.memory.read_word(check_flags);
+    if discard {
+        cpu.memory.write_word(check_flags, set & !flags);
+    } else if set & flags != 0 {
+        cpu.memory.write_word(check_flags, set & !flags);
+        return true;
+    }
+
+    let thumb = cpu.state.cpsr.thumb();
+    let pc = cpu.state.gpr[15] - if thumb { 4 } else { 8 };
+    let wait_loop = base(cpu.arch) + INTR_WAIT_LOOP;
+    if pc != wait_loop {
+        cpu.state.gpr[0] = 0;
+        cpu.state.gpr[1] = flags;
+        cpu.state.gpr[2] = if thumb { (pc + 2) | 1 } else { pc + 4 };
+        cpu.state.cpsr.set_thumb(false);
+    }
+
+    halt(cpu);
+    cpu.branch_to(wait_loop);
+    false
+}
+
+/// An interrupt that's already pending ends the halt right away
+fn halt(cpu: &mut Cpu) {
+    let pending = cpu.memory.read_word(0x04000210) & cpu.memory.read_word(0x04000214);
+    if pending == 0 {
+        cpu.update_halted(true);
+    }
+}
+
+fn div(cpu: &mut Cpu, numerator: i32, denominator: i32) {
+    if denominator == 0 {
+        debug!("BIOS: division of {numerator} by zero");
+        cpu.state.gpr[0] = if numerator < 0 { 1 } else { -1i32 as u32 };
+        cpu.state.gpr[1] = numerator as u32;
+        cpu.state.gpr[3] = 1;
+        return;
+    }
+
+    let quotient = numerator.wrapping_div(denominator);
+    cpu.state.gpr[0] = quotient as u32;
+    cpu.state.gpr[1] = numerator.wrapping_rem(denominator) as u32;
+    cpu.state.gpr[3] = quotient.unsigned_abs();
+}
+
+/// Copies or fills (bit 24) a number of halfwords or words (bit 26)
+fn cpu_set(cpu: &mut Cpu, src: u32, dst: u32, control: u32) {
+    let count = control & 0x1fffff;
+    let fill = control & (1 << 24) != 0;
+    if control & (1 << 26) != 0 {
+        let (src, dst) = (src & !0x3, dst & !0x3);
+        let val = cpu.memory.read_word(src);
+        for i in 0..count {
+            let val = if fill { val } else { cpu.memory.read_word(src.wrapping_add(i * 4)) };
+            cpu.memory.write_word(dst.wrapping_add(i * 4), val);
+        }
+    } else {
+        let (src, dst) = (src & !0x1, dst & !0x1);
+        let val = cpu.memory.read_half(src);
+        for i in 0..count {
+            let val = if fill { val } else { cpu.memory.read_half(src.wrapping_add(i * 2)) };
+            cpu.memory.write_half(dst.wrapping_add(i * 2), val);
+        }
+    }
+}
+
+/// Copies or fills words 8 at a time, the count is rounded up to match
+fn cpu_fast_set(cpu: &mut Cpu, src: u32, dst: u32, control: u32) {
+    let count = ((control & 0x1fffff) + 7) & !0x7;
+    cpu_set(cpu, src, dst, (control & (1 << 24)) | (1 << 26) | count);
+}
+
+/// Widens units of 1, 2, 4 or 8 bits to 1, 2, 4, 8, 16 or 32 bits, adding an offset to each
+fn bit_unpack(cpu: &mut Cpu, src: u32, mut dst: u32, info: u32) {
+    let length = cpu.memory.read_half(info) as u32;
+    let src_width = cpu.memory.read_byte(info.wrapping_add(2)) as u32;
+    let dst_width = cpu.memory.read_byte(info.wrapping_add(3)) as u32;
+    let offset = cpu.memory.read_word(info.wrapping_add(4));
+    if !matches!(src_width, 1 | 2 | 4 | 8) || !matches!(dst_width, 1 | 2 | 4 | 8 | 16 | 32) {
+        error!("BIOS: BitUnPack from {src_width} to {dst_width} bits isn't possible");
+        return;
+    }
+
+    let zero_data = offset & (1 << 31) != 0;
+    let offset = offset & 0x7fffffff;
+    let dst_mask = if dst_width == 32 { u32::MAX } else { (1 << dst_width) - 1 };
+    let (mut word, mut bits) = (0, 0);
+    for i in 0..length {
+        let byte = cpu.memory.read_byte(src.wrapping_add(i)) as u32;
+        for shift in (0..8).step_by(src_width as usize) {
+            let mut unit = (byte >> shift) & ((1 << src_width) - 1);
+            if unit != 0 || zero_data {
+                unit = unit.wrapping_add(offset);
+            }
+
+            word |= (unit & dst_mask) << bits;
+            bits += dst_width;
+            if bits == 32 {
+                cpu.memory.write_word(dst, word);
+                dst = dst.wrapping_add(4);
+                (word, bits) = (0, 0);
+            }
+        }
+    }
+}
+
+/// Header word with the type in bits 4-7 and the decompressed size in bits 8-31
+fn decompressed_size(cpu: &mut Cpu, src: u32) -> usize {
+    (cpu.memory.read_word(src) >> 8) as usize
+}
+
+fn lz77(cpu: &mut Cpu, mut src: u32) -> Vec<u8> {
+    let size = decompressed_size(cpu, src);
+    let mut data = Vec::with_capacity(size);
+    src = src.wrapping_add(4);
+
+    while data.len() < size {
+        let flags = cpu.memory.read_byte(src);
+        src = src.wrapping_add(1);
+        for block in 0..8 {
+            if data.len() >= size {
+                break;
+            }
+
+            if flags & (0x80 >> block) == 0 {
+                data.push(cpu.memory.read_byte(src));
+                src = src.wrapping_add(1);
+                continue;
+            }
+
+            let [hi, lo] = [cpu.memory.read_byte(src), cpu.memory.read_byte(src.wrapping_add(1))];
+            src = src.wrapping_add(2);
+            let length = (hi >> 4) as usize + 3;
+            let distance = (((hi & 0xf) as usize) << 8 | lo as usize) + 1;
+            for _ in 0..length {
+                let byte = data.len().checked_sub(distance).map_or(0, |i| data[i]);
+                data.push(byte);
+            }
+        }
+    }
+
+    data.truncate(size);
+    data
+}
+
+fn run_length(cpu: &mut Cpu, mut src: u32) -> Vec<u8> {
+    let size = decompressed_size(cpu, src);
+    let mut data = Vec::with_capacity(size);
+    src = src.wrapping_add(4);
+
+    while data.len() < size {
+        let flag = cpu.memory.read_byte(src);
+        src = src.wrapping_add(1);
+        if flag & 0x80 != 0 {
+            let byte = cpu.memory.read_byte(src);
+            src = src.wrapping_add(1);
+            data.resize(data.len() + (flag & 0x7f) as usize + 3, byte);
+        } else {
+            for _ in 0..(flag & 0x7f) + 1 {
+                data.push(cpu.memory.read_byte(src));
+                src = src.wrapping_add(1);
+            }
+        }
+    }
+
+    data.truncate(size);
+    data
+}
+
+/// 4 or 8 bit units coded with a tree that follows the header, the bitstream is read a word at a time
+/// from the top bit down
+fn huffman(cpu: &mut Cpu, src: u32) -> Vec<u8> {
+    let size = decompressed_size(cpu, src);
+    let unit_bits = if cpu.memory.read_word(src) & 0xf == 4 { 4 } else { 8 };
+    let root = src.wrapping_add(5);
+    let tree_size = (cpu.memory.read_byte(src.wrapping_add(4)) as u32 + 1) * 2;
+    let mut stream = src.wrapping_add(4 + tree_size);
+    let mut data = Vec::with_capacity(size);
+
+    let (mut node_addr, mut node) = (root, cpu.memory.read_byte(root));
+    let (mut word, mut bits) = (0u32, 0);
+    while data.len() < size {
+        let code = cpu.memory.read_word(stream);
+        stream = stream.wrapping_add(4);
+        for bit in (0..32).rev() {
+            let right = (code >> bit) & 0x1 != 0;
+            let child = (node_addr & !0x1).wrapping_add((node & 0x3f) as u32 * 2 + 2 + right as u32);
+            let leaf_mask = if right { 0x40 } else { 0x80 };
+            let leaf = node & leaf_mask != 0;
+            if !leaf {
+                (node_addr, node) = (child, cpu.memory.read_byte(child));
+                continue;
+            }
+
+            word |= (cpu.memory.read_byte(child) as u32 & ((1 << unit_bits) - 1)) << bits;
+            bits += unit_bits;
+            if bits == 32 {
+                data.extend_from_slice(&word.to_le_bytes());
+                (word, bits) = (0, 0);
+                if data.len() >= size {
+                    break;
+                }
+            }
+            (node_addr, node) = (root, cpu.memory.read_byte(root));
+        }
+    }
+
+    data.truncate(size);
+    data
+}
+
+/// Undoes delta coding of bytes or halfwords, every unit is stored as the difference to the one before
+fn unfilter(cpu: &mut Cpu, src: u32, width: u32) -> Vec<u8> {
+    let size = decompressed_size(cpu, src);
+    let mut data = Vec::with_capacity(size);
+    let mut val = 0u16;
+    for i in 0..(size as u32).div_ceil(width) {
+        let addr = src.wrapping_add(4 + i * width);
+        if width == 1 {
+            val = (val as u8).wrapping_add(cpu.memory.read_byte(addr)) as u16;
+            data.push(val as u8);
+        } else {
+            val = val.wrapping_add(cpu.memory.read_half(addr));
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+    }
+
+    data.truncate(size);
+    data
+}
+
+fn read_bytes(cpu: &mut Cpu, addr: u32, len: usize) -> Vec<u8> {
+    (0..len as u32).map(|i| cpu.memory.read_byte(addr.wrapping_add(i))).collect()
+}
+
+/// Writes with accesses `width` bytes wide, vram for example can't take byte writes
+fn write_output(cpu: &mut Cpu, dst: u32, data: &[u8], width: usize) {
+    for (i, chunk) in data.chunks(width).enumerate() {
+        let addr = dst.wrapping_add((i * width) as u32);
+        let mut bytes = [0; 4];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        match width {
+            1 => cpu.memory.write_byte(addr, bytes[0]),
+            2 => cpu.memory.write_half(addr, u16::from_le_bytes([bytes[0], bytes[1]])),
+            _ => cpu.memory.write_word(addr, u32::from_le_bytes(bytes)),
+        }
+    }
+}
+
+// the arm7 bios tables for the sound driver are computed instead, values can be off by one
+
+/// A quarter of a sine wave in 64 steps
+fn sine_table(index: u32) -> u32 {
+    let angle = (index & 0x3f) as f64 * std::f64::consts::PI / 128.0;
+    (angle.sin() * 0x7fff as f64).round() as u32
+}
+
+/// Timer adjustments for a pitch shift of `index` / 768 octaves
+fn pitch_table(index: u32) -> u32 {
+    let index = index.min(0x2ff) as f64;
+    ((2f64.powf(index / 768.0) - 1.0) * 0x10000 as f64).round() as u32
+}
+
+/// Channel volumes for attenuations of 72.3 to 0 dB in 0.1 dB steps. Each range is meant to be used
+/// with a volume divider, so the values start over at 127 at -6, -12 and -24 dB
+fn volume_table(index: u32) -> u32 {
+    let decibels = index.min(0x2d3) as i32 - 723;
+    let range_top = match decibels {
+        -60.. => 0,
+        -120..=-61 => -60,
+        -240..=-121 => -120,
+        _ => -240,
+    };
+    (127.0 * 10f64.powf((decibels - range_top) as f64 / 200.0)).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::tests::arm_cpu;
+
+    /// A cpu with `data` in memory at 0x1000
+    fn cpu_with(data: &[u8]) -> Cpu {
+        let mut cpu = arm_cpu(Arch::ARMv5, &[]);
+        for (i, &byte) in data.iter().enumerate() {
+            cpu.memory.write_byte(0x1000 + i as u32, byte);
+        }
+        cpu
+    }
+
+    #[test]
+    fn lz77_copies_overlapping_references() {
+        // A, B and C as literals, then 6 bytes from 3 back
+        let mut cpu = cpu_with(&[0x10, 9, 0, 0, 0x10, b'A', b'B', b'C', 0x30, 0x02]);
+        assert_eq!(lz77(&mut cpu, 0x1000), b"ABCABCABC");
+    }
+
+    #[test]
+    fn run_length_mixes_runs_and_literals() {
+        // a run of 4 x, then 3 literals
+        let mut cpu = cpu_with(&[0x30, 7, 0, 0, 0x81, b'x', 0x02, b'a', b'b', b'c']);
+        assert_eq!(run_length(&mut cpu, 0x1000), b"xxxxabc");
+    }
+
+    #[test]
+    fn huffman_walks_the_tree_from_the_top_bit() {
+        // a root with A on the left and B on the right, then the codes 0, 1, 1, 0
+        let mut cpu = cpu_with(&[0x28, 4, 0, 0, 1, 0xc0, b'A', b'B', 0, 0, 0, 0x60]);
+        assert_eq!(huffman(&mut cpu, 0x1000), b"ABBA");
+    }
+
+    #[test]
+    fn unfilter_adds_up_the_differences() {
+        let mut cpu = cpu_with(&[0x81, 4, 0, 0, 1, 1, 1, 0xff]);
+        assert_eq!(unfilter(&mut cpu, 0x1000, 1), [1, 2, 3, 2]);
+
+        let mut cpu = cpu_with(&[0x82, 6, 0, 0, 0x00, 0x01, 0x00, 0x01, 0xff, 0xff]);
+        assert_eq!(unfilter(&mut cpu, 0x1000, 2), [0x00, 0x01, 0x00, 0x02, 0xff, 0x01]);
+    }
+
+    #[test]
+    fn bit_unpack_only_offsets_zero_units_when_asked() {
+        // 2 bytes of 2 bit units widened to 4 bits with an offset of 1
+        let mut cpu = cpu_with(&[0xe4, 0xe4, 0, 0, 2, 0, 2, 4, 1, 0, 0, 0]);
+        bit_unpack(&mut cpu, 0x1000, 0x2000, 0x1004);
+        assert_eq!(cpu.memory.read_word(0x2000), 0x43204320);
+
+        cpu.memory.write_word(0x1008, 0x80000001);
+        bit_unpack(&mut cpu, 0x1000, 0x2000, 0x1004);
+        assert_eq!(cpu.memory.read_word(0x2000), 0x43214321);
+
+        // 3 bit units don't exist, nothing is written
+        cpu.memory.write_word(0x2000, 0);
+        cpu.memory.write_byte(0x1006, 3);
+        bit_unpack(&mut cpu, 0x1000, 0x2000, 0x1004);
+        assert_eq!(cpu.memory.read_word(0x2000), 0);
+    }
+
+    #[test]
+    fn div_returns_quotient_remainder_and_absolute_quotient() {
+        let mut cpu = cpu_with(&[]);
+        let mut results = |numerator: i32, denominator: i32| {
+            div(&mut cpu, numerator, denominator);
+            [0, 1, 3].map(|i| cpu.state.gpr[i])
+        };
+
+        assert_eq!(results(7, -2), [-3i32 as u32, 1, 3]);
+        assert_eq!(results(-7, 2), [-3i32 as u32, -1i32 as u32, 3]);
+        assert_eq!(results(5, 0), [-1i32 as u32, 5, 1]);
+        assert_eq!(results(-5, 0), [1, -5i32 as u32, 1]);
+        assert_eq!(results(i32::MIN, -1), [0x80000000, 0, 0x80000000]);
+    }
+
+    #[test]
+    fn cpu_set_copies_and_fills_from_aligned_addresses() {
+        let data: Vec<u8> = (1..=16).collect();
+
+        // 3 halfwords from misaligned addresses
+        let mut cpu = cpu_with(&data);
+        cpu_set(&mut cpu, 0x1001, 0x2001, 3);
+        assert_eq!(read_bytes(&mut cpu, 0x2000, 8), [1, 2, 3, 4, 5, 6, 0, 0]);
+
+        // 2 words filled with the first one
+        let mut cpu = cpu_with(&data);
+        cpu_set(&mut cpu, 0x1003, 0x2000, (1 << 26) | (1 << 24) | 2);
+        assert_eq!(read_bytes(&mut cpu, 0x2000, 12), [1, 2, 3, 4, 1, 2, 3, 4, 0, 0, 0, 0]);
+
+        // a count of 0 does nothing
+        let mut cpu = cpu_with(&data);
+        cpu_set(&mut cpu, 0x1000, 0x2000, 1 << 26);
+        assert_eq!(cpu.memory.read_word(0x2000), 0);
+    }
+
+    #[test]
+    fn cpu_fast_set_rounds_the_count_up_to_8_words() {
+        let data: Vec<u32> = (1..=10).collect();
+        let mut cpu = cpu_with(&[]);
+        for (i, &word) in data.iter().enumerate() {
+            cpu.memory.write_word(0x1000 + i as u32 * 4, word);
+        }
+
+        cpu_fast_set(&mut cpu, 0x1000, 0x2000, 1);
+        let copied: Vec<u32> = (0..10).map(|i| cpu.memory.read_word(0x2000 + i * 4)).collect();
+        assert_eq!(copied, [1, 2, 3, 4, 5, 6, 7, 8, 0, 0]);
+
+        // without bit 26 it still fills words
+        cpu_fast_set(&mut cpu, 0x1004, 0x3000, (1 << 24) | 9);
+        let filled: Vec<u32> = (0..17).map(|i| cpu.memory.read_word(0x3000 + i * 4)).collect();
+        assert_eq!(filled, [[2; 16].as_slice(), &[0]].concat());
+    }
+}
@@ -1,3 +1,6 @@
+use crate::arm::trace::TraceFormat;
+use crate::core::hardware::firmware::Language;
+
 #[derive(Default)]
 pub enum BootMode {
     #[default]
@@ -5,8 +8,185 @@ pub enum BootMode {
     Direct,
 }
 
+#[derive(Default, Copy, Clone, PartialEq, Eq)]
+pub enum BatteryLevel {
+    #[default]
+    Okay,
+    Low,
+}
+
+#[derive(Default, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ScreenOrder {
+    /// Follow POWCNT1.15 like the hardware does
+    #[default]
+    Auto,
+    /// Follow POWCNT1.15, but with the screens the other way around
+    Swapped,
+    /// Keep engine A on top regardless of POWCNT1.15
+    EngineATop,
+    /// Keep engine B on top regardless of POWCNT1.15
+    EngineBTop,
+}
+
+impl ScreenOrder {
+    pub const ALL: [ScreenOrder; 4] = [ScreenOrder::Auto, ScreenOrder::Swapped, ScreenOrder::EngineATop, ScreenOrder::EngineBTop];
+
+    pub const fn swap(self) -> Self {
+        match self {
+            ScreenOrder::Auto => ScreenOrder::Swapped,
+            ScreenOrder::Swapped => ScreenOrder::Auto,
+            ScreenOrder::EngineATop => ScreenOrder::EngineBTop,
+            ScreenOrder::EngineBTop => ScreenOrder::EngineATop,
+        }
+    }
+}
+
+/// Speed of a cpu relative to its real clock in percent, below 100 underclocks and above 100 overclocks.
+/// The scheduler and all hardware timings stay at the real clock, only the instructions per cycle change
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ClockScale(u32);
+
+impl ClockScale {
+    pub const fn percent(percent: u32) -> Self {
+        Self(if percent == 0 { 1 } else { percent })
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+
+    /// A percentage like `150` or `150%`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim_end_matches('%').parse() {
+            Ok(percent) if percent > 0 => Ok(Self(percent)),
+            _ => Err(format!("invalid clock percentage: {value}")),
+        }
+    }
+
+    /// Scales `cycles`, carrying the fractional cycle over to the next call through `remainder`
+    pub fn scale(self, cycles: u64, remainder: &mut u64) -> u64 {
+        let total = cycles * self.0 as u64 + *remainder;
+        *remainder = total % 100;
+        total / 100
+    }
+}
+
+impl Default for ClockScale {
+    fn default() -> Self {
+        Self(100)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AccuracyPreset {
+    Fast,
+    Balanced,
+    Accurate,
+}
+
+impl AccuracyPreset {
+    pub const ALL: [AccuracyPreset; 3] = [AccuracyPreset::Fast, AccuracyPreset::Balanced, AccuracyPreset::Accurate];
+}
+
+/// Switches for hardware behavior that costs speed or that few games depend on
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AccuracyConfig {
+    /// Limit how many objects can be drawn on a scanline like the hardware does
+    pub obj_cycle_limit: bool,
+    /// Deliver cartridge data words at the ROMCTRL clock rate with its gaps instead of right away
+    pub cartridge_timing: bool,
+    /// Keep div/sqrt busy and the previous results readable for as long as the hardware takes
+    pub math_timing: bool,
+    /// Keep newly enabled backgrounds hidden for 2 scanlines, some games enable a layer before it's set up
+    pub bg_enable_delay: bool,
+    /// Time instructions by their memory accesses and multiplies instead of a cycle each
+    pub cpu_timing: bool,
+}
+
+impl AccuracyConfig {
+    pub const fn preset(preset: AccuracyPreset) -> Self {
+        match preset {
+            AccuracyPreset::Fast => Self {
+                obj_cycle_limit: false,
+                cartridge_timing: false,
+                math_timing: false,
+                bg_enable_delay: false,
+                cpu_timing: false,
+            },
+            AccuracyPreset::Balanced => Self {
+                obj_cycle_limit: false,
+                cartridge_timing: true,
+                math_timing: true,
+                bg_enable_delay: true,
+                cpu_timing: false,
+            },
+            AccuracyPreset::Accurate => Self {
+                obj_cycle_limit: true,
+                cartridge_timing: true,
+                math_timing: true,
+                bg_enable_delay: true,
+                cpu_timing: true,
+            },
+        }
+    }
+
+    /// The preset these settings are equal to, `None` once individual switches were changed
+    pub fn matching_preset(&self) -> Option<AccuracyPreset> {
+        AccuracyPreset::ALL.into_iter().find(|&preset| Self::preset(preset) == *self)
+    }
+}
+
+impl Default for AccuracyConfig {
+    fn default() -> Self {
+        Self::preset(AccuracyPreset::Balanced)
+    }
+}
+
 #[derive(Default)]
 pub struct Config {
     pub game_path: String,
     pub boot_mode: BootMode,
+    pub battery_level: BatteryLevel,
+    pub firmware_path: Option<String>,
+    /// Handle swis and interrupts without bios7.bin and bios9.bin, which is also the fallback when they're missing.
+    /// Only direct boot works this way
+    pub hle_bios: bool,
+    pub threaded_video: bool,
+    pub screen_order: ScreenOrder,
+    /// Overrides the language in the firmware user settings
+    pub language: Option<Language>,
+    /// Let HALTCNT switch the system into gba mode. Not implemented yet, emulation still stops and the switch is
+    /// reported as unimplemented
+    pub gba_mode: bool,
+    pub accuracy: AccuracyConfig,
+    /// Whether a device sits in the gba slot, only a stub that drives the bus low is emulated
+    pub slot2_inserted: bool,
+    pub arm9_clock: ClockScale,
+    pub arm7_clock: ClockScale,
+    /// Layout of the instruction traces written with the log_state feature, picked up on reset
+    pub trace_format: TraceFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_scale_carries_fractional_cycles() {
+        let clock = ClockScale::parse("150%").unwrap();
+        let mut remainder = 0;
+        let cycles: Vec<u64> = (0..4).map(|_| clock.scale(1, &mut remainder)).collect();
+        assert_eq!(cycles, [1, 2, 1, 2]);
+
+        let clock = ClockScale::parse("30").unwrap();
+        let total: u64 = (0..10).map(|_| clock.scale(1, &mut remainder)).sum();
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn clock_scale_rejects_zero_and_garbage() {
+        assert!(ClockScale::parse("0").is_err());
+        assert!(ClockScale::parse("fast").is_err());
+        assert_eq!(ClockScale::parse("100").unwrap(), ClockScale::default());
+    }
 }
@@ -0,0 +1,440 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use log::{debug, info, warn};
+
+use crate::arm::cpu::Arch;
+use crate::arm::memory::Memory;
+use crate::arm::state::{Mode, StatusReg, GPR};
+use crate::core::timing::VISIBLE_LINES;
+use crate::core::System;
+
+/// Registers in the order of the target description: r0-r15 then cpsr
+const REGISTER_COUNT: usize = 17;
+
+/// Largest packet we advertise in qSupported, replies have to fit in it too
+const PACKET_SIZE: usize = 0x4000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.core">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+/// GDB remote serial protocol server for one cpu. Each cpu gets its own port so gdb, IDA or Ghidra
+/// can attach to either without knowing about the other.
+/// The stub is polled once per frame. While a client has its cpu halted the frontend stops running frames,
+/// so the whole system pauses
+pub struct GdbStub {
+    arch: Arch,
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    /// Bytes received that don't form a complete packet yet
+    buffer: Vec<u8>,
+    halted: bool,
+}
+
+impl GdbStub {
+    pub fn bind(arch: Arch, port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("can't listen on port {port}: {e}"))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        info!("GDB: waiting for a client for the {arch:?} on port {port}");
+
+        Ok(Self { arch, listener, client: None, buffer: Vec::new(), halted: false })
+    }
+
+    /// Whether the attached client has its cpu stopped, the system shouldn't run meanwhile
+    pub const fn is_halted(&self) -> bool {
+        self.client.is_some() && self.halted
+    }
+
+    /// Accepts a new client, handles everything it sent since the last poll and reports a breakpoint
+    /// the cpu stopped on
+    pub fn poll(&mut self, system: &mut System) {
+        if self.client.is_none() {
+            self.accept(system);
+        }
+        if self.client.is_none() {
+            return;
+        }
+
+        if !self.halted && system.cpu(self.arch).breakpoint_hit() {
+            self.halted = true;
+            self.send("S05");
+        }
+
+        let mut data = [0; 4096];
+        loop {
+            let read = match self.client.as_mut().map(|client| client.read(&mut data)) {
+                Some(Ok(0)) => return self.detach(system, "the client disconnected"),
+                Some(Ok(read)) => read,
+                Some(Err(e)) if e.kind() == ErrorKind::WouldBlock => break,
+                Some(Err(e)) => return self.detach(system, &e.to_string()),
+                None => return,
+            };
+            self.buffer.extend_from_slice(&data[..read]);
+        }
+
+        while let Some(received) = next_packet(&mut self.buffer) {
+            if self.client.is_none() {
+                break;
+            }
+            match received {
+                Received::Packet(packet) => {
+                    self.write_raw(b"+");
+                    self.handle_packet(system, &packet);
+                }
+                Received::BadChecksum => self.write_raw(b"-"),
+                Received::Interrupt => self.handle_packet(system, "\x03"),
+            }
+        }
+    }
+
+    fn accept(&mut self, system: &mut System) {
+        let stream = match self.listener.accept() {
+            Ok((stream, addr)) => {
+                info!("GDB: {addr} attached to the {:?}", self.arch);
+                stream
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) => return warn!("GDB: accept failed: {e}"),
+        };
+
+        if let Err(e) = stream.set_nonblocking(true) {
+            return warn!("GDB: {e}");
+        }
+
+        // gdb expects the target to be stopped when it attaches
+        self.client = Some(stream);
+        self.buffer.clear();
+        self.halted = true;
+        system.cpu(self.arch).resume();
+    }
+
+    fn detach(&mut self, system: &mut System, reason: &str) {
+        info!("GDB: detached from the {:?}: {reason}", self.arch);
+        self.client = None;
+        self.halted = false;
+        system.cpu(self.arch).clear_breakpoints();
+    }
+
+    fn handle_packet(&mut self, system: &mut System, packet: &str) {
+        debug!("GDB: <- {packet}");
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "\x03" => {
+                if self.halted {
+                    return;
+                }
+                self.halted = true;
+                "S02".to_string()
+            }
+            "?" => "S05".to_string(),
+            "g" => (0..REGISTER_COUNT).map(|i| hex_u32(self.read_register(system, i))).collect(),
+            "G" => {
+                for (i, chunk) in args.as_bytes().chunks(8).take(REGISTER_COUNT).enumerate() {
+                    match parse_hex_u32(chunk) {
+                        Some(val) => self.write_register(system, i, val),
+                        None => break,
+                    }
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(i) if i < REGISTER_COUNT => hex_u32(self.read_register(system, i)),
+                _ => "E01".to_string(),
+            },
+            "P" => match args.split_once('=').and_then(|(reg, val)| Some((usize::from_str_radix(reg, 16).ok()?, parse_hex_u32(val.as_bytes())?))) {
+                Some((i, val)) if i < REGISTER_COUNT => {
+                    self.write_register(system, i, val);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            // reads don't touch io registers or the heatmap, gdb reads memory around the pc on every stop
+            "m" => match parse_range(args) {
+                Some((addr, len)) => read_memory(system.get_memory(self.arch), addr, len),
+                None => "E01".to_string(),
+            },
+            "M" => match args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, data))) {
+                Some(((addr, len), data)) if data.len() == len as usize * 2 => {
+                    let memory = system.get_memory(self.arch);
+                    for (i, byte) in data.as_bytes().chunks(2).enumerate() {
+                        let byte = std::str::from_utf8(byte).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()).unwrap_or(0);
+                        memory.write_byte(addr.wrapping_add(i as u32), byte);
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            // software and hardware breakpoints are the same thing here
+            "Z" | "z" => match args.split(',').collect::<Vec<_>>()[..] {
+                ["0" | "1", addr, _] => match u32::from_str_radix(addr, 16) {
+                    Ok(addr) => {
+                        let cpu = system.cpu(self.arch);
+                        if command == "Z" {
+                            cpu.add_breakpoint(addr);
+                        } else {
+                            cpu.remove_breakpoint(addr);
+                        }
+                        "OK".to_string()
+                    }
+                    Err(_) => "E01".to_string(),
+                },
+                _ => String::new(),
+            },
+            "s" => {
+                self.jump_to(system, args);
+                system.cpu(self.arch).resume();
+                let vcount = system.video_unit.read_vcount();
+                system.run_instructions(self.arch, 1);
+
+                // show the frame once the step finishes it, like the end of `run_frame` would
+                let new_vcount = system.video_unit.read_vcount();
+                if new_vcount != vcount && new_vcount == VISIBLE_LINES as u32 {
+                    system.video_unit.on_finish_frame();
+                }
+                "S05".to_string()
+            }
+            "c" => {
+                self.jump_to(system, args);
+                system.cpu(self.arch).resume();
+                self.halted = false;
+                // the stop reply goes out once a breakpoint hits or the client interrupts
+                return;
+            }
+            "D" => {
+                self.send("OK");
+                return self.detach(system, "detach requested");
+            }
+            "k" => return self.detach(system, "killed"),
+            "H" | "T" => "OK".to_string(),
+            "q" => self.handle_query(args),
+            _ => String::new(),
+        };
+
+        self.send(&reply);
+    }
+
+    fn handle_query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+");
+        }
+
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, len)) => {
+                    let start = (offset as usize).min(TARGET_XML.len());
+                    let end = (start + len as usize).min(TARGET_XML.len());
+                    let prefix = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{prefix}{}", &TARGET_XML[start..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_register(&self, system: &mut System, index: usize) -> u32 {
+        let cpu = system.cpu(self.arch);
+        match index {
+            15 => cpu.current_pc(),
+            16 => cpu.get_cpsr().0,
+            _ => cpu.state.gpr[index],
+        }
+    }
+
+    fn write_register(&self, system: &mut System, index: usize, val: u32) {
+        let cpu = system.cpu(self.arch);
+        match index {
+            15 => cpu.set_gpr(GPR::PC, val),
+            16 => {
+                // a mode change swaps the banked registers and a thumb change refills the pipeline from the same pc
+                let pc = cpu.current_pc();
+                if let Some(mode) = Mode::from_bits(val & 0x1f) {
+                    cpu.switch_mode(mode);
+                }
+                cpu.set_cpsr(StatusReg(val));
+                cpu.set_gpr(GPR::PC, pc);
+            }
+            _ => cpu.state.gpr[index] = val,
+        }
+    }
+
+    /// `s` and `c` can carry an address to resume from
+    fn jump_to(&self, system: &mut System, args: &str) {
+        if let Ok(addr) = u32::from_str_radix(args, 16) {
+            system.cpu(self.arch).set_gpr(GPR::PC, addr);
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        debug!("GDB: -> {data}");
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.write_raw(format!("${data}#{checksum:02x}").as_bytes());
+    }
+
+    /// Replies are small, so they're written blocking rather than queued. A broken connection is
+    /// noticed and detached by the next read
+    fn write_raw(&mut self, data: &[u8]) {
+        let Some(client) = &mut self.client else { return };
+        let result = client
+            .set_nonblocking(false)
+            .and_then(|_| client.write_all(data))
+            .and_then(|_| client.set_nonblocking(true));
+
+        if let Err(e) = result {
+            warn!("GDB: write failed: {e}");
+        }
+    }
+}
+
+/// What the client sent next
+#[derive(Debug, PartialEq)]
+enum Received {
+    /// A packet body with a good checksum, acknowledged with a `+`
+    Packet(String),
+    /// A packet with a bad checksum, a `-` asks the client to send it again
+    BadChecksum,
+    /// A ctrl-c byte, which comes without a packet around it and isn't acknowledged
+    Interrupt,
+}
+
+/// Takes the next packet out of `buffer`, skipping acks and anything else outside a packet.
+/// A packet that isn't complete yet is left for the next poll
+fn next_packet(buffer: &mut Vec<u8>) -> Option<Received> {
+    loop {
+        match buffer.first()? {
+            b'$' => break,
+            0x03 => {
+                buffer.remove(0);
+                return Some(Received::Interrupt);
+            }
+            _ => {
+                buffer.remove(0);
+            }
+        }
+    }
+
+    let end = buffer.iter().position(|&byte| byte == b'#')?;
+    if buffer.len() < end + 3 {
+        return None;
+    }
+
+    let packet: Vec<u8> = buffer.drain(..end + 3).collect();
+    let body = &packet[1..end];
+    let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    if checksum != Some(body.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))) {
+        warn!("GDB: bad checksum on {}", String::from_utf8_lossy(body));
+        return Some(Received::BadChecksum);
+    }
+
+    Some(Received::Packet(String::from_utf8_lossy(body).into_owned()))
+}
+
+/// The reply to an `m` packet. Longer reads are cut short to fit a packet, the client asks again for the rest
+fn read_memory(memory: &mut dyn Memory, addr: u32, len: u32) -> String {
+    let len = len.min(PACKET_SIZE as u32 / 2);
+    (0..len).map(|i| format!("{:02x}", memory.peek_byte(addr.wrapping_add(i)))).collect()
+}
+
+/// Registers go over the wire in target byte order
+fn hex_u32(val: u32) -> String {
+    val.to_le_bytes().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_hex_u32(hex: &[u8]) -> Option<u32> {
+    let hex = std::str::from_utf8(hex).ok()?;
+    u32::from_str_radix(hex, 16).ok().map(u32::swap_bytes).filter(|_| hex.len() == 8)
+}
+
+/// An `addr,length` pair as sent with memory and xfer packets
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (addr, len) = range.split_once(',')?;
+    Some((u32::from_str_radix(addr, 16).ok()?, u32::from_str_radix(len, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::tests::arm_cpu;
+
+    #[test]
+    fn next_packet_checks_the_checksum() {
+        let mut buffer = b"+$g#67$g#00".to_vec();
+        assert_eq!(next_packet(&mut buffer), Some(Received::Packet("g".into())));
+        assert_eq!(next_packet(&mut buffer), Some(Received::BadChecksum));
+        assert_eq!(next_packet(&mut buffer), None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn next_packet_passes_ctrl_c_outside_packets() {
+        let mut buffer = b"\x03$?#3f".to_vec();
+        assert_eq!(next_packet(&mut buffer), Some(Received::Interrupt));
+        assert_eq!(next_packet(&mut buffer), Some(Received::Packet("?".into())));
+    }
+
+    #[test]
+    fn next_packet_waits_for_the_rest_of_a_packet() {
+        let mut buffer = b"$m0,4".to_vec();
+        assert_eq!(next_packet(&mut buffer), None);
+        buffer.extend_from_slice(b"#f");
+        assert_eq!(next_packet(&mut buffer), None);
+        assert_eq!(buffer, b"$m0,4#f");
+
+        buffer.push(b'd');
+        assert_eq!(next_packet(&mut buffer), Some(Received::Packet("m0,4".into())));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn registers_are_little_endian_hex() {
+        assert_eq!(hex_u32(0x12345678), "78563412");
+        assert_eq!(parse_hex_u32(b"78563412"), Some(0x12345678));
+        assert_eq!(parse_hex_u32(b"785634"), None);
+        assert_eq!(parse_hex_u32(b"7856341z"), None);
+    }
+
+    #[test]
+    fn ranges_are_hex_address_and_length() {
+        assert_eq!(parse_range("2000000,10"), Some((0x02000000, 0x10)));
+        assert_eq!(parse_range("2000000"), None);
+        assert_eq!(parse_range("2000000,"), None);
+    }
+
+    #[test]
+    fn memory_reads_fit_in_a_packet() {
+        let mut cpu = arm_cpu(Arch::ARMv5, &[0x12345678]);
+        assert_eq!(read_memory(cpu.memory.as_mut(), 0, 4), "78563412");
+        assert_eq!(read_memory(cpu.memory.as_mut(), 0, 0x100000).len(), PACKET_SIZE);
+    }
+}
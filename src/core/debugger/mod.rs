@@ -0,0 +1,117 @@
+use std::ops::Range;
+
+use log::debug;
+
+use crate::arm::cpu::Arch;
+use crate::core::hardware::irq::IrqSource;
+
+pub mod gdb;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum StepCondition {
+    VBlank,
+    /// Any irq if the source is `None`
+    Irq(Option<IrqSource>),
+    /// A write touching any byte of the register at this address
+    MmioWrite(u32),
+    BranchTo(Range<u32>),
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    conditions: Vec<StepCondition>,
+    hit: bool,
+}
+
+impl Debugger {
+    pub fn arm(&mut self, conditions: &[StepCondition]) {
+        self.conditions = conditions.to_vec();
+        self.hit = false;
+    }
+
+    pub fn disarm(&mut self) {
+        self.conditions.clear();
+        self.hit = false;
+    }
+
+    pub fn is_armed(&self) -> bool {
+        !self.conditions.is_empty()
+    }
+
+    pub fn conditions(&self) -> &[StepCondition] {
+        &self.conditions
+    }
+
+    pub const fn is_hit(&self) -> bool {
+        self.hit
+    }
+
+    pub fn set_hit(&mut self) {
+        if self.is_armed() {
+            self.hit = true;
+        }
+    }
+
+    pub fn on_vblank(&mut self) {
+        if self.conditions.contains(&StepCondition::VBlank) {
+            debug!("Debugger: hit vblank");
+            self.hit = true;
+        }
+    }
+
+    /// Returns whether the irq met a condition, the cpu it goes to should stop right away
+    pub fn on_irq(&mut self, arch: Arch, source: IrqSource) -> bool {
+        let hit = self.conditions.iter().any(|condition| match condition {
+            StepCondition::Irq(filter) => filter.is_none() || *filter == Some(source),
+            _ => false,
+        });
+        if hit {
+            debug!("Debugger: hit {source:?} irq on {arch:?}");
+            self.hit = true;
+        }
+        hit
+    }
+
+    /// Returns whether the write met a condition, the cpu that made it should stop right after it
+    pub fn on_mmio_write(&mut self, arch: Arch, addr: u32, mask: u32) -> bool {
+        let hit = self.conditions.iter().any(|condition| match *condition {
+            StepCondition::MmioWrite(watch) => watch & !0x3 == addr && mask & (0xff << ((watch & 0x3) * 8)) != 0,
+            _ => false,
+        });
+        if hit {
+            debug!("Debugger: hit mmio write to {addr:08x} on {arch:?}");
+            self.hit = true;
+        }
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mmio_write_hits_only_the_watched_bytes() {
+        let mut debugger = Debugger::default();
+        debugger.arm(&[StepCondition::MmioWrite(0x04000216)]);
+
+        assert!(!debugger.on_mmio_write(Arch::ARMv5, 0x04000214, 0x0000ffff));
+        assert!(!debugger.is_hit());
+        assert!(debugger.on_mmio_write(Arch::ARMv5, 0x04000214, 0xffff0000));
+        assert!(debugger.is_hit());
+    }
+
+    #[test]
+    fn any_armed_condition_hits() {
+        let mut debugger = Debugger::default();
+        debugger.arm(&[StepCondition::Irq(Some(IrqSource::VBlank)), StepCondition::VBlank]);
+
+        assert!(!debugger.on_irq(Arch::ARMv4, IrqSource::HBlank));
+        debugger.on_vblank();
+        assert!(debugger.is_hit());
+
+        debugger.disarm();
+        assert!(!debugger.on_irq(Arch::ARMv4, IrqSource::VBlank));
+        assert!(!debugger.is_hit());
+    }
+}
@@ -0,0 +1,248 @@
+use log::warn;
+
+use crate::util::{StateReader, StateWriter};
+
+/// The serial memory chip games keep their save on, talked to through AUXSPICNT/AUXSPIDATA
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BackupType {
+    None,
+    /// 512 bytes, the 9th address bit is part of the command
+    Eeprom512,
+    Eeprom8k,
+    Eeprom64k,
+    Fram32k,
+    Flash256k,
+    Flash512k,
+    Flash1m,
+}
+
+impl BackupType {
+    pub const fn size(self) -> usize {
+        match self {
+            BackupType::None => 0,
+            BackupType::Eeprom512 => 0x200,
+            BackupType::Eeprom8k => 0x2000,
+            BackupType::Eeprom64k => 0x10000,
+            BackupType::Fram32k => 0x8000,
+            BackupType::Flash256k => 0x40000,
+            BackupType::Flash512k => 0x80000,
+            BackupType::Flash1m => 0x100000,
+        }
+    }
+
+    /// Picks the chip matching an existing save
+    pub const fn from_size(size: usize) -> Option<Self> {
+        Some(match size {
+            0x200 => BackupType::Eeprom512,
+            0x2000 => BackupType::Eeprom8k,
+            0x8000 => BackupType::Fram32k,
+            0x10000 => BackupType::Eeprom64k,
+            0x40000 => BackupType::Flash256k,
+            0x80000 => BackupType::Flash512k,
+            0x100000 => BackupType::Flash1m,
+            _ => return None,
+        })
+    }
+
+    /// Games whose chip is known. Matched on the first 3 characters of the gamecode so every region is covered
+    pub fn from_gamecode(gamecode: u32) -> Option<Self> {
+        let code = gamecode.to_le_bytes();
+        Some(match &code[..3] {
+            // super mario 64 ds
+            b"ASM" => BackupType::Eeprom512,
+            // pokemon diamond, pearl, platinum, heartgold, soulsilver, black, white, black 2 and white 2
+            b"ADA" | b"APA" | b"CPU" | b"IPK" | b"IPG" | b"IRB" | b"IRA" | b"IRE" | b"IRD" => BackupType::Flash512k,
+            _ => return None,
+        })
+    }
+
+    /// The gamecode table first, then the size of the existing save. Anything else gets a 64K eeprom,
+    /// the most common chip
+    pub fn detect(gamecode: u32, save_size: usize) -> Self {
+        // homebrew doesn't have a gamecode and rarely uses the slot 1 save
+        if gamecode == u32::from_le_bytes(*b"####") || gamecode == 0 {
+            return BackupType::None;
+        }
+
+        Self::from_gamecode(gamecode)
+            .or_else(|| Self::from_size(save_size))
+            .unwrap_or(BackupType::Eeprom64k)
+    }
+
+    const fn address_bytes(self) -> usize {
+        match self {
+            BackupType::None => 0,
+            BackupType::Eeprom512 => 1,
+            BackupType::Eeprom8k | BackupType::Eeprom64k | BackupType::Fram32k => 2,
+            BackupType::Flash256k | BackupType::Flash512k | BackupType::Flash1m => 3,
+        }
+    }
+
+    const fn is_flash(self) -> bool {
+        matches!(self, BackupType::Flash256k | BackupType::Flash512k | BackupType::Flash1m)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Access {
+    Read,
+    Write,
+    Program,
+}
+
+/// Status register bit set by WREN, writes and erases are ignored without it
+const STATUS_WRITE_ENABLE: u8 = 0x2;
+
+/// Command state of the backup chip. A command lasts from the first byte written after chip select
+/// until the chip is released by a transfer without the chipselect hold bit
+pub struct Backup {
+    kind: BackupType,
+    command: u8,
+    /// Bytes of the current command transferred so far, including the command byte
+    position: usize,
+    address: u32,
+    status: u8,
+    /// Set by writes until the save is flushed to disk
+    pub dirty: bool,
+}
+
+impl Backup {
+    pub fn new(kind: BackupType) -> Self {
+        Self { kind, command: 0, position: 0, address: 0, status: 0, dirty: false }
+    }
+
+    pub const fn kind(&self) -> BackupType {
+        self.kind
+    }
+
+    /// Sends one byte to the chip and returns the byte it clocked out at the same time
+    pub fn transfer(&mut self, data: &mut [u8], val: u8) -> u8 {
+        if self.kind == BackupType::None {
+            return 0xff;
+        }
+
+        if self.position == 0 {
+            self.command = val;
+            self.address = 0;
+            self.position = 1;
+            return self.start_command();
+        }
+
+        let index = self.position;
+        self.position += 1;
+        match self.command {
+            // RDSR
+            0x05 => self.status,
+            // WRSR, only the block protect bits are writable and nothing looks at them
+            0x01 if !self.kind.is_flash() => {
+                self.status = (self.status & STATUS_WRITE_ENABLE) | (val & 0x0c);
+                0xff
+            }
+            // RDID
+            0x9f if self.kind.is_flash() => self.read_id(index - 1),
+            // READ, and FAST READ on flash which has a dummy byte after the address
+            0x03 | 0x0b => self.data_phase(index, val, data, Access::Read),
+            // PAGE PROGRAM can only clear bits
+            0x02 if self.kind.is_flash() => self.data_phase(index, val, data, Access::Program),
+            // WRITE on eeprom and fram, PAGE WRITE on flash
+            0x02 | 0x0a => self.data_phase(index, val, data, Access::Write),
+            // PAGE ERASE (256 bytes), SECTOR ERASE (64K)
+            0xdb | 0xd8 if self.kind.is_flash() => {
+                self.address = (self.address << 8) | val as u32;
+                if index == 3 && self.status & STATUS_WRITE_ENABLE != 0 {
+                    let size = if self.command == 0xdb { 0x100 } else { 0x10000 };
+                    let start = (self.address as usize & !(size - 1)) % data.len();
+                    let end = (start + size).min(data.len());
+                    data[start..end].fill(0xff);
+                    self.dirty = true;
+                }
+                0xff
+            }
+            _ => 0xff,
+        }
+    }
+
+    /// Called when a transfer ends without holding chip select, finishing the current command
+    pub fn deselect(&mut self) {
+        if self.position != 0 && matches!(self.command, 0x02 | 0x0a | 0xdb | 0xd8) {
+            self.status &= !STATUS_WRITE_ENABLE;
+        }
+        self.position = 0;
+    }
+
+    fn start_command(&mut self) -> u8 {
+        match self.command {
+            // WREN
+            0x06 => self.status |= STATUS_WRITE_ENABLE,
+            // WRDI
+            0x04 => self.status &= !STATUS_WRITE_ENABLE,
+            0x01 | 0x02 | 0x03 | 0x05 | 0x0a | 0x0b | 0xd8 | 0xdb | 0x9f => {}
+            command => warn!("Backup: unhandled command {command:02x} for {:?}", self.kind),
+        }
+
+        0xff
+    }
+
+    /// Shifts in the address bytes, then reads or writes one byte per transfer with the address
+    /// wrapping inside the chip
+    fn data_phase(&mut self, index: usize, val: u8, data: &mut [u8], access: Access) -> u8 {
+        let address_bytes = self.kind.address_bytes();
+        if index <= address_bytes {
+            self.address = (self.address << 8) | val as u32;
+            if index == address_bytes && self.kind == BackupType::Eeprom512 {
+                // bit 3 of the command is the 9th address bit
+                self.address |= ((self.command as u32 >> 3) & 1) << 8;
+            }
+            return 0xff;
+        }
+        if self.command == 0x0b && self.kind.is_flash() && index == address_bytes + 1 {
+            return 0xff;
+        }
+
+        let addr = self.address as usize % data.len();
+        self.address = self.address.wrapping_add(1);
+        let old = data[addr];
+        let new = match access {
+            Access::Read => return old,
+            _ if self.status & STATUS_WRITE_ENABLE == 0 => old,
+            Access::Write => val,
+            Access::Program => old & val,
+        };
+
+        if new != old {
+            data[addr] = new;
+            self.dirty = true;
+        }
+        0xff
+    }
+
+    /// Manufacturer and device id of an ST M25PE series flash, which reports its size in the last byte
+    fn read_id(&self, index: usize) -> u8 {
+        let size = match self.kind {
+            BackupType::Flash256k => 0x12,
+            BackupType::Flash512k => 0x13,
+            _ => 0x14,
+        };
+
+        match index {
+            0 => 0x20,
+            1 => 0x40,
+            2 => size,
+            _ => 0xff,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write("command", self.command);
+        state.write("position", self.position as u32);
+        state.write("address", self.address);
+        state.write("status", self.status);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.command = state.read();
+        self.position = state.read::<u32>() as usize;
+        self.address = state.read();
+        self.status = state.read();
+    }
+}
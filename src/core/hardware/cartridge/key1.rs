@@ -0,0 +1,100 @@
+use crate::util::{StateReader, StateWriter};
+
+/// Where the KEY1 tables start in the arm7 bios
+pub const BIOS_KEY_OFFSET: u32 = 0x30;
+/// 18 p-array entries followed by 4 s-boxes of 256 entries each
+pub const KEY_WORDS: usize = 0x412;
+
+/// The KEY1 cipher, blowfish with tables from the arm7 bios that are scrambled by the gamecode.
+/// Commands sent during the secure area handshake and the start of the secure area are encrypted with it
+#[derive(Clone)]
+pub struct Key1 {
+    buffer: Box<[u32; KEY_WORDS]>,
+    code: [u32; 3],
+}
+
+impl Default for Key1 {
+    fn default() -> Self {
+        Self { buffer: Box::new([0; KEY_WORDS]), code: [0; 3] }
+    }
+}
+
+impl Key1 {
+    /// Starts from the bios tables and applies the gamecode `level` times, cartridges use level 2 for
+    /// commands and level 3 for the secure area with a modulo of 2 words
+    pub fn new(bios_table: &[u32; KEY_WORDS], gamecode: u32, level: u32, modulo: usize) -> Self {
+        let mut key = Self { buffer: Box::new(*bios_table), code: [gamecode, gamecode / 2, gamecode.wrapping_mul(2)] };
+        if level >= 1 {
+            key.apply_code(modulo);
+        }
+        if level >= 2 {
+            key.apply_code(modulo);
+        }
+
+        key.code[1] = key.code[1].wrapping_mul(2);
+        key.code[2] /= 2;
+        if level >= 3 {
+            key.apply_code(modulo);
+        }
+        key
+    }
+
+    fn apply_code(&mut self, modulo: usize) {
+        let [a, b, c] = self.code;
+        [self.code[1], self.code[2]] = self.encrypt([b, c]);
+        [self.code[0], self.code[1]] = self.encrypt([a, self.code[1]]);
+
+        for i in 0..0x12 {
+            self.buffer[i] ^= self.code[i % modulo].swap_bytes();
+        }
+
+        let mut scratch = [0; 2];
+        for i in (0..KEY_WORDS).step_by(2) {
+            scratch = self.encrypt(scratch);
+            self.buffer[i] = scratch[1];
+            self.buffer[i + 1] = scratch[0];
+        }
+    }
+
+    fn round(&self, z: u32) -> u32 {
+        let mut x = self.buffer[0x12 + (z >> 24) as usize];
+        x = x.wrapping_add(self.buffer[0x112 + ((z >> 16) & 0xff) as usize]);
+        x ^= self.buffer[0x212 + ((z >> 8) & 0xff) as usize];
+        x.wrapping_add(self.buffer[0x312 + (z & 0xff) as usize])
+    }
+
+    /// Encrypts a 64 bit block stored as low word, high word
+    pub fn encrypt(&self, [mut y, mut x]: [u32; 2]) -> [u32; 2] {
+        for i in 0..0x10 {
+            let z = self.buffer[i] ^ x;
+            x = y ^ self.round(z);
+            y = z;
+        }
+        [x ^ self.buffer[0x10], y ^ self.buffer[0x11]]
+    }
+
+    pub fn decrypt(&self, [mut y, mut x]: [u32; 2]) -> [u32; 2] {
+        for i in (0x2..0x12).rev() {
+            let z = self.buffer[i] ^ x;
+            x = y ^ self.round(z);
+            y = z;
+        }
+        [x ^ self.buffer[0x1], y ^ self.buffer[0x0]]
+    }
+
+    /// Commands go over the bus most significant byte first, which is the high word of the block
+    pub fn decrypt_command(&self, command: u64) -> u64 {
+        let [low, high] = self.decrypt([command as u32, (command >> 32) as u32]);
+        (high as u64) << 32 | low as u64
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_slice("buffer", self.buffer.as_slice());
+        state.write_slice("code", &self.code);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.read_slice(self.buffer.as_mut_slice());
+        state.read_slice(&mut self.code);
+    }
+}
@@ -1,10 +1,23 @@
-use log::{debug, error};
 
+use log::{debug, error, warn};
+
+use crate::arm::cpu::Arch;
 use crate::bitfield;
+use crate::core::hardware::cartridge::backup::{Backup, BackupType};
+use crate::core::hardware::cartridge::key1::{Key1, BIOS_KEY_OFFSET, KEY_WORDS};
+use crate::core::hardware::cartridge::key2::Key2;
+use crate::core::hardware::cartridge::save::SaveFormat;
 use crate::core::hardware::dma::DmaTiming;
 use crate::core::hardware::irq::IrqSource;
+use crate::core::scheduler::EventId;
 use crate::core::System;
-use crate::util::{bit, get_field64, set, Shared};
+use crate::unimplemented_feature;
+use crate::util::{crc32, get_field64, read_le, set, FileLock, Shared, StateReader, StateWriter};
+
+pub mod backup;
+pub mod key1;
+pub mod key2;
+pub mod save;
 
 bitfield! {
     #[derive(Clone, Copy)]
@@ -39,6 +52,7 @@ bitfield! {
     }
 }
 
+#[derive(Copy, Clone)]
 enum CommandType {
     Dummy,
     ReadData,
@@ -50,10 +64,33 @@ enum CommandType {
     None,
 }
 
+/// Identifies a dump independent of its file name, used to name saves and savestates
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct RomId {
+    pub gamecode: u32,
+    /// crc32 of the whole rom, tells apart revisions and regions that share a gamecode
+    pub crc32: u32,
+}
+
+impl std::fmt::Display for RomId {
+    /// `ADME-1a2b3c4d`, characters that can't go in a file name are replaced
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gamecode: String = self.gamecode.to_le_bytes().iter().map(|&c| if c.is_ascii_alphanumeric() { c as char } else { '_' }).collect();
+        write!(f, "{gamecode}-{:08x}", self.crc32)
+    }
+}
+
 pub struct Cartridge {
     system: Shared<System>,
+    path: String,
     file: Vec<u8>,
     header: Header,
+    rom_id: RomId,
+    /// Returned by all three get chip id commands
+    chip_id: u32,
+    backup_data: Vec<u8>,
+    /// Held while the save next to the rom is ours to write, `None` if another instance has it
+    save_lock: Option<FileLock>,
 
     auxspicnt: AuxSpiCnt,
     auxspidata: u8,
@@ -65,23 +102,36 @@ pub struct Cartridge {
     rom_position: u32,
     seed0: u64,
     seed1: u64,
+    /// The KEY2 stream. The console and the cartridge each have one, seeded the same and stepped together,
+    /// so one stands for both. The cartridge follows the console's seed registers like a correctly booted one
+    key2: Key2,
+    /// The cartridge encrypts its data and expects encrypted commands after the activate KEY2 command
+    cartridge_key2: bool,
+    /// Commands are KEY1 encrypted between the activate KEY1 and enter main data mode commands
     key1_encryption: bool,
     command_type: CommandType,
-    key1_buffer: [u32; 0x412],
-    key1_code: [u32; 3],
+    key1: Key1,
+    /// Rom 0x4000 to 0x8000 as the cartridge sends it, with the first 2KB KEY1 encrypted
     secure_area: [u8; 0x4000],
     cartridge_inserted: bool,
+    word_ready_event: EventId,
 
-    backup: (),
-    backup_write_count: (),
+    backup: Backup,
+    /// AUXSPIDATA writes this frame, the save is flushed once a frame passes without any
+    backup_write_count: u32,
 }
 
 impl Cartridge {
     pub fn new(system: &Shared<System>) -> Self {
         Self {
             system: system.clone(),
+            path: String::new(),
             file: vec![],
             header: Header::default(),
+            rom_id: RomId::default(),
+            chip_id: 0,
+            backup_data: vec![],
+            save_lock: None,
             auxspicnt: AuxSpiCnt(0),
             auxspidata: 0,
             romctrl: RomCtrl(0),
@@ -92,44 +142,234 @@ impl Cartridge {
             rom_position: 0,
             seed0: 0,
             seed1: 0,
+            key2: Key2::default(),
+            cartridge_key2: false,
             key1_encryption: false,
             command_type: CommandType::Dummy,
-            key1_buffer: [0; 0x412],
-            key1_code: [0; 3],
+            key1: Key1::default(),
             secure_area: [0; 0x4000],
             cartridge_inserted: false,
+            word_ready_event: Default::default(),
 
-            backup: (),
-            backup_write_count: (),
+            backup: Backup::new(BackupType::None),
+            backup_write_count: 0,
         }
     }
 
+    pub fn reset(&mut self) {
+        self.romctrl = RomCtrl(0);
+        self.transfer_count = 0;
+        self.transfer_size = 0;
+        self.key1_encryption = false;
+        self.cartridge_key2 = false;
+        self.command_type = CommandType::Dummy;
+        self.word_ready_event = self.system.scheduler.register_event("Cartridge Word Ready", |system| system.cartridge.on_word_ready());
+    }
+
     pub fn load(&mut self, path: &str) {
         self.file = std::fs::read(path).unwrap();
         self.cartridge_inserted = true;
         self.header = Header::parse(&self.file);
         debug!("{:#?}", self.header);
+        self.load_secure_area();
+        self.chip_id = chip_id(self.file.len());
+
+        // the rom doesn't change between resets, skip hashing it again
+        if self.path != path || self.rom_id == RomId::default() {
+            self.rom_id = RomId { gamecode: self.header.gamecode, crc32: crc32(&self.file) };
+            debug!("Cartridge: rom id {}", self.rom_id);
+        }
+        self.path = path.to_string();
+
+        self.backup_data = save::load(path, self.rom_id).unwrap_or_default();
+        let kind = BackupType::detect(self.header.gamecode, self.backup_data.len());
+        if self.backup_data.len() < kind.size() {
+            self.backup_data.resize(kind.size(), 0xff);
+        }
+        self.backup = Backup::new(kind);
+        self.backup_write_count = 0;
+        debug!("Cartridge: using {kind:?} backup");
+
+        // drop our own lock first, it would block taking it again on a reset
+        self.save_lock = None;
+        self.save_lock = FileLock::acquire(&save::save_path(self.rom_id, SaveFormat::Raw));
+        if self.save_lock.is_none() {
+            warn!("Cartridge: the save for {path} is in use by another instance, it won't be written");
+        }
+    }
+
+    pub const fn rom_id(&self) -> RomId {
+        self.rom_id
+    }
+
+    pub const fn save_locked(&self) -> bool {
+        self.save_lock.is_none()
+    }
+
+    pub fn take_backup_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.backup_data)
+    }
+
+    pub fn restore_backup_data(&mut self, data: Vec<u8>) {
+        self.backup_data = data;
+        // what's in memory might not have been written yet
+        self.backup.dirty = true;
+    }
+
+    /// Writes the save to disk once the game stops writing to it, so a save spread over several
+    /// frames is only stored when it's complete
+    pub fn flush_backup(&mut self) {
+        if self.backup.dirty && self.backup_write_count == 0 && !self.save_locked() {
+            save::store(self.rom_id, &self.backup_data, SaveFormat::Raw);
+            self.backup.dirty = false;
+        }
+        self.backup_write_count = 0;
+    }
+
+    pub fn export_save(&self, format: SaveFormat) {
+        if self.save_locked() {
+            return error!("Cartridge: not exporting the save, another instance is using it");
+        }
+
+        save::store(self.rom_id, &self.backup_data, format)
+    }
+
+    /// Includes the backup memory, so loading a state also brings the in-game save back to that point.
+    /// States are tied to a dump through its rom id
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"CART");
+        state.write("rom_id.gamecode", self.rom_id.gamecode);
+        state.write("rom_id.crc32", self.rom_id.crc32);
+        state.write("auxspicnt", self.auxspicnt.0);
+        state.write("auxspidata", self.auxspidata);
+        state.write("romctrl", self.romctrl.0);
+        state.write("command_buffer", self.command_buffer);
+        state.write("command", self.command);
+        state.write("transfer_count", self.transfer_count);
+        state.write("transfer_size", self.transfer_size);
+        state.write("rom_position", self.rom_position);
+        state.write("seed0", self.seed0);
+        state.write("seed1", self.seed1);
+        self.key2.save_state(state);
+        state.write_bool("cartridge_key2", self.cartridge_key2);
+        state.write_bool("key1_encryption", self.key1_encryption);
+        state.write("command_type", self.command_type as u8);
+        self.key1.save_state(state);
+        state.write_bytes("secure_area", &self.secure_area);
+        state.write_vec("backup_data", &self.backup_data);
+        self.backup.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"CART");
+        let id = RomId { gamecode: state.read(), crc32: state.read() };
+        if id != self.rom_id {
+            state.fail(format!("savestate is for rom {id}, not {}", self.rom_id));
+            return;
+        }
+
+        self.auxspicnt.0 = state.read();
+        self.auxspidata = state.read();
+        self.romctrl.0 = state.read();
+        self.command_buffer = state.read();
+        self.command = state.read();
+        self.transfer_count = state.read();
+        self.transfer_size = state.read();
+        self.rom_position = state.read();
+        self.seed0 = state.read();
+        self.seed1 = state.read();
+        self.key2.load_state(state);
+        self.cartridge_key2 = state.read_bool();
+        self.key1_encryption = state.read_bool();
+        match state.read::<u8>() {
+            0 => self.command_type = CommandType::Dummy,
+            1 => self.command_type = CommandType::ReadData,
+            2 => self.command_type = CommandType::GetFirstId,
+            3 => self.command_type = CommandType::GetSecondId,
+            4 => self.command_type = CommandType::GetThirdId,
+            5 => self.command_type = CommandType::ReadHeader,
+            6 => self.command_type = CommandType::ReadSecureArea,
+            7 => self.command_type = CommandType::None,
+            command_type => state.fail(format!("invalid cartridge command type {command_type}")),
+        }
+        self.key1.load_state(state);
+        state.read_bytes(&mut self.secure_area);
+        self.backup_data = state.read_vec();
+        self.backup.load_state(state);
+        self.backup.dirty = true;
+    }
+
+    /// The KEY1 tables in the arm7 bios, before any gamecode is applied
+    fn bios_key_table(&mut self) -> [u32; KEY_WORDS] {
+        let memory = self.system.arm7.get_memory();
+        std::array::from_fn(|i| memory.read_word(BIOS_KEY_OFFSET + i as u32 * 4))
+    }
+
+    /// Dumps usually have the secure area decrypted and its first 8 bytes overwritten, which the bios
+    /// would reject. Those get "encryObj" back and are encrypted again, like the original cartridge
+    fn load_secure_area(&mut self) {
+        self.secure_area.fill(0);
+        if let Some(data) = self.file.get(0x4000..self.file.len().min(0x8000)) {
+            self.secure_area[..data.len()].copy_from_slice(data);
+        }
+
+        // without the bios there are no key tables, direct boot copies from the file anyway
+        let in_secure_area = (0x4000..0x8000).contains(&self.header.arm9_offset);
+        if !in_secure_area || self.system.arm7.hle_bios() || read_le::<u64>(&self.secure_area, 0) != Some(0xe7ffdeff_e7ffdeff) {
+            return;
+        }
+
+        let table = self.bios_key_table();
+        self.secure_area[..8].copy_from_slice(b"encryObj");
+        let key = Key1::new(&table, self.header.gamecode, 3, 2);
+        for block in self.secure_area[..0x800].chunks_exact_mut(8) {
+            encrypt_block(&key, block);
+        }
+
+        // the first block is encrypted a second time with the command key
+        let key = Key1::new(&table, self.header.gamecode, 2, 2);
+        encrypt_block(&key, &mut self.secure_area[..8]);
+        debug!("Cartridge: encrypted the secure area");
     }
 
     pub fn direct_boot(&mut self) {
+        // the bios leaves the cartridge in main data mode, where it only talks KEY2
+        self.cartridge_key2 = true;
+
         // transfer the header + workaround for TinyFB
         for i in 0..0x170.min(self.file.len() as u32) {
             self.system.arm9.get_memory().write_byte(0x027ffe00 + i, self.file[i as usize])
         }
 
-        // transfer the arm9 code
+        // transfer the arm9 code, anything past the end of a malformed rom reads as 0
         for i in 0..self.header.arm9_size {
-            self.system.arm9.get_memory().write_byte(self.header.arm9_ram_address + i, self.file[(self.header.arm9_offset + i) as usize])
+            let byte = read_le::<u8>(&self.file, self.header.arm9_offset.wrapping_add(i) as usize).unwrap_or_default();
+            self.system.arm9.get_memory().write_byte(self.header.arm9_ram_address + i, byte)
         }
 
         // transfer the arm7 code
         for i in 0..self.header.arm7_size {
-            self.system.arm7.get_memory().write_byte(self.header.arm7_ram_address + i, self.file[(self.header.arm7_offset + i) as usize])
+            let byte = read_le::<u8>(&self.file, self.header.arm7_offset.wrapping_add(i) as usize).unwrap_or_default();
+            self.system.arm7.get_memory().write_byte(self.header.arm7_ram_address + i, byte)
         }
 
         debug!("Cartridge: cartridge data transferred into memory");
     }
 
+    /// First line of the english banner title, falling back to the short title in the header
+    pub fn title(&self) -> String {
+        let start = self.header.icon_title_offset as usize + 0x340;
+        if self.header.icon_title_offset != 0 && start + 0x100 <= self.file.len() {
+            let chars: Vec<u16> = self.file[start..start + 0x100].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            let title = String::from_utf16_lossy(&chars);
+            if let Some(line) = title.split(['\n', '\0']).next().filter(|line| !line.is_empty()) {
+                return line.to_string();
+            }
+        }
+
+        self.header.title.split('\0').next().unwrap_or_default().trim().to_string()
+    }
+
     pub const fn get_arm9_entrypoint(&self) -> u32 {
         self.header.arm9_entrypoint
     }
@@ -142,18 +382,30 @@ impl Cartridge {
         set(&mut self.auxspicnt.0, val, mask)
     }
 
+    /// Clocks a byte to the backup chip, the chip stays selected for the next byte while the
+    /// chipselect hold bit is set
     pub fn write_auxspidata(&mut self, val: u8) {
-        if self.backup == () {
+        if !self.auxspicnt.slot_enable() || !self.auxspicnt.slot_mode() {
             return;
         }
 
-        todo!()
+        self.auxspidata = self.backup.transfer(&mut self.backup_data, val);
+        if !self.auxspicnt.chipselect_hold() {
+            self.backup.deselect();
+        }
+        self.backup_write_count += 1;
     }
 
     pub fn write_romctrl(&mut self, val: u32, mask: u32) {
         let old = self.romctrl;
         set(&mut self.romctrl.0, val, mask);
 
+        // apply seed is write only
+        if self.romctrl.key2_apply_seed() {
+            self.key2 = Key2::new(self.seed0, self.seed1);
+            self.romctrl.set_key2_apply_seed(false);
+        }
+
         if !old.block_start() && self.romctrl.block_start() {
             self.start_transfer()
         }
@@ -163,6 +415,15 @@ impl Cartridge {
         set(&mut self.command_buffer, val, mask)
     }
 
+    /// Only the low 39 bits of the seeds exist
+    pub fn write_seed0(&mut self, val: u64, mask: u64) {
+        set(&mut self.seed0, val, mask & 0x7f_ffffffff)
+    }
+
+    pub fn write_seed1(&mut self, val: u64, mask: u64) {
+        set(&mut self.seed1, val, mask & 0x7f_ffffffff)
+    }
+
     pub const fn read_auxspicnt(&self) -> u16 {
         self.auxspicnt.0
     }
@@ -189,42 +450,105 @@ impl Cartridge {
                         self.rom_position = 0x8000 + (self.rom_position & 0x1ff);
                     }
 
-                    if (self.rom_position + self.transfer_count) >= self.file.len() as u32 {
-                        error!("Cartridge: read data command exceeds rom size")
+                    let addr = self.data_address(self.romctrl.key2_encrypt_data());
+                    match read_le::<u32>(&self.file, addr as usize) {
+                        Some(word) => data = word,
+                        None => error!("Cartridge: read data command exceeds rom size"),
                     }
-
-                    data = read::<u32>(&self.file, self.rom_position + self.transfer_count)
                 }
                 CommandType::GetFirstId | CommandType::GetSecondId | CommandType::GetThirdId => {
-                    data = 0x1fc2
+                    data = self.chip_id
+                }
+                // the header repeats every 4KB
+                CommandType::ReadHeader => {
+                    data = read_le::<u32>(&self.file, (self.transfer_count & 0xfff) as usize).unwrap_or(0xffffffff)
+                }
+                CommandType::ReadSecureArea => {
+                    let addr = self.data_address(true);
+                    let word = match addr {
+                        0x4000..=0x7fff => read_le::<u32>(&self.secure_area, addr as usize - 0x4000),
+                        _ => read_le::<u32>(&self.file, addr as usize),
+                    };
+                    data = word.unwrap_or(0xffffffff);
                 }
-                CommandType::ReadHeader => todo!(),
-                CommandType::ReadSecureArea => todo!(),
                 CommandType::None => unreachable!()
             }
+
+            data = u32::from_le_bytes(self.apply_key2(self.romctrl.key2_encrypt_data(), data.to_le_bytes()));
         }
 
         self.transfer_count += 4;
+        self.romctrl.set_word_ready(false);
         if self.transfer_count == self.transfer_size {
-            self.romctrl.set_word_ready(false);
-            self.romctrl.set_block_start(false);
-
-            // todo: does this trigger on both cpus?
-            if self.auxspicnt.transfer_ready_irq() {
-                self.system.arm7.get_irq().raise(IrqSource::CartridgeTransfer);
-                self.system.arm9.get_irq().raise(IrqSource::CartridgeTransfer);
-            }
+            self.finish_transfer();
         } else {
-            if bit::<11>(self.system.exmemcnt as u32) {
-                self.system.dma7.trigger(DmaTiming::Slot1)
-            } else {
-                self.system.dma9.trigger(DmaTiming::Slot1)
+            // gap2 is inserted between each 0x200 byte block
+            let mut delay = 4 * self.cycles_per_byte();
+            if self.transfer_count % 0x200 == 0 {
+                delay += self.romctrl.key1_gap2_length() as u64 * self.cycles_per_byte();
             }
+            self.system.scheduler.add_event(delay.max(1), self.word_ready_event);
         }
 
         data
     }
 
+    /// Passes bytes between the console and the cartridge, KEY2 encrypted by whichever end has it on. When
+    /// both do the cartridge's encryption and the console's decryption cancel out and the stream just steps
+    fn apply_key2<const N: usize>(&mut self, console_key2: bool, bytes: [u8; N]) -> [u8; N] {
+        match (console_key2, self.cartridge_key2) {
+            (false, false) => bytes,
+            (true, true) => bytes.map(|byte| {
+                self.key2.step();
+                byte
+            }),
+            _ => bytes.map(|byte| self.key2.apply(byte)),
+        }
+    }
+
+    /// Encrypted and secure area reads don't carry into the next 4KB block, a transfer that crosses a 0x1000
+    /// boundary wraps around to the start of the block it began in. Plain reads carry on linearly
+    const fn data_address(&self, block_wrap: bool) -> u32 {
+        if block_wrap {
+            (self.rom_position & !0xfff) | ((self.rom_position + self.transfer_count) & 0xfff)
+        } else {
+            self.rom_position + self.transfer_count
+        }
+    }
+
+    /// The rom bus clock is either 6.7MHz or 4.2MHz, which is 5 or 8 system cycles per byte.
+    /// Without cartridge timing every word is ready the cycle after the previous one
+    fn cycles_per_byte(&self) -> u64 {
+        if !self.system.config.accuracy.cartridge_timing {
+            0
+        } else if self.romctrl.transfer_rate() {
+            8
+        } else {
+            5
+        }
+    }
+
+    fn on_word_ready(&mut self) {
+        self.romctrl.set_word_ready(true);
+
+        match self.system.nds_slot_owner() {
+            Arch::ARMv4 => self.system.dma7.trigger(DmaTiming::Slot1),
+            Arch::ARMv5 => self.system.dma9.trigger(DmaTiming::Slot1),
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        self.romctrl.set_block_start(false);
+
+        // only the cpu with access to the slot gets the irq
+        if self.auxspicnt.transfer_ready_irq() {
+            match self.system.nds_slot_owner() {
+                Arch::ARMv4 => self.system.arm7.get_irq().raise(IrqSource::CartridgeTransfer),
+                Arch::ARMv5 => self.system.arm9.get_irq().raise(IrqSource::CartridgeTransfer),
+            }
+        }
+    }
+
     fn start_transfer(&mut self) {
         self.transfer_size = match self.romctrl.block_size() {
             0 => 0,
@@ -232,24 +556,25 @@ impl Cartridge {
             other => 0x100 << other
         };
 
-        self.command = self.command_buffer.swap_bytes();
+        let command = self.apply_key2(self.romctrl.key2_encrypt_command(), self.command_buffer.to_le_bytes());
+        self.command = u64::from_le_bytes(command).swap_bytes();
         if self.key1_encryption {
-            error!("Cartridge: handle key1 encryption")
+            self.command = self.key1.decrypt_command(self.command);
+            self.process_key1_command()
         } else {
             self.process_decrypted_command()
         }
 
         if self.transfer_size == 0 {
-            todo!()
+            // commands without data like activate KEY1 end right away
+            self.finish_transfer();
         } else {
+            // the 8 command bytes and gap1 come before the first data word
             self.transfer_count = 0;
-            self.romctrl.set_word_ready(true);
+            self.romctrl.set_word_ready(false);
 
-            if bit::<11>(self.system.exmemcnt as u32) {
-                self.system.dma7.trigger(DmaTiming::Slot1)
-            } else {
-                self.system.dma9.trigger(DmaTiming::Slot1)
-            }
+            let delay = (8 + self.romctrl.key1_gap1_length() as u64 + 4) * self.cycles_per_byte();
+            self.system.scheduler.reschedule_event(delay.max(1), self.word_ready_event);
         }
     }
 
@@ -270,14 +595,58 @@ impl Cartridge {
         } else if self.command == 0x9000000000000000 {
             self.command_type = CommandType::GetFirstId;
         } else if (self.command >> 56) == 0x3c {
+            let table = self.bios_key_table();
+            self.key1 = Key1::new(&table, self.header.gamecode, 2, 2);
             self.key1_encryption = true;
-            self.command_type = CommandType::None;
+            self.command_type = CommandType::Dummy;
         } else {
-            error!("Cartridge: handle decrypted command: {:016x}", self.command);
+            unimplemented_feature!("Cartridge: handle decrypted command: {:016x}", self.command);
+        }
+    }
+
+    /// Secure area handshake commands, the command id is in the top 4 bits
+    fn process_key1_command(&mut self) {
+        if !self.cartridge_inserted {
+            return;
+        }
+
+        match self.command >> 60 {
+            0x1 => self.command_type = CommandType::GetSecondId,
+            0x2 => {
+                // 2bbbbiiijjjkkkkk reads 4KB block bbbb
+                self.rom_position = get_field64::<44, 16>(self.command) as u32 * 0x1000;
+                self.command_type = CommandType::ReadSecureArea;
+            }
+            // activate KEY2, the cartridge's stream is seeded along with the console's
+            0x4 => {
+                self.cartridge_key2 = true;
+                self.command_type = CommandType::Dummy;
+            }
+            0xa => {
+                self.key1_encryption = false;
+                self.command_type = CommandType::Dummy;
+            }
+            _ => unimplemented_feature!("Cartridge: handle key1 command: {:016x}", self.command),
         }
     }
 }
 
+/// A Macronix mask rom, the second byte is the chip size in megabytes minus 1, or for chips of 256MB
+/// and up 0x100 minus the size in 256MB units
+fn chip_id(rom_size: usize) -> u32 {
+    let size = (rom_size as u64).next_power_of_two().max(0x100000);
+    let size_code = if size <= 0x8000000 { (size >> 20) - 1 } else { 0x100 - (size >> 28) };
+    0xc2 | (size_code as u32) << 8
+}
+
+/// Encrypts 8 bytes in place, stored as two little endian words
+fn encrypt_block(key: &Key1, block: &mut [u8]) {
+    let words = [read_le::<u32>(block, 0).unwrap(), read_le::<u32>(block, 4).unwrap()];
+    let [low, high] = key.encrypt(words);
+    block[..4].copy_from_slice(&low.to_le_bytes());
+    block[4..8].copy_from_slice(&high.to_le_bytes());
+}
+
 #[derive(Default, Debug)]
 struct Header {
     title: String,
@@ -305,14 +674,19 @@ struct Header {
 
 impl Header {
     fn parse(data: &[u8]) -> Self {
+        // fields past the end of a truncated rom read as 0
         macro_rules! read {
             ($t:ty, $start:literal) => {
-                <$t>::from_le_bytes(data[$start..$start + std::mem::size_of::<$t>()].try_into().unwrap())
+                read_le::<$t>(data, $start).unwrap_or_default()
             };
         }
 
+        if data.len() < 0x170 {
+            warn!("Cartridge: rom is only {} bytes, smaller than a header", data.len());
+        }
+
         Self {
-            title: String::from_utf8_lossy(&data[0..12]).to_string(),
+            title: String::from_utf8_lossy(data.get(0..12).unwrap_or_default()).to_string(),
             arm9_offset: read!(u32, 0x20),
             arm9_entrypoint: read!(u32, 0x24),
             arm9_ram_address: read!(u32, 0x28),
@@ -327,8 +701,149 @@ impl Header {
     }
 }
 
-const fn read<T: Copy>(data: &[u8], offset: u32) -> T {
-    unsafe {
-        *data.as_ptr().add(offset as usize).cast()
+#[cfg(test)]
+mod tests {
+    use crate::core::hardware::irq::IrqSource;
+    use crate::core::{OwnedSystem, System};
+    use crate::util::state_sections;
+
+    const TRANSFER_READY_IRQ: u16 = 1 << 14;
+    const SLOT_ENABLE: u16 = 1 << 15;
+    const KEY2_ENCRYPT_DATA: u32 = 1 << 13;
+    const KEY2_APPLY_SEED: u32 = 1 << 15;
+    const KEY2_ENCRYPT_COMMAND: u32 = 1 << 22;
+    const BLOCK_START: u32 = 1 << 31;
+    const ARM7_SLOT_ACCESS: u16 = 1 << 11;
+
+    /// Starts a transfer of `block_size` with the slot given to the arm7 or the arm9 and reads it to the end
+    fn finished_transfer(exmemcnt: u16, block_size: u32) -> OwnedSystem {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.cartridge.reset();
+        system.write_exmemcnt(exmemcnt, 0xffff);
+        system.cartridge.write_auxspicnt(SLOT_ENABLE | TRANSFER_READY_IRQ, 0xffff);
+        system.cartridge.write_romctrl(BLOCK_START | block_size << 24, 0xffffffff);
+
+        while system.cartridge.read_romctrl() & BLOCK_START != 0 {
+            let target = system.scheduler.get_current_time() + 100;
+            system.scheduler.run_until(target);
+            system.cartridge.read_data();
+        }
+        system
     }
-}
\ No newline at end of file
+
+    /// Reads a 0x200 byte block with a read data command from `addr` of a rom where every word holds its address.
+    /// The KEY2 seeds are the bios ones for a header seed byte of 0
+    fn read_data_block(addr: u32, romctrl: u32, cartridge_key2: bool) -> Vec<u32> {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.cartridge.reset();
+        system.cartridge.file = (0..0x10000u32).step_by(4).flat_map(u32::to_le_bytes).collect();
+        system.cartridge.cartridge_inserted = true;
+        system.cartridge.cartridge_key2 = cartridge_key2;
+        system.cartridge.write_auxspicnt(SLOT_ENABLE, 0xffff);
+        system.cartridge.write_seed0(0x58c56de0e8, u64::MAX);
+        system.cartridge.write_seed1(0x5c879b9b05, u64::MAX);
+
+        let command = 0xb7 << 56 | (addr as u64) << 24;
+        system.cartridge.write_command_buffer(command.swap_bytes(), u64::MAX);
+        system.cartridge.write_romctrl(BLOCK_START | 1 << 24 | romctrl, 0xffffffff);
+
+        let mut words = vec![];
+        while system.cartridge.read_romctrl() & BLOCK_START != 0 {
+            let target = system.scheduler.get_current_time() + 100;
+            system.scheduler.run_until(target);
+            words.push(system.cartridge.read_data());
+        }
+        words
+    }
+
+    fn irq_raised(system: &mut System) -> (bool, bool) {
+        let mask = 1 << IrqSource::CartridgeTransfer as u32;
+        (system.arm7.get_irq().read_irf() & mask != 0, system.arm9.get_irq().read_irf() & mask != 0)
+    }
+
+    #[test]
+    fn transfer_irq_goes_to_the_cpu_with_slot_access() {
+        // block size 0 ends right away, 7 is a single word
+        for block_size in [0, 7] {
+            let mut system = finished_transfer(0, block_size);
+            assert_eq!(irq_raised(&mut system), (false, true), "block size {block_size}");
+
+            let mut system = finished_transfer(ARM7_SLOT_ACCESS, block_size);
+            assert_eq!(irq_raised(&mut system), (true, false), "block size {block_size}");
+        }
+    }
+
+    #[test]
+    fn transfer_irq_needs_to_be_enabled() {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.cartridge.reset();
+        system.cartridge.write_auxspicnt(SLOT_ENABLE, 0xffff);
+        system.cartridge.write_romctrl(BLOCK_START, 0xffffffff);
+        assert_eq!(irq_raised(&mut system), (false, false));
+    }
+
+    #[test]
+    fn plain_reads_carry_into_the_next_4k_block() {
+        let words = read_data_block(0x8f00, 0, false);
+        assert_eq!(words.len(), 0x80);
+        assert_eq!(words[0x3f], 0x8ffc);
+        assert_eq!(words[0x40], 0x9000);
+        assert_eq!(words[0x7f], 0x90fc);
+    }
+
+    #[test]
+    fn encrypted_reads_wrap_within_their_4k_block() {
+        let words = read_data_block(0x8f00, KEY2_ENCRYPT_DATA | KEY2_ENCRYPT_COMMAND | KEY2_APPLY_SEED, true);
+        assert_eq!(words[0x3f], 0x8ffc);
+        assert_eq!(words[0x40], 0x8000);
+        assert_eq!(words[0x7f], 0x80fc);
+    }
+
+    #[test]
+    fn reads_below_0x8000_are_redirected() {
+        // the secure area can't be read with the read data command, it comes from 0x8000 plus the offset in the block
+        let words = read_data_block(0x1000, 0, false);
+        assert_eq!(words[0], 0x8000);
+        assert_eq!(words[0x7f], 0x81fc);
+    }
+
+    #[test]
+    fn key2_data_arrives_plain_when_both_ends_encrypt() {
+        let words = read_data_block(0x8000, KEY2_ENCRYPT_DATA | KEY2_ENCRYPT_COMMAND | KEY2_APPLY_SEED, true);
+        assert_eq!(words[0], 0x8000);
+        assert_eq!(words[0x7f], 0x81fc);
+    }
+
+    #[test]
+    fn key2_data_arrives_encrypted_without_console_decryption() {
+        // the 8 command bytes take the first 8 bytes of the stream, f0 61 ed db are the next ones
+        let words = read_data_block(0x8000, KEY2_ENCRYPT_COMMAND | KEY2_APPLY_SEED, true);
+        assert_eq!(words[0], 0x8000 ^ 0xdbed61f0);
+    }
+
+    #[test]
+    fn key2_decryption_garbles_plain_data() {
+        let words = read_data_block(0x8000, KEY2_ENCRYPT_DATA | KEY2_APPLY_SEED, false);
+        assert_eq!(words[0], 0x8000 ^ 0x81_3a_c5_46);
+    }
+
+    #[test]
+    fn states_with_an_invalid_command_type_fail_to_load() {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.arm7.cpu.reset();
+        system.arm9.cpu.reset();
+        let mut state = system.save_state();
+        assert_eq!(system.load_state(&state), Ok(()));
+
+        let layout = system.state_layout();
+        let section = layout.sections.iter().find(|section| &section.tag == b"CART").unwrap();
+        let field = section.fields.iter().find(|field| field.name == "command_type").unwrap();
+        let offset = state_sections(&state).unwrap().iter().find(|section| &section.tag == b"CART").unwrap().offset;
+        state[offset + field.offset] = 8;
+        assert_eq!(system.load_state(&state), Err("invalid cartridge command type 8".to_string()));
+    }
+}
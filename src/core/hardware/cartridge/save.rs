@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, error};
+
+use crate::core::hardware::cartridge::RomId;
+use crate::util::paths;
+
+const DSV_COOKIE: &[u8] = b"|-DESMUME SAVE-|";
+const DSV_FOOTER_TEXT: &[u8] = b"|<--Snip above here to create a raw sav by excluding this DeSmuME savedata footer:";
+const DSV_FOOTER_SIZE: usize = 6 * 4 + DSV_COOKIE.len();
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SaveFormat {
+    /// Plain backup memory contents, as used by most emulators and flashcarts
+    Raw,
+    /// DeSmuME save, raw contents followed by a text marker and a metadata footer
+    Dsv,
+}
+
+impl SaveFormat {
+    pub const ALL: [SaveFormat; 2] = [SaveFormat::Raw, SaveFormat::Dsv];
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            SaveFormat::Raw => "sav",
+            SaveFormat::Dsv => "dsv",
+        }
+    }
+}
+
+/// Returns the save in the saves directory with the given format, e.g. `saves/ADME-1a2b3c4d.sav`.
+/// Naming it after the rom id keeps the save when the rom file is renamed
+pub fn save_path(id: RomId, format: SaveFormat) -> PathBuf {
+    paths::saves().join(format!("{id}.{}", format.extension()))
+}
+
+/// Saves used to be named after the rom file, first in the saves directory and before that next to the rom.
+/// They're still loaded from there until the first export
+fn legacy_save_paths(game_path: &str, format: SaveFormat) -> [PathBuf; 2] {
+    let path = Path::new(game_path).with_extension(format.extension());
+    [paths::saves().join(path.file_name().unwrap_or_default()), path]
+}
+
+/// Loads the first save found, preferring raw saves
+pub fn load(game_path: &str, id: RomId) -> Option<Vec<u8>> {
+    let candidates = SaveFormat::ALL.map(|format| save_path(id, format));
+    let legacy = SaveFormat::ALL.map(|format| legacy_save_paths(game_path, format));
+
+    for path in candidates.into_iter().chain(legacy.into_iter().flatten()) {
+        if let Ok(file) = std::fs::read(&path) {
+            let (data, detected) = import(&file);
+            debug!("Save: loaded {} ({detected:?}, {} bytes)", path.display(), data.len());
+            return Some(data);
+        }
+    }
+
+    None
+}
+
+/// Writes the save to the saves directory in the requested format
+pub fn store(id: RomId, data: &[u8], format: SaveFormat) {
+    let path = save_path(id, format);
+    match std::fs::write(&path, export(data, format)) {
+        Ok(_) => debug!("Save: exported {}", path.display()),
+        Err(e) => error!("Save: failed to export {}: {e}", path.display()),
+    }
+}
+
+/// Detects the format from the file contents and strips any footer
+pub fn import(file: &[u8]) -> (Vec<u8>, SaveFormat) {
+    if file.len() < DSV_FOOTER_SIZE || !file.ends_with(DSV_COOKIE) {
+        return (file.to_vec(), SaveFormat::Raw);
+    }
+
+    let footer = &file[file.len() - DSV_FOOTER_SIZE..];
+    let word = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap()) as usize;
+    let padded_size = word(1);
+    let memory_size = word(4);
+
+    // older versions don't fill in the memory size, fall back to the padded size
+    let size = if memory_size != 0 { memory_size } else { padded_size };
+    let data_end = file.len() - DSV_FOOTER_SIZE;
+    let data_end = match find(&file[..data_end], DSV_FOOTER_TEXT) {
+        Some(text) => text,
+        None => data_end,
+    };
+
+    (file[..size.min(data_end)].to_vec(), SaveFormat::Dsv)
+}
+
+pub fn export(data: &[u8], format: SaveFormat) -> Vec<u8> {
+    let mut file = data.to_vec();
+    if format == SaveFormat::Raw {
+        return file;
+    }
+
+    let address_size: u32 = match data.len() {
+        0..=0x200 => 1,
+        0x201..=0x10000 => 2,
+        _ => 3,
+    };
+
+    file.extend_from_slice(DSV_FOOTER_TEXT);
+    for word in [data.len() as u32, data.len() as u32, 0, address_size, data.len() as u32, 0] {
+        file.extend_from_slice(&word.to_le_bytes());
+    }
+    file.extend_from_slice(DSV_COOKIE);
+    file
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|window| window == needle)
+}
@@ -1,15 +1,17 @@
-use std::mem::transmute;
-use std::ops::Shr;
-use std::rc::Rc;
-
 use crate::arm::cpu::Arch;
 use crate::bitfield;
-use crate::core::scheduler::EventInfo;
+use crate::core::hardware::irq::IrqSource;
+use crate::core::scheduler::EventId;
 use crate::core::System;
-use crate::util::{set, Shared};
+use crate::util::{set, Shared, StateReader, StateWriter};
 
 const ADJUST_LUT: [[i32; 4]; 2] = [[2, -2, 0, 2], [4, -4, 0, 4]];
 
+/// Words a gxfifo dma moves each time the geometry fifo drops below half full
+const GXFIFO_BLOCK_WORDS: u32 = 112;
+
+/// Start timings, numbered like the arm9's 3 bit field. The arm7 only has 2 bits with an encoding
+/// of its own, `Dma::timing` translates it
 #[derive(Copy, Clone, PartialEq)]
 pub enum DmaTiming {
     Immediate = 0,
@@ -20,16 +22,8 @@ pub enum DmaTiming {
     Slot1 = 5,
     Slot2 = 6,
     GXFIFO = 7,
-}
-
-impl Shr<usize> for DmaTiming {
-    type Output = DmaTiming;
-
-    fn shr(self, rhs: usize) -> Self::Output {
-        unsafe {
-            transmute(self as u8 >> rhs)
-        }
-    }
+    /// arm7 channels 1 and 3 only
+    Wifi = 8,
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -68,7 +62,7 @@ struct Channel {
 pub struct Dma {
     channels: [Channel; 4],
     dmafill: [u32; 4],
-    transfer_events: [Rc<EventInfo>; 4],
+    transfer_events: [EventId; 4],
     system: Shared<System>,
     arch: Arch,
 }
@@ -105,26 +99,86 @@ impl Dma {
         }
     }
 
+    /// The arm7 uses bits 12-13, 0 is immediate, 1 vblank, 2 slot1 and 3 is slot2 on channels 0 and 2
+    /// and wifi on channels 1 and 3
+    fn timing(&self, id: usize) -> DmaTiming {
+        let control = self.channels[id].control;
+        match self.arch {
+            Arch::ARMv4 => match (control.0 >> 12) & 0x3 {
+                0 => DmaTiming::Immediate,
+                1 => DmaTiming::VBlank,
+                2 => DmaTiming::Slot1,
+                _ if id & 0x1 == 0 => DmaTiming::Slot2,
+                _ => DmaTiming::Wifi,
+            },
+            Arch::ARMv5 => control.timing(),
+        }
+    }
+
     pub fn trigger(&mut self, timing: DmaTiming) {
-        for (i, channel) in self.channels.iter_mut().enumerate() {
-            let channel_timing = match self.arch {
-                Arch::ARMv4 => channel.control.timing() >> 1,
-                Arch::ARMv5 => channel.control.timing(),
-            };
-
-            if channel.control.enable() && channel_timing == timing {
-                self.system.scheduler.add_event(1, &self.transfer_events[i]);
+        for i in 0..4 {
+            // the gxfifo triggers whenever a command runs, a block that's already on its way is enough
+            let pending = self.system.scheduler.event_time(self.transfer_events[i]).is_some();
+            if self.channels[i].control.enable() && self.timing(i) == timing && !pending {
+                self.system.scheduler.add_event(1, self.transfer_events[i]);
+            }
+        }
+    }
+
+    /// Disables the channels waiting for `timing`, a transfer that is already scheduled is dropped
+    pub fn stop(&mut self, timing: DmaTiming) {
+        for i in 0..4 {
+            if self.timing(i) == timing {
+                self.channels[i].control.set_enable(false);
             }
         }
     }
 
     pub fn transfer(&mut self, id: usize) {
+        let timing = self.timing(id);
         let channel = &mut self.channels[id];
+        if !channel.control.enable() {
+            return;
+        }
+
+        // gxfifo dmas move the data in blocks, writing to the fifo may trigger the next block while this one is running
+        let gxfifo = timing == DmaTiming::GXFIFO;
+        let count = if gxfifo { channel.internal_length.min(GXFIFO_BLOCK_WORDS) } else { channel.internal_length };
         let source_adjust = ADJUST_LUT[channel.control.transfer_words() as usize][channel.control.source_control() as usize];
         let dest_adjust = ADJUST_LUT[channel.control.transfer_words() as usize][channel.control.destination_control() as usize];
 
-        if channel.control.transfer_words() {
-            for _ in 0..channel.internal_length {
+        let dmafill_source = self.arch == Arch::ARMv5
+            && channel.control.source_control() == AddressMode::Fixed
+            && (0x040000e0..0x040000f0).contains(&channel.internal_source);
+
+        if dmafill_source && channel.control.transfer_words() && dest_adjust == 4 && channel.internal_destination >> 24 == 0x02 {
+            // memset idiom, fill main memory directly
+            let val = self.dmafill[((channel.internal_source - 0x040000e0) / 4) as usize].to_le_bytes();
+            let mut remaining = count as usize;
+            while remaining != 0 {
+                let offset = (channel.internal_destination & 0x3ffffc) as usize;
+                let count = remaining.min((0x400000 - offset) / 4);
+                for word in self.system.main_memory[offset..offset + count * 4].chunks_exact_mut(4) {
+                    word.copy_from_slice(&val);
+                }
+
+                channel.internal_destination += count as u32 * 4;
+                remaining -= count;
+            }
+        } else if dmafill_source {
+            let val = self.dmafill[((channel.internal_source - 0x040000e0) / 4) as usize];
+            for _ in 0..count {
+                let mem = self.system.get_memory(self.arch);
+                if channel.control.transfer_words() {
+                    mem.write_word(channel.internal_destination, val);
+                } else {
+                    mem.write_half(channel.internal_destination, (val >> ((channel.internal_source & 0x2) * 8)) as u16);
+                }
+
+                channel.internal_destination += dest_adjust as u32;
+            }
+        } else if channel.control.transfer_words() {
+            for _ in 0..count {
                 let mem = self.system.get_memory(self.arch);
                 let val = mem.read_word(channel.internal_source);
                 mem.write_word(channel.internal_destination, val);
@@ -133,7 +187,7 @@ impl Dma {
                 channel.internal_destination += dest_adjust as u32;
             }
         } else {
-            for _ in 0..channel.internal_length {
+            for _ in 0..count {
                 let mem = self.system.get_memory(self.arch);
                 let val = mem.read_half(channel.internal_source);
                 mem.write_half(channel.internal_destination, val);
@@ -143,19 +197,28 @@ impl Dma {
             }
         }
 
+        channel.internal_length -= count;
+        if channel.internal_length != 0 {
+            return;
+        }
+
         if channel.control.irq() {
-            todo!()
+            match self.arch {
+                Arch::ARMv4 => self.system.arm7.irq.raise(IrqSource::dma(id)),
+                Arch::ARMv5 => self.system.arm9.irq.raise(IrqSource::dma(id)),
+            }
         }
 
-        if channel.control.repeat() && channel.control.timing() != DmaTiming::Immediate {
+        if channel.control.repeat() && timing != DmaTiming::Immediate {
             channel.internal_length = channel.length;
 
             if channel.control.destination_control() == AddressMode::Reload {
                 channel.internal_destination = channel.destination
             }
 
-            if channel.control.timing() == DmaTiming::GXFIFO {
-                todo!("DMA: handle gxfifo transfers")
+            // a repeating gxfifo dma starts over right away if the fifo still has room
+            if gxfifo && self.system.video_unit.gpu.fifo_half_empty() {
+                self.trigger(DmaTiming::GXFIFO);
             }
         } else {
             channel.control.set_enable(false);
@@ -182,10 +245,6 @@ impl Dma {
         channel.length |= (val & 0x1f & mask) << 16;
         set(&mut channel.control.0, val as u16, mask as u16);
 
-        if channel.control.enable() && channel.control.timing() == DmaTiming::GXFIFO {
-            todo!()
-        }
-
         if old.enable() || !channel.control.enable() {
             return;
         }
@@ -203,9 +262,41 @@ impl Dma {
             channel.internal_length = channel.length
         }
 
-        if channel.control.timing() == DmaTiming::Immediate {
-            self.system.scheduler.add_event(1, &self.transfer_events[id])
+        let timing = self.timing(id);
+        if timing == DmaTiming::Immediate {
+            self.system.scheduler.add_event(1, self.transfer_events[id])
+        } else if timing == DmaTiming::GXFIFO && self.system.video_unit.gpu.fifo_half_empty() {
+            self.system.scheduler.add_event(1, self.transfer_events[id])
+        }
+    }
+
+    /// Transfers in flight are scheduler events, which the scheduler saves
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"DMA ");
+        for channel in &self.channels {
+            state.write("channel.length", channel.length);
+            state.write("channel.source", channel.source);
+            state.write("channel.internal_source", channel.internal_source);
+            state.write("channel.destination", channel.destination);
+            state.write("channel.internal_destination", channel.internal_destination);
+            state.write("channel.internal_length", channel.internal_length);
+            state.write("channel.control", channel.control.0);
+        }
+        state.write_slice("dmafill", &self.dmafill);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"DMA ");
+        for channel in &mut self.channels {
+            channel.length = state.read();
+            channel.source = state.read();
+            channel.internal_source = state.read();
+            channel.destination = state.read();
+            channel.internal_destination = state.read();
+            channel.internal_length = state.read();
+            channel.control.0 = state.read();
         }
+        state.read_slice(&mut self.dmafill);
     }
 
     pub fn write_dmafill(&mut self, addr: u32, val: u32) {
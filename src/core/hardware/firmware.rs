@@ -0,0 +1,338 @@
+use std::ops::{Deref, DerefMut};
+
+use log::{debug, error, warn};
+
+use crate::util::paths;
+
+const FIRMWARE_SIZE: usize = 0x40000;
+const USER_SETTINGS_SIZE: usize = 0x70;
+const OVERLAY_NAME: &str = "user_settings.bin";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Language {
+    Japanese = 0,
+    English = 1,
+    French = 2,
+    German = 3,
+    Italian = 4,
+    Spanish = 5,
+}
+
+impl Language {
+    pub const ALL: [Language; 6] = [
+        Language::Japanese,
+        Language::English,
+        Language::French,
+        Language::German,
+        Language::Italian,
+        Language::Spanish,
+    ];
+
+    fn from_bits(val: u16) -> Self {
+        match val & 0x7 {
+            0 => Language::Japanese,
+            2 => Language::French,
+            3 => Language::German,
+            4 => Language::Italian,
+            5 => Language::Spanish,
+            _ => Language::English,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UserSettings {
+    pub nickname: String,
+    pub favorite_color: u8,
+    pub birthday_month: u8,
+    pub birthday_day: u8,
+    pub language: Language,
+}
+
+pub struct Firmware {
+    data: Box<[u8]>,
+    /// User settings were changed since the overlay was last written
+    settings_changed: bool,
+}
+
+impl Firmware {
+    pub fn load(path: Option<&str>) -> Self {
+        let mut firmware = match path.map(std::fs::read) {
+            Some(Ok(data)) if data.len() >= FIRMWARE_SIZE => {
+                let mut firmware = Self {
+                    data: data.into_boxed_slice(),
+                    settings_changed: false,
+                };
+                firmware.repair_wifi_calibration();
+                if firmware.header_user_settings_offset() != firmware.user_settings_offset() {
+                    warn!("Firmware: user settings offset in the header is out of range, using the end of the image");
+                }
+                firmware
+            }
+            Some(_) => {
+                error!("Firmware: failed to load {}, falling back to synthesized firmware", path.unwrap());
+                Self::synthesize()
+            }
+            None => Self::synthesize(),
+        };
+        firmware.load_overlay();
+        firmware
+    }
+
+    /// Builds a minimal 256KB firmware image with a header, wifi calibration and user settings,
+    /// which is enough to direct boot games without a firmware dump
+    pub fn synthesize() -> Self {
+        let mut data = vec![0xff; FIRMWARE_SIZE].into_boxed_slice();
+
+        // header
+        data[0x00..0x1d].fill(0);
+        data[0x08..0x0c].copy_from_slice(b"MACP");
+        data[0x18..0x1d].copy_from_slice(&[0x00, 0x00, 0x01, 0x01, 0x06]);
+        data[0x1d] = 0xff;
+        data[0x1e..0x20].copy_from_slice(&0xffffu16.to_le_bytes());
+        data[0x20..0x22].copy_from_slice(&(((FIRMWARE_SIZE - 0x200) / 8) as u16).to_le_bytes());
+        data[0x22..0x2a].fill(0);
+
+        // wifi calibration
+        let wifi = &mut data[0x2a..0x200];
+        wifi.fill(0);
+        wifi[0x02..0x04].copy_from_slice(&0x138u16.to_le_bytes());
+        wifi[0x05] = 0x03;
+        wifi[0x0c..0x12].copy_from_slice(&[0x00, 0x09, 0xbf, 0x12, 0x34, 0x56]);
+        wifi[0x12..0x14].copy_from_slice(&0x3ffeu16.to_le_bytes());
+        let crc = crc16(0x0000, &data[0x2c..0x2c + 0x138]);
+        data[0x2a..0x2c].copy_from_slice(&crc.to_le_bytes());
+
+        // user settings
+        let offset = FIRMWARE_SIZE - 0x200;
+        let block = &mut data[offset..offset + 0x100];
+        block.fill(0);
+        block[0x00] = 0x05;
+        block[0x03] = 1;
+        block[0x04] = 1;
+        for (i, c) in "DS".encode_utf16().enumerate() {
+            block[0x06 + i * 2..0x08 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        block[0x1a..0x1c].copy_from_slice(&2u16.to_le_bytes());
+
+        // touchscreen calibration points
+        block[0x58..0x5a].copy_from_slice(&0x02dfu16.to_le_bytes());
+        block[0x5a..0x5c].copy_from_slice(&0x032cu16.to_le_bytes());
+        block[0x5c] = 0x20;
+        block[0x5d] = 0x20;
+        block[0x5e..0x60].copy_from_slice(&0x0d3bu16.to_le_bytes());
+        block[0x60..0x62].copy_from_slice(&0x0ce7u16.to_le_bytes());
+        block[0x62] = 0xe0;
+        block[0x63] = 0xa0;
+
+        block[0x64..0x66].copy_from_slice(&(0xfc00 | Language::English as u16).to_le_bytes());
+        let crc = crc16(0xffff, &block[..USER_SETTINGS_SIZE]);
+        block[0x72..0x74].copy_from_slice(&crc.to_le_bytes());
+        data.copy_within(offset..offset + 0x100, offset + 0x100);
+
+        debug!("Firmware: synthesized firmware image");
+        Self {
+            data,
+            settings_changed: false,
+        }
+    }
+
+    /// Games check the crc of the wifi calibration block before initializing wifi,
+    /// so replace it with the synthesized defaults if a dump has a broken one
+    fn repair_wifi_calibration(&mut self) {
+        let len = (self.read_u16(0x2c) as usize).min(0x200 - 0x2c);
+        if len != 0 && crc16(0x0000, &self.data[0x2c..0x2c + len]) == self.read_u16(0x2a) {
+            return;
+        }
+
+        warn!("Firmware: invalid wifi calibration data, using defaults");
+        let defaults = Self::synthesize();
+        self.data[0x2a..0x200].copy_from_slice(&defaults.data[0x2a..0x200]);
+    }
+
+    /// Offset of the two user settings copies. A header that points into itself or past the end of the
+    /// image falls back to the last 512 bytes, where every retail firmware keeps them
+    pub fn user_settings_offset(&self) -> usize {
+        let offset = self.header_user_settings_offset();
+        if offset >= 0x200 && offset + 0x200 <= self.data.len() {
+            offset
+        } else {
+            self.data.len() - 0x200
+        }
+    }
+
+    fn header_user_settings_offset(&self) -> usize {
+        self.read_u16(0x20) as usize * 8
+    }
+
+    /// Returns the offset of the most recently written user settings copy with a valid crc
+    pub fn active_user_settings(&self) -> usize {
+        let first = self.user_settings_offset();
+        let second = first + 0x100;
+
+        match (self.user_settings_valid(first), self.user_settings_valid(second)) {
+            (true, true) => {
+                let count1 = self.read_u16(first + 0x70) & 0x7f;
+                let count2 = self.read_u16(second + 0x70) & 0x7f;
+                if (count1 + 1) & 0x7f == count2 {
+                    second
+                } else {
+                    first
+                }
+            }
+            (false, true) => second,
+            _ => first,
+        }
+    }
+
+    pub fn user_settings(&self) -> UserSettings {
+        let offset = self.active_user_settings();
+        let len = (self.read_u16(offset + 0x1a) as usize).min(10);
+        let nickname = (0..len).map(|i| self.read_u16(offset + 0x06 + i * 2)).collect::<Vec<_>>();
+
+        UserSettings {
+            nickname: String::from_utf16_lossy(&nickname),
+            favorite_color: self.data[offset + 0x02] & 0xf,
+            birthday_month: self.data[offset + 0x03],
+            birthday_day: self.data[offset + 0x04],
+            language: Language::from_bits(self.read_u16(offset + 0x64)),
+        }
+    }
+
+    /// Writes the settings into both user settings copies, `save_user_settings` persists them
+    pub fn set_user_settings(&mut self, settings: &UserSettings) {
+        self.write_user_settings(settings);
+        self.settings_changed = true;
+    }
+
+    /// Writes the user settings to the overlay file if they were changed since the last save
+    pub fn save_user_settings(&mut self) {
+        if self.settings_changed {
+            self.save_overlay();
+            self.settings_changed = false;
+        }
+    }
+
+    /// Overrides the language for this session only, without touching the overlay file
+    pub fn override_language(&mut self, language: Language) {
+        let mut settings = self.user_settings();
+        if settings.language != language {
+            settings.language = language;
+            self.write_user_settings(&settings);
+        }
+    }
+
+    fn write_user_settings(&mut self, settings: &UserSettings) {
+        let active = self.active_user_settings();
+        let mut block = self.data[active..active + 0x100].to_vec();
+
+        let nickname = settings.nickname.encode_utf16().take(10).collect::<Vec<_>>();
+        for i in 0..10 {
+            let c = nickname.get(i).copied().unwrap_or(0);
+            block[0x06 + i * 2..0x08 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        block[0x1a..0x1c].copy_from_slice(&(nickname.len() as u16).to_le_bytes());
+
+        block[0x02] = settings.favorite_color & 0xf;
+        block[0x03] = settings.birthday_month;
+        block[0x04] = settings.birthday_day;
+
+        let language = (u16::from_le_bytes([block[0x64], block[0x65]]) & !0x7) | settings.language as u16;
+        block[0x64..0x66].copy_from_slice(&language.to_le_bytes());
+
+        let count = (u16::from_le_bytes([block[0x70], block[0x71]]) + 1) & 0x7f;
+        block[0x70..0x72].copy_from_slice(&count.to_le_bytes());
+
+        let crc = crc16(0xffff, &block[..USER_SETTINGS_SIZE]);
+        block[0x72..0x74].copy_from_slice(&crc.to_le_bytes());
+
+        let offset = self.user_settings_offset();
+        self.data[offset..offset + 0x100].copy_from_slice(&block);
+        self.data[offset + 0x100..offset + 0x200].copy_from_slice(&block);
+    }
+
+    fn user_settings_valid(&self, offset: usize) -> bool {
+        crc16(0xffff, &self.data[offset..offset + USER_SETTINGS_SIZE]) == self.read_u16(offset + 0x72)
+    }
+
+    fn load_overlay(&mut self) {
+        let Ok(overlay) = std::fs::read(paths::config().join(OVERLAY_NAME)) else {
+            return;
+        };
+
+        let offset = self.user_settings_offset();
+        if overlay.len() != 0x200 {
+            return error!("Firmware: ignoring malformed user settings overlay");
+        }
+
+        self.data[offset..offset + 0x200].copy_from_slice(&overlay);
+        debug!("Firmware: loaded user settings overlay");
+    }
+
+    fn save_overlay(&self) {
+        let offset = self.user_settings_offset();
+        if let Err(e) = std::fs::write(paths::config().join(OVERLAY_NAME), &self.data[offset..offset + 0x200]) {
+            error!("Firmware: failed to save user settings overlay: {e}");
+        }
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+}
+
+impl Deref for Firmware {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for Firmware {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    const TABLE: [u16; 8] = [0xc0c1, 0xc181, 0xc301, 0xc601, 0xcc01, 0xd801, 0xf001, 0xa001];
+
+    for &byte in data {
+        crc ^= byte as u16;
+        for (i, val) in TABLE.iter().enumerate() {
+            let carry = crc & 1 != 0;
+            crc >>= 1;
+            if carry {
+                crc ^= val << (7 - i);
+            }
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_user_settings_offset_falls_back_to_the_end() {
+        let mut firmware = Firmware::synthesize();
+        firmware.data[0x20..0x22].copy_from_slice(&0xffffu16.to_le_bytes());
+
+        assert_eq!(firmware.user_settings_offset(), FIRMWARE_SIZE - 0x200);
+        assert_eq!(firmware.user_settings().nickname, "DS");
+    }
+
+    #[test]
+    fn changed_settings_are_kept_until_saved() {
+        let mut firmware = Firmware::synthesize();
+        let mut settings = firmware.user_settings();
+        settings.nickname = "Nitro".to_string();
+        firmware.set_user_settings(&settings);
+
+        assert!(firmware.settings_changed);
+        assert_eq!(firmware.user_settings().nickname, "Nitro");
+    }
+}
@@ -1,5 +1,6 @@
 use crate::bitfield;
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum InputEvent {
     A,
     B,
@@ -11,15 +12,35 @@ pub enum InputEvent {
     Down,
     L,
     R,
+    X,
+    Y,
 }
 
-#[derive(Copy, Clone)]
+impl InputEvent {
+    pub const ALL: [InputEvent; 12] = [
+        InputEvent::A,
+        InputEvent::B,
+        InputEvent::X,
+        InputEvent::Y,
+        InputEvent::L,
+        InputEvent::R,
+        InputEvent::Start,
+        InputEvent::Select,
+        InputEvent::Up,
+        InputEvent::Down,
+        InputEvent::Left,
+        InputEvent::Right,
+    ];
+}
+
+#[derive(Copy, Clone, PartialEq)]
 pub struct Point {
     pub x: u32,
     pub y: u32,
 }
 
 bitfield! {
+    #[derive(Clone, Copy, PartialEq)]
     struct KeyInput(u16) {
         a: bool => 0,
         b: bool => 1,
@@ -34,18 +55,52 @@ bitfield! {
     }
 }
 
-pub struct Input {
-    pub point: Point,
+/// Everything the emulated hardware can observe about the buttons and touchscreen
+#[derive(Copy, Clone, PartialEq)]
+pub struct InputState {
     keyinput: KeyInput,
     extkeyin: u16,
+    point: Point,
 }
 
-impl Input {
-    pub fn new() -> Self {
+impl InputState {
+    const fn new() -> Self {
         Self {
-            point: Point { x: 0, y: 0 },
             keyinput: KeyInput(0x3ff),
             extkeyin: 0x7f,
+            point: Point { x: 0, y: 0 },
+        }
+    }
+
+    pub const fn keyinput(&self) -> u16 {
+        self.keyinput.0
+    }
+
+    pub const fn extkeyin(&self) -> u16 {
+        self.extkeyin
+    }
+
+    pub const fn touch_down(&self) -> bool {
+        self.extkeyin & (1 << 6) == 0
+    }
+
+    pub const fn point(&self) -> Point {
+        self.point
+    }
+}
+
+/// Host input is collected into `pending` as it arrives and only becomes visible to the
+/// emulated hardware when it is latched at the start of a frame, so a frame always sees one input state
+pub struct Input {
+    pending: InputState,
+    latched: InputState,
+}
+
+impl Input {
+    pub fn new() -> Self {
+        Self {
+            pending: InputState::new(),
+            latched: InputState::new(),
         }
     }
 
@@ -54,46 +109,70 @@ impl Input {
     }
 
     pub fn handle_input(&mut self, event: InputEvent, pressed: bool) {
+        let keyinput = &mut self.pending.keyinput;
         match event {
-            InputEvent::A => self.keyinput.set_a(!pressed),
-            InputEvent::B => self.keyinput.set_b(!pressed),
-            InputEvent::Start => self.keyinput.set_start(!pressed),
-            InputEvent::Select => self.keyinput.set_select(!pressed),
-            InputEvent::Left => self.keyinput.set_left(!pressed),
-            InputEvent::Right => self.keyinput.set_right(!pressed),
-            InputEvent::Up => self.keyinput.set_up(!pressed),
-            InputEvent::Down => self.keyinput.set_down(!pressed),
-            InputEvent::L => self.keyinput.set_l(!pressed),
-            InputEvent::R => self.keyinput.set_r(!pressed),
+            InputEvent::A => keyinput.set_a(!pressed),
+            InputEvent::B => keyinput.set_b(!pressed),
+            InputEvent::Start => keyinput.set_start(!pressed),
+            InputEvent::Select => keyinput.set_select(!pressed),
+            InputEvent::Left => keyinput.set_left(!pressed),
+            InputEvent::Right => keyinput.set_right(!pressed),
+            InputEvent::Up => keyinput.set_up(!pressed),
+            InputEvent::Down => keyinput.set_down(!pressed),
+            InputEvent::L => keyinput.set_l(!pressed),
+            InputEvent::R => keyinput.set_r(!pressed),
+            // x and y are only wired to the arm7
+            InputEvent::X => self.pending.extkeyin = (self.pending.extkeyin & !0x1) | !pressed as u16,
+            InputEvent::Y => self.pending.extkeyin = (self.pending.extkeyin & !0x2) | (!pressed as u16) << 1,
         }
     }
 
     pub fn set_touch(&mut self, pressed: bool) {
         if pressed {
-            self.extkeyin &= !(1 << 6)
+            self.pending.extkeyin &= !(1 << 6)
         } else {
-            self.extkeyin |= 1 << 6
+            self.pending.extkeyin |= 1 << 6
         }
     }
 
     pub fn set_point(&mut self, x: u32, y: u32) {
-        self.point.x = x;
-        self.point.y = y;
+        self.pending.point = Point { x, y };
+    }
+
+    /// Makes the pending host input visible to the hardware, returning the state the frame will see
+    pub fn latch(&mut self) -> InputState {
+        self.latched = self.pending;
+        self.latched
+    }
+
+    /// Host input that will be latched next, what the frontend sees
+    pub const fn pending(&self) -> InputState {
+        self.pending
+    }
+
+    /// Replaces the pending host input, for movie playback and netplay
+    pub fn set_pending(&mut self, state: InputState) {
+        self.pending = state;
+    }
+
+    /// The input the current frame runs with, for movie recording and netplay
+    pub const fn latched(&self) -> InputState {
+        self.latched
     }
 
     pub fn touch_down(&self) -> bool {
-        self.extkeyin & (1 << 6) == 0
+        self.latched.touch_down()
     }
 
     pub fn get_point(&self) -> Point {
-        self.point
+        self.latched.point
     }
 
     pub fn read_keyinput(&self) -> u16 {
-        self.keyinput.0
+        self.latched.keyinput()
     }
 
     pub fn read_extkeyin(&self) -> u16 {
-        self.extkeyin
+        self.latched.extkeyin
     }
 }
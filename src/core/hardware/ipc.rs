@@ -2,7 +2,7 @@ use crate::arm::cpu::Arch;
 use crate::bitfield;
 use crate::core::hardware::irq::{Irq, IrqSource};
 use crate::util::RingBuffer;
-use crate::util::Shared;
+use crate::util::{Shared, StateReader, StateWriter};
 
 bitfield! {
     #[derive(Clone, Copy, Default)]
@@ -53,7 +53,30 @@ impl Ipc {
     }
 
     pub fn reset(&mut self) {
-        todo!()
+        self.ipcsync = Default::default();
+        self.ipcfifocnt = [IpcFifoCnt(0x101); 2];
+        self.fifo = Default::default();
+        self.ipcfiforecv = Default::default();
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"IPC ");
+        for i in 0..2 {
+            state.write("ipcsync", self.ipcsync[i].0);
+            state.write("ipcfifocnt", self.ipcfifocnt[i].0);
+            self.fifo[i].save_state(state);
+            state.write("ipcfiforecv", self.ipcfiforecv[i]);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"IPC ");
+        for i in 0..2 {
+            self.ipcsync[i].0 = state.read();
+            self.ipcfifocnt[i].0 = state.read();
+            self.fifo[i].load_state(state);
+            self.ipcfiforecv[i] = state.read();
+        }
     }
 
     pub fn read_ipcsync(&mut self, arch: Arch) -> u32 {
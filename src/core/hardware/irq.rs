@@ -1,6 +1,8 @@
 use crate::arm::cpu::{Arch, Cpu};
-use crate::util::Shared;
+use crate::core::System;
+use crate::util::{Shared, StateReader, StateWriter};
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum IrqSource {
     VBlank = 0,
     HBlank = 1,
@@ -15,12 +17,14 @@ pub enum IrqSource {
     DMA2 = 10,
     DMA3 = 11,
     Input = 12,
+    GbaSlot = 13,
     IPCSync = 16,
     IPCSendEmpty = 17,
     IPCReceiveNonEmpty = 18,
     CartridgeTransfer = 19,
     GXFIFO = 21,
     SPI = 23,
+    Wifi = 24,
 }
 
 impl IrqSource {
@@ -33,10 +37,21 @@ impl IrqSource {
             _ => unreachable!()
         }
     }
+
+    pub const fn dma(id: usize) -> Self {
+        match id {
+            0 => Self::DMA0,
+            1 => Self::DMA1,
+            2 => Self::DMA2,
+            3 => Self::DMA3,
+            _ => unreachable!()
+        }
+    }
 }
 
 // todo: replace cpu ref with Rc<Cell<bool>> or something
 pub struct Irq {
+    system: Shared<System>,
     cpu: Shared<Cpu>,
     ime: bool,
     ie: u32,
@@ -44,8 +59,9 @@ pub struct Irq {
 }
 
 impl Irq {
-    pub fn new(cpu: &Shared<Cpu>) -> Self {
+    pub fn new(system: &Shared<System>, cpu: &Shared<Cpu>) -> Self {
         Self {
+            system: system.clone(),
             cpu: cpu.clone(),
             ime: false,
             ie: 0,
@@ -60,17 +76,28 @@ impl Irq {
     }
 
     pub fn raise(&mut self, source: IrqSource) {
+        if self.system.debugger.on_irq(self.cpu.arch, source) {
+            self.cpu.set_watch_hit();
+        }
         let source = source as u32;
 
         self.irf |= 1 << source;
-        if self.ie & (1 << source) != 0 {
-            if self.ime || self.cpu.arch == Arch::ARMv4 {
-                self.cpu.update_halted(false);
-            }
-        }
         self.update();
     }
 
+    /// The cpu's irq line is part of the cpu state, so loading doesn't recompute it
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool("ime", self.ime);
+        state.write("ie", self.ie);
+        state.write("irf", self.irf);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.ime = state.read_bool();
+        self.ie = state.read();
+        self.irf = state.read();
+    }
+
     pub const fn read_ime(&self) -> bool {
         self.ime
     }
@@ -96,7 +123,61 @@ impl Irq {
         self.update()
     }
 
+    /// Recomputes the irq line after any of ime/ie/if changed. Enabling a source in ie that is
+    /// already pending in if interrupts right away, the same as raising it would. The arm7 leaves
+    /// halt on any enabled irq even with ime off, the arm9 only wakes up when ime is set
     fn update(&mut self) {
-        self.cpu.update_irq(self.ime && (self.ie & self.irf != 0))
+        let pending = self.ie & self.irf != 0;
+        if pending && (self.ime || self.cpu.arch == Arch::ARMv4) {
+            self.cpu.update_halted(false);
+        }
+
+        // clearing ime only masks the line, an interrupt that was already taken keeps running
+        self.cpu.update_irq(self.ime && pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMER0: u32 = 1 << 3;
+
+    #[test]
+    fn enabling_a_pending_irq_wakes_the_arm9_once_ime_is_set() {
+        let mut system = System::new();
+        system.arm9.cpu.update_halted(true);
+        system.arm9.get_irq().raise(IrqSource::timer(0));
+        assert!(system.arm9.is_halted());
+
+        system.arm9.get_irq().write_ie(TIMER0, 0xffffffff);
+        assert!(system.arm9.is_halted());
+
+        system.arm9.get_irq().write_ime(1, 0xffffffff);
+        assert!(!system.arm9.is_halted());
+    }
+
+    #[test]
+    fn enabling_a_pending_irq_wakes_the_arm7_without_ime() {
+        let mut system = System::new();
+        system.arm7.cpu.update_halted(true);
+        system.arm7.get_irq().raise(IrqSource::timer(0));
+        assert!(system.arm7.cpu.is_halted());
+
+        system.arm7.get_irq().write_ie(TIMER0, 0xffffffff);
+        assert!(!system.arm7.cpu.is_halted());
+    }
+
+    #[test]
+    fn acknowledged_irqs_dont_wake_the_cpu() {
+        let mut system = System::new();
+        let irq = system.arm7.get_irq();
+        irq.raise(IrqSource::timer(0));
+        irq.write_irf(TIMER0, 0xffffffff);
+        assert_eq!(irq.read_irf(), 0);
+
+        system.arm7.cpu.update_halted(true);
+        system.arm7.get_irq().write_ie(TIMER0, 0xffffffff);
+        assert!(system.arm7.cpu.is_halted());
     }
 }
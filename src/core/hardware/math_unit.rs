@@ -1,18 +1,95 @@
-#[derive(Default)]
+
+use crate::core::scheduler::EventId;
+use crate::core::System;
+use crate::util::{Shared, StateReader, StateWriter};
+
+const DIV_BUSY: u16 = 1 << 15;
+const SQRT_BUSY: u16 = 1 << 15;
+
+/// The results only become visible once the unit finishes, until then the previous results stay readable.
+/// Every parameter write restarts the computation, so the intermediate values of a torn 64 bit write never get published
 pub struct MathUnit {
+    system: Shared<System>,
     divcnt: u16,
     div_numer: u64,
     div_denom: u64,
     divrem_result: u64,
     div_result: u64,
+    pending_div_result: u64,
+    pending_divrem_result: u64,
     sqrtcnt: u16,
     sqrt_param: u64,
     sqrt_result: u32,
+    pending_sqrt_result: u32,
+    division_event: EventId,
+    square_root_event: EventId,
 }
 
 impl MathUnit {
+    pub fn new(system: &Shared<System>) -> Self {
+        Self {
+            system: system.clone(),
+            divcnt: 0,
+            div_numer: 0,
+            div_denom: 0,
+            divrem_result: 0,
+            div_result: 0,
+            pending_div_result: 0,
+            pending_divrem_result: 0,
+            sqrtcnt: 0,
+            sqrt_param: 0,
+            sqrt_result: 0,
+            pending_sqrt_result: 0,
+            division_event: Default::default(),
+            square_root_event: Default::default(),
+        }
+    }
+
     pub fn reset(&mut self) {
-        *self = Self::default();
+        self.divcnt = 0;
+        self.div_numer = 0;
+        self.div_denom = 0;
+        self.divrem_result = 0;
+        self.div_result = 0;
+        self.pending_div_result = 0;
+        self.pending_divrem_result = 0;
+        self.sqrtcnt = 0;
+        self.sqrt_param = 0;
+        self.sqrt_result = 0;
+        self.pending_sqrt_result = 0;
+
+        self.division_event = self.system.scheduler.register_event("Division", |system| system.math_unit.finish_division());
+        self.square_root_event = self.system.scheduler.register_event("Square Root", |system| system.math_unit.finish_square_root());
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"MATH");
+        state.write("divcnt", self.divcnt);
+        state.write("div_numer", self.div_numer);
+        state.write("div_denom", self.div_denom);
+        state.write("divrem_result", self.divrem_result);
+        state.write("div_result", self.div_result);
+        state.write("pending_div_result", self.pending_div_result);
+        state.write("pending_divrem_result", self.pending_divrem_result);
+        state.write("sqrtcnt", self.sqrtcnt);
+        state.write("sqrt_param", self.sqrt_param);
+        state.write("sqrt_result", self.sqrt_result);
+        state.write("pending_sqrt_result", self.pending_sqrt_result);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"MATH");
+        self.divcnt = state.read();
+        self.div_numer = state.read();
+        self.div_denom = state.read();
+        self.divrem_result = state.read();
+        self.div_result = state.read();
+        self.pending_div_result = state.read();
+        self.pending_divrem_result = state.read();
+        self.sqrtcnt = state.read();
+        self.sqrt_param = state.read();
+        self.sqrt_result = state.read();
+        self.pending_sqrt_result = state.read();
     }
 
     pub fn read_divcnt(&self) -> u16 {
@@ -41,6 +118,7 @@ impl MathUnit {
     }
 
     pub fn write_divcnt(&mut self, val: u16, mask: u16) {
+        let mask = mask & 0x3;
         self.divcnt = (self.divcnt & !mask) | (val & mask);
         self.start_division();
     }
@@ -53,6 +131,7 @@ impl MathUnit {
         self.start_division();
     }
     pub fn write_sqrtcnt(&mut self, val: u16, mask: u16) {
+        let mask = mask & 0x1;
         self.sqrtcnt = (self.sqrtcnt & !mask) | (val & mask);
         self.start_square_root();
     }
@@ -62,6 +141,11 @@ impl MathUnit {
     }
 
     fn start_division(&mut self) {
+        // 32 bit division takes 18 cycles, the 64 bit modes take 34
+        let cycles = if self.divcnt & 0x3 == 0 { 18 } else { 34 };
+        self.divcnt |= DIV_BUSY;
+        self.system.scheduler.cancel_event(self.division_event);
+
         // set the division by 0 error bit only if the full 64 bits of div_denom is 0 (even in 32 bit mode)
         if self.div_denom == 0 {
             self.divcnt |= 1 << 14;
@@ -78,28 +162,43 @@ impl MathUnit {
 
         let special_invert = |num: &mut u64| *num ^= 0xFFFF_FFFF_0000_0000;
         if numer == i64::MIN && denom == -1 {
-            self.div_result = numer as u64;
-            self.divrem_result = 0;
+            self.pending_div_result = numer as u64;
+            self.pending_divrem_result = 0;
             if self.divcnt & 0x3 == 0 {
-                special_invert(&mut self.div_result)
+                special_invert(&mut self.pending_div_result)
             }
         } else if denom == 0 {
             if numer == 0 {
-                self.div_result = -1i64 as u64;
+                self.pending_div_result = -1i64 as u64;
             } else {
-                self.div_result = (-numer.signum()) as u64;
+                self.pending_div_result = (-numer.signum()) as u64;
             }
-            self.divrem_result = numer as u64;
+            self.pending_divrem_result = numer as u64;
             if self.divcnt & 0x3 == 0 {
-                special_invert(&mut self.div_result)
+                special_invert(&mut self.pending_div_result)
             }
         } else {
-            self.div_result = (numer / denom) as u64;
-            self.divrem_result = (numer % denom) as u64;
+            self.pending_div_result = (numer / denom) as u64;
+            self.pending_divrem_result = (numer % denom) as u64;
+        }
+
+        if self.system.config.accuracy.math_timing {
+            self.system.scheduler.add_event(cycles, self.division_event);
+        } else {
+            self.finish_division();
         }
     }
 
+    fn finish_division(&mut self) {
+        self.div_result = self.pending_div_result;
+        self.divrem_result = self.pending_divrem_result;
+        self.divcnt &= !DIV_BUSY;
+    }
+
     fn start_square_root(&mut self) {
+        self.sqrtcnt |= SQRT_BUSY;
+        self.system.scheduler.cancel_event(self.square_root_event);
+
         // todo: can this be replaced with i64::sqrt()?
         let mut res: u32 = 0;
         let mut rem: u64 = 0;
@@ -123,6 +222,107 @@ impl MathUnit {
             }
         }
 
-        self.sqrt_result = res;
+        self.pending_sqrt_result = res;
+
+        if self.system.config.accuracy.math_timing {
+            self.system.scheduler.add_event(13, self.square_root_event);
+        } else {
+            self.finish_square_root();
+        }
+    }
+
+    fn finish_square_root(&mut self) {
+        self.sqrt_result = self.pending_sqrt_result;
+        self.sqrtcnt &= !SQRT_BUSY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::OwnedSystem;
+
+    fn math_unit(math_timing: bool) -> OwnedSystem {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.math_unit.reset();
+        system.config.accuracy.math_timing = math_timing;
+        system
+    }
+
+    fn advance(system: &mut System, cycles: u64) {
+        let target = system.scheduler.get_current_time() + cycles;
+        system.scheduler.run_until(target);
+    }
+
+    #[test]
+    fn halfword_writes_only_publish_the_final_division() {
+        let mut system = math_unit(true);
+
+        // 100000 / 7 in 32 bit mode, written a halfword at a time with the halves interleaved
+        system.math_unit.write_div_numer(100000, 0xffff);
+        system.math_unit.write_div_denom(7, 0xffff);
+        advance(&mut system, 10);
+        system.math_unit.write_div_numer(100000, 0xffff_0000);
+        system.math_unit.write_div_denom(0, 0xffff_0000);
+        assert_ne!(system.math_unit.read_divcnt() & DIV_BUSY, 0);
+
+        // the first halves would have finished by now, but every write restarted the division
+        advance(&mut system, 17);
+        assert_ne!(system.math_unit.read_divcnt() & DIV_BUSY, 0);
+        assert_eq!(system.math_unit.read_div_result(), 0);
+
+        advance(&mut system, 1);
+        assert_eq!(system.math_unit.read_divcnt() & DIV_BUSY, 0);
+        assert_eq!(system.math_unit.read_div_result(), 14285);
+        assert_eq!(system.math_unit.read_divrem_result(), 5);
+    }
+
+    #[test]
+    fn previous_results_stay_readable_while_busy() {
+        let mut system = math_unit(true);
+        system.math_unit.write_div_numer(10, u64::MAX);
+        system.math_unit.write_div_denom(3, u64::MAX);
+        advance(&mut system, 18);
+        assert_eq!(system.math_unit.read_div_result(), 3);
+
+        // the 64 bit modes take 34 cycles
+        system.math_unit.write_divcnt(2, 0xffff);
+        system.math_unit.write_div_numer(-9i64 as u64, u64::MAX);
+        advance(&mut system, 33);
+        assert_eq!(system.math_unit.read_div_result(), 3);
+        advance(&mut system, 1);
+        assert_eq!(system.math_unit.read_div_result(), -3i64 as u64);
+        assert_eq!(system.math_unit.read_divrem_result(), 0);
+    }
+
+    #[test]
+    fn division_by_zero_sets_the_error_bit() {
+        let mut system = math_unit(false);
+        system.math_unit.write_div_numer(5, u64::MAX);
+        system.math_unit.write_div_denom(0, u64::MAX);
+        assert_ne!(system.math_unit.read_divcnt() & (1 << 14), 0);
+        // the 32 bit mode flips the upper half of the result
+        assert_eq!(system.math_unit.read_div_result(), 0x0000_0000_ffff_ffff);
+        assert_eq!(system.math_unit.read_divrem_result(), 5);
+
+        // only a denominator that's 0 in all 64 bits counts, even in 32 bit mode
+        system.math_unit.write_div_denom(1 << 32, u64::MAX);
+        assert_eq!(system.math_unit.read_divcnt() & (1 << 14), 0);
+    }
+
+    #[test]
+    fn square_root_finishes_after_13_cycles() {
+        let mut system = math_unit(true);
+        system.math_unit.write_sqrtcnt(1, 0xffff);
+        system.math_unit.write_sqrt_param(0x1_0000_0000, 0xffff_ffff);
+        system.math_unit.write_sqrt_param(0x1_0000_0000, 0xffff_ffff_0000_0000);
+        advance(&mut system, 12);
+        assert_ne!(system.math_unit.read_sqrtcnt() & SQRT_BUSY, 0);
+        assert_eq!(system.math_unit.read_sqrt_result(), 0);
+
+        advance(&mut system, 1);
+        assert_eq!(system.math_unit.read_sqrtcnt() & SQRT_BUSY, 0);
+        assert_eq!(system.math_unit.read_sqrt_result(), 0x10000);
     }
 }
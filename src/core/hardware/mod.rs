@@ -1,5 +1,6 @@
 pub mod cartridge;
 pub mod dma;
+pub mod firmware;
 pub mod input;
 pub mod ipc;
 pub mod irq;
@@ -7,4 +8,5 @@ pub mod math_unit;
 pub mod spi;
 pub mod timer;
 pub mod spu;
-pub mod rtc;
\ No newline at end of file
+pub mod rtc;
+pub mod wifi;
\ No newline at end of file
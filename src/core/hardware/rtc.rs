@@ -1,4 +1,12 @@
+use log::{debug, error};
+
 use crate::bitfield;
+use crate::core::hardware::irq::IrqSource;
+use crate::core::scheduler::EventId;
+use crate::core::timing::CLOCK_RATE;
+use crate::core::System;
+use crate::unimplemented_feature;
+use crate::util::{paths, Shared, StateReader, StateWriter};
 
 bitfield! {
     #[derive(Clone, Copy)]
@@ -14,69 +22,390 @@ bitfield! {
     }
 }
 
+// commands in bits 4-6 of the command byte
+const COMMAND_STATUS1: u8 = 0;
+const COMMAND_ALARM1: u8 = 1;
+const COMMAND_DATE_TIME: u8 = 2;
+const COMMAND_CLOCK_ADJUST: u8 = 3;
+const COMMAND_STATUS2: u8 = 4;
+const COMMAND_ALARM2: u8 = 5;
+const COMMAND_TIME: u8 = 6;
+const COMMAND_FREE: u8 = 7;
+
+const STATUS1_RESET: u8 = 1 << 0;
+const STATUS1_24_HOUR: u8 = 1 << 1;
+const STATUS1_INT1: u8 = 1 << 4;
+const STATUS1_INT2: u8 = 1 << 5;
+const STATUS2_INT2_ENABLE: u8 = 1 << 6;
+
+/// Alarm registers compare a field only when its bit 7 is set
+const ALARM_COMPARE: u8 = 1 << 7;
+const HOUR_PM: u8 = 1 << 6;
+
+/// Seconds from 1970 to 2000, the clock counts from 2000-01-01 00:00
+const EPOCH_2000: i64 = 946684800;
+
+const OFFSET_NAME: &str = "rtc-offset";
+
+/// The S-35180 clock on the arm7's serial port. Time is the host's local time plus an offset that
+/// changes when a game sets the clock, the offset is kept in the config directory so the clock a
+/// game set survives restarts
 pub struct Rtc {
+    system: Shared<System>,
     rtc: Register,
     write_count: u8,
     command: u8,
     status1: u8,
     status2: u8,
-    date_time: [u8; 7],
+    alarm1: [u8; 3],
+    alarm2: [u8; 3],
+    clock_adjust: u8,
+    free: u8,
+    /// Bytes of the command being transferred, latched when a read starts
+    data: [u8; 7],
+    /// Seconds added to the host's local time
+    offset: i64,
+    /// Minute the interrupts were last checked for, so each one fires once
+    last_minute: i64,
+    tick_event: EventId,
 }
 
 impl Rtc {
-    pub const fn new() -> Self {
+    pub fn new(system: &Shared<System>) -> Self {
         Self {
+            system: system.clone(),
             rtc: Register(0),
             write_count: 0,
             command: 0,
             status1: 0,
             status2: 0,
-            date_time: [0; 7],
+            alarm1: [0; 3],
+            alarm2: [0; 3],
+            clock_adjust: 0,
+            free: 0,
+            data: [0; 7],
+            offset: 0,
+            last_minute: 0,
+            tick_event: Default::default(),
         }
     }
 
     pub fn reset(&mut self) {
-        *self = Self::new()
+        self.rtc = Register(0);
+        self.write_count = 0;
+        self.command = 0;
+        // the firmware leaves the clock in 24 hour mode
+        self.status1 = STATUS1_24_HOUR;
+        self.status2 = 0;
+        self.alarm1 = [0; 3];
+        self.alarm2 = [0; 3];
+        self.clock_adjust = 0;
+        self.free = 0;
+        self.data = [0; 7];
+        self.offset = load_offset();
+        self.last_minute = self.now() / 60;
+
+        self.tick_event = self.system.scheduler.register_event("RTC Tick", |system| system.rtc.tick());
+        self.system.scheduler.add_event(CLOCK_RATE, self.tick_event);
+    }
+
+    /// The offset isn't part of the state, loading an old state doesn't turn the clock back
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"RTC ");
+        state.write("rtc", self.rtc.0);
+        state.write("write_count", self.write_count);
+        state.write("command", self.command);
+        state.write("status1", self.status1);
+        state.write("status2", self.status2);
+        state.write_bytes("alarm1", &self.alarm1);
+        state.write_bytes("alarm2", &self.alarm2);
+        state.write("clock_adjust", self.clock_adjust);
+        state.write("free", self.free);
+        state.write_bytes("data", &self.data);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"RTC ");
+        self.rtc.0 = state.read();
+        self.write_count = state.read();
+        self.command = state.read();
+        self.status1 = state.read();
+        self.status2 = state.read();
+        state.read_bytes(&mut self.alarm1);
+        state.read_bytes(&mut self.alarm2);
+        self.clock_adjust = state.read();
+        self.free = state.read();
+        state.read_bytes(&mut self.data);
+        self.last_minute = self.now() / 60;
     }
 
     pub const fn read_rtc(&self) -> u8 {
         self.rtc.0
     }
 
+    /// Bits go over the data line lsb first, on the falling edge of the clock while select is high.
+    /// The first byte is the command, the bytes after it are the command's data
     pub fn write_rtc(&mut self, val: u8) {
         let old_rtc = self.rtc;
         self.rtc.0 = val;
 
-        if !old_rtc.select() && self.rtc.select() {
-            if old_rtc.clock() && !self.rtc.clock() {
-                if self.write_count < 8 {
-                    self.command |= (self.rtc.0 & 0x1) << self.write_count;
-                } else if self.rtc.data_io_direction() {
-                    self.interpret_write_command(self.rtc.0);
-                } else {
-                    self.rtc.0 = (self.rtc.0 & !0x1) | (old_rtc.0 & 0x1);
-                    self.rtc.0 = self.interpret_read_command(self.rtc.0);
-                }
+        if !self.rtc.select() {
+            self.write_count = 0;
+            self.command = 0;
+            return;
+        }
+
+        // the chip drives the data line while it's an input
+        if !self.rtc.data_io_direction() {
+            self.rtc.0 = (self.rtc.0 & !0x1) | (old_rtc.0 & 0x1);
+        }
+
+        if !old_rtc.select() || !old_rtc.clock() || self.rtc.clock() {
+            return;
+        }
 
-                self.write_count += 1;
-            } else if !self.rtc.data_io_direction() {
-                self.rtc.0 = (self.rtc.0 & !0x1) | (old_rtc.0 & 0x1);
+        if self.write_count < 8 {
+            self.command |= (self.rtc.0 & 0x1) << self.write_count;
+            if self.write_count == 7 {
+                self.start_command();
             }
+        } else if self.rtc.data_io_direction() {
+            self.interpret_write_command(self.rtc.0);
         } else {
-            self.write_count = 0;
-            self.command = 0;
+            self.rtc.0 = self.interpret_read_command(self.rtc.0);
+        }
+
+        self.write_count = self.write_count.saturating_add(1);
+    }
+
+    /// The command byte has the fixed code 0110 in the low nibble, some games send it the other way
+    /// around. Reads latch everything they return up front
+    fn start_command(&mut self) {
+        if self.command & 0xf != 0x6 && self.command >> 4 == 0x6 {
+            self.command = self.command.reverse_bits();
+        }
+
+        self.data = [0; 7];
+        if self.command & 0x80 == 0 {
+            return;
+        }
+
+        match (self.command >> 4) & 0x7 {
+            COMMAND_STATUS1 => {
+                self.data[0] = self.status1;
+                // the interrupt and power flags clear once they're read
+                self.status1 &= 0x0f;
+            }
+            COMMAND_STATUS2 => self.data[0] = self.status2,
+            COMMAND_DATE_TIME => self.data = self.date_time(),
+            COMMAND_TIME => {
+                let now = self.date_time();
+                self.data[..3].copy_from_slice(&now[4..]);
+            }
+            COMMAND_ALARM1 => self.data[..3].copy_from_slice(&self.alarm1),
+            COMMAND_ALARM2 => self.data[..3].copy_from_slice(&self.alarm2),
+            COMMAND_CLOCK_ADJUST => self.data[0] = self.clock_adjust,
+            COMMAND_FREE => self.data[0] = self.free,
+            _ => unreachable!(),
         }
     }
 
     fn interpret_read_command(&mut self, val: u8) -> u8 {
-        todo!()
+        let bit = (self.write_count - 8) as usize;
+        let byte = self.data.get(bit / 8).copied().unwrap_or(0);
+        (val & !0x1) | ((byte >> (bit % 8)) & 0x1)
     }
 
     fn interpret_write_command(&mut self, val: u8) {
-        todo!()
+        let bit = (self.write_count - 8) as usize;
+        let Some(byte) = self.data.get_mut(bit / 8) else {
+            return;
+        };
+
+        *byte |= (val & 0x1) << (bit % 8);
+        if bit % 8 == 7 {
+            self.write_byte(bit / 8);
+        }
+    }
+
+    /// Applies a byte once it's complete, the clock is only set once every byte of the time arrived
+    fn write_byte(&mut self, index: usize) {
+        let val = self.data[index];
+        match (self.command >> 4) & 0x7 {
+            COMMAND_STATUS1 => {
+                if val & STATUS1_RESET != 0 {
+                    self.status1 = 0;
+                    self.status2 = 0;
+                    self.alarm1 = [0; 3];
+                    self.alarm2 = [0; 3];
+                    self.clock_adjust = 0;
+                    self.set_clock(EPOCH_2000);
+                }
+                self.status1 = (self.status1 & !0x0e) | (val & 0x0e);
+            }
+            COMMAND_STATUS2 => self.status2 = val,
+            COMMAND_DATE_TIME if index == 6 => {
+                let date = from_bcd_date(&self.data[..3]);
+                self.set_clock(date + self.seconds_from_bcd(&self.data[4..7]));
+            }
+            COMMAND_TIME if index == 2 => {
+                let today = self.now().div_euclid(86400) * 86400;
+                self.set_clock(today + self.seconds_from_bcd(&self.data[..3]));
+            }
+            COMMAND_ALARM1 if index < 3 => self.alarm1[index] = val,
+            COMMAND_ALARM2 if index < 3 => self.alarm2[index] = val,
+            COMMAND_CLOCK_ADJUST if index == 0 => self.clock_adjust = val,
+            COMMAND_FREE if index == 0 => self.free = val,
+            _ => {}
+        }
+    }
+
+    /// Local time as seconds since 1970
+    fn now(&self) -> i64 {
+        host_local_time() + self.offset
+    }
+
+    fn set_clock(&mut self, time: i64) {
+        self.offset = time - host_local_time();
+        self.last_minute = time / 60;
+        save_offset(self.offset);
+        debug!("RTC: clock set, {} seconds from the host clock", self.offset);
     }
 
-    const fn convert_bcd(val: u8) -> u8 {
-        ((val / 10) << 4) | (val % 10)
+    /// Year, month, day, weekday, hour, minute and second in bcd, the year counting from 2000.
+    /// The weekday is worked out from the date, 0 is sunday
+    fn date_time(&self) -> [u8; 7] {
+        let now = self.now().max(EPOCH_2000);
+        let days = now.div_euclid(86400);
+        let seconds = now.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        let weekday = (days + 4).rem_euclid(7);
+
+        let hour = (seconds / 3600) as u8;
+        let pm = if hour >= 12 { HOUR_PM } else { 0 };
+        let hour = if self.status1 & STATUS1_24_HOUR != 0 { to_bcd(hour) } else { to_bcd(hour % 12) };
+
+        [
+            to_bcd((year - 2000).clamp(0, 99) as u8),
+            to_bcd(month as u8),
+            to_bcd(day as u8),
+            weekday as u8,
+            hour | pm,
+            to_bcd((seconds / 60 % 60) as u8),
+            to_bcd((seconds % 60) as u8),
+        ]
+    }
+
+    /// Hour, minute and second in bcd to seconds into the day, the pm flag only counts in 12 hour mode
+    fn seconds_from_bcd(&self, time: &[u8]) -> i64 {
+        let mut hour = from_bcd(time[0] & 0x3f) as i64;
+        if self.status1 & STATUS1_24_HOUR == 0 && time[0] & HOUR_PM != 0 {
+            hour += 12;
+        }
+        hour.min(23) * 3600 + from_bcd(time[1]).min(59) as i64 * 60 + from_bcd(time[2]).min(59) as i64
+    }
+
+    /// Runs once per emulated second and raises the interrupts due in a new minute
+    fn tick(&mut self) {
+        self.system.scheduler.add_event(CLOCK_RATE, self.tick_event);
+
+        let minute = self.now() / 60;
+        if minute == self.last_minute {
+            return;
+        }
+        self.last_minute = minute;
+
+        let now = self.date_time();
+        let mut raise = false;
+        match self.status2 & 0xf {
+            0x0 => {}
+            0x4 => {
+                if alarm_matches(&self.alarm1, &now) {
+                    self.status1 |= STATUS1_INT1;
+                    raise = true;
+                }
+            }
+            // 32kHz clock output, no interrupt
+            mode if mode & 0x8 != 0 => {}
+            mode if mode & 0x3 == 0x1 => unimplemented_feature!("RTC: handle selected frequency interrupts"),
+            // per minute edge and steady interrupts, both start on the minute
+            _ => {
+                self.status1 |= STATUS1_INT1;
+                raise = true;
+            }
+        }
+
+        if self.status2 & STATUS2_INT2_ENABLE != 0 && alarm_matches(&self.alarm2, &now) {
+            self.status1 |= STATUS1_INT2;
+            raise = true;
+        }
+
+        if raise {
+            self.system.arm7.irq.raise(IrqSource::RTC);
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Weekday, hour and minute, each compared only if enabled. The pm flag is ignored since the hour is in 24 hour form
+fn alarm_matches(alarm: &[u8; 3], now: &[u8; 7]) -> bool {
+    let fields = [(alarm[0], now[3] & 0x7, 0x7), (alarm[1], now[4] & 0x3f, 0x3f), (alarm[2], now[5], 0x7f)];
+    fields.iter().all(|&(alarm, now, mask)| alarm & ALARM_COMPARE == 0 || alarm & mask == now)
+}
+
+fn from_bcd_date(date: &[u8]) -> i64 {
+    let year = 2000 + from_bcd(date[0]).min(99) as i64;
+    let month = from_bcd(date[1]).clamp(1, 12) as i64;
+    let day = from_bcd(date[2]).clamp(1, 31) as i64;
+    days_from_civil(year, month, day) * 86400
+}
+
+const fn to_bcd(val: u8) -> u8 {
+    ((val / 10) << 4) | (val % 10)
+}
+
+const fn from_bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0xf)
+}
+
+/// Days since 1970-01-01 for a date in the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Seconds since 1970 on the host's wall clock in its local timezone
+fn host_local_time() -> i64 {
+    let now = chrono::Local::now();
+    now.timestamp() + now.offset().local_minus_utc() as i64
+}
+
+fn load_offset() -> i64 {
+    std::fs::read_to_string(paths::config().join(OFFSET_NAME))
+        .ok()
+        .and_then(|offset| offset.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn save_offset(offset: i64) {
+    if let Err(e) = std::fs::write(paths::config().join(OFFSET_NAME), offset.to_string()) {
+        error!("RTC: failed to save the clock offset: {e}");
+    }
+}
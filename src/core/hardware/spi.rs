@@ -1,9 +1,11 @@
-use log::{debug, error};
+use log::{debug, error, warn};
 
 use crate::bitfield;
+use crate::core::config::BatteryLevel;
+use crate::core::hardware::firmware::{Firmware, UserSettings};
 use crate::core::hardware::irq::IrqSource;
 use crate::core::System;
-use crate::util::{get_field, Shared};
+use crate::util::{get_field, read_le, Shared, StateReader, StateWriter};
 
 #[repr(u16)]
 enum Device {
@@ -29,7 +31,7 @@ bitfield! {
 
 pub struct Spi {
     system: Shared<System>,
-    firmware: Box<[u8]>,
+    firmware: Firmware,
 
     spicnt: SpiCnt,
     spidata: u8,
@@ -48,13 +50,15 @@ pub struct Spi {
     scr_y1: u8,
     scr_y2: u8,
     output: u16,
+
+    powerman_registers: [u8; 5],
 }
 
 impl Spi {
     pub fn new(system: &Shared<System>) -> Self {
         Self {
             system: system.clone(),
-            firmware: std::fs::read("firmware/firmware.bin").unwrap().into_boxed_slice(),
+            firmware: Firmware::synthesize(),
             spicnt: SpiCnt(0),
             spidata: 0,
             write_count: 0,
@@ -71,6 +75,7 @@ impl Spi {
             scr_y1: 0,
             scr_y2: 0,
             output: 0,
+            powerman_registers: [0; 5],
         }
     }
 
@@ -83,27 +88,63 @@ impl Spi {
         self.command = 0;
         self.address = 0;
         self.output = 0;
+        self.powerman_registers = [0x0d, 0x00, 0x00, 0x00, 0x00];
+        self.firmware.save_user_settings();
+        self.firmware = Firmware::load(self.system.config.firmware_path.as_deref());
+        if let Some(language) = self.system.config.language {
+            self.firmware.override_language(language);
+        }
 
         self.load_calibration_points();
     }
 
+    /// The firmware image and the touchscreen calibration taken from it aren't saved, they come from
+    /// the firmware that was loaded on reset
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"SPI ");
+        state.write("spicnt", self.spicnt.0);
+        state.write("spidata", self.spidata);
+        state.write("write_count", self.write_count as u32);
+        state.write_bool("write_enable_latch", self.write_enable_latch);
+        state.write_bool("write_in_progress", self.write_in_progress);
+        state.write("command", self.command);
+        state.write("address", self.address);
+        state.write("output", self.output);
+        state.write_bytes("powerman_registers", &self.powerman_registers);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"SPI ");
+        self.spicnt.0 = state.read();
+        self.spidata = state.read();
+        self.write_count = state.read::<u32>() as usize;
+        self.write_enable_latch = state.read_bool();
+        self.write_in_progress = state.read_bool();
+        self.command = state.read();
+        self.address = state.read();
+        self.output = state.read();
+        state.read_bytes(&mut self.powerman_registers);
+    }
+
     pub fn direct_boot(&mut self) {
+        // the firmware normally copies the active user settings to main memory before booting the cartridge
+        let offset = self.firmware.active_user_settings();
         for i in 0..0x70 {
             self.system
                 .arm9
                 .get_memory()
-                .write_byte(0x027ffc80 + i, self.firmware[0x3ff00 + i as usize])
+                .write_byte(0x027ffc80 + i, self.firmware[offset + i as usize])
         }
     }
 
     fn load_calibration_points(&mut self) {
         macro_rules! read {
             ($t:ty, $start:expr) => {
-                <$t>::from_le_bytes(self.firmware[$start..$start + std::mem::size_of::<$t>()].try_into().unwrap())
+                read_le::<$t>(&self.firmware, $start).unwrap_or_default()
             };
         }
 
-        let offset = read!(u16, 0x20) as usize * 8;
+        let offset = self.firmware.active_user_settings();
 
         self.adc_x1 = read!(u16, offset + 0x58);
         self.adc_y1 = read!(u16, offset + 0x5a);
@@ -128,6 +169,15 @@ impl Spi {
     pub fn write_spicnt(&mut self, val: u16, mask: u32) {
         let mask = (mask & 0xcf03) as u16;
         self.spicnt.0 = (self.spicnt.0 & !mask) | (val & mask);
+
+        // disabling the bus deselects the device even if the last transfer held chip select
+        if !self.spicnt.enable() && self.write_count != 0 {
+            self.release_chipselect();
+        }
+
+        if self.spicnt.transfer_halfwords() {
+            warn!("SPI: 16-bit transfers are broken on hardware, only the low byte is transferred");
+        }
     }
 
     pub fn write_spidata(&mut self, val: u8) {
@@ -143,19 +193,29 @@ impl Spi {
             self.command = val;
             self.address = 0;
             self.spidata = 0;
+
+            if let Device::Firmware = self.spicnt.device() {
+                match val {
+                    0x04 => self.write_enable_latch = false,
+                    0x06 => self.write_enable_latch = true,
+                    _ => {}
+                }
+            }
         } else {
             match self.spicnt.device() {
-                Device::Powerman => self.spidata = 0, // todo: figure out what to actually do here
+                Device::Powerman => self.powerman_transfer(val),
                 Device::Firmware => self.firmware_transfer(val),
                 Device::Touchscreen => self.touchscreen_transfer(val),
                 Device::Reserved => todo!(),
             }
         }
 
+        // chip select stays low between bytes while the hold bit is set, which keeps the
+        // command and address going. The first byte written without it is the last one
         if self.spicnt.chipselect_hold() {
             self.write_count += 1;
         } else {
-            self.write_count = 0;
+            self.release_chipselect();
         }
 
         if self.spicnt.irq() {
@@ -163,11 +223,56 @@ impl Spi {
         }
     }
 
-    fn firmware_transfer(&mut self, val: u8) {
-        if self.spicnt.transfer_halfwords() {
-            error!("SPI: handle 16-bit transfer")
+    fn release_chipselect(&mut self) {
+        if let Device::Firmware = self.spicnt.device() {
+            if self.command == 0x0a && self.write_count != 0 {
+                self.write_enable_latch = false;
+            }
+        }
+        self.write_count = 0;
+    }
+
+    pub fn user_settings(&self) -> UserSettings {
+        self.firmware.user_settings()
+    }
+
+    pub fn set_user_settings(&mut self, settings: &UserSettings) {
+        self.firmware.set_user_settings(settings)
+    }
+
+    pub fn save_user_settings(&mut self) {
+        self.firmware.save_user_settings()
+    }
+
+    fn powerman_transfer(&mut self, val: u8) {
+        let index = (self.command & 0x7) as usize;
+        if index >= self.powerman_registers.len() {
+            warn!("SPI: unknown powerman register {index}");
+            self.spidata = 0;
+            return;
         }
 
+        if self.command & (1 << 7) != 0 {
+            self.spidata = match index {
+                1 => (self.system.config.battery_level == BatteryLevel::Low) as u8,
+                _ => self.powerman_registers[index],
+            };
+        } else {
+            match index {
+                0 => {
+                    self.powerman_registers[0] = val & 0x3f;
+                    if val & (1 << 6) != 0 {
+                        self.system.power_off();
+                    }
+                }
+                1 => {}
+                _ => self.powerman_registers[index] = val,
+            }
+            self.spidata = 0;
+        }
+    }
+
+    fn firmware_transfer(&mut self, val: u8) {
         match self.command {
             0x03 => {
                 if self.write_count < 4 {
@@ -178,10 +283,26 @@ impl Spi {
                     }
 
                     self.spidata = self.firmware[self.address as usize];
-                    self.address += if self.spicnt.transfer_halfwords() { 2 } else { 1 };
+                    self.address += 1;
                 }
             }
             0x05 => self.spidata = self.write_in_progress as u8 | ((self.write_enable_latch as u8) << 1),
+            0x0a => {
+                if self.write_count < 4 {
+                    self.address |= (val as u32) << ((3 - self.write_count) * 8)
+                } else {
+                    // everything before the user settings area is write protected
+                    let address = self.address as usize;
+                    if self.write_enable_latch && address >= self.firmware.user_settings_offset() && address < self.firmware.len() {
+                        self.firmware[address] = val;
+                    } else {
+                        error!("SPI: ignoring write to protected firmware address {address:06x}");
+                    }
+
+                    self.spidata = 0;
+                    self.address += 1;
+                }
+            }
             _ => error!("SPI: unimplemented firmware command {:02x}", self.command),
         }
     }
@@ -192,21 +313,104 @@ impl Spi {
 
         if val & (1 << 7) != 0 {
             let channel = get_field::<4, 3>(val as u32);
-            let mut touch_x = 0;
-            let mut touch_y = 0xfff;
+            // released reads as x = 0 and y = 0xfff, like an untouched panel
+            let (touch_x, touch_y) = if self.system.input.touch_down() { self.touch_adc() } else { (0, 0xfff) };
+            let value = match channel {
+                1 => touch_y,
+                5 => touch_x,
+                _ => 0,
+            };
 
-            if self.system.input.touch_down() {
-                touch_x = (self.system.input.point.x as u16 - self.scr_x1 as u16 + 1) * (self.adc_x2 - self.adc_x1) / (self.scr_x2 - self.scr_x1) as u16 + self.adc_x1;
-                touch_y = (self.system.input.point.y as u16 - self.scr_y1 as u16 + 1) * (self.adc_y2 - self.adc_y1) / (self.scr_y2 - self.scr_y1) as u16 + self.adc_y1;
+            // the result follows a busy bit, 8 bit conversions only return the top bits
+            self.output = if val & (1 << 3) != 0 { (value >> 4) << 7 } else { value << 3 };
+        }
 
-                match channel {
-                    1 => self.output = touch_y << 3,
-                    5 => self.output = touch_x << 3,
-                    _ => self.spidata = 0
-                }
+        self.spidata = upper;
+    }
+
+    /// Turns the touched pixel into raw adc values using the firmware calibration points,
+    /// the inverse of the conversion games do
+    fn touch_adc(&self) -> (u16, u16) {
+        let convert = |pos: u32, scr1: u8, scr2: u8, adc1: u16, adc2: u16| {
+            let scr_range = scr2 as i32 - scr1 as i32;
+            if scr_range == 0 {
+                return 0;
             }
+
+            let val = (pos as i32 - scr1 as i32 + 1) * (adc2 as i32 - adc1 as i32) / scr_range + adc1 as i32;
+            val.clamp(0, 0xfff) as u16
+        };
+
+        let point = self.system.input.get_point();
+        (
+            convert(point.x, self.scr_x1, self.scr_x2, self.adc_x1, self.adc_x2),
+            convert(point.y, self.scr_y1, self.scr_y2, self.adc_y1, self.adc_y2),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRMWARE: u16 = (Device::Firmware as u16) << 8;
+    const HALFWORDS: u16 = 1 << 10;
+    const HOLD: u16 = 1 << 11;
+    const ENABLE: u16 = 1 << 15;
+
+    fn write(spi: &mut Spi, spicnt: u16, bytes: &[u8]) {
+        spi.write_spicnt(spicnt, 0xffff);
+        for &byte in bytes {
+            spi.write_spidata(byte);
         }
+    }
 
-        self.spidata = upper;
+    #[test]
+    fn halfword_transfers_only_move_the_low_byte() {
+        let mut system = System::new();
+        let spi = &mut system.spi;
+        spi.firmware[0x20..0x24].copy_from_slice(&[0x11, 0x22, 0x33, 0x44]);
+        write(spi, ENABLE | HOLD | FIRMWARE | HALFWORDS, &[0x03, 0x00, 0x00, 0x20]);
+
+        for expected in [0x11, 0x22, 0x33, 0x44] {
+            spi.write_spidata(0);
+            assert_eq!(spi.read_spidata(), expected);
+        }
+    }
+
+    #[test]
+    fn disabling_the_bus_releases_chip_select() {
+        let mut system = System::new();
+        let spi = &mut system.spi;
+        write(spi, ENABLE | HOLD | FIRMWARE, &[0x03, 0x00]);
+        assert_ne!(spi.write_count, 0);
+
+        spi.write_spicnt(FIRMWARE, 0xffff);
+        assert_eq!(spi.write_count, 0);
+
+        // the next byte starts a new command instead of continuing the address
+        write(spi, ENABLE | HOLD | FIRMWARE, &[0x05, 0x00]);
+        assert_eq!(spi.command, 0x05);
+    }
+
+    #[test]
+    fn page_writes_clear_the_write_enable_latch_when_released() {
+        let mut system = System::new();
+        let spi = &mut system.spi;
+        write(spi, ENABLE | FIRMWARE, &[0x06]);
+        assert!(spi.write_enable_latch);
+
+        // the command byte alone doesn't write anything
+        write(spi, ENABLE | FIRMWARE, &[0x0a]);
+        assert!(spi.write_enable_latch);
+
+        write(spi, ENABLE | HOLD | FIRMWARE, &[0x0a, 0x00, 0x00]);
+        write(spi, ENABLE | FIRMWARE, &[0x00]);
+        assert!(!spi.write_enable_latch);
+
+        write(spi, ENABLE | FIRMWARE, &[0x06]);
+        write(spi, ENABLE | HOLD | FIRMWARE, &[0x0a, 0x00]);
+        spi.write_spicnt(FIRMWARE, 0xffff);
+        assert!(!spi.write_enable_latch);
     }
 }
@@ -1,9 +1,15 @@
+use crate::arm::cpu::Arch;
 use crate::bitfield;
+use crate::core::scheduler::Timestamp;
+use crate::core::timing::CYCLES_PER_SAMPLE;
+use crate::core::System;
+use crate::util::{set, RingBuffer, Shared, StateReader, StateWriter};
 
+#[derive(Copy, Clone, PartialEq)]
 enum SampleOutput {
     Mixer = 0,
     Channel1 = 1,
-    Channel2 = 2,
+    Channel3 = 2,
     Channel1And3 = 3,
 }
 
@@ -20,19 +26,387 @@ bitfield! {
     }
 }
 
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct SoundChannelCnt(u32) {
+        volume_mul: u32 => 0 | 6,
+        // 7
+        volume_div: u32 => 8 | 9,
+        // 10 | 14
+        hold: bool => 15,
+        panning: u32 => 16 | 22,
+        // 23
+        wave_duty: u32 => 24 | 26,
+        repeat_mode: u32 => 27 | 28,
+        format: u32 => 29 | 30,
+        start: bool => 31
+    }
+}
+
+const FORMAT_PCM8: u32 = 0;
+const FORMAT_PCM16: u32 = 1;
+const FORMAT_ADPCM: u32 = 2;
+const FORMAT_PSG: u32 = 3;
+
+const REPEAT_MANUAL: u32 = 0;
+const REPEAT_LOOP: u32 = 1;
+
+/// Channel timers tick at half the system clock
+const TIMER_TICKS_PER_SAMPLE: u32 = CYCLES_PER_SAMPLE as u32 / 2;
+
+const ADPCM_INDEX_TABLE: [i32; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+
+const ADPCM_STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66, 73, 80, 88, 97, 107,
+    118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449, 494, 544, 598, 658, 724, 796, 876, 963,
+    1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894,
+    6484, 7132, 7845, 8630, 9493, 10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794,
+    32767,
+];
+
+#[derive(Copy, Clone)]
+struct Channel {
+    control: SoundChannelCnt,
+    source: u32,
+    timer: u16,
+    /// In words
+    loop_start: u16,
+    /// In words, counted from the loop start
+    length: u32,
+
+    /// Bytes played from `source`, or the duty step and noise state for psg channels
+    position: u32,
+    /// Timer ticks towards the next sample
+    counter: u32,
+    sample: i16,
+
+    adpcm_value: i16,
+    adpcm_index: i32,
+    adpcm_high_nibble: bool,
+    /// Decoder state when the loop start was reached, restored every time the sample loops
+    adpcm_loop_value: i16,
+    adpcm_loop_index: i32,
+
+    noise_lfsr: u16,
+}
+
+impl Channel {
+    const fn new() -> Self {
+        Self {
+            control: SoundChannelCnt(0),
+            source: 0,
+            timer: 0,
+            loop_start: 0,
+            length: 0,
+            position: 0,
+            counter: 0,
+            sample: 0,
+            adpcm_value: 0,
+            adpcm_index: 0,
+            adpcm_high_nibble: false,
+            adpcm_loop_value: 0,
+            adpcm_loop_index: 0,
+            noise_lfsr: 0,
+        }
+    }
+
+    fn start(&mut self) {
+        self.position = 0;
+        self.counter = 0;
+        self.sample = 0;
+        self.adpcm_high_nibble = false;
+        self.noise_lfsr = 0x7fff;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write("control", self.control.0);
+        state.write("source", self.source);
+        state.write("timer", self.timer);
+        state.write("loop_start", self.loop_start);
+        state.write("length", self.length);
+        state.write("position", self.position);
+        state.write("counter", self.counter);
+        state.write("sample", self.sample);
+        state.write("adpcm_value", self.adpcm_value);
+        state.write("adpcm_index", self.adpcm_index);
+        state.write_bool("adpcm_high_nibble", self.adpcm_high_nibble);
+        state.write("adpcm_loop_value", self.adpcm_loop_value);
+        state.write("adpcm_loop_index", self.adpcm_loop_index);
+        state.write("noise_lfsr", self.noise_lfsr);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.control.0 = state.read();
+        self.source = state.read();
+        self.timer = state.read();
+        self.loop_start = state.read();
+        self.length = state.read();
+        self.position = state.read();
+        self.counter = state.read();
+        self.sample = state.read();
+        self.adpcm_value = state.read();
+        self.adpcm_index = state.read::<i32>().clamp(0, 88);
+        self.adpcm_high_nibble = state.read_bool();
+        self.adpcm_loop_value = state.read();
+        self.adpcm_loop_index = state.read::<i32>().clamp(0, 88);
+        self.noise_lfsr = state.read();
+    }
+}
+
 pub struct Spu {
-    soundcnt: SoundCnt
+    system: Shared<System>,
+    channels: [Channel; 16],
+    soundcnt: SoundCnt,
+    soundbias: u16,
+    /// Interleaved left/right samples waiting for the frontend, the newest are dropped when it falls behind
+    samples: RingBuffer<[i16; 2], 4096>,
+    /// Scheduler time the next sample is due at
+    next_sample: Timestamp,
+    /// Samples produced since the last `run`, register writes catch up in between
+    pending_samples: usize,
+    last_run_samples: usize,
 }
 
 impl Spu {
-    pub fn new() -> Self {
+    pub fn new(system: &Shared<System>) -> Self {
         Self {
-            soundcnt: SoundCnt(0)
+            system: system.clone(),
+            channels: [Channel::new(); 16],
+            soundcnt: SoundCnt(0),
+            soundbias: 0,
+            samples: RingBuffer::default(),
+            next_sample: Timestamp::default(),
+            pending_samples: 0,
+            last_run_samples: 0,
         }
     }
 
     pub fn reset(&mut self) {
-        // todo
+        self.channels = [Channel::new(); 16];
+        self.soundcnt = SoundCnt(0);
+        // the bios centers the output while booting
+        self.soundbias = 0x200;
+        self.samples.clear();
+        self.next_sample = Timestamp::default();
+        self.pending_samples = 0;
+        self.last_run_samples = 0;
+    }
+
+    /// Queued samples belong to the frontend and are dropped on load
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"SPU ");
+        state.write("soundcnt", self.soundcnt.0);
+        state.write("soundbias", self.soundbias);
+        state.write("next_sample", self.next_sample);
+        for channel in &self.channels {
+            channel.save_state(state);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"SPU ");
+        self.soundcnt.0 = state.read();
+        self.soundbias = state.read();
+        self.next_sample = state.read();
+        for channel in &mut self.channels {
+            channel.load_state(state);
+        }
+        self.samples.clear();
+        self.pending_samples = 0;
+        self.last_run_samples = 0;
+    }
+
+    /// Produces every sample that is due by scheduler time `now`, called once a frame
+    pub fn run(&mut self, now: Timestamp) {
+        self.catch_up(now);
+        self.last_run_samples = self.pending_samples;
+        self.pending_samples = 0;
+    }
+
+    fn catch_up(&mut self, now: Timestamp) {
+        while self.next_sample <= now {
+            for id in 0..self.channels.len() {
+                self.step_channel(id);
+            }
+
+            let sample = self.mix();
+            self.samples.push(sample);
+            self.next_sample += CYCLES_PER_SAMPLE;
+            self.pending_samples += 1;
+        }
+    }
+
+    /// Brings the output up to date before a register changes how it sounds
+    fn sync(&mut self) {
+        let now = self.system.scheduler.get_current_time();
+        self.catch_up(now);
+    }
+
+    /// How many samples the last `run` produced
+    pub const fn last_run_samples(&self) -> usize {
+        self.last_run_samples
+    }
+
+    /// Moves queued samples into `out` as interleaved left/right pairs and returns how many pairs were written.
+    /// The rest of `out` is filled with silence
+    pub fn drain(&mut self, out: &mut [i16]) -> usize {
+        let mut written = 0;
+        for pair in out.chunks_exact_mut(2) {
+            if self.samples.is_empty() {
+                pair.fill(0);
+            } else {
+                pair.copy_from_slice(&self.samples.pop());
+                written += 1;
+            }
+        }
+        written
+    }
+
+    /// Advances a channel's timer by one output sample and fetches every sample it overflowed for
+    fn step_channel(&mut self, id: usize) {
+        if !self.channels[id].control.start() {
+            return;
+        }
+
+        let period = 0x10000 - self.channels[id].timer as u32;
+        self.channels[id].counter += TIMER_TICKS_PER_SAMPLE;
+        while self.channels[id].control.start() && self.channels[id].counter >= period {
+            self.channels[id].counter -= period;
+            self.next_channel_sample(id);
+        }
+    }
+
+    fn next_channel_sample(&mut self, id: usize) {
+        let format = self.channels[id].control.format();
+        if format == FORMAT_PSG {
+            return self.next_psg_sample(id);
+        }
+
+        let channel = self.channels[id];
+        let end = (channel.loop_start as u32 + channel.length) * 4;
+        let header = if format == FORMAT_ADPCM { 4 } else { 0 };
+        if channel.control.repeat_mode() != REPEAT_MANUAL && channel.position >= end.max(header) {
+            let channel = &mut self.channels[id];
+            if channel.control.repeat_mode() == REPEAT_LOOP {
+                channel.position = channel.loop_start as u32 * 4;
+                channel.adpcm_value = channel.adpcm_loop_value;
+                channel.adpcm_index = channel.adpcm_loop_index;
+                channel.adpcm_high_nibble = false;
+            } else {
+                channel.control.set_start(false);
+                if !channel.control.hold() {
+                    channel.sample = 0;
+                }
+                return;
+            }
+        }
+
+        let channel = self.channels[id];
+        let addr = channel.source.wrapping_add(channel.position);
+        let memory = self.system.get_memory(Arch::ARMv4);
+        let channel = &mut self.channels[id];
+        match format {
+            FORMAT_PCM8 => {
+                channel.sample = (memory.read_byte(addr) as i8 as i16) << 8;
+                channel.position += 1;
+            }
+            FORMAT_PCM16 => {
+                channel.sample = memory.read_half(addr & !0x1) as i16;
+                channel.position += 2;
+            }
+            _ => {
+                if channel.position == 0 {
+                    let header = memory.read_word(channel.source & !0x3);
+                    channel.adpcm_value = header as i16;
+                    channel.adpcm_index = ((header >> 16) & 0x7f).min(88) as i32;
+                    channel.position = 4;
+                }
+
+                let addr = channel.source.wrapping_add(channel.position);
+                if channel.position == channel.loop_start as u32 * 4 && !channel.adpcm_high_nibble {
+                    channel.adpcm_loop_value = channel.adpcm_value;
+                    channel.adpcm_loop_index = channel.adpcm_index;
+                }
+
+                let byte = memory.read_byte(addr);
+                let nibble = if channel.adpcm_high_nibble { byte >> 4 } else { byte & 0xf };
+                decode_adpcm(channel, nibble);
+                channel.sample = channel.adpcm_value;
+
+                channel.adpcm_high_nibble = !channel.adpcm_high_nibble;
+                if !channel.adpcm_high_nibble {
+                    channel.position += 1;
+                }
+            }
+        }
+    }
+
+    /// Channels 8 to 13 play square waves, 14 and 15 noise, the rest are silent in psg mode
+    fn next_psg_sample(&mut self, id: usize) {
+        let channel = &mut self.channels[id];
+        match id {
+            8..=13 => {
+                let duty = channel.control.wave_duty();
+                let step = channel.position % 8;
+                channel.sample = if duty != 7 && step >= 7 - duty { 0x7fff } else { -0x7fff };
+                channel.position = (step + 1) % 8;
+            }
+            14 | 15 => {
+                let carry = channel.noise_lfsr & 0x1 != 0;
+                channel.noise_lfsr >>= 1;
+                if carry {
+                    channel.noise_lfsr ^= 0x6000;
+                    channel.sample = -0x7fff;
+                } else {
+                    channel.sample = 0x7fff;
+                }
+            }
+            _ => channel.sample = 0,
+        }
+    }
+
+    /// Volume and panning are applied per channel, then the mixer output is scaled by the master
+    /// volume, biased and clipped to the 10 bits the dac takes. Like the other 7 bit factors, 127
+    /// counts as 128, following melonDS. None of this has been compared with recordings from hardware,
+    /// the tests only pin down the integer steps
+    fn mix(&self) -> [i16; 2] {
+        if !self.soundcnt.master_enable() {
+            return [0, 0];
+        }
+
+        let mut mixer = [0i64; 2];
+        let mut outputs = [[0i64; 2]; 16];
+        for (id, channel) in self.channels.iter().enumerate() {
+            if !channel.control.start() && !channel.control.hold() {
+                continue;
+            }
+
+            let shift = [0, 1, 2, 4][channel.control.volume_div() as usize];
+            let val = (channel.sample as i64 * seven_bit_factor(channel.control.volume_mul())) >> 7 >> shift;
+            let pan = seven_bit_factor(channel.control.panning());
+            let output = [(val * (128 - pan)) >> 7, (val * pan) >> 7];
+            outputs[id] = output;
+
+            if (id == 1 && self.soundcnt.skip_ch1_mixer_output()) || (id == 3 && self.soundcnt.skip_ch3_mixer_output()) {
+                continue;
+            }
+            mixer[0] += output[0];
+            mixer[1] += output[1];
+        }
+
+        let select = |output: SampleOutput, side: usize| match output {
+            SampleOutput::Mixer => mixer[side],
+            SampleOutput::Channel1 => outputs[1][side],
+            SampleOutput::Channel3 => outputs[3][side],
+            SampleOutput::Channel1And3 => outputs[1][side] + outputs[3][side],
+        };
+
+        let master = seven_bit_factor(self.soundcnt.master_volume() as u32);
+        let bias = self.soundbias as i64;
+        [select(self.soundcnt.left_output(), 0), select(self.soundcnt.right_output(), 1)].map(|val| {
+            let dac = (((val * master) >> 7 >> 6) + bias).clamp(0, 0x3ff);
+            ((dac - 0x200) << 6) as i16
+        })
     }
 
     pub const fn read_soundcnt(&self) -> u16 {
@@ -40,6 +414,132 @@ impl Spu {
     }
 
     pub fn write_soundcnt(&mut self, val: u16, mask: u16) {
-        self.soundcnt.0 = (self.soundcnt.0 & !mask) | (val & mask)
+        self.sync();
+        set(&mut self.soundcnt.0, val, mask & 0xbf7f);
+    }
+
+    pub const fn read_soundbias(&self) -> u16 {
+        self.soundbias
+    }
+
+    pub fn write_soundbias(&mut self, val: u16, mask: u16) {
+        self.sync();
+        set(&mut self.soundbias, val, mask & 0x3ff);
+    }
+
+    /// Only SOUNDxCNT can be read back, the other channel registers are write only
+    pub fn read_channel(&self, addr: u32) -> u32 {
+        let offset = addr - 0x04000400;
+        match offset & 0xf {
+            0x0 => self.channels[(offset / 16) as usize].control.0,
+            _ => 0,
+        }
+    }
+
+    pub fn write_channel(&mut self, addr: u32, val: u32, mask: u32) {
+        self.sync();
+
+        let offset = addr - 0x04000400;
+        let channel = &mut self.channels[(offset / 16) as usize];
+        match offset & 0xf {
+            0x0 => {
+                let was_started = channel.control.start();
+                set(&mut channel.control.0, val, mask & 0xff7f837f);
+                if channel.control.start() && !was_started {
+                    channel.start();
+                }
+            }
+            0x4 => set(&mut channel.source, val, mask & 0x07fffffc),
+            0x8 => {
+                if mask & 0xffff != 0 {
+                    set(&mut channel.timer, val as u16, mask as u16);
+                }
+                if mask & 0xffff0000 != 0 {
+                    set(&mut channel.loop_start, (val >> 16) as u16, (mask >> 16) as u16);
+                }
+            }
+            _ => set(&mut channel.length, val, mask & 0x3fffff),
+        }
+    }
+}
+
+const fn seven_bit_factor(val: u32) -> i64 {
+    if val == 127 {
+        128
+    } else {
+        val as i64
+    }
+}
+
+fn decode_adpcm(channel: &mut Channel, nibble: u8) {
+    let step = ADPCM_STEP_TABLE[channel.adpcm_index as usize];
+    let mut diff = step / 8;
+    if nibble & 0x1 != 0 {
+        diff += step / 4;
     }
-}
\ No newline at end of file
+    if nibble & 0x2 != 0 {
+        diff += step / 2;
+    }
+    if nibble & 0x4 != 0 {
+        diff += step;
+    }
+
+    let value = channel.adpcm_value as i32;
+    channel.adpcm_value = if nibble & 0x8 != 0 {
+        (value - diff).max(-0x7fff)
+    } else {
+        (value + diff).min(0x7fff)
+    } as i16;
+    channel.adpcm_index = (channel.adpcm_index + ADPCM_INDEX_TABLE[(nibble & 0x7) as usize]).clamp(0, 88);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::System;
+
+    const MASTER_ENABLE: u16 = 1 << 15;
+    const START: u32 = 1 << 31;
+
+    /// Mixes a single full scale sample on channel 0, with the master volume at 127 and the mixer on both sides
+    fn mix_tone(soundcnt: u16, control: u32) -> [i16; 2] {
+        let mut system = System::new();
+        let spu = &mut system.spu;
+        spu.reset();
+        spu.soundcnt = SoundCnt(soundcnt | 0x7f);
+        spu.channels[0].control = SoundChannelCnt(START | control);
+        spu.channels[0].sample = 0x7fff;
+        spu.mix()
+    }
+
+    #[test]
+    fn nothing_is_mixed_without_the_master_enable() {
+        assert_eq!(mix_tone(0, 0x7f | (64 << 16)), [0, 0]);
+    }
+
+    #[test]
+    fn full_volume_panned_hard_right_reaches_full_scale_on_the_right() {
+        // 127 volume, pan and master volume each scale by 128/128, 0x7fff comes out as 511 above the 0x200 bias
+        assert_eq!(mix_tone(MASTER_ENABLE, 0x7f | (127 << 16)), [0, 511 << 6]);
+        assert_eq!(mix_tone(MASTER_ENABLE, 0x7f), [511 << 6, 0]);
+    }
+
+    #[test]
+    fn centered_channels_get_half_on_each_side() {
+        assert_eq!(mix_tone(MASTER_ENABLE, 0x7f | (64 << 16)), [255 << 6, 255 << 6]);
+    }
+
+    #[test]
+    fn the_volume_divider_shifts_by_0_1_2_or_4() {
+        for (div, shift) in [(0, 0), (1, 1), (2, 2), (3, 4)] {
+            let [_, right] = mix_tone(MASTER_ENABLE, 0x7f | (div << 8) | (127 << 16));
+            assert_eq!(right, ((0x7fff >> shift) >> 6) << 6, "divider {div}");
+        }
+    }
+
+    #[test]
+    fn output_selection_can_skip_the_mixer() {
+        // the left side takes channel 1 alone, which is silent, the right side stays on the mixer
+        assert_eq!(mix_tone(MASTER_ENABLE | (1 << 8), 0x7f | (64 << 16)), [0, 255 << 6]);
+    }
+}
@@ -1,12 +1,10 @@
-use std::rc::Rc;
-use log::error;
 
 use crate::arm::cpu::Arch;
 use crate::bitfield;
 use crate::core::hardware::irq::{Irq, IrqSource};
-use crate::core::scheduler::EventInfo;
+use crate::core::scheduler::{EventId, Timestamp};
 use crate::core::System;
-use crate::util::Shared;
+use crate::util::{Shared, StateReader, StateWriter};
 
 const SHIFTS: [u32; 4] = [0, 6, 8, 10];
 
@@ -27,7 +25,7 @@ struct Channel {
     control: Control,
     counter: u32,
     reload_value: u32,
-    activation_timestamp: u64,
+    activation_timestamp: Timestamp,
     active: bool,
     shift: u32,
 }
@@ -36,7 +34,7 @@ pub struct Timers {
     system: Shared<System>,
     irq: Shared<Irq>,
     channels: [Channel; 4],
-    overflow_events: [Rc<EventInfo>; 4],
+    overflow_events: [EventId; 4],
 }
 
 impl Timers {
@@ -66,6 +64,31 @@ impl Timers {
         }
     }
 
+    /// Pending overflows are scheduler events, which the scheduler saves
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"TMR ");
+        for channel in &self.channels {
+            state.write("channel.control", channel.control.0);
+            state.write("channel.counter", channel.counter);
+            state.write("channel.reload_value", channel.reload_value);
+            state.write("channel.activation_timestamp", channel.activation_timestamp);
+            state.write_bool("channel.active", channel.active);
+            state.write("channel.shift", channel.shift);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"TMR ");
+        for channel in &mut self.channels {
+            channel.control.0 = state.read();
+            channel.counter = state.read();
+            channel.reload_value = state.read();
+            channel.activation_timestamp = state.read();
+            channel.active = state.read_bool();
+            channel.shift = state.read();
+        }
+    }
+
     pub fn read_length(&mut self, id: usize) -> u16 {
         self.update_counter(id)
     }
@@ -100,6 +123,9 @@ impl Timers {
     }
 
     fn overflow(&mut self, id: usize) {
+        // restart from when the overflow was due rather than now, so reloads never drift
+        let channel = &self.channels[id];
+        let timestamp = channel.activation_timestamp + ((0x10000 - channel.counter as u64) << channel.shift);
         self.channels[id].counter = self.channels[id].reload_value;
 
         if self.channels[id].control.irq() {
@@ -107,7 +133,7 @@ impl Timers {
         }
 
         if id == 0 || !self.channels[id].control.count_up() {
-            self.activate_channel(id);
+            self.activate_channel_at(id, timestamp);
         }
 
         if id < 3 {
@@ -122,32 +148,107 @@ impl Timers {
     }
 
     fn activate_channel(&mut self, id: usize) {
+        let timestamp = self.system.scheduler.get_current_time();
+        self.activate_channel_at(id, timestamp)
+    }
+
+    fn activate_channel_at(&mut self, id: usize, timestamp: Timestamp) {
         let channel = &mut self.channels[id];
         channel.active = true;
-        channel.activation_timestamp = self.system.scheduler.get_current_time();
+        channel.activation_timestamp = timestamp;
 
-        let delay = (0x10000 - channel.counter as u64) << channel.shift;
-        self.system.scheduler.add_event(delay, &self.overflow_events[id]);
+        let overflow_time = timestamp + ((0x10000 - channel.counter as u64) << channel.shift);
+        self.system.scheduler.add_event_at(overflow_time, self.overflow_events[id]);
     }
 
     fn deactivate_channel(&mut self, id: usize) {
         self.channels[id].counter = self.update_counter(id) as u32;
         self.channels[id].active = false;
-
-        if self.channels[id].counter >= 0x10000 {
-            error!("Timers: handle counter greater than 16-bits");
-        }
-
-        self.system.scheduler.cancel_event(&self.overflow_events[id]);
+        self.system.scheduler.cancel_event(self.overflow_events[id]);
     }
 
+    /// Extrapolates the counter from the time the channel was activated,
+    /// since it is only written back when the channel is stopped or overflows
     fn update_counter(&mut self, id: usize) -> u16 {
-        let channel = &mut self.channels[id];
+        let channel = &self.channels[id];
         if !channel.active {
-            return channel.counter as u16
+            return channel.counter as u16;
         }
 
         let delta = (self.system.scheduler.get_current_time() - channel.activation_timestamp) >> channel.shift;
-        (channel.counter as u64 + delta) as u16
+        let counter = channel.counter as u64 + delta;
+        if counter < 0x10000 {
+            counter as u16
+        } else {
+            // the overflow event hasn't run yet, account for the reloads that already happened
+            let reload = channel.reload_value as u64;
+            (reload + (counter - 0x10000) % (0x10000 - reload)) as u16
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arm::cpu::Arch;
+    use crate::core::scheduler::Timestamp;
+    use crate::core::{OwnedSystem, System};
+
+    const START: u16 = 1 << 7;
+
+    fn advance(system: &mut System, cycles: u64) {
+        let target = system.scheduler.get_current_time() + cycles;
+        system.scheduler.run_until(target);
+    }
+
+    fn started_timer(reload: u32, control: u16) -> OwnedSystem {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.timer9.reset(Arch::ARMv5);
+        system.timer9.write_length(0, reload, 0xffff);
+        system.timer9.write_control(0, START | control, 0xffff);
+        system
+    }
+
+    #[test]
+    fn running_counter_reflects_elapsed_cycles() {
+        let mut system = started_timer(0, 0);
+        for expected in [100, 250, 1000] {
+            let target = Timestamp::default() + expected;
+            system.scheduler.run_until(target);
+            assert_eq!(system.timer9.read_length(0), expected as u16);
+        }
+    }
+
+    #[test]
+    fn prescaler_divides_elapsed_cycles() {
+        // prescaler 1 counts every 64 cycles
+        let mut system = started_timer(0, 1);
+        advance(&mut system, 64 * 10 + 63);
+        assert_eq!(system.timer9.read_length(0), 10);
+    }
+
+    #[test]
+    fn counter_reloads_on_overflow() {
+        let mut system = started_timer(0xfff0, 0);
+        advance(&mut system, 0x10 + 8);
+        assert_eq!(system.timer9.read_length(0), 0xfff8);
+
+        // two more overflows later the reloads still haven't drifted
+        advance(&mut system, 0x20);
+        assert_eq!(system.timer9.read_length(0), 0xfff8);
+    }
+
+    #[test]
+    fn stopping_latches_the_counter() {
+        let mut system = started_timer(0, 0);
+        advance(&mut system, 50);
+        system.timer9.write_control(0, 0, 0xffff);
+        advance(&mut system, 50);
+        assert_eq!(system.timer9.read_length(0), 50);
+
+        // restarting reloads the counter
+        system.timer9.write_control(0, START, 0xffff);
+        advance(&mut system, 5);
+        assert_eq!(system.timer9.read_length(0), 5);
+    }
+}
@@ -0,0 +1,489 @@
+use log::{error, info};
+
+use crate::core::hardware::irq::IrqSource;
+use crate::core::scheduler::{EventId, Timestamp};
+use crate::core::timing::CLOCK_RATE;
+use crate::core::System;
+use crate::unimplemented_feature;
+use crate::util::{set, Shared, StateReader, StateWriter};
+
+use transport::Transport;
+
+pub mod transport;
+
+const W_ID: u32 = 0x000;
+const W_IF: u32 = 0x010;
+const W_IE: u32 = 0x012;
+const W_RXCNT: u32 = 0x030;
+const W_POWERSTATE: u32 = 0x03c;
+const W_RANDOM: u32 = 0x044;
+const W_RXBUF_BEGIN: u32 = 0x050;
+const W_RXBUF_END: u32 = 0x052;
+const W_RXBUF_WRCSR: u32 = 0x054;
+const W_RXBUF_WR_ADDR: u32 = 0x056;
+const W_RXBUF_RD_ADDR: u32 = 0x058;
+const W_RXBUF_COUNT: u32 = 0x05c;
+const W_RXBUF_RD_DATA: u32 = 0x060;
+const W_RXBUF_GAP: u32 = 0x062;
+const W_RXBUF_GAPDISP: u32 = 0x064;
+const W_TXBUF_WR_ADDR: u32 = 0x068;
+const W_TXBUF_COUNT: u32 = 0x06c;
+const W_TXBUF_WR_DATA: u32 = 0x070;
+const W_TXBUF_GAP: u32 = 0x074;
+const W_TXBUF_GAPDISP: u32 = 0x076;
+const W_TXBUF_LOC1: u32 = 0x0a0;
+const W_TXBUF_LOC2: u32 = 0x0a4;
+const W_TXBUF_LOC3: u32 = 0x0a8;
+const W_TXREQ_RESET: u32 = 0x0ac;
+const W_TXREQ_SET: u32 = 0x0ae;
+const W_TXREQ_READ: u32 = 0x0b0;
+const W_TXBUSY: u32 = 0x0b6;
+const W_TXSTAT: u32 = 0x0b8;
+const W_US_COUNTCNT: u32 = 0x0e8;
+const W_US_COMPARECNT: u32 = 0x0ea;
+const W_US_COUNT0: u32 = 0x0f8;
+const W_US_COUNT3: u32 = 0x0fe;
+const W_BB_CNT: u32 = 0x158;
+const W_BB_WRITE: u32 = 0x15a;
+const W_BB_READ: u32 = 0x15c;
+const W_BB_BUSY: u32 = 0x15e;
+const W_RF_DATA2: u32 = 0x17c;
+const W_RF_DATA1: u32 = 0x17e;
+const W_RF_BUSY: u32 = 0x180;
+const W_RF_CNT: u32 = 0x184;
+
+// W_IF and W_IE bits
+const IRQ_RX_COMPLETE: u16 = 1 << 0;
+const IRQ_TX_COMPLETE: u16 = 1 << 1;
+const IRQ_RX_START: u16 = 1 << 6;
+const IRQ_TX_START: u16 = 1 << 7;
+const IRQ_TXBUF_COUNT: u16 = 1 << 8;
+const IRQ_RXBUF_COUNT: u16 = 1 << 9;
+
+// W_TXREQ bits in order of priority, the command slot is for multiplayer hosts and isn't handled
+const TXREQ_SLOTS: [(u16, u32); 3] = [(1 << 3, W_TXBUF_LOC3), (1 << 2, W_TXBUF_LOC2), (1 << 0, W_TXBUF_LOC1)];
+const TXREQ_CMD: u16 = 1 << 1;
+
+/// Transmit and receive headers in wifi ram are 12 bytes
+const HEADER_SIZE: u32 = 12;
+/// Every frame on air starts with the long preamble
+const PREAMBLE_US: u64 = 192;
+/// Transfer rate values in the headers
+const RATE_2MBIT: u16 = 0x14;
+
+/// Max and min rssi in the receive header, instances on the same machine are always close
+const RX_SIGNAL: u16 = 0x2020;
+
+/// How often frames from other instances are picked up, in system cycles
+const RECEIVE_INTERVAL: u64 = CLOCK_RATE / 1000;
+
+/// The arm7's wifi controller: the register file, 8K of wifi ram holding the transmit slots and the
+/// receive ring, and the baseband and rf chips behind it. Nothing is on the air, frames only reach
+/// other instances when the local transport is enabled
+pub struct Wifi {
+    system: Shared<System>,
+    io: Box<[u16]>,
+    ram: Box<[u8]>,
+    bb: [u8; 0x100],
+    rf: [u32; 0x20],
+    /// 11 bit lfsr behind W_RANDOM
+    random: u16,
+    /// Microsecond counter as of `us_count_time`, it only runs while W_US_COUNTCNT is set
+    us_count: u64,
+    us_count_time: Timestamp,
+    /// W_TXREQ bit of the slot on air
+    transmitting: u16,
+    transport: Option<Transport>,
+    transmit_event: EventId,
+    receive_event: EventId,
+}
+
+impl Wifi {
+    pub fn new(system: &Shared<System>) -> Self {
+        Self {
+            system: system.clone(),
+            io: vec![0; 0x800].into_boxed_slice(),
+            ram: vec![0; 0x2000].into_boxed_slice(),
+            bb: [0; 0x100],
+            rf: [0; 0x20],
+            random: 1,
+            us_count: 0,
+            us_count_time: Timestamp::default(),
+            transmitting: 0,
+            transport: None,
+            transmit_event: Default::default(),
+            receive_event: Default::default(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.io.fill(0);
+        self.ram.fill(0);
+        self.bb = [0; 0x100];
+        self.rf = [0; 0x20];
+        self.random = 1;
+        self.us_count = 0;
+        self.us_count_time = Timestamp::default();
+        self.transmitting = 0;
+
+        // original ds, the ds lite has c340
+        self.io[reg(W_ID)] = 0x1440;
+        self.io[reg(W_POWERSTATE)] = 0x0200;
+        self.io[reg(W_RF_CNT)] = 0x0018;
+        // baseband chip id
+        self.bb[0x00] = 0x6d;
+
+        self.transmit_event = self.system.scheduler.register_event("WiFi Transmit", |system| system.wifi.finish_transmit());
+        self.receive_event = self.system.scheduler.register_event("WiFi Receive", |system| system.wifi.poll_transport());
+        if self.transport.is_some() {
+            self.system.scheduler.add_event(RECEIVE_INTERVAL, self.receive_event);
+        }
+    }
+
+    /// Connects to other instances on this machine, takes effect on the next reset
+    pub fn set_local_transport(&mut self, enabled: bool) {
+        if !enabled {
+            self.transport = None;
+            return;
+        }
+        if self.transport.is_some() {
+            return;
+        }
+
+        match Transport::bind() {
+            Ok(transport) => {
+                info!("WiFi: local transport on port {}", transport.port());
+                self.transport = Some(transport);
+            }
+            Err(e) => error!("WiFi: no local transport: {e}"),
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"WIFI");
+        state.write_slice("io", &self.io);
+        state.write_bytes("ram", &self.ram);
+        state.write_bytes("bb", &self.bb);
+        state.write_slice("rf", &self.rf);
+        state.write("random", self.random);
+        state.write("us_count", self.us_count);
+        state.write("us_count_time", self.us_count_time);
+        state.write("transmitting", self.transmitting);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"WIFI");
+        state.read_slice(&mut self.io);
+        state.read_bytes(&mut self.ram);
+        state.read_bytes(&mut self.bb);
+        state.read_slice(&mut self.rf);
+        self.random = state.read();
+        self.us_count = state.read();
+        self.us_count_time = state.read();
+        self.transmitting = state.read();
+    }
+
+    /// 0x04800000 to 0x04807fff, mirrored above that
+    pub fn read(&mut self, addr: u32) -> u16 {
+        let offset = addr & 0x7fff;
+        match offset {
+            0x4000..=0x5fff => self.read_ram(offset),
+            0x0000..=0x0fff | 0x6000..=0x6fff => self.read_register(offset & 0xffe),
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, val: u16, mask: u16) {
+        let offset = addr & 0x7fff;
+        match offset {
+            0x4000..=0x5fff => {
+                let old = self.read_ram(offset);
+                self.write_ram(offset, (old & !mask) | (val & mask));
+            }
+            0x0000..=0x0fff | 0x6000..=0x6fff => self.write_register(offset & 0xffe, val, mask),
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, offset: u32) -> u16 {
+        match offset {
+            W_RANDOM => {
+                let val = self.random;
+                self.random = (self.random & 0x1) ^ (((self.random & 0x3ff) << 1) | (self.random >> 10));
+                val
+            }
+            W_RXBUF_RD_DATA => self.read_rx_data(),
+            W_US_COUNT0..=W_US_COUNT3 => (self.current_us_count() >> ((offset - W_US_COUNT0) * 8)) as u16,
+            W_BB_BUSY | W_RF_BUSY => 0,
+            _ => self.io[reg(offset)],
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, val: u16, mask: u16) {
+        match offset {
+            W_ID | W_RANDOM | W_RXBUF_RD_DATA | W_TXREQ_READ | W_TXBUSY | W_TXSTAT | W_BB_READ | W_BB_BUSY | W_RF_BUSY => {}
+            // writing 1 acknowledges
+            W_IF => self.io[reg(W_IF)] &= !(val & mask),
+            W_IE => {
+                set(&mut self.io[reg(W_IE)], val, mask);
+                if self.io[reg(W_IF)] & self.io[reg(W_IE)] != 0 {
+                    self.system.arm7.irq.raise(IrqSource::Wifi);
+                }
+            }
+            W_RXCNT => {
+                set(&mut self.io[reg(W_RXCNT)], val, mask);
+                // bit 0 latches the write cursor software set up
+                if val & mask & 0x1 != 0 {
+                    self.io[reg(W_RXBUF_WRCSR)] = self.io[reg(W_RXBUF_WR_ADDR)];
+                }
+            }
+            W_TXREQ_RESET => self.io[reg(W_TXREQ_READ)] &= !(val & mask),
+            W_TXREQ_SET => {
+                self.io[reg(W_TXREQ_READ)] |= val & mask & 0xf;
+                self.start_transmit();
+            }
+            W_TXBUF_WR_DATA => self.write_tx_data(val & mask),
+            W_US_COUNTCNT => {
+                self.latch_us_count();
+                set(&mut self.io[reg(W_US_COUNTCNT)], val, mask & 0x1);
+            }
+            W_US_COUNT0..=W_US_COUNT3 => {
+                self.latch_us_count();
+                let shift = (offset - W_US_COUNT0) * 8;
+                let mask = (mask as u64) << shift;
+                self.us_count = (self.us_count & !mask) | (((val as u64) << shift) & mask);
+            }
+            W_US_COMPARECNT => {
+                set(&mut self.io[reg(W_US_COMPARECNT)], val, mask);
+                if val & mask & 0x1 != 0 {
+                    unimplemented_feature!("WiFi: handle beacon timing");
+                }
+            }
+            W_BB_CNT => {
+                set(&mut self.io[reg(W_BB_CNT)], val, mask);
+                self.transfer_bb();
+            }
+            W_RF_DATA1 => {
+                set(&mut self.io[reg(W_RF_DATA1)], val, mask);
+                self.transfer_rf();
+            }
+            _ => set(&mut self.io[reg(offset)], val, mask),
+        }
+    }
+
+    fn read_ram(&self, addr: u32) -> u16 {
+        let addr = (addr & 0x1ffe) as usize;
+        u16::from_le_bytes([self.ram[addr], self.ram[addr + 1]])
+    }
+
+    fn write_ram(&mut self, addr: u32, val: u16) {
+        let addr = (addr & 0x1ffe) as usize;
+        self.ram[addr..addr + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn raise(&mut self, irq: u16) {
+        self.io[reg(W_IF)] |= irq;
+        if self.io[reg(W_IE)] & irq != 0 {
+            self.system.arm7.irq.raise(IrqSource::Wifi);
+        }
+    }
+
+    /// W_BB_CNT selects a register in the low byte and the direction in the top nibble
+    fn transfer_bb(&mut self) {
+        let cnt = self.io[reg(W_BB_CNT)];
+        let index = (cnt & 0xff) as usize;
+        match cnt >> 12 {
+            0x5 => self.bb[index] = self.io[reg(W_BB_WRITE)] as u8,
+            0x6 => self.io[reg(W_BB_READ)] = self.bb[index] as u16,
+            _ => {}
+        }
+    }
+
+    /// Writing W_RF_DATA1 sends the 24 bit command in W_RF_DATA2:W_RF_DATA1: a read flag, a 5 bit
+    /// register index and 18 bits of data
+    fn transfer_rf(&mut self) {
+        let command = ((self.io[reg(W_RF_DATA2)] as u32) << 16) | self.io[reg(W_RF_DATA1)] as u32;
+        let index = ((command >> 18) & 0x1f) as usize;
+        if command & (1 << 23) != 0 {
+            let data = self.rf[index];
+            self.io[reg(W_RF_DATA1)] = data as u16;
+            self.io[reg(W_RF_DATA2)] = (self.io[reg(W_RF_DATA2)] & !0x3) | ((data >> 16) & 0x3) as u16;
+        } else {
+            self.rf[index] = command & 0x3ffff;
+        }
+    }
+
+    fn current_us_count(&self) -> u64 {
+        if self.io[reg(W_US_COUNTCNT)] & 0x1 == 0 {
+            return self.us_count;
+        }
+
+        let elapsed = self.system.scheduler.get_current_time() - self.us_count_time;
+        self.us_count + elapsed * 1_000_000 / CLOCK_RATE
+    }
+
+    fn latch_us_count(&mut self) {
+        self.us_count = self.current_us_count();
+        self.us_count_time = self.system.scheduler.get_current_time();
+    }
+
+    /// Software fills the transmit slots through W_TXBUF_WR_DATA, skipping the gap like the receive side
+    fn write_tx_data(&mut self, val: u16) {
+        let addr = self.io[reg(W_TXBUF_WR_ADDR)] as u32;
+        self.write_ram(addr, val);
+
+        let mut next = (addr + 2) & 0x1ffe;
+        if next == self.io[reg(W_TXBUF_GAP)] as u32 & 0x1ffe {
+            next = (next + self.io[reg(W_TXBUF_GAPDISP)] as u32 * 2) & 0x1ffe;
+        }
+        self.io[reg(W_TXBUF_WR_ADDR)] = next as u16;
+
+        if self.io[reg(W_TXBUF_COUNT)] > 0 {
+            self.io[reg(W_TXBUF_COUNT)] -= 1;
+            if self.io[reg(W_TXBUF_COUNT)] == 0 {
+                self.raise(IRQ_TXBUF_COUNT);
+            }
+        }
+    }
+
+    fn read_rx_data(&mut self) -> u16 {
+        let addr = self.io[reg(W_RXBUF_RD_ADDR)] as u32;
+        let val = self.read_ram(addr);
+
+        let mut next = self.next_rx_addr(addr);
+        if next == self.io[reg(W_RXBUF_GAP)] as u32 & 0x1ffe {
+            next = (next + self.io[reg(W_RXBUF_GAPDISP)] as u32 * 2) & 0x1ffe;
+        }
+        self.io[reg(W_RXBUF_RD_ADDR)] = next as u16;
+
+        if self.io[reg(W_RXBUF_COUNT)] > 0 {
+            self.io[reg(W_RXBUF_COUNT)] -= 1;
+            if self.io[reg(W_RXBUF_COUNT)] == 0 {
+                self.raise(IRQ_RXBUF_COUNT);
+            }
+        }
+        val
+    }
+
+    /// The receive ring runs from W_RXBUF_BEGIN up to W_RXBUF_END, both byte addresses in 0x4000..0x6000
+    fn next_rx_addr(&self, addr: u32) -> u32 {
+        let next = (addr + 2) & 0x1ffe;
+        if next == self.io[reg(W_RXBUF_END)] as u32 & 0x1ffe {
+            self.io[reg(W_RXBUF_BEGIN)] as u32 & 0x1ffe
+        } else {
+            next
+        }
+    }
+
+    /// Puts the highest priority enabled slot on air. A slot in W_TXBUF_LOCx is enabled by bit 15
+    /// and points at a 12 byte header followed by the frame, in halfwords
+    fn start_transmit(&mut self) {
+        if self.transmitting != 0 {
+            return;
+        }
+
+        let requested = self.io[reg(W_TXREQ_READ)];
+        if requested & TXREQ_CMD != 0 {
+            unimplemented_feature!("WiFi: handle multiplayer command transfers");
+        }
+
+        let Some(&(slot, loc)) = TXREQ_SLOTS.iter().find(|&&(slot, loc)| requested & slot != 0 && self.io[reg(loc)] & 0x8000 != 0) else {
+            return;
+        };
+
+        let header = (self.io[reg(loc)] as u32 & 0xfff) * 2;
+        let rate = self.read_ram(header + 0x8) & 0xff;
+        let length = (self.read_ram(header + 0xa) & 0x3fff) as u64;
+        let bits_per_us = if rate == RATE_2MBIT { 2 } else { 1 };
+        let duration = PREAMBLE_US + length * 8 / bits_per_us;
+
+        self.transmitting = slot;
+        self.io[reg(W_TXBUSY)] |= slot;
+        self.raise(IRQ_TX_START);
+        self.system.scheduler.add_event(duration * CLOCK_RATE / 1_000_000, self.transmit_event);
+    }
+
+    fn finish_transmit(&mut self) {
+        let slot = std::mem::take(&mut self.transmitting);
+        let Some(&(_, loc)) = TXREQ_SLOTS.iter().find(|&&(s, _)| s == slot) else {
+            return;
+        };
+
+        let header = (self.io[reg(loc)] as u32 & 0xfff) * 2;
+        // the length in the header counts the fcs, which the hardware appends
+        let length = (self.read_ram(header + 0xa) & 0x3fff).saturating_sub(4) as u32;
+        if let Some(transport) = &self.transport {
+            let frame: Vec<u8> = (0..length).map(|i| self.ram[((header + HEADER_SIZE + i) & 0x1fff) as usize]).collect();
+            transport.send(&frame);
+        }
+
+        self.write_ram(header, 0x0001);
+        self.io[reg(loc)] &= !0x8000;
+        self.io[reg(W_TXREQ_READ)] &= !slot;
+        self.io[reg(W_TXBUSY)] &= !slot;
+        self.io[reg(W_TXSTAT)] = 0x0001;
+        self.raise(IRQ_TX_COMPLETE);
+
+        self.start_transmit();
+    }
+
+    fn poll_transport(&mut self) {
+        let Some(transport) = &mut self.transport else {
+            return;
+        };
+
+        let mut frames = vec![];
+        while let Some(frame) = transport.receive() {
+            frames.push(frame);
+        }
+        for frame in frames {
+            self.receive(&frame);
+        }
+
+        self.system.scheduler.add_event(RECEIVE_INTERVAL, self.receive_event);
+    }
+
+    /// Writes a header and the frame at W_RXBUF_WRCSR, which is in halfwords, and moves it past the
+    /// frame rounded up to a word. Frames are dropped while receiving is off
+    fn receive(&mut self, frame: &[u8]) {
+        if self.io[reg(W_RXCNT)] & 0x8000 == 0 || frame.len() > 0x1000 {
+            return;
+        }
+
+        self.raise(IRQ_RX_START);
+
+        let mut addr = (self.io[reg(W_RXBUF_WRCSR)] as u32 & 0xfff) * 2;
+        let header = [rx_frame_kind(frame), 0x0000, 0x0000, RATE_2MBIT, frame.len() as u16, RX_SIGNAL];
+        let words = header.into_iter().chain(frame.chunks(2).map(|pair| u16::from_le_bytes([pair[0], pair.get(1).copied().unwrap_or(0)])));
+
+        let mut written = 0;
+        for val in words {
+            self.write_ram(addr, val);
+            addr = self.next_rx_addr(addr);
+            written += 1;
+        }
+        if written % 2 != 0 {
+            addr = self.next_rx_addr(addr);
+        }
+
+        self.io[reg(W_RXBUF_WRCSR)] = (addr / 2) as u16;
+        self.raise(IRQ_RX_COMPLETE);
+    }
+}
+
+/// Low bits of the receive header flags, as far as the frame control field tells
+fn rx_frame_kind(frame: &[u8]) -> u16 {
+    let control = frame.first().copied().unwrap_or(0);
+    match ((control >> 2) & 0x3, control >> 4) {
+        // beacons are told apart from the other management frames
+        (0, 0x8) => 0x1,
+        (0, _) => 0x0,
+        (2, _) => 0x8,
+        _ => 0x0,
+    }
+}
+
+/// Index into the register file
+const fn reg(offset: u32) -> usize {
+    (offset >> 1) as usize
+}
@@ -0,0 +1,55 @@
+use std::net::{Ipv4Addr, UdpSocket};
+
+/// Instances on this machine each take the first free port in the range
+const BASE_PORT: u16 = 7064;
+const MAX_INSTANCES: u16 = 4;
+/// Every datagram starts with this, so unrelated traffic on the ports is dropped
+const MAGIC: [u8; 4] = *b"ESWF";
+
+/// Passes 802.11 frames between emulator instances on the same machine over localhost udp. Frames
+/// go to every other port in the range, which is as close to a shared radio channel as it gets
+pub struct Transport {
+    socket: UdpSocket,
+    port: u16,
+    buffer: Box<[u8]>,
+}
+
+impl Transport {
+    pub fn bind() -> Result<Self, String> {
+        for port in BASE_PORT..BASE_PORT + MAX_INSTANCES {
+            if let Ok(socket) = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)) {
+                socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+                return Ok(Self { socket, port, buffer: vec![0; 0x2000].into_boxed_slice() });
+            }
+        }
+
+        Err(format!("ports {BASE_PORT} to {} are all in use", BASE_PORT + MAX_INSTANCES - 1))
+    }
+
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send(&self, frame: &[u8]) {
+        let mut packet = Vec::with_capacity(MAGIC.len() + frame.len());
+        packet.extend_from_slice(&MAGIC);
+        packet.extend_from_slice(frame);
+
+        for port in BASE_PORT..BASE_PORT + MAX_INSTANCES {
+            if port != self.port {
+                // nobody listening isn't an error, the frame is lost like it would be over the air
+                let _ = self.socket.send_to(&packet, (Ipv4Addr::LOCALHOST, port));
+            }
+        }
+    }
+
+    /// The next frame another instance sent, `None` once nothing is waiting
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (len, _) = self.socket.recv_from(&mut self.buffer).ok()?;
+            if let Some(frame) = self.buffer[..len].strip_prefix(&MAGIC) {
+                return Some(frame.to_vec());
+            }
+        }
+    }
+}
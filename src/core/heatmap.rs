@@ -0,0 +1,41 @@
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_COUNT: usize = 0x400000 >> PAGE_SHIFT;
+
+/// Per 4KB page access counts for main memory.
+/// While enabled main memory is unmapped from the cpu page tables so every access goes through the slow path.
+pub struct Heatmap {
+    enabled: bool,
+    pub reads: Box<[u32; PAGE_COUNT]>,
+    pub writes: Box<[u32; PAGE_COUNT]>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            reads: Box::new([0; PAGE_COUNT]),
+            writes: Box::new([0; PAGE_COUNT]),
+        }
+    }
+
+    pub const fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.fill(0);
+        self.writes.fill(0);
+    }
+
+    pub fn record_read(&mut self, addr: u32) {
+        self.reads[((addr & 0x3fffff) >> PAGE_SHIFT) as usize] += 1;
+    }
+
+    pub fn record_write(&mut self, addr: u32) {
+        self.writes[((addr & 0x3fffff) >> PAGE_SHIFT) as usize] += 1;
+    }
+}
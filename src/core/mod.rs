@@ -1,30 +1,59 @@
-use log::debug;
+use std::ops::{Deref, DerefMut};
 
-use crate::arm::cpu::Arch;
+use log::{debug, error, warn};
+
+use crate::arm::cpu::{Arch, Cpu};
 use crate::arm::memory::Memory;
+use crate::arm::trace::TraceFormat;
 use crate::core::arm7::Arm7;
 use crate::core::arm9::Arm9;
-use crate::core::config::{BootMode, Config};
-use crate::core::hardware::cartridge::Cartridge;
+use crate::core::config::{AccuracyConfig, BatteryLevel, BootMode, ClockScale, Config, ScreenOrder};
+use crate::core::debugger::{Debugger, StepCondition};
+use crate::core::heatmap::Heatmap;
+use crate::core::hardware::cartridge::save::SaveFormat;
+use crate::core::hardware::cartridge::{Cartridge, RomId};
 use crate::core::hardware::dma::Dma;
+use crate::core::hardware::firmware::{Language, UserSettings};
 use crate::core::hardware::input::Input;
 use crate::core::hardware::ipc::Ipc;
+use crate::core::hardware::irq::IrqSource;
 use crate::core::hardware::math_unit::MathUnit;
 use crate::core::hardware::rtc::Rtc;
 use crate::core::hardware::spi::Spi;
 use crate::core::hardware::spu::Spu;
 use crate::core::hardware::timer::Timers;
-use crate::core::scheduler::Scheduler;
+use crate::core::hardware::wifi::Wifi;
+use crate::core::scheduler::{Scheduler, Timestamp};
+use crate::core::timing::{CYCLES_PER_FRAME, SAMPLE_RATE};
 use crate::core::video::VideoUnit;
-use crate::util::Shared;
+use crate::unimplemented_feature;
+use crate::util::{clear_unimplemented_hits, Shared, StateLayout, StateReader, StateWriter};
 
 pub mod arm7;
 pub mod arm9;
+pub mod bios;
 pub mod config;
+pub mod debugger;
 pub mod hardware;
+pub mod heatmap;
 pub mod scheduler;
+pub mod timing;
 pub mod video;
 
+/// EXMEMCNT bits the arm9 can write: the gba slot timings (0-6) and access (7), nds slot access (11), and the main
+/// memory interface mode (14) and priority (15). The main memory bits are only kept for reads, bus contention isn't emulated
+const EXMEMCNT_WRITE_MASK: u16 = 0xc8ff;
+const EXMEMCNT_ALWAYS_SET: u16 = 1 << 13;
+const EXMEMCNT_SYNCHRONOUS: u16 = 1 << 14;
+/// EXMEMSTAT bits the arm7 can write, its own gba slot timings
+const EXMEMSTAT_WRITE_MASK: u16 = 0x7f;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StopReason {
+    PoweredOff,
+    GbaMode,
+}
+
 pub struct System {
     pub arm7: Arm7,
     pub arm9: Arm9,
@@ -40,7 +69,7 @@ pub struct System {
     spi: Spi,
     timer7: Timers,
     timer9: Timers,
-    // wifi: (),
+    wifi: Wifi,
     scheduler: Scheduler,
 
     main_memory: Box<[u8]>,
@@ -51,26 +80,60 @@ pub struct System {
     exmemcnt: u16,
     exmemstat: u16,
     config: Config,
+    stop_reason: Option<StopReason>,
+    arm9_half_cycle: bool,
+    /// Fractional cycles left over from scaling the arm9 and arm7 clocks
+    clock_remainder: [u64; 2],
+    pub debugger: Debugger,
+    pub heatmap: Heatmap,
+}
+
+/// The system `System::new` hands out, which owns it and through it every component. Frontends can move
+/// it to an emulation thread, the components themselves are only ever used from the thread it is on
+pub struct OwnedSystem(Shared<System>);
+
+// SAFETY: the components hold `Shared` handles with non atomic counts to each other and to the system, and
+// the page tables and tcms hold raw pointers into memory the system owns, none of which is Send. Every one of
+// them points into the graph under this handle. `System::new` is the only way to build the graph, handles can
+// only be cloned inside the crate and only ever into other parts of the same graph, and this handle derefs to
+// the system instead of giving out its `Shared`. Moving it therefore moves every handle to the graph at once
+// and leaves nothing on the old thread that could reach it. Threaded video only lends a ppu to a render thread
+// while the video unit waits for it to come back
+unsafe impl Send for OwnedSystem {}
+
+impl Deref for OwnedSystem {
+    type Target = System;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for OwnedSystem {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
 }
 
 impl System {
-    pub fn new() -> Shared<Self> {
-        Shared::new_cyclic(|system| {
+    pub fn new() -> OwnedSystem {
+        OwnedSystem(Shared::new_cyclic(|system| {
             let arm7 = Arm7::new(system);
             let arm9 = Arm9::new(system);
             Self {
                 cartridge: Cartridge::new(system),
                 video_unit: VideoUnit::new(system, &arm7.irq, &arm9.irq),
                 input: Input::new(),
-                spu: Spu::new(),
+                spu: Spu::new(system),
                 dma7: Dma::new(Arch::ARMv4, system),
                 dma9: Dma::new(Arch::ARMv5, system),
                 ipc: Ipc::new(&arm7.irq, &arm9.irq),
-                math_unit: MathUnit::default(),
-                rtc: Rtc::new(),
+                math_unit: MathUnit::new(system),
+                rtc: Rtc::new(system),
                 spi: Spi::new(system),
                 timer7: Timers::new(system, &arm7.irq),
                 timer9: Timers::new(system, &arm9.irq),
+                wifi: Wifi::new(system),
                 scheduler: Scheduler::new(system),
                 main_memory: vec![0; 0x400000].into_boxed_slice(),
                 shared_wram: vec![0; 0x8000].into_boxed_slice(),
@@ -79,30 +142,157 @@ impl System {
                 exmemcnt: 0,
                 exmemstat: 0,
                 config: Config::default(),
+                stop_reason: None,
+                arm9_half_cycle: false,
+                clock_remainder: [0; 2],
+                debugger: Debugger::default(),
+                heatmap: Heatmap::new(),
                 arm7,
                 arm9,
             }
-        })
+        }))
     }
 
     pub fn reset(&mut self) {
+        self.scheduler.reset();
+        self.main_memory.fill(0);
+        self.shared_wram.fill(0);
+        self.input.reset();
+        self.ipc.reset();
         self.arm7.reset();
         self.arm9.reset();
+        self.cartridge.reset();
         self.cartridge.load(&self.config.game_path);
         self.video_unit.reset();
+        self.set_accuracy(self.config.accuracy);
         self.dma7.reset();
         self.dma9.reset();
         self.spi.reset();
         self.timer7.reset(Arch::ARMv4);
         self.timer9.reset(Arch::ARMv5);
+        self.math_unit.reset();
         self.spu.reset();
         self.rtc.reset();
+        self.wifi.reset();
+        self.stop_reason = None;
+        self.arm9_half_cycle = false;
+        self.clock_remainder = [0; 2];
+        self.start_traces();
+        // unimplemented features are reported per boot, so the list shows what this game needs
+        clear_unimplemented_hits();
         match self.config.boot_mode {
             BootMode::Firmware => todo!(),
             BootMode::Direct => self.direct_boot(),
         }
     }
 
+    /// Resets the console like the power button would, but keeps the in-memory backup data
+    /// so unsaved progress isn't replaced by what's on disk
+    pub fn soft_reset(&mut self) {
+        let backup = self.cartridge.take_backup_data();
+        self.reset();
+        self.cartridge.restore_backup_data(backup);
+        debug!("System: soft reset");
+    }
+
+    /// Snapshot of everything needed to resume emulation, the rom and bios are expected to be the same on load
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.write_state(&mut state);
+        state.finish()
+    }
+
+    /// `save_state` reusing the allocation of `buffer`, an old state that's no longer needed
+    pub fn save_state_into(&mut self, buffer: Vec<u8>) -> Vec<u8> {
+        let mut state = StateWriter::with_buffer(buffer);
+        self.write_state(&mut state);
+        state.finish()
+    }
+
+    /// Where each field ends up in a state of this build. Sections whose length depends on what's
+    /// going on, like pending events, only line up with states that have as many
+    pub fn state_layout(&mut self) -> StateLayout {
+        let mut state = StateWriter::with_layout();
+        self.write_state(&mut state);
+        state.finish_with_layout().1
+    }
+
+    fn write_state(&mut self, state: &mut StateWriter) {
+        state.section(b"SYS ");
+        state.write_bytes("main_memory", &self.main_memory);
+        state.write_bytes("shared_wram", &self.shared_wram);
+        state.write("wramcnt", self.wramcnt);
+        state.write("haltcnt", self.haltcnt);
+        state.write("exmemcnt", self.exmemcnt);
+        state.write("exmemstat", self.exmemstat);
+        state.write_bool("arm9_half_cycle", self.arm9_half_cycle);
+        state.write_slice("clock_remainder", &self.clock_remainder);
+
+        self.scheduler.save_state(state);
+        self.arm7.save_state(state);
+        self.arm9.save_state(state);
+        self.cartridge.save_state(state);
+        self.video_unit.save_state(state);
+        self.dma7.save_state(state);
+        self.dma9.save_state(state);
+        self.ipc.save_state(state);
+        self.math_unit.save_state(state);
+        self.rtc.save_state(state);
+        self.spi.save_state(state);
+        self.timer7.save_state(state);
+        self.timer9.save_state(state);
+        self.spu.save_state(state);
+        self.wifi.save_state(state);
+    }
+
+    /// Restores a state made by `save_state`. If it turns out to be broken halfway through,
+    /// the state from before the load is put back so emulation can carry on
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data)?;
+        let backup = self.save_state();
+
+        self.apply_state(&mut state);
+        if let Err(error) = state.finish() {
+            let mut state = StateReader::new(&backup).unwrap();
+            self.apply_state(&mut state);
+            return Err(error);
+        }
+
+        self.stop_reason = None;
+        debug!("System: loaded state");
+        Ok(())
+    }
+
+    fn apply_state(&mut self, state: &mut StateReader) {
+        state.section(b"SYS ");
+        state.read_bytes(&mut self.main_memory);
+        state.read_bytes(&mut self.shared_wram);
+        self.wramcnt = state.read();
+        self.haltcnt = state.read();
+        self.exmemcnt = state.read();
+        self.exmemstat = state.read();
+        self.arm9_half_cycle = state.read_bool();
+        state.read_slice(&mut self.clock_remainder);
+
+        self.scheduler.load_state(state);
+        self.arm7.load_state(state);
+        self.arm9.load_state(state);
+        self.arm7.update_wram_mapping();
+        self.arm9.update_wram_mapping();
+        self.cartridge.load_state(state);
+        self.video_unit.load_state(state);
+        self.dma7.load_state(state);
+        self.dma9.load_state(state);
+        self.ipc.load_state(state);
+        self.math_unit.load_state(state);
+        self.rtc.load_state(state);
+        self.spi.load_state(state);
+        self.timer7.load_state(state);
+        self.timer9.load_state(state);
+        self.spu.load_state(state);
+        self.wifi.load_state(state);
+    }
+
     pub fn set_game_path(&mut self, path: &str) {
         self.config.game_path = path.to_string();
     }
@@ -111,23 +301,257 @@ impl System {
         self.config.boot_mode = boot_mode;
     }
 
+    pub fn set_firmware_path(&mut self, path: Option<&str>) {
+        self.config.firmware_path = path.map(str::to_string);
+    }
+
+    /// Boots without the bios dumps even when they're there. Takes effect on the next reset
+    pub fn set_hle_bios(&mut self, enabled: bool) {
+        self.config.hle_bios = enabled;
+    }
+
+    /// Lets wifi frames reach other instances on this machine, for local multiplayer. Takes effect on the next reset
+    pub fn set_local_wifi(&mut self, enabled: bool) {
+        self.wifi.set_local_transport(enabled);
+    }
+
+    /// Takes effect on the next reset
+    pub fn set_trace_format(&mut self, format: TraceFormat) {
+        self.config.trace_format = format;
+    }
+
+    /// Every reset starts the traces over, so they line up with traces of other runs from boot
+    #[cfg(feature = "log_state")]
+    fn start_traces(&mut self) {
+        use crate::arm::trace::Tracer;
+
+        let format = self.config.trace_format;
+        self.arm7.cpu.set_tracer(Tracer::new("ARMv4.log", format).ok());
+        self.arm9.cpu.set_tracer(Tracer::new("ARMv5.log", format).ok());
+    }
+
+    #[cfg(not(feature = "log_state"))]
+    fn start_traces(&mut self) {}
+
+    pub fn set_threaded_video(&mut self, threaded: bool) {
+        self.config.threaded_video = threaded;
+    }
+
+    pub fn set_screen_order(&mut self, order: ScreenOrder) {
+        self.config.screen_order = order;
+    }
+
+    pub const fn screen_order(&self) -> ScreenOrder {
+        self.config.screen_order
+    }
+
+    pub fn game_title(&self) -> String {
+        self.cartridge.title()
+    }
+
+    pub const fn rom_id(&self) -> RomId {
+        self.cartridge.rom_id()
+    }
+
+    /// Whether another instance owns the save of the loaded game
+    pub const fn save_locked(&self) -> bool {
+        self.cartridge.save_locked()
+    }
+
+    pub fn export_save(&self, format: SaveFormat) {
+        self.cartridge.export_save(format)
+    }
+
+    pub const fn accuracy(&self) -> AccuracyConfig {
+        self.config.accuracy
+    }
+
+    /// Safe to change at any time, every switch takes effect on the next access or scanline
+    pub fn set_accuracy(&mut self, accuracy: AccuracyConfig) {
+        self.config.accuracy = accuracy;
+        self.video_unit.ppu_a.obj_cycle_limit = accuracy.obj_cycle_limit;
+        self.video_unit.ppu_b.obj_cycle_limit = accuracy.obj_cycle_limit;
+        self.video_unit.ppu_a.bg_enable_delay = accuracy.bg_enable_delay;
+        self.video_unit.ppu_b.bg_enable_delay = accuracy.bg_enable_delay;
+        self.arm7.cpu.set_timing(accuracy.cpu_timing);
+        self.arm9.cpu.set_timing(accuracy.cpu_timing);
+    }
+
+    /// Takes effect from the next slice, the fractional cycle carried over at the old speed is dropped
+    pub fn set_arm9_clock(&mut self, clock: ClockScale) {
+        self.config.arm9_clock = clock;
+        self.clock_remainder[0] = 0;
+    }
+
+    pub fn set_arm7_clock(&mut self, clock: ClockScale) {
+        self.config.arm7_clock = clock;
+        self.clock_remainder[1] = 0;
+    }
+
+    pub fn set_language(&mut self, language: Option<Language>) {
+        self.config.language = language;
+    }
+
+    pub fn set_battery_level(&mut self, level: BatteryLevel) {
+        self.config.battery_level = level;
+    }
+
+    pub fn user_settings(&self) -> UserSettings {
+        self.spi.user_settings()
+    }
+
+    pub fn set_user_settings(&mut self, settings: &UserSettings) {
+        self.spi.set_user_settings(settings)
+    }
+
+    /// Persists changed user settings, the frontend calls this once an edit is done rather than on every change
+    pub fn save_user_settings(&mut self) {
+        self.spi.save_user_settings()
+    }
+
+    pub fn power_off(&mut self) {
+        warn!("System: powered off by software");
+        self.stop_reason = Some(StopReason::PoweredOff);
+    }
+
+    /// Why emulation stopped, if it did
+    pub const fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
     pub fn run_frame(&mut self) {
-        let frame_end = self.scheduler.get_current_time() + 560190;
-        while self.scheduler.get_current_time() < frame_end {
-            let mut cycles = self.scheduler.get_event_time() - self.scheduler.get_current_time();
+        if self.stop_reason.is_some() {
+            return;
+        }
 
-            if !self.arm7.cpu.is_halted() || !self.arm9.is_halted() {
-                cycles = cycles.min(16);
+        self.input.latch();
+        let frame_end = self.scheduler.get_current_time() + CYCLES_PER_FRAME;
+        self.run_until(frame_end);
+        self.spu.run(self.scheduler.get_current_time());
+        self.video_unit.on_finish_frame();
+        self.cartridge.flush_backup();
+    }
+
+    /// Stereo samples per second the spu produces
+    pub const fn audio_sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    /// How many stereo samples the last `run_frame` queued. A frame is 560190 cycles and a sample
+    /// 1024, so this is 547 with a 548 about every 16 frames, averaging `audio_sample_rate` per second of emulated time
+    pub const fn samples_per_frame(&self) -> usize {
+        self.spu.last_run_samples()
+    }
+
+    /// Pulls queued audio into `out` as interleaved left/right i16 pairs and returns how many pairs were real samples,
+    /// the rest is silence. Frontends call this after every `run_frame` with room for at least `samples_per_frame` pairs,
+    /// or from their audio callback, since the queue only holds a few frames worth before dropping samples
+    pub fn fill_audio(&mut self, out: &mut [i16]) -> usize {
+        self.spu.drain(out)
+    }
+
+    /// Runs both cpus and the scheduler until the scheduler reaches `target` cycles, without overshooting it.
+    /// Returns early when a cpu stops on a breakpoint
+    pub fn run_until(&mut self, target: Timestamp) {
+        while self.scheduler.get_current_time() < target
+            && self.stop_reason.is_none()
+            && self.breakpoint_hit().is_none()
+            && !self.debugger.is_hit()
+        {
+            self.run_slice(target);
+        }
+    }
+
+    /// Steps `count` instructions on one cpu, keeping the other cpu and the scheduler in lockstep.
+    /// The arm9 runs two instructions per system cycle, an odd count leaves the last half cycle pending.
+    /// Instruction stepping ignores the configured cpu clocks
+    pub fn run_instructions(&mut self, arch: Arch, count: u64) {
+        for _ in 0..count {
+            if self.stop_reason.is_some() {
+                break;
             }
 
-            self.arm9.run(2 * cycles);
-            self.arm7.run(cycles);
-            self.scheduler.tick(cycles);
-            self.scheduler.run();
+            match arch {
+                Arch::ARMv4 => {
+                    self.arm9.run(if self.arm9_half_cycle { 1 } else { 2 });
+                    self.arm9_half_cycle = false;
+                }
+                Arch::ARMv5 => {
+                    self.arm9.run(1);
+                    self.arm9_half_cycle ^= true;
+                    if self.arm9_half_cycle {
+                        continue;
+                    }
+                }
+            }
+
+            self.arm7.run(1);
+            let next = self.scheduler.get_current_time() + 1;
+            self.scheduler.run_until(next);
         }
+    }
+
+    /// Steps one instruction on `arch` like `run_instructions` and leaves it paused there, while
+    /// the other cpu keeps running with the rest of the system
+    pub fn step_cpu(&mut self, arch: Arch) {
+        self.cpu(arch).set_paused(false);
+        self.cpu(arch).resume();
+        self.run_instructions(arch, 1);
+        self.cpu(arch).set_paused(true);
+    }
+
+    /// Arms `conditions` and returns right away. From then on `run_frame` and `run_until` stop on the cycle
+    /// any of them is met, however many frames that takes, and `step_hit` stays set until `cancel_step`
+    pub fn step_until(&mut self, conditions: &[StepCondition]) {
+        let ranges = conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                StepCondition::BranchTo(range) => Some(range.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        self.arm7.cpu.set_branch_watch(&ranges);
+        self.arm9.cpu.set_branch_watch(&ranges);
+        self.debugger.arm(conditions);
+    }
 
-        self.video_unit.ppu_a.on_finish_frame();
-        self.video_unit.ppu_b.on_finish_frame();
+    pub const fn step_hit(&self) -> bool {
+        self.debugger.is_hit()
+    }
+
+    /// Drops the armed conditions, after a hit this lets emulation carry on
+    pub fn cancel_step(&mut self) {
+        self.debugger.disarm();
+        self.arm7.cpu.set_branch_watch(&[]);
+        self.arm9.cpu.set_branch_watch(&[]);
+    }
+
+    fn run_slice(&mut self, target: Timestamp) {
+        let mut cycles = self.scheduler.get_event_time().min(target) - self.scheduler.get_current_time();
+
+        if !self.arm7.cpu.is_halted() || !self.arm9.is_halted() {
+            cycles = cycles.min(16);
+        }
+
+        // a cycle at a time while stepping, so nothing runs past the cycle a condition is met on
+        if self.debugger.is_armed() {
+            cycles = cycles.min(1);
+        }
+
+        // the arm9 may already be half a cycle ahead from run_instructions
+        let arm9_cycles = self.config.arm9_clock.scale(2 * cycles, &mut self.clock_remainder[0]);
+        if self.arm9_half_cycle && arm9_cycles != 0 {
+            self.arm9.run(arm9_cycles - 1);
+            self.arm9_half_cycle = false;
+        } else {
+            self.arm9.run(arm9_cycles);
+        }
+        self.arm7.run(self.config.arm7_clock.scale(cycles, &mut self.clock_remainder[1]));
+        if self.arm7.cpu.watch_hit() || self.arm9.cpu.watch_hit() {
+            self.debugger.set_hit();
+        }
+        let end = self.scheduler.get_current_time() + cycles;
+        self.scheduler.run_until(end);
     }
 
     // pub fn step(&mut self) {
@@ -141,6 +565,9 @@ impl System {
 
     fn direct_boot(&mut self) {
         self.write_wramcnt(0x03);
+        // both slots start out with the arm9 and main memory in synchronous mode
+        self.exmemcnt = EXMEMCNT_ALWAYS_SET | EXMEMCNT_SYNCHRONOUS;
+        self.exmemstat = 0;
 
         self.cartridge.direct_boot();
         self.arm7.direct_boot();
@@ -150,6 +577,12 @@ impl System {
         debug!("System: direct booted successfully")
     }
 
+    pub fn set_heatmap_enabled(&mut self, enabled: bool) {
+        self.heatmap.set_enabled(enabled);
+        self.arm7.update_main_memory_mapping();
+        self.arm9.update_main_memory_mapping();
+    }
+
     fn write_wramcnt(&mut self, val: u8) {
         self.wramcnt = val & 0x3;
         self.arm7.update_wram_mapping();
@@ -160,6 +593,24 @@ impl System {
         self.wramcnt
     }
 
+    pub fn cpu(&mut self, arch: Arch) -> &mut Cpu {
+        match arch {
+            Arch::ARMv4 => &mut self.arm7.cpu,
+            Arch::ARMv5 => &mut self.arm9.cpu,
+        }
+    }
+
+    /// The cpu sitting on a breakpoint, nothing runs until it's resumed
+    pub fn breakpoint_hit(&self) -> Option<Arch> {
+        if self.arm9.cpu.breakpoint_hit() {
+            Some(Arch::ARMv5)
+        } else if self.arm7.cpu.breakpoint_hit() {
+            Some(Arch::ARMv4)
+        } else {
+            None
+        }
+    }
+
     pub fn get_memory(&mut self, arch: Arch) -> &mut dyn Memory {
         match arch {
             Arch::ARMv4 => self.arm7.get_memory(),
@@ -170,9 +621,18 @@ impl System {
     pub fn write_haltcnt(&mut self, val: u8) {
         self.haltcnt = val & 0xc0;
         match (self.haltcnt >> 6) & 0x3 {
+            0x0 => {}
+            0x1 => {
+                if self.config.gba_mode {
+                    unimplemented_feature!("System: switch to gba mode");
+                } else {
+                    error!("System: the arm7 requested gba mode, which is not supported");
+                }
+                self.stop_reason = Some(StopReason::GbaMode);
+            }
             0x2 => self.arm7.cpu.update_halted(true),
             0x3 => todo!(),
-            _ => todo!(),
+            _ => unreachable!(),
         }
     }
 
@@ -180,15 +640,96 @@ impl System {
         self.exmemcnt
     }
 
+    /// Which cpu has access to the nds slot, selected by EXMEMCNT bit 11
+    pub const fn nds_slot_owner(&self) -> Arch {
+        if self.exmemcnt & (1 << 11) != 0 {
+            Arch::ARMv4
+        } else {
+            Arch::ARMv5
+        }
+    }
+
+    /// Which cpu has access to the gba slot, selected by EXMEMCNT bit 7
+    pub const fn gba_slot_owner(&self) -> Arch {
+        if self.exmemcnt & (1 << 7) != 0 {
+            Arch::ARMv4
+        } else {
+            Arch::ARMv5
+        }
+    }
+
+    /// Halfword `arch` reads from the gba slot. The cpu without access sees zeroes,
+    /// an empty slot floats high and the stub device drives the bus low
+    pub fn read_gba_slot(&self, arch: Arch, _addr: u32) -> u16 {
+        if self.gba_slot_owner() != arch || self.config.slot2_inserted {
+            0
+        } else {
+            0xffff
+        }
+    }
+
+    /// Byte `arch` reads from the gba slot sram region, which has an 8 bit bus. Same rules as the rom region
+    pub fn read_gba_slot_sram(&self, arch: Arch, addr: u32) -> u8 {
+        self.read_gba_slot(arch, addr) as u8
+    }
+
+    /// Inserts or pulls the gba slot device at runtime. Pulling it raises the gba slot irq
+    /// on the cpu with access, which is how games notice the removal
+    pub fn set_slot2_inserted(&mut self, inserted: bool) {
+        let removed = self.config.slot2_inserted && !inserted;
+        self.config.slot2_inserted = inserted;
+
+        if removed {
+            debug!("System: gba slot device removed");
+            match self.gba_slot_owner() {
+                Arch::ARMv4 => self.arm7.get_irq().raise(IrqSource::GbaSlot),
+                Arch::ARMv5 => self.arm9.get_irq().raise(IrqSource::GbaSlot),
+            }
+        }
+    }
+
+    pub const fn slot2_inserted(&self) -> bool {
+        self.config.slot2_inserted
+    }
+
+    /// Bits 8-10 and 12 don't exist and bit 13 is always set
     pub fn write_exmemcnt(&mut self, val: u16, mask: u16) {
-        self.exmemcnt = (self.exmemcnt & !mask) | (val & mask)
+        let mask = mask & EXMEMCNT_WRITE_MASK;
+        self.exmemcnt = (self.exmemcnt & !mask) | (val & mask) | EXMEMCNT_ALWAYS_SET;
     }
 
+    /// The arm7's own gba slot timings in the low 7 bits, the rest is a read only mirror of EXMEMCNT
     pub const fn read_exmemstat(&self) -> u16 {
-        self.exmemstat
+        (self.exmemstat & EXMEMSTAT_WRITE_MASK) | (self.exmemcnt & !EXMEMSTAT_WRITE_MASK)
+    }
+
+    /// Bus cycles for a gba slot access by `arch`, using the wait states in the low bits of
+    /// EXMEMCNT for the arm9 and EXMEMSTAT for the arm7. Sram uses `sram`, rom the rest
+    pub const fn gba_slot_cycles(&self, arch: Arch, sram: bool, word: bool, sequential: bool) -> u64 {
+        const FIRST: [u64; 4] = [10, 8, 6, 18];
+        const SECOND: [u64; 2] = [6, 4];
+
+        let waitstates = match arch {
+            Arch::ARMv4 => self.exmemstat,
+            Arch::ARMv5 => self.exmemcnt,
+        };
+        if sram {
+            return FIRST[(waitstates & 0x3) as usize];
+        }
+
+        let first = FIRST[((waitstates >> 2) & 0x3) as usize];
+        let second = SECOND[((waitstates >> 4) & 0x1) as usize];
+        match (word, sequential) {
+            (false, false) => first,
+            (false, true) => second,
+            (true, false) => first + second,
+            (true, true) => second * 2,
+        }
     }
 
+    /// Only the timings, the access rights and main memory bits belong to the arm9
     pub fn write_exmemstat(&mut self, val: u16, mask: u16) {
-        self.exmemstat = (self.exmemstat & !mask) | (val | mask)
+        let mask = mask & EXMEMSTAT_WRITE_MASK;
+        self.exmemstat = (self.exmemstat & !mask) | (val & mask)
     }
 }
@@ -1,111 +1,204 @@
-use std::rc::Rc;
+use std::ops::{Add, AddAssign, Sub};
+
 use log::trace;
 
 use crate::core::System;
-use crate::util::Shared;
+use crate::util::{LeBytes, Shared, StateReader, StateWriter};
+
+/// More events than are ever pending at once, so adding one doesn't have to grow the queue
+const EVENT_CAPACITY: usize = 64;
+
+/// A point in scheduler time, counted in system cycles since reset. Adding cycles gives a later
+/// timestamp and subtracting two gives the cycles between them, so components can't mix up times and delays
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// Cycles since reset
+    pub const fn cycles(self) -> u64 {
+        self.0
+    }
+
+    /// Cycles from `earlier` to this, 0 if `earlier` is actually later
+    pub const fn saturating_since(self, earlier: Timestamp) -> u64 {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+impl Add<u64> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, cycles: u64) -> Timestamp {
+        Timestamp(self.0 + cycles)
+    }
+}
+
+impl AddAssign<u64> for Timestamp {
+    fn add_assign(&mut self, cycles: u64) {
+        self.0 += cycles;
+    }
+}
+
+impl Sub for Timestamp {
+    type Output = u64;
+
+    fn sub(self, earlier: Timestamp) -> u64 {
+        self.0 - earlier.0
+    }
+}
+
+impl LeBytes for Timestamp {
+    const SIZE: usize = u64::SIZE;
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        Self(u64::from_le_slice(bytes))
+    }
+
+    fn to_le_slice(self, out: &mut [u8]) {
+        self.0.to_le_slice(out)
+    }
+}
 
 struct Event {
-    time: u64,
-    info: Rc<EventInfo>,
+    time: Timestamp,
+    id: EventId,
 }
 
-pub struct EventInfo {
+struct EventInfo {
     name: String,
-    id: usize,
     callback: fn(&mut System),
 }
 
-impl Default for EventInfo {
+/// Handle to a registered event, an index into the scheduler's event list. Ids are handed out in
+/// registration order, so they stay valid across savestates and can be copied around freely
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct EventId(usize);
+
+impl Default for EventId {
+    /// An id that's never registered, components hold it until their first reset
     fn default() -> Self {
-        Self {
-            name: "default".to_string(),
-            id: usize::MAX,
-            callback: |_| unreachable!(),
-        }
+        Self(usize::MAX)
     }
 }
 
 pub struct Scheduler {
     system: Shared<System>,
     events: Vec<Event>,
-    current_time: u64,
-    current_event_id: usize,
+    /// Every event registered since the last reset indexed by id, so a savestate can refer to events by id
+    registered: Vec<EventInfo>,
+    current_time: Timestamp,
 }
 
 impl Scheduler {
     pub fn new(system: &Shared<System>) -> Self {
         Self {
             system: system.clone(),
-            events: vec![],
-            current_time: 0,
-            current_event_id: 0,
+            events: Vec::with_capacity(EVENT_CAPACITY),
+            registered: vec![],
+            current_time: Timestamp::default(),
         }
     }
 
     pub fn reset(&mut self) {
         self.events.clear();
-        self.current_time = 0;
-        self.current_event_id = 0;
-    }
-
-    pub fn tick(&mut self, cycles: u64) {
-        // trace!("event_time: {}, current_time: {}, cycles: {cycles}", self.get_event_time(), self.get_current_time());
-        self.current_time += cycles;
+        self.registered.clear();
+        self.current_time = Timestamp::default();
     }
 
-    pub fn run(&mut self) {
-        let mut to_remove = vec![];
-        for (idx, event) in self.events.iter().enumerate() {
-            if event.time <= self.current_time {
-                let name = &event.info.name;
-                // if event.info.name.contains("DMA") {
-                //     trace!("running '{}' at {}", event.info.name, event.time);
-                // }
-                to_remove.push(idx);
-                (event.info.callback)(&mut self.system);
+    /// Advances to `target`, running every event due by then in order. The clock is set to each event's
+    /// time before its callback runs, so callbacks see exactly when they were due and anything they
+    /// schedule is relative to that. Events are taken off the front, which also picks up events a
+    /// callback schedules before `target` without a scratch list
+    pub fn run_until(&mut self, target: Timestamp) {
+        while self.events.first().is_some_and(|event| event.time <= target) {
+            // a met step condition stops the clock right after whatever met it
+            if self.system.debugger.is_hit() {
+                return;
             }
+            let event = self.events.remove(0);
+            // if event.info.name.contains("DMA") {
+            //     trace!("running '{}' at {}", event.info.name, event.time);
+            // }
+            self.current_time = event.time;
+            let callback = self.registered[event.id.0].callback;
+            callback(&mut self.system);
         }
 
-        for idx in to_remove.into_iter().rev() {
-            self.events.remove(idx);
-        }
+        self.current_time = self.current_time.max(target);
+    }
+
+    pub fn add_event(&mut self, delay: u64, id: EventId) {
+        // trace!("adding event '{}', delay: {}, current: {}", self.registered[id.0].name, delay, self.current_time);
+        self.add_event_at(self.current_time + delay, id);
     }
 
-    pub fn add_event(&mut self, delay: u64, info: &Rc<EventInfo>) {
-        // trace!("adding event '{}', delay: {}, current: {}", info.name, delay, self.current_time);
-        let time = self.current_time + delay;
-        let event = Event { time, info: info.clone() };
+    /// Schedules `id` at an absolute time. A time that already passed runs as soon as possible
+    pub fn add_event_at(&mut self, time: Timestamp, id: EventId) {
+        let event = Event { time: time.max(self.current_time), id };
         let index = self.calc_event_index(&event);
         self.events.insert(index, event);
     }
 
-    pub fn cancel_event(&mut self, info: &EventInfo) {
-        self.events.retain(|e| e.info.id != info.id);
+    /// Removes every pending occurrence of `id`, nothing happens if it isn't scheduled
+    pub fn cancel_event(&mut self, id: EventId) {
+        self.events.retain(|e| e.id != id);
+    }
+
+    /// Moves `id` to `delay` cycles from now, as if it was cancelled and added again
+    pub fn reschedule_event(&mut self, delay: u64, id: EventId) {
+        self.cancel_event(id);
+        self.add_event(delay, id);
     }
 
-    pub fn register_event(&mut self, name: &str, callback: fn(&mut System)) -> Rc<EventInfo> {
-        let info = EventInfo {
-            name: name.to_string(),
-            id: self.current_event_id,
-            callback,
-        };
-        self.current_event_id += 1;
-        Rc::new(info)
+    /// When `id` next runs, `None` if it isn't scheduled
+    pub fn event_time(&self, id: EventId) -> Option<Timestamp> {
+        self.events.iter().find(|e| e.id == id).map(|e| e.time)
     }
 
-    pub fn get_current_time(&self) -> u64 {
+    pub fn register_event(&mut self, name: &str, callback: fn(&mut System)) -> EventId {
+        self.registered.push(EventInfo { name: name.to_string(), callback });
+        EventId(self.registered.len() - 1)
+    }
+
+    pub fn get_current_time(&self) -> Timestamp {
         self.current_time
     }
 
-    pub fn get_event_time(&self) -> u64 {
+    pub fn get_event_time(&self) -> Timestamp {
         assert!(!self.events.is_empty());
         self.events[0].time
     }
 
-    fn calc_event_index(&self, event: &Event) -> usize {
-        match self.events.binary_search_by(|other| other.time.cmp(&event.time)) {
-            Ok(idx) => idx,
-            Err(idx) => idx,
+    /// Pending events are saved by id. Components register their events in the same order on every
+    /// reset, so the ids match as long as the state is loaded into the same build
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"SCHD");
+        state.write("current_time", self.current_time);
+        state.write("events.len", self.events.len() as u32);
+        for event in &self.events {
+            state.write("event.id", event.id.0 as u32);
+            state.write("event.time", event.time);
         }
     }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"SCHD");
+        self.current_time = state.read();
+        self.events.clear();
+        let len = state.read::<u32>();
+        for _ in 0..len {
+            let id = state.read::<u32>() as usize;
+            let time = state.read::<Timestamp>();
+            if id < self.registered.len() {
+                self.events.push(Event { time, id: EventId(id) });
+            } else {
+                state.fail(format!("unknown scheduler event {id}"));
+            }
+        }
+    }
+
+    fn calc_event_index(&self, event: &Event) -> usize {
+        // after any events at the same time, so events due together run in the order they were added
+        self.events.partition_point(|other| other.time <= event.time)
+    }
 }
@@ -0,0 +1,29 @@
+//! NTSC display timings from GBATEK, in system cycles of the 33.513982 MHz bus clock.
+//! The arm9 runs at twice this rate
+
+/// System cycles per second
+pub const CLOCK_RATE: u64 = 33513982;
+
+/// Each dot takes 6 system cycles, 256 visible plus 99 hblank dots make a line
+pub const CYCLES_PER_DOT: u64 = 6;
+pub const DOTS_PER_LINE: u64 = 355;
+pub const CYCLES_PER_LINE: u64 = CYCLES_PER_DOT * DOTS_PER_LINE;
+
+/// Cycles from the start of a line until the hblank flag goes up, the rest of the line is hblank
+pub const HDRAW_CYCLES: u64 = 1606;
+pub const HBLANK_CYCLES: u64 = CYCLES_PER_LINE - HDRAW_CYCLES;
+
+pub const VISIBLE_LINES: u16 = 192;
+/// VCOUNT runs from 0 to 262
+pub const TOTAL_LINES: u16 = 263;
+/// The vblank flag is set from line 192 and already cleared on the last line, not when vcount wraps
+pub const VBLANK_END_LINE: u16 = TOTAL_LINES - 1;
+
+pub const CYCLES_PER_FRAME: u64 = CYCLES_PER_LINE * TOTAL_LINES as u64;
+
+/// The mixer outputs one stereo sample every 1024 cycles, about 32728 Hz
+pub const CYCLES_PER_SAMPLE: u64 = 1024;
+pub const SAMPLE_RATE: u32 = (CLOCK_RATE / CYCLES_PER_SAMPLE) as u32;
+
+/// About 59.8261 Hz
+pub const REFRESH_RATE: f64 = CLOCK_RATE as f64 / CYCLES_PER_FRAME as f64;
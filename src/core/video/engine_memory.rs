@@ -0,0 +1,61 @@
+use std::mem::size_of;
+
+use crate::util::{StateReader, StateWriter};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Engine {
+    A,
+    B,
+}
+
+impl Engine {
+    const fn offset(self) -> u32 {
+        match self {
+            Engine::A => 0,
+            Engine::B => 0x400,
+        }
+    }
+}
+
+/// Palette ram or oam. Both are 2KB with engine A owning the first half and engine B the second
+pub struct EngineMemory {
+    data: Box<[u8; 0x800]>,
+}
+
+impl EngineMemory {
+    pub fn new() -> Self {
+        Self { data: Box::new([0; 0x800]) }
+    }
+
+    pub fn reset(&mut self) {
+        self.data.fill(0);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes("data", &*self.data);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.read_bytes(&mut *self.data);
+    }
+
+    /// Read from an engine's half, `addr` wraps within it
+    pub fn read<T: Copy>(&self, engine: Engine, addr: u32) -> T {
+        self.read_bus(engine.offset() | (addr & 0x3ff))
+    }
+
+    /// Read using a bus address, bit 10 picks the engine
+    pub fn read_bus<T: Copy>(&self, addr: u32) -> T {
+        unsafe { self.data.as_ptr().add(Self::index::<T>(addr)).cast::<T>().read_unaligned() }
+    }
+
+    /// Write using a bus address, bit 10 picks the engine
+    pub fn write_bus<T>(&mut self, addr: u32, val: T) {
+        unsafe { self.data.as_mut_ptr().add(Self::index::<T>(addr)).cast::<T>().write_unaligned(val) }
+    }
+
+    /// Accesses are aligned to their size, which keeps them inside the buffer
+    const fn index<T>(addr: u32) -> usize {
+        (addr as usize & 0x7ff) & !(size_of::<T>() - 1)
+    }
+}
@@ -0,0 +1,752 @@
+use log::warn;
+
+use crate::bitfield;
+use crate::core::video::gpu::matrix::Matrix;
+use crate::util::sign_extend;
+
+/// Per frame limits of the polygon and vertex ram
+pub const MAX_POLYGONS: usize = 2048;
+pub const MAX_VERTICES: usize = 6144;
+
+/// A quad clipped against all 6 planes of the view volume can gain one vertex per plane
+pub const MAX_POLYGON_VERTICES: usize = 10;
+
+bitfield! {
+    #[derive(Copy, Clone, Default)]
+    pub struct PolygonAttr(u32) {
+        pub lights: u32 => 0 | 3,
+        pub mode: u32 => 4 | 5,
+        pub render_back: bool => 6,
+        pub render_front: bool => 7,
+        // 8 | 10
+        pub depth_write_translucent: bool => 11,
+        pub far_plane_clip: bool => 12,
+        pub one_dot: bool => 13,
+        pub depth_equal: bool => 14,
+        pub fog: bool => 15,
+        pub alpha: u32 => 16 | 20,
+        // 21 | 23
+        pub id: u32 => 24 | 29
+    }
+}
+
+bitfield! {
+    #[derive(Copy, Clone, Default)]
+    pub struct TexImageParam(u32) {
+        pub offset: u32 => 0 | 15,
+        pub repeat_s: bool => 16,
+        pub repeat_t: bool => 17,
+        pub flip_s: bool => 18,
+        pub flip_t: bool => 19,
+        pub size_s: u32 => 20 | 22,
+        pub size_t: u32 => 23 | 25,
+        pub format: u32 => 26 | 28,
+        pub color0_transparent: bool => 29,
+        pub transform: u32 => 30 | 31
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Primitive {
+    Triangles,
+    Quads,
+    TriangleStrip,
+    QuadStrip,
+}
+
+/// A vertex in clip space, as it comes out of the clip matrix
+#[derive(Copy, Clone, Default)]
+struct Vertex {
+    position: [i32; 4],
+    /// 5 bit color
+    color: [u8; 3],
+    /// 12.4 fixed point texel coordinates
+    texcoord: [i16; 2],
+}
+
+/// A clip space vertex with every attribute converted to floats, so clipping can interpolate them
+#[derive(Copy, Clone, Default)]
+struct ClipVertex {
+    position: [f32; 4],
+    color: [f32; 3],
+    texcoord: [f32; 2],
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        ClipVertex {
+            position: std::array::from_fn(|i| mix(self.position[i], other.position[i])),
+            color: std::array::from_fn(|i| mix(self.color[i], other.color[i])),
+            texcoord: std::array::from_fn(|i| mix(self.texcoord[i], other.texcoord[i])),
+        }
+    }
+}
+
+/// A vertex after the viewport transform, ready for the rendering engine
+#[derive(Copy, Clone, Default)]
+pub struct ScreenVertex {
+    pub x: f32,
+    pub y: f32,
+    /// Depth for z-buffering, 0 to 0xffffff
+    pub z: f32,
+    /// Clip space w in 20.12 fixed point, used for perspective correction and w-buffering
+    pub w: f32,
+    /// 6 bit color
+    pub color: [f32; 3],
+    /// Texel coordinates
+    pub texcoord: [f32; 2],
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Polygon {
+    pub vertices: [ScreenVertex; MAX_POLYGON_VERTICES],
+    pub len: usize,
+    pub attr: PolygonAttr,
+    pub texture: TexImageParam,
+    pub palette_base: u32,
+    pub translucent: bool,
+    pub top: f32,
+    pub bottom: f32,
+}
+
+impl Polygon {
+    pub fn vertices(&self) -> &[ScreenVertex] {
+        &self.vertices[..self.len]
+    }
+}
+
+/// Everything between the command fifo and polygon ram: matrices, lighting, vertex assembly,
+/// clipping and the viewport transform
+pub struct GeometryEngine {
+    matrix_mode: u32,
+    projection: Matrix,
+    projection_stack: Matrix,
+    projection_sp: i32,
+    position: Matrix,
+    vector: Matrix,
+    position_stack: [Matrix; 31],
+    vector_stack: [Matrix; 31],
+    position_sp: i32,
+    texture: Matrix,
+    texture_stack: Matrix,
+    texture_sp: i32,
+    clip: Matrix,
+    clip_dirty: bool,
+    /// Set when a push, pop, store or restore goes outside its stack, cleared through GXSTAT
+    pub stack_overflow: bool,
+
+    color: [u8; 3],
+    normal: [i32; 3],
+    texcoord: [i16; 2],
+    raw_texcoord: [i16; 2],
+    /// Last vertex position in 4.12 fixed point, for the commands that only change some coordinates
+    last_position: [i32; 3],
+
+    pending_attr: PolygonAttr,
+    attr: PolygonAttr,
+    texture_param: TexImageParam,
+    palette_base: u32,
+
+    primitive: Primitive,
+    primitive_vertices: [Vertex; 4],
+    primitive_len: usize,
+    odd_strip_triangle: bool,
+
+    diffuse: [u8; 3],
+    ambient: [u8; 3],
+    specular: [u8; 3],
+    emission: [u8; 3],
+    use_shininess_table: bool,
+    shininess: [u8; 128],
+    light_direction: [[i32; 3]; 4],
+    light_color: [[u8; 3]; 4],
+
+    /// x1, y1, x2, y2 with y measured from the bottom of the screen
+    viewport: [u32; 4],
+
+    /// Polygon ram, handed to the rendering engine on the next swap
+    pub polygons: Vec<Polygon>,
+    pub vertex_count: usize,
+    /// Set when a polygon didn't fit in polygon or vertex ram
+    pub ram_overflow: bool,
+
+    pub position_result: [i32; 4],
+    pub vector_result: [i16; 3],
+    pub box_test_result: bool,
+}
+
+impl GeometryEngine {
+    pub fn new() -> Self {
+        Self {
+            matrix_mode: 0,
+            projection: Matrix::IDENTITY,
+            projection_stack: Matrix::IDENTITY,
+            projection_sp: 0,
+            position: Matrix::IDENTITY,
+            vector: Matrix::IDENTITY,
+            position_stack: [Matrix::IDENTITY; 31],
+            vector_stack: [Matrix::IDENTITY; 31],
+            position_sp: 0,
+            texture: Matrix::IDENTITY,
+            texture_stack: Matrix::IDENTITY,
+            texture_sp: 0,
+            clip: Matrix::IDENTITY,
+            clip_dirty: false,
+            stack_overflow: false,
+            color: [31; 3],
+            normal: [0; 3],
+            texcoord: [0; 2],
+            raw_texcoord: [0; 2],
+            last_position: [0; 3],
+            pending_attr: PolygonAttr(0),
+            attr: PolygonAttr(0),
+            texture_param: TexImageParam(0),
+            palette_base: 0,
+            primitive: Primitive::Triangles,
+            primitive_vertices: [Vertex::default(); 4],
+            primitive_len: 0,
+            odd_strip_triangle: false,
+            diffuse: [0; 3],
+            ambient: [0; 3],
+            specular: [0; 3],
+            emission: [0; 3],
+            use_shininess_table: false,
+            shininess: [0; 128],
+            light_direction: [[0; 3]; 4],
+            light_color: [[0; 3]; 4],
+            viewport: [0, 0, 255, 191],
+            polygons: Vec::with_capacity(MAX_POLYGONS),
+            vertex_count: 0,
+            ram_overflow: false,
+            position_result: [0; 4],
+            vector_result: [0; 3],
+            box_test_result: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        let polygons = std::mem::take(&mut self.polygons);
+        *self = Self { polygons, ..Self::new() };
+        self.polygons.clear();
+    }
+
+    pub const fn position_stack_level(&self) -> u32 {
+        (self.position_sp & 0x1f) as u32
+    }
+
+    pub const fn projection_stack_level(&self) -> u32 {
+        (self.projection_sp & 0x1) as u32
+    }
+
+    pub fn clip_matrix(&mut self) -> Matrix {
+        if self.clip_dirty {
+            self.clip = self.position * self.projection;
+            self.clip_dirty = false;
+        }
+        self.clip
+    }
+
+    pub const fn vector_matrix(&self) -> Matrix {
+        self.vector
+    }
+
+    pub fn execute(&mut self, command: u8, params: &[u32]) {
+        match command {
+            0x10 => self.matrix_mode = params[0] & 0x3,
+            0x11 => self.push_matrix(),
+            0x12 => self.pop_matrix(params[0]),
+            0x13 => self.store_matrix(params[0]),
+            0x14 => self.restore_matrix(params[0]),
+            0x15 => self.load_matrix(Matrix::IDENTITY),
+            0x16 => self.load_matrix(Matrix::from_4x4(params)),
+            0x17 => self.load_matrix(Matrix::from_4x3(params)),
+            0x18 => self.multiply_matrix(Matrix::from_4x4(params)),
+            0x19 => self.multiply_matrix(Matrix::from_4x3(params)),
+            0x1a => self.multiply_matrix(Matrix::from_3x3(params)),
+            0x1b => self.scale_matrix([params[0] as i32, params[1] as i32, params[2] as i32]),
+            0x1c => self.translate_matrix([params[0] as i32, params[1] as i32, params[2] as i32]),
+            0x20 => self.color = rgb555_to_rgb(params[0]),
+            0x21 => self.set_normal(params[0]),
+            0x22 => self.set_texcoord(params[0]),
+            0x23 => self.submit_vertex([
+                params[0] as i16 as i32,
+                (params[0] >> 16) as i16 as i32,
+                params[1] as i16 as i32,
+            ]),
+            0x24 => self.submit_vertex([
+                (sign_extend::<10>(params[0]) as i32) << 6,
+                (sign_extend::<10>(params[0] >> 10) as i32) << 6,
+                (sign_extend::<10>(params[0] >> 20) as i32) << 6,
+            ]),
+            0x25 => self.submit_vertex([params[0] as i16 as i32, (params[0] >> 16) as i16 as i32, self.last_position[2]]),
+            0x26 => self.submit_vertex([params[0] as i16 as i32, self.last_position[1], (params[0] >> 16) as i16 as i32]),
+            0x27 => self.submit_vertex([self.last_position[0], params[0] as i16 as i32, (params[0] >> 16) as i16 as i32]),
+            0x28 => {
+                // 10 bit offsets with 9 fractional bits, so 1/8th of a 4.12 unit
+                let diff = [0, 10, 20].map(|shift| (sign_extend::<10>(params[0] >> shift) as i32) << 3);
+                let position = std::array::from_fn(|i| (self.last_position[i] + diff[i]) as i16 as i32);
+                self.submit_vertex(position)
+            }
+            0x29 => self.pending_attr = PolygonAttr(params[0]),
+            0x2a => self.texture_param = TexImageParam(params[0]),
+            0x2b => self.palette_base = params[0] & 0x1fff,
+            0x30 => {
+                self.diffuse = rgb555_to_rgb(params[0]);
+                self.ambient = rgb555_to_rgb(params[0] >> 16);
+                if params[0] & (1 << 15) != 0 {
+                    self.color = self.diffuse;
+                }
+            }
+            0x31 => {
+                self.specular = rgb555_to_rgb(params[0]);
+                self.emission = rgb555_to_rgb(params[0] >> 16);
+                self.use_shininess_table = params[0] & (1 << 15) != 0;
+            }
+            0x32 => {
+                let light = (params[0] >> 30) as usize;
+                let direction = [0, 10, 20].map(|shift| (sign_extend::<10>(params[0] >> shift) as i32) << 3);
+                self.light_direction[light] = self.vector.transform_direction(direction);
+            }
+            0x33 => self.light_color[(params[0] >> 30) as usize] = rgb555_to_rgb(params[0]),
+            0x34 => {
+                for (i, param) in params.iter().enumerate() {
+                    self.shininess[i * 4..i * 4 + 4].copy_from_slice(&param.to_le_bytes());
+                }
+            }
+            0x40 => self.begin_vertices(params[0]),
+            0x41 => {} // end vtxs doesn't do anything on hardware
+            0x60 => self.viewport = [params[0] & 0xff, (params[0] >> 8) & 0xff, (params[0] >> 16) & 0xff, params[0] >> 24],
+            0x70 => self.box_test(params),
+            0x71 => {
+                self.last_position = [params[0] as i16 as i32, (params[0] >> 16) as i16 as i32, params[1] as i16 as i32];
+                self.position_result = self.clip_matrix().transform(self.last_position);
+            }
+            0x72 => {
+                let direction = [0, 10, 20].map(|shift| (sign_extend::<10>(params[0] >> shift) as i32) << 3);
+                self.vector_result = self.vector.transform_direction(direction).map(|val| val as i16);
+            }
+            _ => warn!("GPU: unknown geometry command {command:02x}"),
+        }
+    }
+
+    /// A push onto a full stack sets the overflow flag and leaves the stack and its pointer alone
+    fn push_matrix(&mut self) {
+        match self.matrix_mode {
+            0 => {
+                if self.projection_sp != 0 {
+                    self.stack_overflow = true;
+                    return;
+                }
+                self.projection_stack = self.projection;
+                self.projection_sp = 1;
+            }
+            3 => {
+                if self.texture_sp != 0 {
+                    self.stack_overflow = true;
+                    return;
+                }
+                self.texture_stack = self.texture;
+                self.texture_sp = 1;
+            }
+            _ => {
+                if !(0..=30).contains(&self.position_sp) {
+                    self.stack_overflow = true;
+                    return;
+                }
+                self.position_stack[self.position_sp as usize] = self.position;
+                self.vector_stack[self.position_sp as usize] = self.vector;
+                self.position_sp += 1;
+            }
+        }
+    }
+
+    /// A pop that would leave the stack sets the overflow flag without loading a matrix, the pointer
+    /// stops at the end it went past. The position stack pops a signed 6 bit number of entries
+    fn pop_matrix(&mut self, param: u32) {
+        match self.matrix_mode {
+            0 => {
+                if self.projection_sp == 0 {
+                    self.stack_overflow = true;
+                    return;
+                }
+                self.projection_sp = 0;
+                self.projection = self.projection_stack;
+                self.clip_dirty = true;
+            }
+            3 => {
+                if self.texture_sp == 0 {
+                    self.stack_overflow = true;
+                    return;
+                }
+                self.texture_sp = 0;
+                self.texture = self.texture_stack;
+            }
+            _ => {
+                let sp = self.position_sp - sign_extend::<6>(param) as i32;
+                if !(0..=30).contains(&sp) {
+                    self.stack_overflow = true;
+                    self.position_sp = sp.clamp(0, 31);
+                    return;
+                }
+                self.position_sp = sp;
+                self.position = self.position_stack[sp as usize];
+                self.vector = self.vector_stack[sp as usize];
+                self.clip_dirty = true;
+            }
+        }
+    }
+
+    fn store_matrix(&mut self, param: u32) {
+        match self.matrix_mode {
+            0 => self.projection_stack = self.projection,
+            3 => self.texture_stack = self.texture,
+            _ => {
+                let index = (param & 0x1f) as usize;
+                if index == 31 {
+                    self.stack_overflow = true;
+                    return;
+                }
+                self.position_stack[index] = self.position;
+                self.vector_stack[index] = self.vector;
+            }
+        }
+    }
+
+    fn restore_matrix(&mut self, param: u32) {
+        match self.matrix_mode {
+            0 => {
+                self.projection = self.projection_stack;
+                self.clip_dirty = true;
+            }
+            3 => self.texture = self.texture_stack,
+            _ => {
+                let index = (param & 0x1f) as usize;
+                if index == 31 {
+                    self.stack_overflow = true;
+                    return;
+                }
+                self.position = self.position_stack[index];
+                self.vector = self.vector_stack[index];
+                self.clip_dirty = true;
+            }
+        }
+    }
+
+    fn load_matrix(&mut self, matrix: Matrix) {
+        match self.matrix_mode {
+            0 => self.projection = matrix,
+            1 => self.position = matrix,
+            2 => {
+                self.position = matrix;
+                self.vector = matrix;
+            }
+            _ => self.texture = matrix,
+        }
+        self.clip_dirty = true;
+    }
+
+    fn multiply_matrix(&mut self, matrix: Matrix) {
+        match self.matrix_mode {
+            0 => self.projection = matrix * self.projection,
+            1 => self.position = matrix * self.position,
+            2 => {
+                self.position = matrix * self.position;
+                self.vector = matrix * self.vector;
+            }
+            _ => self.texture = matrix * self.texture,
+        }
+        self.clip_dirty = true;
+    }
+
+    /// Scaling never touches the vector matrix, so normals keep their length
+    fn scale_matrix(&mut self, scale: [i32; 3]) {
+        match self.matrix_mode {
+            0 => self.projection.scale(scale),
+            1 | 2 => self.position.scale(scale),
+            _ => self.texture.scale(scale),
+        }
+        self.clip_dirty = true;
+    }
+
+    fn translate_matrix(&mut self, offset: [i32; 3]) {
+        match self.matrix_mode {
+            0 => self.projection.translate(offset),
+            1 => self.position.translate(offset),
+            2 => {
+                self.position.translate(offset);
+                self.vector.translate(offset);
+            }
+            _ => self.texture.translate(offset),
+        }
+        self.clip_dirty = true;
+    }
+
+    fn set_texcoord(&mut self, param: u32) {
+        self.raw_texcoord = [param as i16, (param >> 16) as i16];
+        self.texcoord = self.raw_texcoord;
+
+        if self.texture_param.transform() == 1 {
+            let m = &self.texture.0;
+            let [s, t] = self.raw_texcoord.map(|val| val as i64);
+            self.texcoord = std::array::from_fn(|col| {
+                ((s * m[0][col] as i64 + t * m[1][col] as i64 + m[2][col] as i64 + m[3][col] as i64) >> 12) as i16
+            });
+        }
+    }
+
+    fn set_normal(&mut self, param: u32) {
+        self.normal = [0, 10, 20].map(|shift| (sign_extend::<10>(param >> shift) as i32) << 3);
+
+        if self.texture_param.transform() == 2 {
+            self.texcoord = self.transform_texcoord(self.normal);
+        }
+
+        if self.attr.lights() != 0 {
+            self.apply_lighting();
+        }
+    }
+
+    /// Texture coordinates generated from a normal or vertex, offset by the last texcoord
+    fn transform_texcoord(&self, source: [i32; 3]) -> [i16; 2] {
+        let m = &self.texture.0;
+        std::array::from_fn(|col| {
+            let sum: i64 = (0..3).map(|row| source[row] as i64 * m[row][col] as i64).sum();
+            ((sum + ((self.raw_texcoord[col] as i64) << 24)) >> 24) as i16
+        })
+    }
+
+    fn apply_lighting(&mut self) {
+        let normal = self.vector.transform_direction(self.normal);
+        let dot = |a: [i32; 3], b: [i32; 3]| -> i64 { (0..3).map(|i| a[i] as i64 * b[i] as i64).sum::<i64>() >> 12 };
+
+        let mut color = self.emission.map(|c| c as i64);
+        for light in 0..4 {
+            if self.attr.lights() & (1 << light) == 0 {
+                continue;
+            }
+
+            let direction = self.light_direction[light];
+            let diffuse = (-dot(direction, normal)).clamp(0, 0x1000);
+
+            // the half way vector between the light and the line of sight (0, 0, -1)
+            let half = [direction[0] / 2, direction[1] / 2, (direction[2] - 0x1000) / 2];
+            let shine = (-dot(half, normal)).clamp(0, 0x1000);
+            let mut shine = (shine * shine) >> 12;
+            if self.use_shininess_table {
+                shine = (self.shininess[(shine >> 5).min(127) as usize] as i64) << 4;
+            }
+
+            for c in 0..3 {
+                let light_color = self.light_color[light][c] as i64;
+                color[c] += self.specular[c] as i64 * light_color * shine / (31 * 0x1000);
+                color[c] += self.diffuse[c] as i64 * light_color * diffuse / (31 * 0x1000);
+                color[c] += self.ambient[c] as i64 * light_color / 31;
+            }
+        }
+
+        self.color = color.map(|c| c.min(31) as u8);
+    }
+
+    fn begin_vertices(&mut self, param: u32) {
+        self.primitive = match param & 0x3 {
+            0 => Primitive::Triangles,
+            1 => Primitive::Quads,
+            2 => Primitive::TriangleStrip,
+            _ => Primitive::QuadStrip,
+        };
+        self.primitive_len = 0;
+        self.odd_strip_triangle = false;
+        self.attr = self.pending_attr;
+    }
+
+    fn submit_vertex(&mut self, position: [i32; 3]) {
+        self.last_position = position;
+
+        if self.texture_param.transform() == 3 {
+            self.texcoord = self.transform_texcoord(position);
+        }
+
+        let vertex = Vertex {
+            position: self.clip_matrix().transform(position),
+            color: self.color,
+            texcoord: self.texcoord,
+        };
+
+        self.primitive_vertices[self.primitive_len] = vertex;
+        self.primitive_len += 1;
+
+        let v = self.primitive_vertices;
+        match (self.primitive, self.primitive_len) {
+            (Primitive::Triangles, 3) => {
+                self.submit_polygon(&[v[0], v[1], v[2]]);
+                self.primitive_len = 0;
+            }
+            (Primitive::Quads, 4) => {
+                self.submit_polygon(&[v[0], v[1], v[2], v[3]]);
+                self.primitive_len = 0;
+            }
+            (Primitive::TriangleStrip, 3) => {
+                // every other triangle is wound the other way, swap it back
+                if self.odd_strip_triangle {
+                    self.submit_polygon(&[v[1], v[0], v[2]]);
+                } else {
+                    self.submit_polygon(&[v[0], v[1], v[2]]);
+                }
+                self.odd_strip_triangle = !self.odd_strip_triangle;
+                self.primitive_vertices[0] = v[1];
+                self.primitive_vertices[1] = v[2];
+                self.primitive_len = 2;
+            }
+            (Primitive::QuadStrip, 4) => {
+                self.submit_polygon(&[v[0], v[1], v[3], v[2]]);
+                self.primitive_vertices[0] = v[2];
+                self.primitive_vertices[1] = v[3];
+                self.primitive_len = 2;
+            }
+            _ => {}
+        }
+    }
+
+    fn submit_polygon(&mut self, vertices: &[Vertex]) {
+        let mut buffer = [ClipVertex::default(); MAX_POLYGON_VERTICES];
+        for (clip, vertex) in buffer.iter_mut().zip(vertices) {
+            *clip = ClipVertex {
+                position: vertex.position.map(|val| val as f32),
+                color: vertex.color.map(|c| (c * 2 + (c != 0) as u8) as f32),
+                texcoord: vertex.texcoord.map(|val| val as f32 / 16.0),
+            };
+        }
+
+        // polygons crossing the far plane are hidden unless the attributes ask for them to be clipped
+        if !self.attr.far_plane_clip() && buffer[..vertices.len()].iter().any(|v| v.position[2] > v.position[3]) {
+            return;
+        }
+
+        let len = clip_polygon(&mut buffer, vertices.len());
+        if len < 3 {
+            return;
+        }
+
+        let mut polygon = Polygon {
+            len,
+            attr: self.attr,
+            texture: self.texture_param,
+            palette_base: self.palette_base,
+            ..Default::default()
+        };
+        for (screen, vertex) in polygon.vertices.iter_mut().zip(&buffer[..len]) {
+            *screen = self.viewport_transform(vertex);
+        }
+
+        // the front side has its vertices counter clockwise on screen
+        let screen = polygon.vertices();
+        let area: f32 = (0..len).map(|i| {
+            let (a, b) = (screen[i], screen[(i + 1) % len]);
+            a.x * b.y - b.x * a.y
+        }).sum();
+        let front = area <= 0.0;
+        if (front && !self.attr.render_front()) || (!front && !self.attr.render_back()) {
+            return;
+        }
+
+        if self.polygons.len() >= MAX_POLYGONS || self.vertex_count + len > MAX_VERTICES {
+            self.ram_overflow = true;
+            return;
+        }
+
+        let top = screen.iter().map(|v| v.y).fold(f32::MAX, f32::min);
+        let bottom = screen.iter().map(|v| v.y).fold(f32::MIN, f32::max);
+        let alpha = self.attr.alpha();
+        polygon.translucent = (alpha != 0 && alpha != 31) || matches!(self.texture_param.format(), 1 | 6);
+        polygon.top = top;
+        polygon.bottom = bottom;
+
+        self.vertex_count += len;
+        self.polygons.push(polygon);
+    }
+
+    fn viewport_transform(&self, vertex: &ClipVertex) -> ScreenVertex {
+        let [x1, y1, x2, y2] = self.viewport.map(|val| val as f32);
+        let width = x2 - x1 + 1.0;
+        let height = y2 - y1 + 1.0;
+        let [x, y, z, w] = vertex.position;
+        let w = w.max(1.0);
+
+        ScreenVertex {
+            x: (x1 + (x + w) * width / (2.0 * w)).round(),
+            y: (192.0 - y1 - (y + w) * height / (2.0 * w)).round(),
+            z: ((z / w + 1.0) * 0.5 * 0xffffff as f32).clamp(0.0, 0xffffff as f32),
+            w,
+            color: vertex.color,
+            texcoord: vertex.texcoord,
+        }
+    }
+
+    /// Whether any part of the box might be visible. A box is only rejected when all its
+    /// corners are outside the same plane of the view volume
+    fn box_test(&mut self, params: &[u32]) {
+        let [x, y, z] = [params[0] as i16 as i32, (params[0] >> 16) as i16 as i32, params[1] as i16 as i32];
+        let [width, height, depth] = [(params[1] >> 16) as i16 as i32, params[2] as i16 as i32, (params[2] >> 16) as i16 as i32];
+
+        let clip = self.clip_matrix();
+        let corners: [[i32; 4]; 8] = std::array::from_fn(|i| {
+            clip.transform([
+                x + if i & 1 != 0 { width } else { 0 },
+                y + if i & 2 != 0 { height } else { 0 },
+                z + if i & 4 != 0 { depth } else { 0 },
+            ])
+        });
+
+        self.box_test_result = (0..6).all(|plane| {
+            let axis = plane / 2;
+            let sign = if plane % 2 == 0 { 1 } else { -1 };
+            corners.iter().any(|c| c[3] as i64 - sign * c[axis] as i64 >= 0)
+        });
+    }
+}
+
+/// Sutherland-Hodgman against the 6 planes of the view volume, returns the new vertex count
+fn clip_polygon(vertices: &mut [ClipVertex; MAX_POLYGON_VERTICES], mut len: usize) -> usize {
+    for plane in 0..6 {
+        let axis = plane / 2;
+        let sign = if plane % 2 == 0 { 1.0 } else { -1.0 };
+        let distance = |v: &ClipVertex| v.position[3] - sign * v.position[axis];
+
+        let input = *vertices;
+        let mut out = 0;
+        let mut emit = |vertex: ClipVertex| {
+            if out < MAX_POLYGON_VERTICES {
+                vertices[out] = vertex;
+                out += 1;
+            }
+        };
+
+        for i in 0..len {
+            let current = &input[i];
+            let previous = &input[(i + len - 1) % len];
+            let (dc, dp) = (distance(current), distance(previous));
+
+            if dc >= 0.0 {
+                if dp < 0.0 {
+                    emit(previous.lerp(current, dp / (dp - dc)));
+                }
+                emit(*current);
+            } else if dp >= 0.0 {
+                emit(previous.lerp(current, dp / (dp - dc)));
+            }
+        }
+
+        len = out;
+        if len == 0 {
+            break;
+        }
+    }
+
+    len
+}
+
+/// Splits a 15 bit color into 5 bit channels
+fn rgb555_to_rgb(color: u32) -> [u8; 3] {
+    [color & 0x1f, (color >> 5) & 0x1f, (color >> 10) & 0x1f].map(|c| c as u8)
+}
@@ -0,0 +1,91 @@
+use std::ops::Mul;
+
+/// 4x4 matrix of 20.12 fixed point values. Vectors are rows, so a point is transformed with `v * m`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Matrix(pub [[i32; 4]; 4]);
+
+impl Matrix {
+    pub const IDENTITY: Matrix = Matrix([[0x1000, 0, 0, 0], [0, 0x1000, 0, 0], [0, 0, 0x1000, 0], [0, 0, 0, 0x1000]]);
+
+    /// Builds a matrix from `MTX_LOAD_4x4` / `MTX_MULT_4x4` parameters, which are in row order
+    pub fn from_4x4(params: &[u32]) -> Self {
+        Self(std::array::from_fn(|row| std::array::from_fn(|col| params[row * 4 + col] as i32)))
+    }
+
+    /// Builds a matrix from 12 parameters, the last column is always `0, 0, 0, 1`
+    pub fn from_4x3(params: &[u32]) -> Self {
+        let mut matrix = Self::IDENTITY;
+        for row in 0..4 {
+            for col in 0..3 {
+                matrix.0[row][col] = params[row * 3 + col] as i32;
+            }
+        }
+        matrix
+    }
+
+    /// Builds a matrix from 9 parameters, with no translation
+    pub fn from_3x3(params: &[u32]) -> Self {
+        let mut matrix = Self::IDENTITY;
+        for row in 0..3 {
+            for col in 0..3 {
+                matrix.0[row][col] = params[row * 3 + col] as i32;
+            }
+        }
+        matrix
+    }
+
+    /// Same as multiplying with a scale matrix from the left
+    pub fn scale(&mut self, scale: [i32; 3]) {
+        for (row, factor) in scale.iter().enumerate() {
+            for col in 0..4 {
+                self.0[row][col] = ((self.0[row][col] as i64 * *factor as i64) >> 12) as i32;
+            }
+        }
+    }
+
+    /// Same as multiplying with a translation matrix from the left
+    pub fn translate(&mut self, offset: [i32; 3]) {
+        for col in 0..4 {
+            let sum: i64 = (0..3).map(|row| offset[row] as i64 * self.0[row][col] as i64).sum();
+            self.0[3][col] = self.0[3][col].wrapping_add((sum >> 12) as i32);
+        }
+    }
+
+    /// Transforms a 4.12 fixed point point, `w` is assumed to be 1
+    pub fn transform(&self, point: [i32; 3]) -> [i32; 4] {
+        std::array::from_fn(|col| {
+            let sum = point[0] as i64 * self.0[0][col] as i64
+                + point[1] as i64 * self.0[1][col] as i64
+                + point[2] as i64 * self.0[2][col] as i64
+                + ((self.0[3][col] as i64) << 12);
+            (sum >> 12) as i32
+        })
+    }
+
+    /// Transforms a direction with the upper 3x3, ignoring any translation
+    pub fn transform_direction(&self, direction: [i32; 3]) -> [i32; 3] {
+        std::array::from_fn(|col| {
+            let sum: i64 = (0..3).map(|row| direction[row] as i64 * self.0[row][col] as i64).sum();
+            (sum >> 12) as i32
+        })
+    }
+}
+
+impl Default for Matrix {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Mul for Matrix {
+    type Output = Matrix;
+
+    fn mul(self, rhs: Matrix) -> Matrix {
+        Matrix(std::array::from_fn(|row| {
+            std::array::from_fn(|col| {
+                let sum: i64 = (0..4).map(|i| self.0[row][i] as i64 * rhs.0[i][col] as i64).sum();
+                (sum >> 12) as i32
+            })
+        }))
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::VecDeque;
+
+use crate::bitfield;
+use crate::core::hardware::dma::DmaTiming;
+use crate::core::hardware::irq::{Irq, IrqSource};
+use crate::core::video::gpu::geometry::{GeometryEngine, Polygon};
+use crate::core::video::gpu::renderer::{RenderState, Renderer};
+use crate::core::video::vram::VramRegion;
+use crate::core::System;
+use crate::util::{set, Shared, StateReader, StateWriter};
+
+mod geometry;
+mod matrix;
+mod renderer;
+mod texture;
+
+pub use renderer::COLOR_TRANSPARENT;
+
+/// Entries the fifo holds, a write to a full fifo stalls the arm9 until there's room for it
+const FIFO_SIZE: usize = 256;
+
+bitfield! {
+    #[derive(Copy, Clone)]
+    pub struct Disp3dCnt(u32) {
+        pub textures: bool => 0,
+        pub highlight_shading: bool => 1,
+        pub alpha_test: bool => 2,
+        pub alpha_blending: bool => 3,
+        pub anti_aliasing: bool => 4,
+        pub edge_marking: bool => 5,
+        pub fog_alpha_only: bool => 6,
+        pub fog: bool => 7,
+        pub fog_shift: u32 => 8 | 11,
+        pub color_underflow: bool => 12,
+        pub ram_overflow: bool => 13,
+        pub clear_image: bool => 14
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    command: u8,
+    param: u32,
+}
+
+/// Number of parameter words each geometry command takes, unused ids take none
+const fn param_count(command: u8) -> usize {
+    match command {
+        0x10 | 0x12 | 0x13 | 0x14 => 1,
+        0x16 | 0x18 => 16,
+        0x17 | 0x19 => 12,
+        0x1a => 9,
+        0x1b | 0x1c => 3,
+        0x20..=0x22 | 0x24..=0x2b => 1,
+        0x23 => 2,
+        0x30..=0x33 => 1,
+        0x34 => 32,
+        0x40 | 0x50 | 0x60 => 1,
+        0x70 => 3,
+        0x71 => 2,
+        0x72 => 1,
+        _ => 0,
+    }
+}
+
+/// The 3d engine: a command fifo feeding the geometry engine, and a rendering engine that draws the
+/// swapped polygon list once per frame for engine A to show as bg0
+pub struct Gpu {
+    system: Shared<System>,
+    irq9: Shared<Irq>,
+
+    disp3dcnt: Disp3dCnt,
+    gxstat_irq_mode: u32,
+    clear_color: u32,
+    clear_depth: u16,
+    clear_offset: u16,
+    alpha_test_ref: u8,
+    toon_table: [u16; 32],
+
+    /// Up to `FIFO_SIZE` entries, followed by what the stalled writer is still waiting to write
+    fifo: VecDeque<Entry>,
+    /// Command ids of the packed fifo word still waiting for parameters
+    packed_commands: u32,
+    packed_params_left: usize,
+    params: Vec<u32>,
+
+    /// Set by SWAP_BUFFERS, the geometry engine stalls until the swap happens at vblank
+    swap_pending: bool,
+    swap_params: u32,
+    w_buffer: bool,
+
+    geometry: GeometryEngine,
+    renderer: Renderer,
+    /// Polygons handed over by the last swap, drawn again every frame until the next one
+    render_polygons: Vec<Polygon>,
+}
+
+impl Gpu {
+    pub fn new(system: &Shared<System>, irq9: &Shared<Irq>) -> Self {
+        Self {
+            system: system.clone(),
+            irq9: irq9.clone(),
+            disp3dcnt: Disp3dCnt(0),
+            gxstat_irq_mode: 0,
+            clear_color: 0,
+            clear_depth: 0,
+            clear_offset: 0,
+            alpha_test_ref: 0,
+            toon_table: [0; 32],
+            fifo: VecDeque::with_capacity(FIFO_SIZE),
+            packed_commands: 0,
+            packed_params_left: 0,
+            params: Vec::with_capacity(32),
+            swap_pending: false,
+            swap_params: 0,
+            w_buffer: false,
+            geometry: GeometryEngine::new(),
+            renderer: Renderer::new(),
+            render_polygons: Vec::with_capacity(geometry::MAX_POLYGONS),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.disp3dcnt = Disp3dCnt(0);
+        self.gxstat_irq_mode = 0;
+        self.clear_color = 0;
+        self.clear_depth = 0;
+        self.clear_offset = 0;
+        self.alpha_test_ref = 0;
+        self.toon_table = [0; 32];
+        self.fifo.clear();
+        self.packed_commands = 0;
+        self.packed_params_left = 0;
+        self.swap_pending = false;
+        self.swap_params = 0;
+        self.w_buffer = false;
+        self.geometry.reset();
+        self.renderer.reset();
+        self.render_polygons.clear();
+    }
+
+    /// Registers and the command fifo. Geometry engine state and polygon lists aren't saved,
+    /// games rebuild both every frame so the 3d layer recovers after one swap
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"GPU ");
+        state.write("disp3dcnt", self.disp3dcnt.0);
+        state.write("gxstat_irq_mode", self.gxstat_irq_mode);
+        state.write("clear_color", self.clear_color);
+        state.write("clear_depth", self.clear_depth);
+        state.write("clear_offset", self.clear_offset);
+        state.write("alpha_test_ref", self.alpha_test_ref);
+        state.write_slice("toon_table", &self.toon_table);
+
+        state.write("fifo.len", self.fifo.len() as u32);
+        for entry in &self.fifo {
+            state.write("entry.command", entry.command);
+            state.write("entry.param", entry.param);
+        }
+        state.write("packed_commands", self.packed_commands);
+        state.write("packed_params_left", self.packed_params_left as u32);
+        state.write_bool("swap_pending", self.swap_pending);
+        state.write("swap_params", self.swap_params);
+        state.write_bool("w_buffer", self.w_buffer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"GPU ");
+        self.reset();
+        self.disp3dcnt.0 = state.read();
+        self.gxstat_irq_mode = state.read();
+        self.clear_color = state.read();
+        self.clear_depth = state.read();
+        self.clear_offset = state.read();
+        self.alpha_test_ref = state.read();
+        state.read_slice(&mut self.toon_table);
+
+        let len = state.read::<u32>() as usize;
+        for _ in 0..len.min(FIFO_SIZE * 2) {
+            let command = state.read();
+            let param = state.read();
+            self.fifo.push_back(Entry { command, param });
+        }
+        self.packed_commands = state.read();
+        self.packed_params_left = state.read::<u32>() as usize;
+        self.swap_pending = state.read_bool();
+        self.swap_params = state.read();
+        self.w_buffer = state.read_bool();
+    }
+
+    /// The 3d layer for a line, rgb555 with `COLOR_TRANSPARENT` where nothing was drawn
+    pub fn output_line(&self, line: u16) -> &[u16] {
+        self.renderer.output_line(line)
+    }
+
+    /// Swaps the polygon lists if a swap was requested, lets the geometry engine continue,
+    /// then draws the current list for the next frame
+    pub fn on_vblank(&mut self, texture_data: &mut VramRegion, texture_palette: &mut VramRegion) {
+        if self.swap_pending {
+            std::mem::swap(&mut self.render_polygons, &mut self.geometry.polygons);
+            self.geometry.polygons.clear();
+            self.geometry.vertex_count = 0;
+            self.w_buffer = self.swap_params & 0x2 != 0;
+
+            // opaque polygons always go first sorted by their top and bottom, translucent ones are
+            // only sorted when bit 0 of the swap parameter doesn't ask for manual ordering
+            let manual_sort = self.swap_params & 0x1 != 0;
+            self.render_polygons.sort_by(|a, b| {
+                let key = |p: &Polygon| (p.translucent, if p.translucent && manual_sort { (0.0, 0.0) } else { (p.bottom, p.top) });
+                let (a, b) = (key(a), key(b));
+                a.0.cmp(&b.0).then(a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            });
+
+            self.swap_pending = false;
+            self.process_fifo();
+        }
+
+        let state = RenderState {
+            disp3dcnt: self.disp3dcnt,
+            clear_color: self.clear_color,
+            clear_depth: self.clear_depth,
+            clear_offset: self.clear_offset,
+            alpha_test_ref: self.alpha_test_ref,
+            toon_table: self.toon_table,
+            w_buffer: self.w_buffer,
+        };
+        self.renderer.render(&self.render_polygons, &state, texture_data, texture_palette);
+    }
+
+    fn queue(&mut self, command: u8, param: u32) {
+        self.fifo.push_back(Entry { command, param });
+        self.process_fifo();
+    }
+
+    /// Runs every command whose parameters have all arrived. Commands are executed as soon as
+    /// they're complete, so the fifo only fills up while a buffer swap is pending. The arm9 stays
+    /// stalled for as long as more than the fifo can hold has been written
+    fn process_fifo(&mut self) {
+        while !self.swap_pending {
+            let Some(&Entry { command, .. }) = self.fifo.front() else {
+                break;
+            };
+
+            let count = param_count(command);
+            if self.fifo.len() < count {
+                break;
+            }
+
+            self.params.clear();
+            for _ in 0..count.max(1) {
+                let entry = self.fifo.pop_front().unwrap();
+                self.params.push(entry.param);
+            }
+
+            match command {
+                0x50 => {
+                    self.swap_pending = true;
+                    self.swap_params = self.params[0];
+                }
+                _ => {
+                    let params = std::mem::take(&mut self.params);
+                    self.geometry.execute(command, &params);
+                    self.params = params;
+                }
+            }
+        }
+
+        if self.geometry.ram_overflow {
+            self.disp3dcnt.set_ram_overflow(true);
+        }
+        self.system.arm9.cpu.set_stalled(self.fifo.len() > FIFO_SIZE);
+        self.update_irq();
+
+        if self.fifo_half_empty() {
+            self.system.dma9.trigger(DmaTiming::GXFIFO);
+        }
+    }
+
+    /// Less than half full, when gxfifo dmas send the next block
+    pub fn fifo_half_empty(&self) -> bool {
+        self.fifo.len() < FIFO_SIZE / 2
+    }
+
+    fn update_irq(&mut self) {
+        let raise = match self.gxstat_irq_mode {
+            1 => self.fifo_half_empty(),
+            2 => self.fifo.is_empty(),
+            _ => false,
+        };
+
+        if raise {
+            self.irq9.raise(IrqSource::GXFIFO);
+        }
+    }
+
+    /// Words written to GXFIFO start with up to 4 packed command ids, followed by their parameters
+    pub fn write_gxfifo(&mut self, val: u32) {
+        if self.packed_params_left == 0 {
+            self.packed_commands = val;
+            self.unpack_commands();
+            return;
+        }
+
+        self.queue(self.packed_commands as u8, val);
+        self.packed_params_left -= 1;
+        if self.packed_params_left == 0 {
+            self.packed_commands >>= 8;
+            self.unpack_commands();
+        }
+    }
+
+    /// Queues packed commands until one needs parameters
+    fn unpack_commands(&mut self) {
+        while self.packed_commands != 0 {
+            let command = self.packed_commands as u8;
+            let count = param_count(command);
+            if count != 0 {
+                self.packed_params_left = count;
+                return;
+            }
+
+            if command != 0 {
+                self.queue(command, 0);
+            }
+            self.packed_commands >>= 8;
+        }
+    }
+
+    /// Writes to 0x04000440 and up, each address is a command and every write is a parameter
+    pub fn write_command_port(&mut self, addr: u32, val: u32) {
+        self.queue(((addr - 0x04000400) / 4) as u8, val);
+    }
+
+    pub const fn read_disp3dcnt(&self) -> u32 {
+        self.disp3dcnt.0
+    }
+
+    /// Bits 12 and 13 are acknowledged by writing 1
+    pub fn write_disp3dcnt(&mut self, val: u32, mask: u32) {
+        let mask = mask & 0x7fff;
+        set(&mut self.disp3dcnt.0, val, mask & !0x3000);
+        self.disp3dcnt.0 &= !(val & mask & 0x3000);
+        if val & mask & 0x2000 != 0 {
+            self.geometry.ram_overflow = false;
+        }
+    }
+
+    pub fn read_gxstat(&self) -> u32 {
+        let busy = self.swap_pending || !self.fifo.is_empty();
+        let level = self.fifo.len().min(FIFO_SIZE) as u32;
+        (self.geometry.box_test_result as u32) << 1
+            | self.geometry.position_stack_level() << 8
+            | self.geometry.projection_stack_level() << 13
+            | (self.geometry.stack_overflow as u32) << 15
+            | level << 16
+            | (self.fifo_half_empty() as u32) << 25
+            | ((level == 0) as u32) << 26
+            | (busy as u32) << 27
+            | self.gxstat_irq_mode << 30
+    }
+
+    pub fn write_gxstat(&mut self, val: u32, mask: u32) {
+        if mask & 0x8000 != 0 && val & 0x8000 != 0 {
+            self.geometry.stack_overflow = false;
+        }
+        if mask & 0xc0000000 != 0 {
+            self.gxstat_irq_mode = (val >> 30) & 0x3;
+            self.update_irq();
+        }
+    }
+
+    /// Polygons and vertices in the list that is being built
+    pub fn read_ram_count(&self) -> u32 {
+        self.geometry.polygons.len() as u32 | (self.geometry.vertex_count as u32) << 16
+    }
+
+    pub fn write_clear_color(&mut self, val: u32, mask: u32) {
+        set(&mut self.clear_color, val, mask);
+    }
+
+    pub fn write_clear_depth(&mut self, val: u32, mask: u32) {
+        if mask & 0xffff != 0 {
+            set(&mut self.clear_depth, val as u16, mask as u16);
+        }
+        if mask & 0xffff0000 != 0 {
+            set(&mut self.clear_offset, (val >> 16) as u16, (mask >> 16) as u16);
+        }
+    }
+
+    pub fn write_alpha_test_ref(&mut self, val: u32) {
+        self.alpha_test_ref = (val & 0x1f) as u8;
+    }
+
+    pub fn write_toon_table(&mut self, addr: u32, val: u32, mask: u32) {
+        let index = ((addr - 0x04000380) / 2) as usize;
+        if mask & 0xffff != 0 {
+            self.toon_table[index] = val as u16 & 0x7fff;
+        }
+        if mask & 0xffff0000 != 0 {
+            self.toon_table[index + 1] = (val >> 16) as u16 & 0x7fff;
+        }
+    }
+
+    /// CLIPMTX_RESULT, the 16 entries of the current clip matrix in row order
+    pub fn read_clip_matrix(&mut self, addr: u32) -> u32 {
+        let index = ((addr - 0x04000640) / 4) as usize;
+        self.geometry.clip_matrix().0[index / 4][index % 4] as u32
+    }
+
+    /// VECMTX_RESULT, the upper 3x3 of the vector matrix in row order
+    pub fn read_vector_matrix(&self, addr: u32) -> u32 {
+        let index = ((addr - 0x04000680) / 4) as usize;
+        self.geometry.vector_matrix().0[index / 3][index % 3] as u32
+    }
+
+    pub fn read_position_result(&self, addr: u32) -> u32 {
+        self.geometry.position_result[((addr - 0x04000620) / 4) as usize] as u32
+    }
+
+    /// VEC_RESULT, 3 halfwords packed into 2 words
+    pub fn read_vector_result(&self, addr: u32) -> u32 {
+        let result = self.geometry.vector_result.map(|val| val as u16 as u32);
+        match addr {
+            0x04000630 => result[0] | result[1] << 16,
+            _ => result[2],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::OwnedSystem;
+
+    const MTX_MODE: u32 = 0x04000440;
+    const MTX_PUSH: u32 = 0x04000444;
+    const MTX_POP: u32 = 0x04000448;
+    const MTX_IDENTITY: u32 = 0x04000454;
+    const SWAP_BUFFERS: u32 = 0x04000540;
+
+    fn system() -> OwnedSystem {
+        let mut system = System::new();
+        system.video_unit.gpu.reset();
+        system.arm9.cpu.reset();
+        system
+    }
+
+    /// Position stack level and overflow flag from GXSTAT
+    fn position_stack(gpu: &Gpu) -> (u32, bool) {
+        let gxstat = gpu.read_gxstat();
+        ((gxstat >> 8) & 0x1f, gxstat & (1 << 15) != 0)
+    }
+
+    #[test]
+    fn popping_past_the_bottom_of_the_position_stack_stops_at_0() {
+        let mut system = system();
+        let gpu = &mut system.video_unit.gpu;
+        gpu.write_command_port(MTX_MODE, 2);
+        gpu.write_command_port(MTX_PUSH, 0);
+        gpu.write_command_port(MTX_PUSH, 0);
+        gpu.write_command_port(MTX_POP, 3);
+        assert_eq!(position_stack(gpu), (0, true));
+
+        // the stack still works from there
+        gpu.write_gxstat(1 << 15, 0xffffffff);
+        gpu.write_command_port(MTX_PUSH, 0);
+        assert_eq!(position_stack(gpu), (1, false));
+        // -1 pushes the pointer back up
+        gpu.write_command_port(MTX_POP, 0x3f);
+        assert_eq!(position_stack(gpu), (2, false));
+    }
+
+    #[test]
+    fn pushing_onto_a_full_position_stack_keeps_the_pointer() {
+        let mut system = system();
+        let gpu = &mut system.video_unit.gpu;
+        gpu.write_command_port(MTX_MODE, 1);
+        for _ in 0..31 {
+            gpu.write_command_port(MTX_PUSH, 0);
+        }
+        assert_eq!(position_stack(gpu), (31, false));
+
+        gpu.write_command_port(MTX_PUSH, 0);
+        assert_eq!(position_stack(gpu), (31, true));
+
+        // popping past the top stops at the last entry
+        gpu.write_command_port(MTX_POP, 0x3e);
+        assert_eq!(position_stack(gpu), (31, true));
+        gpu.write_command_port(MTX_POP, 1);
+        assert_eq!(position_stack(gpu), (30, true));
+    }
+
+    #[test]
+    fn writes_to_a_full_fifo_stall_the_arm9_until_the_swap() {
+        let mut system = system();
+        let gpu = &mut system.video_unit.gpu;
+        // nothing runs after a swap until vblank, so everything written after it stays queued
+        gpu.write_command_port(SWAP_BUFFERS, 0);
+        for _ in 0..FIFO_SIZE {
+            gpu.write_command_port(MTX_IDENTITY, 0);
+        }
+        assert!(!system.arm9.cpu.is_stalled());
+        assert_eq!(system.video_unit.gpu.read_gxstat() >> 16 & 0x1ff, FIFO_SIZE as u32);
+
+        system.video_unit.gpu.write_command_port(MTX_IDENTITY, 0);
+        assert!(system.arm9.cpu.is_stalled());
+        assert_eq!(system.video_unit.gpu.read_gxstat() >> 16 & 0x1ff, FIFO_SIZE as u32);
+
+        system.video_unit.gpu.on_vblank(&mut VramRegion::default(), &mut VramRegion::default());
+        assert!(!system.arm9.cpu.is_stalled());
+        assert_eq!(system.video_unit.gpu.read_gxstat() >> 16 & 0x1ff, 0);
+    }
+}
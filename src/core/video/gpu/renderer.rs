@@ -0,0 +1,286 @@
+use crate::core::video::gpu::geometry::{Polygon, ScreenVertex};
+use crate::core::video::gpu::texture::{Texel, TextureCache};
+use crate::core::video::gpu::Disp3dCnt;
+use crate::core::video::vram::VramRegion;
+
+/// Bit 15 marks a pixel no polygon was drawn on, the same way the ppu marks transparent layer pixels
+pub const COLOR_TRANSPARENT: u16 = 0x8000;
+
+/// Register state the rendering engine reads while drawing a frame
+pub struct RenderState {
+    pub disp3dcnt: Disp3dCnt,
+    pub clear_color: u32,
+    pub clear_depth: u16,
+    pub clear_offset: u16,
+    pub alpha_test_ref: u8,
+    pub toon_table: [u16; 32],
+    /// Compare w instead of z, picked by the last SWAP_BUFFERS
+    pub w_buffer: bool,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Pixel {
+    /// 6 bit channels
+    color: [u8; 3],
+    /// 5 bit alpha, 0 means nothing was drawn
+    alpha: u8,
+}
+
+/// Attributes interpolated across a polygon. Everything but `x` and `z` is divided by w so that
+/// interpolating linearly in screen space stays perspective correct
+#[derive(Copy, Clone)]
+struct Interpolant {
+    x: f32,
+    z: f32,
+    inv_w: f32,
+    color: [f32; 3],
+    texcoord: [f32; 2],
+}
+
+impl Interpolant {
+    fn new(vertex: &ScreenVertex) -> Self {
+        let inv_w = 1.0 / vertex.w;
+        Self {
+            x: vertex.x,
+            z: vertex.z,
+            inv_w,
+            color: vertex.color.map(|c| c * inv_w),
+            texcoord: vertex.texcoord.map(|c| c * inv_w),
+        }
+    }
+
+    fn lerp(&self, other: &Interpolant, t: f32) -> Interpolant {
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        Interpolant {
+            x: mix(self.x, other.x),
+            z: mix(self.z, other.z),
+            inv_w: mix(self.inv_w, other.inv_w),
+            color: std::array::from_fn(|i| mix(self.color[i], other.color[i])),
+            texcoord: std::array::from_fn(|i| mix(self.texcoord[i], other.texcoord[i])),
+        }
+    }
+}
+
+/// Scanline rasterizer for the polygons of one frame. Edge marking, fog, anti-aliasing and
+/// shadow polygons aren't emulated
+pub struct Renderer {
+    color: Box<[Pixel; 256 * 192]>,
+    depth: Box<[u32; 256 * 192]>,
+    /// The finished frame as rgb555, composited by engine A as bg0
+    output: Box<[u16; 256 * 192]>,
+    textures: TextureCache,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self {
+            color: Box::new([Pixel::default(); 256 * 192]),
+            depth: Box::new([0; 256 * 192]),
+            output: Box::new([COLOR_TRANSPARENT; 256 * 192]),
+            textures: TextureCache::default(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.color.fill(Pixel::default());
+        self.depth.fill(0);
+        self.output.fill(COLOR_TRANSPARENT);
+        self.textures.reset();
+    }
+
+    pub fn output_line(&self, line: u16) -> &[u16] {
+        let start = line as usize * 256;
+        &self.output[start..start + 256]
+    }
+
+    pub fn render(&mut self, polygons: &[Polygon], state: &RenderState, data: &mut VramRegion, palette: &mut VramRegion) {
+        self.textures.invalidate(data, palette);
+        self.clear(state, data);
+
+        for polygon in polygons {
+            self.render_polygon(polygon, state, data, palette);
+        }
+
+        for (out, pixel) in self.output.iter_mut().zip(self.color.iter()) {
+            *out = if pixel.alpha == 0 {
+                COLOR_TRANSPARENT
+            } else {
+                let [r, g, b] = pixel.color.map(|c| (c >> 1) as u16);
+                (b << 10) | (g << 5) | r
+            };
+        }
+    }
+
+    fn clear(&mut self, state: &RenderState, data: &mut VramRegion) {
+        if !state.disp3dcnt.clear_image() {
+            let pixel = Pixel {
+                color: rgb555_to_rgb666(state.clear_color as u16),
+                alpha: ((state.clear_color >> 16) & 0x1f) as u8,
+            };
+            self.color.fill(pixel);
+            self.depth.fill(expand_depth(state.clear_depth));
+            return;
+        }
+
+        // the rear plane bitmap, colors in texture slot 2 and depths in slot 3, scrolled by the clear offset
+        let offset_x = (state.clear_offset & 0xff) as usize;
+        let offset_y = (state.clear_offset >> 8) as usize;
+        for y in 0..192 {
+            for x in 0..256 {
+                let addr = ((((y + offset_y) & 0xff) * 256 + ((x + offset_x) & 0xff)) * 2) as u32;
+                let color = data.read::<u16>(0x40000 + addr);
+                let depth = data.read::<u16>(0x60000 + addr);
+                self.color[y * 256 + x] = Pixel {
+                    color: rgb555_to_rgb666(color),
+                    alpha: if color & 0x8000 != 0 { 31 } else { 0 },
+                };
+                self.depth[y * 256 + x] = expand_depth(depth);
+            }
+        }
+    }
+
+    fn render_polygon(&mut self, polygon: &Polygon, state: &RenderState, data: &mut VramRegion, palette: &mut VramRegion) {
+        // shadow polygons need the stencil buffer, skip them rather than drawing them as solid shapes
+        if polygon.attr.mode() == 3 {
+            return;
+        }
+
+        let texture = (state.disp3dcnt.textures() && polygon.texture.format() != 0)
+            .then(|| self.textures.get(polygon.texture, polygon.palette_base, data, palette));
+        let vertices = polygon.vertices();
+        let wireframe = polygon.attr.alpha() == 0;
+        let y_start = polygon.top.max(0.0) as i32;
+        let y_end = polygon.bottom.min(192.0) as i32;
+
+        for y in y_start..y_end {
+            let sample_y = y as f32 + 0.5;
+            let mut left: Option<Interpolant> = None;
+            let mut right: Option<Interpolant> = None;
+
+            for i in 0..vertices.len() {
+                let (a, b) = (&vertices[i], &vertices[(i + 1) % vertices.len()]);
+                let (top, bottom) = if a.y <= b.y { (a, b) } else { (b, a) };
+                if sample_y < top.y || sample_y >= bottom.y {
+                    continue;
+                }
+
+                let edge = Interpolant::new(top).lerp(&Interpolant::new(bottom), (sample_y - top.y) / (bottom.y - top.y));
+                if left.is_none_or(|left| edge.x < left.x) {
+                    left = Some(edge);
+                }
+                if right.is_none_or(|right| edge.x > right.x) {
+                    right = Some(edge);
+                }
+            }
+
+            let (Some(left), Some(right)) = (left, right) else {
+                continue;
+            };
+
+            let x_start = (left.x - 0.5).ceil().max(0.0) as i32;
+            let x_end = (right.x - 0.5).ceil().min(256.0) as i32;
+            let width = (right.x - left.x).max(f32::EPSILON);
+
+            for x in x_start..x_end {
+                if wireframe && x != x_start && x != x_end - 1 && y != y_start && y != y_end - 1 {
+                    continue;
+                }
+
+                let point = left.lerp(&right, (x as f32 + 0.5 - left.x) / width);
+                self.render_pixel(polygon, state, &point, (y * 256 + x) as usize, texture);
+            }
+        }
+    }
+
+    fn render_pixel(&mut self, polygon: &Polygon, state: &RenderState, point: &Interpolant, index: usize, texture: Option<usize>) {
+        let w = 1.0 / point.inv_w;
+        let depth = if state.w_buffer {
+            w.clamp(0.0, 0xffffff as f32) as u32
+        } else {
+            point.z as u32
+        };
+
+        let passed = if polygon.attr.depth_equal() {
+            depth.abs_diff(self.depth[index]) <= 0x200
+        } else {
+            depth < self.depth[index]
+        };
+        if !passed {
+            return;
+        }
+
+        let color = point.color.map(|c| (c * w).clamp(0.0, 63.0) as u8);
+        let texcoord = point.texcoord.map(|c| (c * w).floor() as i32);
+        let (color, alpha) = self.shade(polygon, state, color, texcoord, texture);
+
+        if alpha == 0 || (state.disp3dcnt.alpha_test() && alpha <= state.alpha_test_ref) {
+            return;
+        }
+
+        let dest = &mut self.color[index];
+        if alpha < 31 && state.disp3dcnt.alpha_blending() && dest.alpha != 0 {
+            let a = alpha as u32;
+            dest.color = std::array::from_fn(|i| ((color[i] as u32 * (a + 1) + dest.color[i] as u32 * (31 - a)) / 32) as u8);
+            dest.alpha = dest.alpha.max(alpha);
+        } else {
+            *dest = Pixel { color, alpha };
+        }
+
+        if alpha == 31 || polygon.attr.depth_write_translucent() {
+            self.depth[index] = depth;
+        }
+    }
+
+    /// Combines the vertex color with the texture and toon table depending on the polygon mode.
+    /// `texture` is the polygon's texture in the cache, `None` when it's drawn untextured
+    fn shade(&self, polygon: &Polygon, state: &RenderState, vertex: [u8; 3], texcoord: [i32; 2], texture: Option<usize>) -> ([u8; 3], u8) {
+        let polygon_alpha = match polygon.attr.alpha() {
+            0 => 31,
+            alpha => alpha as u8,
+        };
+
+        let texel = texture.map(|index| self.textures.sample(index, polygon.texture, texcoord[0], texcoord[1]));
+
+        let modulate = |base: [u8; 3], texel: Option<Texel>| match texel {
+            Some(texel) => {
+                let color = rgb555_to_rgb666(texel.color);
+                let rgb = std::array::from_fn(|i| (((color[i] as u32 + 1) * (base[i] as u32 + 1) - 1) / 64) as u8);
+                let alpha = (((texel.alpha as u32 + 1) * (polygon_alpha as u32 + 1) - 1) / 32) as u8;
+                (rgb, alpha)
+            }
+            None => (base, polygon_alpha),
+        };
+
+        match polygon.attr.mode() {
+            1 => match texel {
+                Some(texel) => {
+                    let color = rgb555_to_rgb666(texel.color);
+                    let a = texel.alpha as u32;
+                    let rgb = std::array::from_fn(|i| ((color[i] as u32 * a + vertex[i] as u32 * (31 - a)) / 31) as u8);
+                    (rgb, polygon_alpha)
+                }
+                None => (vertex, polygon_alpha),
+            },
+            2 => {
+                // the toon table is indexed by the red channel of the vertex color
+                let toon = rgb555_to_rgb666(state.toon_table[(vertex[0] >> 1) as usize]);
+                if state.disp3dcnt.highlight_shading() {
+                    let (rgb, alpha) = modulate([vertex[0]; 3], texel);
+                    (std::array::from_fn(|i| (rgb[i] + toon[i]).min(63)), alpha)
+                } else {
+                    modulate(toon, texel)
+                }
+            }
+            _ => modulate(vertex, texel),
+        }
+    }
+}
+
+const fn expand_depth(depth: u16) -> u32 {
+    let depth = (depth & 0x7fff) as u32;
+    depth * 0x200 + ((depth + 1) / 0x8000) * 0x1ff
+}
+
+fn rgb555_to_rgb666(color: u16) -> [u8; 3] {
+    [color & 0x1f, (color >> 5) & 0x1f, (color >> 10) & 0x1f].map(|c| (c * 2 + (c != 0) as u16) as u8)
+}
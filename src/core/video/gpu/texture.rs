@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+
+use crate::core::video::gpu::geometry::TexImageParam;
+use crate::core::video::vram::VramRegion;
+
+/// TEXIMAGE_PARAM bits that change the decoded texels. Repeat, flip and the coordinate transform only
+/// change how a texture is sampled
+const DECODED_PARAMS: u32 = 0x3ff0ffff;
+
+/// Past this many textures the cache starts over rather than growing without end
+const MAX_TEXTURES: usize = 1024;
+
+/// A decoded texel, `color` is rgb555 and `alpha` 5 bits where 0 is fully transparent
+#[derive(Copy, Clone)]
+pub struct Texel {
+    pub color: u16,
+    pub alpha: u8,
+}
+
+const TRANSPARENT: Texel = Texel { color: 0, alpha: 0 };
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct TextureKey {
+    params: u32,
+    palette_base: u32,
+}
+
+struct CachedTexture {
+    key: TextureKey,
+    width: i32,
+    height: i32,
+    texels: Vec<Texel>,
+}
+
+/// Textures decoded once and reused until the vram they were decoded from changes. Texture data and
+/// palettes can only be written through the lcdc, so it's banks being mapped in and out of the texture
+/// slots that invalidates them, which the regions track with their page generations
+#[derive(Default)]
+pub struct TextureCache {
+    textures: Vec<CachedTexture>,
+    lookup: HashMap<TextureKey, usize>,
+    data_checkpoint: u64,
+    palette_checkpoint: u64,
+}
+
+impl TextureCache {
+    pub fn reset(&mut self) {
+        self.textures.clear();
+        self.lookup.clear();
+        self.data_checkpoint = 0;
+        self.palette_checkpoint = 0;
+    }
+
+    /// Drops the textures whose data or palette changed since the last call
+    pub fn invalidate(&mut self, data: &mut VramRegion, palette: &mut VramRegion) {
+        let (data_checkpoint, palette_checkpoint) = (self.data_checkpoint, self.palette_checkpoint);
+        let len = self.textures.len();
+        self.textures.retain(|texture| {
+            let params = TexImageParam::new(texture.key.params);
+            let ((data_addr, data_length), info) = data_range(params);
+            let (palette_addr, palette_length) = palette_range(params, texture.key.palette_base);
+            !is_dirty(data, data_checkpoint, data_addr, data_length, 0x7ffff)
+                && !info.is_some_and(|(addr, length)| is_dirty(data, data_checkpoint, addr, length, 0x7ffff))
+                && !is_dirty(palette, palette_checkpoint, palette_addr, palette_length, 0x1ffff)
+        });
+        if self.textures.len() != len {
+            self.lookup = self.textures.iter().enumerate().map(|(i, texture)| (texture.key, i)).collect();
+        }
+
+        self.data_checkpoint = data.checkpoint();
+        self.palette_checkpoint = palette.checkpoint();
+    }
+
+    /// Decodes the texture unless it's already cached, and returns where it's kept for `sample`
+    pub fn get(&mut self, texture: TexImageParam, palette_base: u32, data: &mut VramRegion, palette: &mut VramRegion) -> usize {
+        let key = TextureKey { params: texture.bits() & DECODED_PARAMS, palette_base };
+        if let Some(&index) = self.lookup.get(&key) {
+            return index;
+        }
+
+        if self.textures.len() >= MAX_TEXTURES {
+            self.textures.clear();
+            self.lookup.clear();
+        }
+
+        let width = 8 << texture.size_s() as i32;
+        let height = 8 << texture.size_t() as i32;
+        let texels = (0..height as u32)
+            .flat_map(|t| (0..width as u32).map(move |s| (s, t)))
+            .map(|(s, t)| decode_texel(texture, palette_base, s, t, data, palette))
+            .collect();
+        self.textures.push(CachedTexture { key, width, height, texels });
+        self.lookup.insert(key, self.textures.len() - 1);
+        self.textures.len() - 1
+    }
+
+    /// Fetches the texel at integer texel coordinates of the texture `get` returned, wrapping or
+    /// clamping them first as `texture` asks
+    pub fn sample(&self, index: usize, texture: TexImageParam, s: i32, t: i32) -> Texel {
+        let cached = &self.textures[index];
+        let s = wrap(s, cached.width, texture.repeat_s(), texture.flip_s());
+        let t = wrap(t, cached.height, texture.repeat_t(), texture.flip_t());
+        cached.texels[(t * cached.width + s) as usize]
+    }
+}
+
+/// The texel data a texture reads, and for compressed textures the block info in slot 1 as well
+fn data_range(texture: TexImageParam) -> ((u32, u32), Option<(u32, u32)>) {
+    let base = texture.offset() * 8;
+    let texels = (8 << texture.size_s()) * (8 << texture.size_t());
+    let bits = [0, 8, 2, 4, 8, 2, 8, 16][texture.format() as usize];
+    let length = texels * bits / 8;
+    let info = (texture.format() == 5).then(|| {
+        let addr = 0x20000 + (base & 0x1ffff) / 2 + if base >= 0x40000 { 0x10000 } else { 0 };
+        (addr, length / 2)
+    });
+    ((base, length), info)
+}
+
+/// The palette entries a texture reads, compressed blocks can pick any of them
+fn palette_range(texture: TexImageParam, palette_base: u32) -> (u32, u32) {
+    match texture.format() {
+        1 => (palette_base * 16, 32 * 2),
+        2 => (palette_base * 8, 4 * 2),
+        3 => (palette_base * 16, 16 * 2),
+        4 => (palette_base * 16, 256 * 2),
+        5 => (0, 0x20000),
+        6 => (palette_base * 16, 8 * 2),
+        _ => (0, 0),
+    }
+}
+
+/// `is_dirty_since` for a range that can wrap around the end of a region `mask + 1` bytes big
+fn is_dirty(region: &VramRegion, checkpoint: u64, addr: u32, length: u32, mask: u32) -> bool {
+    if length == 0 {
+        return false;
+    }
+
+    let start = addr & mask;
+    let wrapped = (start + length).saturating_sub(mask + 1);
+    region.is_dirty_since(checkpoint, start, length - wrapped) || (wrapped != 0 && region.is_dirty_since(checkpoint, 0, wrapped))
+}
+
+/// Decodes the texel at `s`, `t`, which have to be inside the texture
+fn decode_texel(texture: TexImageParam, palette_base: u32, s: u32, t: u32, data: &mut VramRegion, palette: &mut VramRegion) -> Texel {
+    let width = 8 << texture.size_s();
+    let base = texture.offset() * 8;
+    let index = t * width + s;
+    let palette_addr = palette_base * 16;
+    let read_data = |data: &mut VramRegion, addr: u32| data.read::<u8>(addr & 0x7ffff) as u32;
+    let read_color = |palette: &mut VramRegion, addr: u32| palette.read::<u16>(addr & 0x1fffe) & 0x7fff;
+    let opaque = |color: u16| Texel { color, alpha: 31 };
+
+    match texture.format() {
+        // a3i5, 3 bit alpha expanded to 5 bits
+        1 => {
+            let val = read_data(data, base + index);
+            let alpha = val >> 5;
+            Texel { color: read_color(palette, palette_addr + (val & 0x1f) * 2), alpha: ((alpha << 2) | (alpha >> 1)) as u8 }
+        }
+        2 => {
+            let val = (read_data(data, base + index / 4) >> ((index % 4) * 2)) & 0x3;
+            if val == 0 && texture.color0_transparent() {
+                return TRANSPARENT;
+            }
+            // the 4 color format uses 8 byte palette steps
+            opaque(read_color(palette, palette_base * 8 + val * 2))
+        }
+        3 => {
+            let val = (read_data(data, base + index / 2) >> ((index % 2) * 4)) & 0xf;
+            if val == 0 && texture.color0_transparent() {
+                return TRANSPARENT;
+            }
+            opaque(read_color(palette, palette_addr + val * 2))
+        }
+        4 => {
+            let val = read_data(data, base + index);
+            if val == 0 && texture.color0_transparent() {
+                return TRANSPARENT;
+            }
+            opaque(read_color(palette, palette_addr + val * 2))
+        }
+        5 => decode_compressed(base, palette_addr, s, t, width, data, palette),
+        // a5i3
+        6 => {
+            let val = read_data(data, base + index);
+            Texel { color: read_color(palette, palette_addr + (val & 0x7) * 2), alpha: (val >> 3) as u8 }
+        }
+        7 => {
+            let val = data.read::<u16>((base + index * 2) & 0x7fffe);
+            Texel { color: val & 0x7fff, alpha: if val & 0x8000 != 0 { 31 } else { 0 } }
+        }
+        _ => TRANSPARENT,
+    }
+}
+
+/// 4x4 blocks of 2 bit texels, each block has a 16 bit palette entry in slot 1
+fn decode_compressed(base: u32, palette_addr: u32, s: u32, t: u32, width: u32, data: &mut VramRegion, palette: &mut VramRegion) -> Texel {
+    let block_addr = base + ((t / 4) * (width / 4) + s / 4) * 4;
+    let row = data.read::<u8>((block_addr + (t % 4)) & 0x7ffff);
+    let texel = (row >> ((s % 4) * 2)) & 0x3;
+
+    // texels in slot 0 take their block info from the first half of slot 1, slot 2 from the second half
+    let info_addr = 0x20000 + (block_addr & 0x1ffff) / 2 + if block_addr >= 0x40000 { 0x10000 } else { 0 };
+    let info = data.read::<u16>(info_addr & 0x7fffe) as u32;
+    let palette_addr = palette_addr + (info & 0x3fff) * 4;
+    let mut color = |i: u32| palette.read::<u16>((palette_addr + i * 2) & 0x1fffe) & 0x7fff;
+
+    let opaque = |color: u16| Texel { color, alpha: 31 };
+    match (info >> 14, texel) {
+        (0 | 1, 3) => TRANSPARENT,
+        (_, 0) => opaque(color(0)),
+        (_, 1) => opaque(color(1)),
+        (0 | 2, 2) => opaque(color(2)),
+        (2, 3) => opaque(color(3)),
+        (1, 2) => opaque(mix(color(0), color(1), 4, 4)),
+        (3, 2) => opaque(mix(color(0), color(1), 5, 3)),
+        _ => opaque(mix(color(0), color(1), 3, 5)),
+    }
+}
+
+/// Per channel `(a * weight_a + b * weight_b) / 8`
+fn mix(a: u16, b: u16, weight_a: u16, weight_b: u16) -> u16 {
+    let channel = |shift: u16| ((((a >> shift) & 0x1f) * weight_a + ((b >> shift) & 0x1f) * weight_b) / 8) << shift;
+    channel(0) | channel(5) | channel(10)
+}
+
+fn wrap(coord: i32, size: i32, repeat: bool, flip: bool) -> i32 {
+    if !repeat {
+        return coord.clamp(0, size - 1);
+    }
+
+    if flip {
+        let coord = coord.rem_euclid(size * 2);
+        if coord >= size {
+            size * 2 - 1 - coord
+        } else {
+            coord
+        }
+    } else {
+        coord.rem_euclid(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::video::vram::{Vram, VramBank};
+
+    /// An 8x8 direct color texture at the start of slot 0
+    const DIRECT_8X8: TexImageParam = TexImageParam::new(7 << 26);
+    const REPEAT_FLIP_S: u32 = (1 << 16) | (1 << 18);
+
+    fn vram() -> Vram {
+        let mut vram = Vram::new();
+        vram.reset();
+        vram
+    }
+
+    /// Writes the first texel of bank A through the lcdc and maps the bank to texture slot 0
+    fn write_first_texel(vram: &mut Vram, color: u16) {
+        vram.write_vramcnt(VramBank::A, 0x80);
+        vram.write::<u16>(0x06800000, color);
+        vram.write_vramcnt(VramBank::A, 0x83);
+    }
+
+    fn first_texel(cache: &mut TextureCache, vram: &mut Vram, texture: TexImageParam) -> u16 {
+        cache.invalidate(&mut vram.texture_data, &mut vram.texture_palette);
+        let index = cache.get(texture, 0, &mut vram.texture_data, &mut vram.texture_palette);
+        cache.sample(index, texture, 0, 0).color
+    }
+
+    #[test]
+    fn textures_are_decoded_again_once_their_bank_is_remapped() {
+        let mut vram = vram();
+        let mut cache = TextureCache::default();
+        write_first_texel(&mut vram, 0x801f);
+        assert_eq!(first_texel(&mut cache, &mut vram, DIRECT_8X8), 0x001f);
+
+        // remapping a bank into another slot leaves textures in slot 0 alone
+        vram.write_vramcnt(VramBank::B, 0x8b);
+        assert_eq!(first_texel(&mut cache, &mut vram, DIRECT_8X8), 0x001f);
+        assert_eq!(cache.textures.len(), 1);
+
+        write_first_texel(&mut vram, 0x83e0);
+        assert_eq!(first_texel(&mut cache, &mut vram, DIRECT_8X8), 0x03e0);
+        assert_eq!(cache.textures.len(), 1);
+    }
+
+    #[test]
+    fn repeat_and_flip_share_the_decoded_texture() {
+        let mut vram = vram();
+        let mut cache = TextureCache::default();
+        write_first_texel(&mut vram, 0x801f);
+
+        let flipped = TexImageParam::new(DIRECT_8X8.bits() | REPEAT_FLIP_S);
+        let index = cache.get(DIRECT_8X8, 0, &mut vram.texture_data, &mut vram.texture_palette);
+        assert_eq!(cache.get(flipped, 0, &mut vram.texture_data, &mut vram.texture_palette), index);
+
+        // s = 15 mirrors back onto the first column, without repeat it clamps to the last one
+        assert_eq!(cache.sample(index, flipped, 15, 0).color, 0x001f);
+        assert_eq!(cache.sample(index, DIRECT_8X8, 15, 0).color, 0);
+    }
+}
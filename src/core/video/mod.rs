@@ -1,20 +1,27 @@
 use crate::arm::cpu::Arch;
-use std::rc::Rc;
+use std::hash::Hasher;
 use std::sync::Arc;
-use log::error;
 
 use crate::bitfield;
+use crate::core::config::ScreenOrder;
 use crate::core::hardware::dma::DmaTiming;
 use crate::core::hardware::irq::{Irq, IrqSource};
-use crate::core::scheduler::EventInfo;
-use crate::core::video::ppu::Ppu;
+use crate::core::scheduler::EventId;
+use crate::core::timing::{HBLANK_CYCLES, HDRAW_CYCLES, TOTAL_LINES, VBLANK_END_LINE, VISIBLE_LINES};
+use crate::core::video::engine_memory::{Engine, EngineMemory};
+use crate::core::video::gpu::Gpu;
+use crate::core::video::ppu::{Ppu, RenderJob, ThreadedPpu};
 use crate::core::video::vram::{Vram, VramBank};
 use crate::core::System;
-use crate::util::{set, Shared};
+use crate::unimplemented_feature;
+use crate::util::{set, Shared, StateReader, StateWriter};
 
+pub mod engine_memory;
+pub mod gpu;
 pub mod ppu;
 pub mod vram;
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Screen {
     Top,
     Bottom,
@@ -69,12 +76,12 @@ bitfield! {
 pub struct VideoUnit {
     system: Shared<System>,
     pub vram: Vram,
-    pub ppu_a: Ppu,
-    pub ppu_b: Ppu,
-    pub gpu: (),
+    pub ppu_a: ThreadedPpu,
+    pub ppu_b: ThreadedPpu,
+    pub gpu: Gpu,
 
-    palette_ram: Box<[u8; 0x800]>,
-    oam: Box<[u8; 0x800]>,
+    palette_ram: Shared<EngineMemory>,
+    oam: Shared<EngineMemory>,
 
     powcnt1: PowCnt1,
     vcount: u16,
@@ -84,37 +91,43 @@ pub struct VideoUnit {
     irq7: Shared<Irq>,
     irq9: Shared<Irq>,
 
-    scanline_start_event: Rc<EventInfo>,
-    scanline_end_event: Rc<EventInfo>,
+    /// Pixel the display fifo is drawn up to on the current line
+    display_fifo_x: u16,
+
+    scanline_start_event: EventId,
+    scanline_end_event: EventId,
+    display_fifo_event: EventId,
 }
 
 impl VideoUnit {
     pub fn new(system: &Shared<System>, irq7: &Shared<Irq>, irq9: &Shared<Irq>) -> Self {
         let vram = Vram::new();
-        let mut palette_ram = Box::new([0; 0x800]);
-        let mut oam = Box::new([0; 0x800]);
+        let palette_ram = Shared::new(EngineMemory::new());
+        let oam = Shared::new(EngineMemory::new());
         Self {
             system: system.clone(),
-            ppu_a: Ppu::new(
+            ppu_a: ThreadedPpu::new(Ppu::new(
+                Engine::A,
                 &vram.bga,
                 &vram.obja,
                 &vram.bga_extended_palette,
                 &vram.obja_extended_palette,
                 &vram.lcdc,
-                palette_ram.as_mut_slice(),
-                oam.as_mut_slice()
-            ),
-            ppu_b: Ppu::new(
+                &palette_ram,
+                &oam
+            )),
+            ppu_b: ThreadedPpu::new(Ppu::new(
+                Engine::B,
                 &vram.bgb,
                 &vram.objb,
                 &vram.bgb_extended_palette,
                 &vram.objb_extended_palette,
                 &vram.lcdc,
-                &mut palette_ram.as_mut_slice()[0x400..],
-                &mut oam.as_mut_slice()[0x400..]
-            ),
+                &palette_ram,
+                &oam
+            )),
             vram,
-            gpu: (),
+            gpu: Gpu::new(system, irq9),
             palette_ram,
             oam,
             powcnt1: PowCnt1(0),
@@ -125,79 +138,201 @@ impl VideoUnit {
             irq7: irq7.clone(),
             irq9: irq9.clone(),
 
-            scanline_start_event: Rc::default(),
-            scanline_end_event: Rc::default(),
+            display_fifo_x: 0,
+
+            scanline_start_event: EventId::default(),
+            scanline_end_event: EventId::default(),
+            display_fifo_event: EventId::default(),
         }
     }
 
     pub fn reset(&mut self) {
-        self.palette_ram.fill(0);
-        self.oam.fill(0);
+        self.palette_ram.reset();
+        self.oam.reset();
         self.powcnt1.0 = 0;
         self.dispstat7.0 = 0;
         self.dispstat9.0 = 0;
         self.vcount = 0;
+        self.display_fifo_x = 0;
 
         self.vram.reset();
         self.ppu_a.reset();
         self.ppu_b.reset();
+        self.gpu.reset();
 
         let scheduler = &mut self.system.scheduler;
         self.scanline_start_event = scheduler.register_event("Scanline Start", |system| {
             system.video_unit.render_scanline_start();
-            system.scheduler.add_event(524, &system.video_unit.scanline_end_event);
+            system.scheduler.add_event(HBLANK_CYCLES, system.video_unit.scanline_end_event);
         });
         self.scanline_end_event = scheduler.register_event("Scanline End", |system| {
             system.video_unit.render_scanline_end();
-            system.scheduler.add_event(1606, &system.video_unit.scanline_start_event);
+            system.scheduler.add_event(HDRAW_CYCLES, system.video_unit.scanline_start_event);
         });
+        self.display_fifo_event = scheduler.register_event("Display FIFO", |system| system.video_unit.on_display_fifo());
+
+        scheduler.add_event(HDRAW_CYCLES, self.scanline_start_event);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"VIDE");
+        state.write("powcnt1", self.powcnt1.0);
+        state.write("vcount", self.vcount);
+        state.write("dispstat7", self.dispstat7.0);
+        state.write("dispstat9", self.dispstat9.0);
+        state.write("dispcapcnt", self.dispcapcnt.0);
+        state.write("display_fifo_x", self.display_fifo_x);
+        self.palette_ram.save_state(state);
+        self.oam.save_state(state);
+        self.vram.save_state(state);
+        self.ppu_a.save_state(state);
+        self.ppu_b.save_state(state);
+        self.gpu.save_state(state);
+    }
 
-        scheduler.add_event(1606, &self.scanline_start_event);
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"VIDE");
+        self.powcnt1.0 = state.read();
+        self.vcount = state.read();
+        self.dispstat7.0 = state.read();
+        self.dispstat9.0 = state.read();
+        self.dispcapcnt.0 = state.read();
+        self.display_fifo_x = state.read();
+        self.palette_ram.load_state(state);
+        self.oam.load_state(state);
+        self.vram.load_state(state);
+        self.ppu_a.load_state(state);
+        self.ppu_b.load_state(state);
+        self.gpu.load_state(state);
     }
 
     pub fn fetch_framebuffer(&self, screen: Screen) -> &[u8] {
-        if self.powcnt1.display_swap() == matches!(screen, Screen::Top) {
+        let engine_a_on_top = match self.system.config.screen_order {
+            ScreenOrder::Auto => self.powcnt1.display_swap(),
+            ScreenOrder::Swapped => !self.powcnt1.display_swap(),
+            ScreenOrder::EngineATop => true,
+            ScreenOrder::EngineBTop => false,
+        };
+
+        if engine_a_on_top == matches!(screen, Screen::Top) {
             self.ppu_a.fetch_framebuffer()
         } else {
             self.ppu_b.fetch_framebuffer()
         }
     }
 
+    /// Hash of both converted screens top first, for comparing frames between runs
+    pub fn frame_hash(&self) -> u64 {
+        let mut hasher = seahash::SeaHasher::new();
+        hasher.write(self.fetch_framebuffer(Screen::Top));
+        hasher.write(self.fetch_framebuffer(Screen::Bottom));
+        hasher.finish()
+    }
+
+    /// Which of the fetched framebuffers belongs to the lcd with the touchscreen
+    pub fn touch_screen(&self) -> Screen {
+        let swapped = match self.system.config.screen_order {
+            ScreenOrder::Auto => false,
+            ScreenOrder::Swapped => true,
+            ScreenOrder::EngineATop => !self.powcnt1.display_swap(),
+            ScreenOrder::EngineBTop => self.powcnt1.display_swap(),
+        };
+
+        if swapped {
+            Screen::Top
+        } else {
+            Screen::Bottom
+        }
+    }
+
+    pub fn on_finish_frame(&mut self) {
+        if self.system.config.threaded_video {
+            self.render_threaded(RenderJob::FinishFrame);
+        } else {
+            self.ppu_a.on_finish_frame();
+            self.ppu_b.on_finish_frame();
+        }
+    }
+
+    fn render_scanline(&mut self, line: u16) {
+        self.ppu_a.set_3d_line(self.gpu.output_line(line));
+
+        if self.system.config.threaded_video {
+            self.render_threaded(RenderJob::Scanline(line));
+        } else {
+            self.ppu_a.render_scanline(line);
+            self.ppu_b.render_scanline(line);
+        }
+    }
+
+    /// Runs `job` for both engines on their render threads and waits for both to finish
+    fn render_threaded(&mut self, job: RenderJob) {
+        self.ppu_a.start(job);
+        self.ppu_b.start(job);
+        self.ppu_a.wait();
+        self.ppu_b.wait();
+    }
+
     fn render_scanline_start(&mut self) {
-        if self.vcount < 192 {
-            self.ppu_a.render_scanline(self.vcount);
-            self.ppu_b.render_scanline(self.vcount);
+        if self.vcount < VISIBLE_LINES {
+            self.render_scanline(self.vcount);
             self.system.dma9.trigger(DmaTiming::HBlank);
         }
 
         self.dispstat7.set_hblank(true);
         self.dispstat9.set_hblank(true);
 
+        // hblank irqs fire on every line including vblank ones, unlike hblank dmas. The line is already drawn,
+        // so whatever the handler writes (scroll, affine reference points, palettes) shows up from the next line
         if self.dispstat7.hblank_irq() {
-            todo!()
+            self.irq7.raise(IrqSource::HBlank)
         }
 
         if self.dispstat9.hblank_irq() {
-            todo!()
+            self.irq9.raise(IrqSource::HBlank)
         }
+    }
 
-        // todo: 3d rendering
+    /// Every 8 pixels (48 cycles) the ppu takes 4 words out of the display fifo and the dma refills it
+    fn on_display_fifo(&mut self) {
+        if self.display_fifo_x > 0 {
+            self.ppu_a.sample_display_fifo(self.display_fifo_x as usize - 8);
+        }
 
-        if self.vcount > 1 && self.vcount < 194 {
-            self.system.dma9.trigger(DmaTiming::StartOfDisplay)
+        if self.display_fifo_x < 256 {
+            self.system.dma9.trigger(DmaTiming::MainMemoryDisplay);
+            self.display_fifo_x += 8;
+            self.system.scheduler.add_event(48, self.display_fifo_event);
         }
     }
 
     fn render_scanline_end(&mut self) {
+        self.ppu_a.on_scanline_end();
+        self.ppu_b.on_scanline_end();
+
         self.vcount += 1;
-        if self.vcount == 263 {
+        if self.vcount == TOTAL_LINES {
             self.vcount = 0;
         }
 
+        // start of display dmas fire at the start of lines 2 to 193 and are turned off on line 194, repeat or not.
+        // the arm7 has no display timings, its only video trigger is vblank
+        if self.vcount > 1 && self.vcount < 194 {
+            self.system.dma9.trigger(DmaTiming::StartOfDisplay)
+        } else if self.vcount == 194 {
+            self.system.dma9.stop(DmaTiming::StartOfDisplay)
+        }
+
+        if self.vcount < VISIBLE_LINES && self.ppu_a.main_memory_display() {
+            self.display_fifo_x = 0;
+            self.system.scheduler.add_event(1, self.display_fifo_event);
+        }
+
         self.dispstat7.set_hblank(false);
         self.dispstat9.set_hblank(false);
 
-        if self.vcount == 192 {
+        if self.vcount == VISIBLE_LINES {
+            self.system.debugger.on_vblank();
             self.dispstat7.set_vblank(true);
             self.dispstat9.set_vblank(true);
 
@@ -208,8 +343,10 @@ impl VideoUnit {
                 self.irq9.raise(IrqSource::VBlank)
             }
 
+            self.system.dma7.trigger(DmaTiming::VBlank);
             self.system.dma9.trigger(DmaTiming::VBlank);
-        } else if self.vcount == 262 {
+            self.gpu.on_vblank(&mut self.vram.texture_data, &mut self.vram.texture_palette);
+        } else if self.vcount == VBLANK_END_LINE {
             self.dispstat7.set_vblank(false);
             self.dispstat9.set_vblank(false);
         }
@@ -256,12 +393,22 @@ impl VideoUnit {
         self.powcnt1.0 = (self.powcnt1.0 & !mask) | (val & mask);
     }
 
+    // the ppus read palette ram and oam through handles of their own, with `debug_shared` the guards
+    // catch a bus access racing a render thread
+    pub fn read_oam<T: Copy>(&self, addr: u32) -> T {
+        self.oam.borrow().read_bus(addr)
+    }
+
+    pub fn read_palette_ram<T: Copy>(&self, addr: u32) -> T {
+        self.palette_ram.borrow().read_bus(addr)
+    }
+
     pub fn write_oam<T>(&mut self, addr: u32, val: T) {
-        unsafe { std::ptr::write(self.oam.as_mut_ptr().add((addr & 0x7ff) as usize).cast(), val) }
+        self.oam.borrow_mut().write_bus(addr, val)
     }
 
     pub fn write_palette_ram<T>(&mut self, addr: u32, val: T) {
-        unsafe { std::ptr::write(self.palette_ram.as_mut_ptr().add((addr & 0x7ff) as usize).cast(), val) }
+        self.palette_ram.borrow_mut().write_bus(addr, val)
     }
 
     pub fn write_dispstat(&mut self, arch: Arch, val: u32, mask: u32) {
@@ -279,7 +426,7 @@ impl VideoUnit {
     pub fn write_dispcapcnt(&mut self, val: u32, mask: u32) {
         set(&mut self.dispcapcnt.0, val, mask);
         if self.dispcapcnt.capture_enable() {
-            error!("VideoUnit: handle display capture")
+            unimplemented_feature!("VideoUnit: handle display capture")
         }
     }
 }
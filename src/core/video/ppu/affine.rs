@@ -1,13 +1,44 @@
-use std::ptr::NonNull;
-
 use crate::core::video::ppu::{COLOR_TRANSPARENT, Ppu};
 use crate::util::bit;
 
 const EXTENDED_DIMENSIONS: [[u32; 2]; 4] = [[128, 128], [256, 256], [512, 256], [512, 512]];
+const LARGE_DIMENSIONS: [[u32; 2]; 2] = [[512, 1024], [1024, 512]];
 
 impl Ppu {
     pub(super) fn render_affine(&mut self, id: usize) {
-        todo!()
+        let bgcnt = self.bgcnt[id];
+        let screen_base = (bgcnt.screen_base() * 2048) + (self.dispcnt.screen_base() * 65536);
+        let character_base = (bgcnt.character_base() * 16384) + (self.dispcnt.character_base() * 65536);
+        let size = 128 << bgcnt.size();
+
+        // 8-bit bgmap entries without flips or palettes, tiles are always 8bpp
+        self.affine_loop(id, size, size, |ppu, pixel, x, y| {
+            let screen_addr = screen_base + (y / 8) * (size / 8) + (x / 8);
+            let tile_number = ppu.bg.read::<u8>(screen_addr) as u32;
+            let tile_addr = character_base + (tile_number * 64) + ((y % 8) * 8) + (x % 8);
+            let palette_index = ppu.bg.read::<u8>(tile_addr) as u32;
+
+            ppu.bg_layers[id][pixel] = if palette_index == 0 {
+                COLOR_TRANSPARENT
+            } else {
+                ppu.palette_color(palette_index * 2)
+            };
+        });
+    }
+
+    /// Mode 6 bg2, a 256 color bitmap filling all 512K of bg vram
+    pub(super) fn render_large(&mut self, id: usize) {
+        let [width, height] = LARGE_DIMENSIONS[self.bgcnt[id].size() & 0x1];
+
+        self.affine_loop(id, width, height, |ppu, pixel, x, y| {
+            let palette_index = ppu.bg.read::<u8>((y * width) + x) as u32;
+
+            ppu.bg_layers[id][pixel] = if palette_index == 0 {
+                COLOR_TRANSPARENT
+            } else {
+                ppu.palette_color(palette_index * 2)
+            };
+        });
     }
 
     pub(super) fn render_extended(&mut self, id: usize) {
@@ -23,7 +54,7 @@ impl Ppu {
                     let color = ppu.bg.read::<u16>(data_addr);
 
                     ppu.bg_layers[id][pixel] = if (color >> 15) & 0x1 != 0 {
-                        color
+                        color & 0x7fff
                     } else {
                         COLOR_TRANSPARENT
                     };
@@ -37,7 +68,7 @@ impl Ppu {
                     ppu.bg_layers[id][pixel] = if palette_index == 0 {
                         COLOR_TRANSPARENT
                     } else {
-                        read(&ppu.palette_ram, (palette_index as u32 * 2) & 0x3ff)
+                        ppu.palette_color(palette_index as u32 * 2)
                     };
                 });
             }
@@ -64,46 +95,43 @@ impl Ppu {
                     COLOR_TRANSPARENT
                 } else if ppu.dispcnt.bg_extended_palette() {
                     let extended_palette_addr: u32 = (id as u32 * 8192) + ((palette_number * 256) + palette_index) * 2;
-                    ppu.bg_extended_palette.read::<u16>(extended_palette_addr)
+                    ppu.bg_extended_palette.read::<u16>(extended_palette_addr) & 0x7fff
                 } else {
-                    read::<u16>(&ppu.palette_ram, (palette_index * 2) & 0x3ff)
+                    ppu.palette_color(palette_index * 2)
                 };
             });
         }
     }
 
+    /// Walks the line from the internal reference point, calling `f` with the bg coordinates of every
+    /// pixel that lands inside the bg. Outside of it the pixel is transparent unless the bg wraps around
     fn affine_loop<F: FnMut(&mut Self, usize, u32, u32)>(&mut self, id: usize, width: u32, height: u32, mut f: F) {
-        let mut copy_x = self.internal_x[id - 2] as u32;
-        let mut copy_y = self.internal_y[id - 2] as u32;
-        let mut mosaic_bg_horizontal_counter = 0;
+        let index = id - 2;
+        let mut copy_x = self.internal_x[index];
+        let mut copy_y = self.internal_y[index];
+        let mosaic_width = if self.bgcnt[id].mosaic() { self.mosaic.bg_width() as usize + 1 } else { 1 };
+        let wraparound = self.bgcnt[id].wraparound_ext_palette_slot();
+        let (mut x, mut y) = (0, 0);
 
         for pixel in 0..256 {
-            let mut x = copy_x >> 8;
-            let mut y = copy_y >> 8;
-
-            // apply horizontal mosaic
-            if self.bgcnt[id].mosaic() && self.mosaic.bg_width() != 0 {
-                todo!()
-            } else {
-                copy_x += self.bgpa[id - 2] as u32;
-                copy_y += self.bgpc[id - 2] as u32;
+            // horizontal mosaic repeats the first sample of each block while the reference point keeps moving
+            if pixel % mosaic_width == 0 {
+                x = copy_x >> 8;
+                y = copy_y >> 8;
             }
+            copy_x += self.bgpa[index] as i32;
+            copy_y += self.bgpc[index] as i32;
 
-            if self.bgcnt[id].wraparound_ext_palette_slot() {
-                x &= width - 1;
-                y &= height - 1;
-            } else if x < 0 || x >= width || y < 0 || y >= height {
+            let (x, y) = if wraparound {
+                (x & (width as i32 - 1), y & (height as i32 - 1))
+            } else if x < 0 || x >= width as i32 || y < 0 || y >= height as i32 {
                 self.bg_layers[id][pixel] = COLOR_TRANSPARENT;
                 continue;
-            }
+            } else {
+                (x, y)
+            };
 
-            f(self, pixel, x, y)
+            f(self, pixel, x as u32, y as u32)
         }
     }
 }
-
-fn read<T: Copy>(ptr: &NonNull<[u8]>, offset: u32) -> T {
-    unsafe {
-        *ptr.as_ref().as_ptr().add(offset as usize).cast()
-    }
-}
\ No newline at end of file
@@ -1,7 +1,17 @@
-use std::ptr::NonNull;
-
 use crate::core::video::ppu::{COLOR_TRANSPARENT, Ppu, rgb555_to_rgb666, SpecialEffect};
-use crate::util::get_field;
+
+const LAYER_OBJ: usize = 4;
+const LAYER_BACKDROP: usize = 5;
+/// Bit in the enabled layer mask that allows color special effects, matching WININ/WINOUT
+const EFFECTS_ENABLE: u8 = 1 << 5;
+
+/// Sort key for a layer, lower keys are drawn on top. Layers with the same priority are ordered by
+/// a fixed rank: objects go over every background, then bg0 over bg1 over bg2 over bg3, with the
+/// backdrop always at the bottom
+const fn layer_key(priority: u32, layer: usize) -> u32 {
+    const RANK: [u32; 6] = [1, 2, 3, 4, 0, 5];
+    priority * 8 + RANK[layer]
+}
 
 impl Ppu {
     pub(super) fn compose_scanline(&mut self, line: u16) {
@@ -17,36 +27,8 @@ impl Ppu {
 
     fn compose_pixel_with_special_effects(&mut self, x: u16, line: u16) {
         let enabled = self.calculate_enabled_layers(x, line);
-        let backdrop = read::<u16>(&self.palette_ram, 0);
-        let mut targets = [5; 2];
-        let mut priorities = [4; 2];
-
-        // find the 2 top-most background pixels
-        for i in (0..=3).rev() {
-            let bg_pixel = self.bg_layers[i][x as usize];
-            if ((enabled >> i) & 0x1 != 0) && bg_pixel != COLOR_TRANSPARENT {
-                if self.bgcnt[i].priority() <= priorities[0] {
-                    targets[1] = targets[0];
-                    priorities[1] = priorities[0];
-                    targets[0] = i;
-                    priorities[0] = self.bgcnt[i].priority();
-                } else if self.bgcnt[i].priority() <= priorities[1] {
-                    targets[1] = i;
-                    priorities[1] = self.bgcnt[i].priority();
-                }
-            }
-        }
-
-        // check if an object pixel can replace one of the background pixels
-        // TODO: handle object window later
-        if self.dispcnt.enable_obj() && self.obj_buffer[x as usize].color != COLOR_TRANSPARENT {
-            if self.obj_buffer[x as usize].priority <= priorities[0] {
-                targets[1] = targets[0];
-                targets[0] = 4;
-            } else if self.obj_buffer[x as usize].priority <= priorities[1] {
-                targets[1] = 4;
-            }
-        }
+        let backdrop = self.palette_color(0);
+        let targets = self.find_top_layers(x, enabled);
 
         // map target indices to pixels
         // blending operations use 18-bit colours, so convert to that first
@@ -62,8 +44,8 @@ impl Ppu {
         let top_selected = (self.bldcnt.first_target() >> targets[0]) & 0x1 != 0;
         let bottom_selected = (self.bldcnt.second_target() >> targets[1]) & 0x1 != 0;
 
-        // skip blending if the targets aren't selected
-        if !top_selected || (self.bldcnt.special_effect() == SpecialEffect::AlphaBlending && !bottom_selected) {
+        // skip blending if the window disables it or the targets aren't selected
+        if enabled & EFFECTS_ENABLE == 0 || !top_selected || (self.bldcnt.special_effect() == SpecialEffect::AlphaBlending && !bottom_selected) {
             self.plot(x, line, pixels[0]);
             return;
         }
@@ -73,55 +55,75 @@ impl Ppu {
 
     fn compose_pixel(&mut self, x: u16, line: u16) {
         let enabled = self.calculate_enabled_layers(x, line);
-        let backdrop = read::<u16>(&self.palette_ram, 0);
-        let mut pixel: u16 = backdrop;
-        let mut priority = 4;
-
-        for i in (0..=3).rev() {
-            if ((enabled >> i) & 0x1 != 0) && self.bgcnt[i].priority() <= priority {
-                let bg_pixel: u16 = self.bg_layers[i][x as usize];
-                if bg_pixel != COLOR_TRANSPARENT {
-                    pixel = bg_pixel;
-                    priority = self.bgcnt[i].priority();
-                }
+        let pixel = match self.find_top_layers(x, enabled)[0] {
+            layer @ 0..=3 => self.bg_layers[layer][x as usize],
+            4 => self.obj_buffer[x as usize].color,
+            _ => self.palette_color(0),
+        };
+
+        self.plot(x, line, rgb555_to_rgb666(pixel as u32))
+    }
+
+    /// Returns the 2 top-most layers at `x`: 0-3 for backgrounds, 4 for objects and 5 for the backdrop
+    fn find_top_layers(&self, x: u16, enabled: u8) -> [usize; 2] {
+        let mut targets = [LAYER_BACKDROP; 2];
+        let mut keys = [layer_key(4, LAYER_BACKDROP); 2];
+
+        for layer in 0..LAYER_BACKDROP {
+            let (color, priority) = if layer == LAYER_OBJ {
+                let object = &self.obj_buffer[x as usize];
+                (object.color, object.priority)
+            } else {
+                (self.bg_layers[layer][x as usize], self.bgcnt[layer].priority())
+            };
+
+            if (enabled >> layer) & 0x1 == 0 || color == COLOR_TRANSPARENT {
+                continue;
             }
-        }
 
-        if self.dispcnt.enable_obj() && self.obj_buffer[x as usize].color != COLOR_TRANSPARENT {
-            if self.obj_buffer[x as usize].priority <= priority as u32 {
-                pixel = self.obj_buffer[x as usize].color;
+            let key = layer_key(priority, layer);
+            if key < keys[0] {
+                targets[1] = targets[0];
+                keys[1] = keys[0];
+                targets[0] = layer;
+                keys[0] = key;
+            } else if key < keys[1] {
+                targets[1] = layer;
+                keys[1] = key;
             }
         }
 
-        self.plot(x, line, rgb555_to_rgb666(pixel as u32))
+        targets
     }
 
+    /// Layers visible at a pixel as bits 0-4 (bg0-bg3, obj) and whether special effects apply as bit 5.
+    /// Window 0 has priority over window 1, which has priority over the object window, and pixels outside
+    /// of every enabled window use WINOUT
     fn calculate_enabled_layers(&self, x: u16, line: u16) -> u8 {
-        let mut enabled = get_field::<8, 5>(self.dispcnt.0) as u8;
-        let window = get_field::<13, 3>(self.dispcnt.0) as u8;
-
-        if window != 0 {
-            let win0_x1 = self.winh[0] >> 8;
-            let win0_x2 = self.winh[0] & 0xff;
-            let win0_y1 = self.winv[0] >> 8;
-            let win0_y2 = self.winv[0] & 0xff;
-            let win1_x1 = self.winh[1] >> 8;
-            let win1_x2 = self.winh[1] & 0xff;
-            let win1_y1 = self.winv[1] >> 8;
-            let win1_y2 = self.winv[1] & 0xff;
-
-            if self.dispcnt.enable_win0() && in_window_bounds(x, win0_x1, win0_x2) && in_window_bounds(line, win0_y1, win0_y2) {
-                enabled &= (self.winin & 0xf) as u8;
-            } else if self.dispcnt.enable_win1() && in_window_bounds(x, win1_x1, win1_x2) && in_window_bounds(line, win1_y1, win1_y2) {
-                enabled &= ((self.winin >> 8) & 0xf) as u8;
-            } else if self.dispcnt.enable_objwin() {
-                todo!("PPU: handle object window");
-            } else {
-                enabled &= (self.winout & 0xf) as u8;
-            }
+        let enabled = self.enabled_bgs() | ((self.dispcnt.enable_obj() as u8) << LAYER_OBJ) | EFFECTS_ENABLE;
+        if !self.dispcnt.enable_win0() && !self.dispcnt.enable_win1() && !self.dispcnt.enable_objwin() {
+            return enabled;
         }
 
-        enabled
+        let control = if self.dispcnt.enable_win0() && self.in_window(0, x, line) {
+            self.winin
+        } else if self.dispcnt.enable_win1() && self.in_window(1, x, line) {
+            self.winin >> 8
+        } else if self.dispcnt.enable_objwin() && self.obj_window[x as usize] {
+            self.winout >> 8
+        } else {
+            self.winout
+        };
+
+        enabled & (control & 0x3f) as u8
+    }
+
+    /// WINH and WINV hold the left/top edge in the upper byte and the right/bottom edge, exclusive,
+    /// in the lower byte
+    fn in_window(&self, id: usize, x: u16, line: u16) -> bool {
+        let (x1, x2) = (self.winh[id] >> 8, self.winh[id] & 0xff);
+        let (y1, y2) = (self.winv[id] >> 8, self.winv[id] & 0xff);
+        in_window_bounds(x, x1, x2) && in_window_bounds(line, y1, y2)
     }
 
     fn blend(&self, top: u32, bottom: u32, effect: SpecialEffect) -> u32 {
@@ -161,16 +163,54 @@ impl Ppu {
     }
 }
 
-fn read<T: Copy>(ptr: &NonNull<[u8]>, offset: usize) -> T {
-    unsafe {
-        *ptr.as_ref().as_ptr().add(offset).cast()
-    }
-}
-
 const fn in_window_bounds(coord: u16, start: u16, end: u16) -> bool {
     if start <= end {
         coord >= start && coord < end
     } else {
         coord >= start || coord < end
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::video::ppu::{BgCnt, Object};
+    use crate::core::System;
+
+    /// Enabled bits for bg0-bg3 and the objects
+    const ALL_LAYERS: u8 = 0x1f;
+
+    #[test]
+    fn layer_keys_order_priority_then_rank() {
+        assert!(layer_key(0, LAYER_OBJ) < layer_key(0, 0));
+        assert!(layer_key(0, 0) < layer_key(0, 1));
+        assert!(layer_key(0, 2) < layer_key(0, 3));
+        assert!(layer_key(0, 3) < layer_key(1, LAYER_OBJ));
+        assert!(layer_key(3, 3) < layer_key(4, LAYER_BACKDROP));
+    }
+
+    #[test]
+    fn top_layers_follow_priority_then_rank() {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.video_unit.reset();
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.reset_layers();
+        for (layer, priority) in [(0, 2), (1, 1), (2, 1), (3, 0)] {
+            ppu.bgcnt[layer] = BgCnt(priority);
+            ppu.bg_layers[layer][0] = 0x1f;
+        }
+        assert_eq!(ppu.find_top_layers(0, ALL_LAYERS), [3, 1]);
+
+        // an object of the same priority goes over the background
+        ppu.obj_buffer[0] = Object { priority: 1, color: 0x3e0 };
+        assert_eq!(ppu.find_top_layers(0, ALL_LAYERS), [3, LAYER_OBJ]);
+        assert_eq!(ppu.find_top_layers(0, ALL_LAYERS & !0x8), [LAYER_OBJ, 1]);
+
+        // unless it's disabled at that pixel
+        assert_eq!(ppu.find_top_layers(0, ALL_LAYERS & !0x18), [1, 2]);
+
+        // transparent and disabled layers leave the backdrop
+        assert_eq!(ppu.find_top_layers(1, ALL_LAYERS), [LAYER_BACKDROP; 2]);
+        assert_eq!(ppu.find_top_layers(0, 0x1), [0, LAYER_BACKDROP]);
+    }
+}
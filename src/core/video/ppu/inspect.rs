@@ -0,0 +1,98 @@
+use crate::core::video::ppu::{COLOR_TRANSPARENT, Ppu};
+
+/// Layers kept by the capture, bg0-bg3 followed by the objects
+const CAPTURED_LAYERS: usize = 5;
+
+/// Debugger views of what the ppu draws from. None of these touch the registers or the frame being drawn
+impl Ppu {
+    /// Start or stop keeping every line of each layer, nothing is copied while it's off
+    pub fn set_layer_capture(&mut self, enabled: bool) {
+        if enabled != self.layer_capture.is_some() {
+            self.layer_capture = enabled.then(|| vec![COLOR_TRANSPARENT; CAPTURED_LAYERS * 256 * 192].into_boxed_slice());
+        }
+    }
+
+    /// The last 256x192 frame of a layer before windows, blending and priorities were applied.
+    /// `None` while the capture is off
+    pub fn captured_layer(&self, layer: usize) -> Option<&[u16]> {
+        let start = layer * 256 * 192;
+        self.layer_capture.as_ref().map(|capture| &capture[start..start + 256 * 192])
+    }
+
+    pub(super) fn capture_layers(&mut self, line: u16) {
+        let Some(capture) = &mut self.layer_capture else {
+            return;
+        };
+
+        let offset = line as usize * 256;
+        for (layer, pixels) in self.bg_layers.iter().enumerate() {
+            let start = layer * 256 * 192 + offset;
+            capture[start..start + 256].copy_from_slice(pixels);
+        }
+
+        let start = 4 * 256 * 192 + offset;
+        for (pixel, object) in capture[start..start + 256].iter_mut().zip(&self.obj_buffer) {
+            *pixel = object.color;
+        }
+    }
+
+    /// Color `index` of the standard palettes, 0-255 are the bg palette and 256-511 the obj palette
+    pub fn palette_entry(&self, index: u32) -> u16 {
+        self.palette_color((index & 0x1ff) * 2)
+    }
+
+    /// Color `index` of an extended palette. Backgrounds have 4 slots of 16 palettes,
+    /// objects a single slot and `slot` is ignored
+    pub fn extended_palette_entry(&mut self, obj: bool, slot: u32, palette_number: u32, index: u32) -> u16 {
+        let addr = ((palette_number & 0xf) * 256 + (index & 0xff)) * 2;
+        if obj {
+            self.obj_extended_palette.read::<u16>(addr) & 0x7fff
+        } else {
+            self.bg_extended_palette.read::<u16>((slot & 0x3) * 0x2000 + addr) & 0x7fff
+        }
+    }
+
+    /// 32x32 tiles from `base` in bg or obj vram as 256x256 pixels, colored with the standard palettes.
+    /// 4bpp tiles use 16 color palette `palette_number`, 8bpp tiles ignore it
+    pub fn decode_tiles(&mut self, obj: bool, base: u32, palette_8bpp: bool, palette_number: u32, pixels: &mut [u16; 256 * 256]) {
+        let palette_base = if obj { 256 } else { 0 };
+        let tile_size = if palette_8bpp { 64 } else { 32 };
+
+        for tile in 0..32 * 32 {
+            let tile_addr = base + tile * tile_size;
+            for y in 0..8 {
+                for x in 0..8 {
+                    let palette_index = if palette_8bpp {
+                        self.read_tile_byte(obj, tile_addr + y * 8 + x) as u32
+                    } else {
+                        (self.read_tile_byte(obj, tile_addr + y * 4 + x / 2) as u32 >> (4 * (x & 0x1))) & 0xf
+                    };
+
+                    let color = match palette_index {
+                        0 => COLOR_TRANSPARENT,
+                        index if palette_8bpp => self.palette_entry(palette_base + index),
+                        index => self.palette_entry(palette_base + (palette_number & 0xf) * 16 + index),
+                    };
+                    pixels[(((tile / 32) * 8 + y) * 256 + (tile % 32) * 8 + x) as usize] = color;
+                }
+            }
+        }
+    }
+
+    fn read_tile_byte(&mut self, obj: bool, addr: u32) -> u8 {
+        if obj {
+            self.obj.read::<u8>(addr)
+        } else {
+            self.bg.read::<u8>(addr)
+        }
+    }
+}
+
+/// 15 bit color to rgba8, the transparent marker comes out with an alpha of 0
+pub const fn rgb555_to_rgba(color: u16) -> [u8; 4] {
+    let r = (color & 0x1f) as u8;
+    let g = ((color >> 5) & 0x1f) as u8;
+    let b = ((color >> 10) & 0x1f) as u8;
+    let a = if color == COLOR_TRANSPARENT { 0 } else { 0xff };
+    [(r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2), a]
+}
@@ -1,17 +1,29 @@
-use std::ptr::NonNull;
-use log::error;
+use log::warn;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::bitfield;
+use crate::core::video::engine_memory::{Engine, EngineMemory};
 use crate::core::video::vram::VramRegion;
-use crate::util::{set, Shared};
+use crate::util::{set, RingBuffer, Shared, StateReader, StateWriter};
 
 mod composer;
 mod text;
 mod tile_decoder;
 mod object;
 mod affine;
+mod inspect;
+mod threaded;
+
+pub use inspect::rgb555_to_rgba;
+pub use threaded::{RenderJob, ThreadedPpu};
 
-const COLOR_TRANSPARENT: u16 = 0x8000;
+/// Bit 15 is unused in palette colors, so it marks a pixel that lets the layers below (or the backdrop) show through.
+/// Colors are masked to 15 bits when they are fetched so that real colors never collide with it
+pub const COLOR_TRANSPARENT: u16 = 0x8000;
+
+/// Scanlines a background stays hidden for after its DISPCNT enable bit is set
+const BG_ENABLE_DELAY: u8 = 2;
 
 bitfield! {
     struct DispCnt(u32) {
@@ -142,13 +154,33 @@ pub struct Ppu {
 
     mosaic_bg_vertical_counter: u16,
 
+    /// Main memory display source, filled by dma and drained 8 pixels at a time while the line is drawn
+    display_fifo: RingBuffer<u32, 16>,
+    display_fifo_line: Box<[u16; 256]>,
+    /// Line of the 3d engine output, only used by engine A for bg0
+    layer_3d: Box<[u16; 256]>,
+
     framebuffer: Box<[u32; 256 * 192]>,
-    converted_framebuffer: Box<[u8; 256 * 192 * 4]>,
+    /// Converted frames, the one at `front` is the last completed frame and the other is written next
+    converted_framebuffers: [Box<[u8; 256 * 192 * 4]>; 2],
+    front: AtomicUsize,
     bg_layers: [[u16; 256]; 4],
     obj_buffer: [Object; 256],
-
-    palette_ram: NonNull<[u8]>,
-    oam: NonNull<[u8]>,
+    /// Pixels covered by an opaque pixel of an object window object
+    obj_window: [bool; 256],
+    /// Every line of bg0-bg3 and the objects before composing, only kept while the debugger looks at them
+    layer_capture: Option<Box<[u16]>>,
+
+    /// Drop objects once the per scanline rendering budget is used up
+    pub obj_cycle_limit: bool,
+    /// Hide newly enabled backgrounds for a couple of scanlines like the hardware does
+    pub bg_enable_delay: bool,
+    /// Scanlines left before each enabled background is shown
+    bg_enable_countdown: [u8; 4],
+
+    engine: Engine,
+    palette_ram: Shared<EngineMemory>,
+    oam: Shared<EngineMemory>,
     bg: Shared<VramRegion>,
     obj: Shared<VramRegion>,
     bg_extended_palette: Shared<VramRegion>,
@@ -158,13 +190,14 @@ pub struct Ppu {
 
 impl Ppu {
     pub fn new(
+        engine: Engine,
         bg: &Shared<VramRegion>,
         obj: &Shared<VramRegion>,
         bg_extended: &Shared<VramRegion>,
         obj_extended: &Shared<VramRegion>,
         lcdc: &Shared<VramRegion>,
-        palette_ram: &mut [u8],
-        oam: &mut [u8],
+        palette_ram: &Shared<EngineMemory>,
+        oam: &Shared<EngineMemory>,
     ) -> Self {
         Self {
             dispcnt: DispCnt(0),
@@ -189,12 +222,22 @@ impl Ppu {
             master_bright: MasterBright(0),
             bldalpha: BldAlpha(0),
             mosaic_bg_vertical_counter: 0,
+            display_fifo: RingBuffer::default(),
+            display_fifo_line: Box::new([0; 256]),
+            layer_3d: Box::new([COLOR_TRANSPARENT; 256]),
             framebuffer: Box::new([0; 256 * 192]),
-            converted_framebuffer: Box::new([0; 256 * 192 * 4]),
+            converted_framebuffers: [Box::new([0; 256 * 192 * 4]), Box::new([0; 256 * 192 * 4])],
+            front: AtomicUsize::new(0),
             bg_layers: [[0; 256]; 4],
             obj_buffer: std::array::from_fn(|_| Object { priority: 0, color: 0 }),
-            palette_ram: NonNull::new(palette_ram).unwrap(),
-            oam: NonNull::new(oam).unwrap(),
+            obj_window: [false; 256],
+            layer_capture: None,
+            obj_cycle_limit: false,
+            bg_enable_delay: false,
+            bg_enable_countdown: [0; 4],
+            engine,
+            palette_ram: palette_ram.clone(),
+            oam: oam.clone(),
             bg: bg.clone(),
             obj: obj.clone(),
             bg_extended_palette: bg_extended.clone(),
@@ -205,19 +248,27 @@ impl Ppu {
 
     pub fn reset(&mut self) {
         // todo
+        self.display_fifo.clear();
+        self.display_fifo_line.fill(0);
+        self.layer_3d.fill(COLOR_TRANSPARENT);
+        self.bg_enable_countdown = [0; 4];
 
         self.reset_layers();
     }
 
     pub fn on_finish_frame(&mut self) {
+        let back = self.front.load(Ordering::Relaxed) ^ 1;
+        let converted = &mut self.converted_framebuffers[back];
         for i in 0..256 * 192 {
             let j = i * 4;
-            self.converted_framebuffer[j..j + 4].copy_from_slice(&rgb666_to_rgb888(self.framebuffer[i]));
+            converted[j..j + 4].copy_from_slice(&rgb666_to_rgb888(self.framebuffer[i]));
         }
+        self.front.store(back, Ordering::Release);
     }
 
+    /// The last completed frame, never one that is still being converted
     pub fn fetch_framebuffer(&self) -> &[u8] {
-        self.converted_framebuffer.as_slice()
+        self.converted_framebuffers[self.front.load(Ordering::Acquire)].as_slice()
     }
 
     pub fn render_scanline(&mut self, line: u16) {
@@ -229,48 +280,87 @@ impl Ppu {
             self.mosaic_bg_vertical_counter = 0;
         }
 
+        // forced blank outputs white lines without fetching anything from vram.
+        // vram/oam/palette accesses from the cpu are never restricted while drawing, so there is nothing to relax here
+        if self.dispcnt.forced_blank() {
+            self.render_blank_screen(line);
+            self.capture_layers(line);
+            self.apply_master_brightness(line);
+            return self.update_internal_registers();
+        }
+
         match self.dispcnt.display_mode() {
             0 => self.render_blank_screen(line),
             1 => self.render_graphics_display(line),
             2 => self.render_vram_display(line),
-            3 => todo!(),
+            3 => self.render_main_memory_display(line),
             _ => unreachable!(),
         }
 
+        self.capture_layers(line);
         self.apply_master_brightness(line);
+        self.update_internal_registers();
+    }
 
+    /// Called after every line including vblank ones, the enable delay keeps counting while nothing is drawn
+    pub fn on_scanline_end(&mut self) {
+        for countdown in &mut self.bg_enable_countdown {
+            *countdown = countdown.saturating_sub(1);
+        }
+    }
+
+    /// DISPCNT bg enable bits with the backgrounds that are still waiting out their enable delay cleared
+    fn enabled_bgs(&self) -> u8 {
+        let mut enabled = ((self.dispcnt.0 >> 8) & 0xf) as u8;
+        for (i, &countdown) in self.bg_enable_countdown.iter().enumerate() {
+            if countdown != 0 {
+                enabled &= !(1 << i);
+            }
+        }
+        enabled
+    }
+
+    /// `addr` is relative to this engine's palette ram, objects start at 0x200
+    fn palette_color(&self, addr: u32) -> u16 {
+        self.palette_ram.read::<u16>(self.engine, addr) & 0x7fff
+    }
+
+    fn update_internal_registers(&mut self) {
         if self.mosaic_bg_vertical_counter == self.mosaic.bg_height() {
             self.mosaic_bg_vertical_counter = 0;
         } else {
             self.mosaic_bg_vertical_counter += 1;
         }
 
-        // update internal affine registers
-        // affine registers don't get updated in mode 0
-        if self.dispcnt.bg_mode() != 0 {
-            for i in 0..2 {
-                if self.bgcnt[i + 2].mosaic() && self.mosaic.bg_height() != 0 {
-                    if self.mosaic_bg_vertical_counter == 0 {
-                        self.internal_x[i] += self.mosaic.bg_height() as i32 * self.bgpb[i] as i32;
-                        self.internal_y[i] += self.mosaic.bg_height() as i32 * self.bgpd[i] as i32;
-                    }
-                } else {
-                    self.internal_x[i] += self.bgpb[i] as i32;
-                    self.internal_y[i] += self.bgpd[i] as i32;
+        // the internal reference points advance by dmx/dmy after every line the affine layer was enabled on.
+        // writes to BGnX/BGnY reload them immediately, so a write during hblank takes effect on the next line without the increment
+        for i in 0..2 {
+            if self.dispcnt.0 & (1 << (10 + i)) == 0 {
+                continue;
+            }
+
+            if self.bgcnt[i + 2].mosaic() && self.mosaic.bg_height() != 0 {
+                if self.mosaic_bg_vertical_counter == 0 {
+                    self.internal_x[i] += (self.mosaic.bg_height() as i32 + 1) * self.bgpb[i] as i32;
+                    self.internal_y[i] += (self.mosaic.bg_height() as i32 + 1) * self.bgpd[i] as i32;
                 }
+            } else {
+                self.internal_x[i] += self.bgpb[i] as i32;
+                self.internal_y[i] += self.bgpd[i] as i32;
             }
         }
     }
 
     fn reset_layers(&mut self) {
         for layer in &mut self.bg_layers {
-            layer.fill(0)
+            layer.fill(COLOR_TRANSPARENT)
         }
 
         for obj in &mut self.obj_buffer {
             obj.priority = 4;
             obj.color = COLOR_TRANSPARENT;
         }
+        self.obj_window.fill(false);
     }
 
     fn apply_master_brightness(&mut self, _line: u16) {
@@ -290,6 +380,67 @@ impl Ppu {
         self.framebuffer[((256 * y) + x) as usize] = color;
     }
 
+    /// Only registers and the display fifo, the framebuffers are redrawn on the next frame
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"PPU ");
+        state.write("dispcnt", self.dispcnt.0);
+        for bgcnt in &self.bgcnt {
+            state.write("bgcnt", bgcnt.0);
+        }
+        state.write_slice("bghofs", &self.bghofs);
+        state.write_slice("bgvofs", &self.bgvofs);
+        state.write_slice("bgpa", &self.bgpa);
+        state.write_slice("bgpb", &self.bgpb);
+        state.write_slice("bgpc", &self.bgpc);
+        state.write_slice("bgpd", &self.bgpd);
+        state.write_slice("bgx", &self.bgx);
+        state.write_slice("bgy", &self.bgy);
+        state.write_slice("internal_x", &self.internal_x);
+        state.write_slice("internal_y", &self.internal_y);
+        state.write_slice("winh", &self.winh);
+        state.write_slice("winv", &self.winv);
+        state.write("winin", self.winin);
+        state.write("winout", self.winout);
+        state.write("mosaic", self.mosaic.0);
+        state.write("bldcnt", self.bldcnt.0);
+        state.write("bldy", self.bldy.0);
+        state.write("master_bright", self.master_bright.0);
+        state.write("bldalpha", self.bldalpha.0);
+        state.write("mosaic_bg_vertical_counter", self.mosaic_bg_vertical_counter);
+        state.write_slice("bg_enable_countdown", &self.bg_enable_countdown);
+        self.display_fifo.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"PPU ");
+        self.dispcnt.0 = state.read();
+        for bgcnt in &mut self.bgcnt {
+            bgcnt.0 = state.read();
+        }
+        state.read_slice(&mut self.bghofs);
+        state.read_slice(&mut self.bgvofs);
+        state.read_slice(&mut self.bgpa);
+        state.read_slice(&mut self.bgpb);
+        state.read_slice(&mut self.bgpc);
+        state.read_slice(&mut self.bgpd);
+        state.read_slice(&mut self.bgx);
+        state.read_slice(&mut self.bgy);
+        state.read_slice(&mut self.internal_x);
+        state.read_slice(&mut self.internal_y);
+        state.read_slice(&mut self.winh);
+        state.read_slice(&mut self.winv);
+        self.winin = state.read();
+        self.winout = state.read();
+        self.mosaic.0 = state.read();
+        self.bldcnt.0 = state.read();
+        self.bldy.0 = state.read();
+        self.master_bright.0 = state.read();
+        self.bldalpha.0 = state.read();
+        self.mosaic_bg_vertical_counter = state.read();
+        state.read_slice(&mut self.bg_enable_countdown);
+        self.display_fifo.load_state(state);
+    }
+
     pub const fn read_dispcnt(&self) -> u32 {
         self.dispcnt.0
     }
@@ -307,7 +458,18 @@ impl Ppu {
     }
 
     pub fn write_dispcnt(&mut self, val: u32, mask: u32) {
-        self.dispcnt.0 = (self.dispcnt.0 & !mask) | (val & mask)
+        let old = self.dispcnt.0;
+        self.dispcnt.0 = (self.dispcnt.0 & !mask) | (val & mask);
+
+        // a background only starts its delay on a 0 to 1 transition, rewriting the bit while it's set does nothing
+        for i in 0..4 {
+            let bit = 1 << (8 + i);
+            if self.dispcnt.0 & bit == 0 {
+                self.bg_enable_countdown[i] = 0;
+            } else if old & bit == 0 && self.bg_enable_delay {
+                self.bg_enable_countdown[i] = BG_ENABLE_DELAY;
+            }
+        }
     }
 
     pub fn write_bgcnt(&mut self, id: usize, val: u16, mask: u16) {
@@ -333,12 +495,14 @@ impl Ppu {
     }
     pub fn write_bgx(&mut self, id: usize, val: u32, mask: u32) {
         set(&mut self.bgx[id], val as _, (mask & 0xfffffff) as _);
-        self.bgx[id] = (self.bgx[id] << 28 >> 28) as u32 as i32;
+        // sign extend the 28 bit value
+        self.bgx[id] = (self.bgx[id] << 4) >> 4;
         self.internal_x[id] = self.bgx[id]
     }
     pub fn write_bgy(&mut self, id: usize, val: u32, mask: u32) {
         set(&mut self.bgy[id], val as _, (mask & 0xfffffff) as _);
-        self.bgy[id] = (self.bgy[id] << 28 >> 28) as u32 as i32;
+        // sign extend the 28 bit value
+        self.bgy[id] = (self.bgy[id] << 4) >> 4;
         self.internal_y[id] = self.bgy[id]
     }
     pub fn write_winh(&mut self, id: usize, val: u16, mask: u16) {
@@ -377,37 +541,81 @@ impl Ppu {
         }
     }
 
+    pub fn main_memory_display(&self) -> bool {
+        self.dispcnt.display_mode() == 3
+    }
+
+    fn render_main_memory_display(&mut self, line: u16) {
+        for x in 0..256 {
+            self.plot(x, line, rgb555_to_rgb666(self.display_fifo_line[x as usize] as u32));
+        }
+    }
+
+    /// Moves the next 8 pixels out of the display fifo, an empty fifo gives black pixels
+    pub fn sample_display_fifo(&mut self, x: usize) {
+        for i in 0..4 {
+            let data = if self.display_fifo.is_empty() { 0 } else { self.display_fifo.pop() };
+            self.display_fifo_line[x + i * 2] = data as u16;
+            self.display_fifo_line[x + i * 2 + 1] = (data >> 16) as u16;
+        }
+    }
+
+    pub fn write_display_fifo(&mut self, val: u32) {
+        self.display_fifo.push(val);
+    }
+
+    pub fn set_3d_line(&mut self, line: &[u16]) {
+        self.layer_3d.copy_from_slice(line);
+    }
+
+    /// The 3d layer only scrolls horizontally, by the 9 bit signed bg0 offset
+    fn render_3d(&mut self) {
+        let offset = self.bghofs[0] & 0x1ff;
+        for x in 0..256 {
+            let src = (x + offset) & 0x1ff;
+            self.bg_layers[0][x as usize] = if src < 256 { self.layer_3d[src as usize] } else { COLOR_TRANSPARENT };
+        }
+    }
+
     fn render_graphics_display(&mut self, line: u16) {
+        // mode 7 is prohibited, draw it without any backgrounds rather than trusting the game
+        let bg_mode = self.dispcnt.bg_mode();
+        if bg_mode == 7 {
+            warn!("PPU: invalid bg mode 7");
+            if self.dispcnt.enable_obj() {
+                self.render_objects(line)
+            }
+            return self.compose_scanline(line);
+        }
+
         if self.dispcnt.enable_bg0() {
-            if self.dispcnt.bg0_3d() || self.dispcnt.bg_mode() == 6 {
-                error!("PPU: handle 3d rendering")
+            if self.dispcnt.bg0_3d() || bg_mode == 6 {
+                self.render_3d()
             } else {
                 self.render_text(0, line)
             }
         }
 
-        if self.dispcnt.enable_bg1() {
-            if self.dispcnt.bg_mode() != 6 {
-                self.render_text(1, line)
-            }
+        // bg1 and bg3 don't exist in mode 6
+        if self.dispcnt.enable_bg1() && bg_mode != 6 {
+            self.render_text(1, line)
         }
 
         if self.dispcnt.enable_bg2() {
-            match self.dispcnt.bg_mode() {
+            match bg_mode {
                 0 | 1 | 3 => self.render_text(2, line),
                 2 | 4 => self.render_affine(2),
                 5 => self.render_extended(2),
-                6 => todo!("render_large"),
-                _ => unreachable!(),
+                _ => self.render_large(2),
             }
         }
 
         if self.dispcnt.enable_bg3() {
-            match self.dispcnt.bg_mode() {
+            match bg_mode {
                 0 => self.render_text(3, line),
                 1 | 2 => self.render_affine(3),
                 3 | 4 | 5 => self.render_extended(3),
-                _ => unreachable!(),
+                _ => {}
             }
         }
 
@@ -435,3 +643,247 @@ const fn rgb666_to_rgb888(colour: u32) -> [u8; 4] {
     // [b, g, r, 0xff]
     [r, g, b, 0xff]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::video::vram::{Vram, VramBank};
+    use crate::core::{OwnedSystem, System};
+
+    /// DISPCNT display mode 1, the graphics display
+    const GRAPHICS_DISPLAY: u32 = 1 << 16;
+
+    fn line(ppu: &Ppu, line: usize) -> &[u32] {
+        &ppu.framebuffer[line * 256..(line + 1) * 256]
+    }
+
+    fn system_with_backdrop(color: u16) -> OwnedSystem {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.video_unit.reset();
+        system.video_unit.write_palette_ram(0x05000000, color);
+        system
+    }
+
+    #[test]
+    fn scanline_without_layers_shows_the_backdrop() {
+        // bit 15 of the palette entry is ignored rather than read as transparent
+        let mut system = system_with_backdrop(0x8000 | 0x001f);
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.write_dispcnt(GRAPHICS_DISPLAY, 0xffffffff);
+        ppu.render_scanline(5);
+
+        assert!(line(ppu, 5).iter().all(|&pixel| pixel == rgb555_to_rgb666(0x001f)));
+    }
+
+    #[test]
+    fn enabled_layers_with_only_transparent_pixels_show_the_backdrop() {
+        // every bg reads tile 0 from empty vram, which is palette index 0 and so transparent
+        let mut system = system_with_backdrop(0x03e0);
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 0x1f00, 0xffffffff);
+        ppu.render_scanline(0);
+
+        assert!(line(ppu, 0).iter().all(|&pixel| pixel == rgb555_to_rgb666(0x03e0)));
+    }
+
+    #[test]
+    fn black_pixels_cover_the_backdrop() {
+        let mut system = system_with_backdrop(0x7fff);
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 0x100, 0xffffffff);
+        ppu.reset_layers();
+        ppu.bg_layers[0][0] = 0;
+        ppu.compose_scanline(0);
+
+        assert_eq!(line(ppu, 0)[0], 0);
+        assert_eq!(line(ppu, 0)[1], rgb555_to_rgb666(0x7fff));
+    }
+
+    #[test]
+    fn affine_reference_points_follow_raster_writes() {
+        let mut system = system_with_backdrop(0);
+        let ppu = &mut system.video_unit.ppu_a;
+        // mode 2 with bg2 enabled, dmx and dmy move the reference point every line
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 2 | 0x400, 0xffffffff);
+        ppu.write_bgx(0, 0x100, 0xffffffff);
+        ppu.write_bgy(0, 0x200, 0xffffffff);
+        ppu.write_bgpb(0, 0x10, 0xffff);
+        ppu.write_bgpd(0, 0xfff0, 0xffff);
+
+        ppu.render_scanline(0);
+        ppu.render_scanline(1);
+        assert_eq!((ppu.internal_x[0], ppu.internal_y[0]), (0x120, 0x1e0));
+
+        // a write during hblank reloads the reference point for the next line without the increment
+        ppu.write_bgx(0, 0x500, 0xffffffff);
+        assert_eq!(ppu.internal_x[0], 0x500);
+        ppu.render_scanline(2);
+        assert_eq!((ppu.internal_x[0], ppu.internal_y[0]), (0x510, 0x1d0));
+
+        // nothing advances while the layer is off
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 2, 0xffffffff);
+        ppu.render_scanline(3);
+        assert_eq!((ppu.internal_x[0], ppu.internal_y[0]), (0x510, 0x1d0));
+
+        // line 0 starts over from the registers
+        ppu.render_scanline(0);
+        assert_eq!((ppu.internal_x[0], ppu.internal_y[0]), (0x500, 0x200));
+    }
+
+    #[test]
+    fn affine_reference_points_are_28_bit_signed() {
+        let mut system = system_with_backdrop(0);
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.write_bgx(1, 0x0fff_ff00, 0xffffffff);
+        ppu.write_bgy(1, 0xf7ff_ffff, 0xffffffff);
+        assert_eq!(ppu.internal_x[1], -0x100);
+        assert_eq!(ppu.internal_y[1], 0x07ff_ffff);
+
+        // halfword writes build up the same value
+        ppu.write_bgx(1, 0x0000_0100, 0x0000ffff);
+        ppu.write_bgx(1, 0x0800_0000, 0xffff0000);
+        assert_eq!(ppu.internal_x[1], -0x0800_0000 + 0x100);
+    }
+
+    #[test]
+    fn bg_extended_palettes_hold_256_colors_per_palette() {
+        let mut system = system_with_backdrop(0);
+        let vram = &mut system.video_unit.vram;
+        vram.write_vramcnt(VramBank::A, 0x81);
+        // index 5 in the first pixel of tile 0
+        vram.write::<u8>(0x06000000, 5);
+        // bank E through the lcdc, then as engine A's extended palette slots, palette 3 of slot 2
+        vram.write_vramcnt(VramBank::E, 0x80);
+        vram.write::<u16>(0x06880000 + 2 * 0x2000 + (3 * 256 + 5) * 2, 0x1234);
+        vram.write_vramcnt(VramBank::E, 0x84);
+
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.write_dispcnt(1 << 30, 0xffffffff);
+        assert_eq!(ppu.decode_tile_row_8bpp(0, 0, 3, 0, false, false, 2)[0], 0x1234);
+        assert_eq!(ppu.extended_palette_entry(false, 2, 3, 5), 0x1234);
+    }
+
+    /// 8bpp text bgs of an engine drawing tile 0 everywhere, its first row going through indices 1 to 8.
+    /// Index `i` of palette 0 in extended palette slot `s` is `s * 0x100 + i`
+    fn check_8bpp_text_bg_slots(system: &mut System, engine_b: bool) {
+        let ppu = if engine_b { &mut system.video_unit.ppu_b } else { &mut system.video_unit.ppu_a };
+        ppu.write_dispcnt(1 << 30, 0xffffffff);
+        // the map at 0x800, bit 13 is the slot bit for bg0/bg1 and wraparound for bg2/bg3
+        for (id, plain_slot, bit13_slot) in [(0, 0, 2), (1, 1, 3), (2, 2, 2), (3, 3, 3)] {
+            for (bgcnt, slot) in [(0x180, plain_slot), (0x2180, bit13_slot)] {
+                ppu.write_bgcnt(id, bgcnt, 0xffff);
+                ppu.render_text(id, 0);
+                for x in 0..16 {
+                    assert_eq!(ppu.bg_layers[id][x], slot * 0x100 + (x as u16 % 8) + 1, "bg{id} with bgcnt {bgcnt:04x} at {x}");
+                }
+            }
+        }
+    }
+
+    fn write_8bpp_text_bg(vram: &mut Vram, bg_base: u32, lcdc_palettes: u32) {
+        for i in 0..8 {
+            vram.write::<u8>(bg_base + i, i as u8 + 1);
+        }
+        for slot in 0..4 {
+            for index in 1..=8 {
+                vram.write::<u16>(lcdc_palettes + slot * 0x2000 + index * 2, (slot * 0x100 + index) as u16);
+            }
+        }
+    }
+
+    #[test]
+    fn text_bgs_draw_8bpp_tiles_from_their_extended_palette_slot() {
+        let mut system = system_with_backdrop(0);
+        let vram = &mut system.video_unit.vram;
+        vram.write_vramcnt(VramBank::A, 0x81);
+        vram.write_vramcnt(VramBank::E, 0x80);
+        write_8bpp_text_bg(vram, 0x06000000, 0x06880000);
+        vram.write_vramcnt(VramBank::E, 0x84);
+        check_8bpp_text_bg_slots(&mut system, false);
+
+        let vram = &mut system.video_unit.vram;
+        vram.write_vramcnt(VramBank::C, 0x84);
+        vram.write_vramcnt(VramBank::H, 0x80);
+        write_8bpp_text_bg(vram, 0x06200000, 0x06898000);
+        vram.write_vramcnt(VramBank::H, 0x82);
+        check_8bpp_text_bg_slots(&mut system, true);
+    }
+
+    #[test]
+    fn obj_extended_palettes_hold_256_colors_per_palette() {
+        let mut system = system_with_backdrop(0);
+        let vram = &mut system.video_unit.vram;
+        vram.write_vramcnt(VramBank::B, 0x82);
+        vram.write::<u8>(0x06400000, 7);
+        vram.write_vramcnt(VramBank::F, 0x80);
+        vram.write::<u16>(0x06890000 + (2 * 256 + 7) * 2, 0x4321);
+        vram.write_vramcnt(VramBank::F, 0x85);
+
+        // an 8x8 8bpp object with palette 2
+        system.video_unit.write_oam(0x07000000, 1u16 << 13);
+        system.video_unit.write_oam(0x07000002, 0u16);
+        system.video_unit.write_oam(0x07000004, 2u16 << 12);
+
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.write_dispcnt(1 << 31, 0xffffffff);
+        let mut pixels = [0; 64 * 64];
+        assert_eq!(ppu.decode_object(0, &mut pixels), [8, 8]);
+        assert_eq!(pixels[0], 0x4321);
+        assert_eq!(pixels[1], COLOR_TRANSPARENT);
+    }
+
+    /// Fills every pixel of engine A's bg0 with color 1 of palette 0, 0x001f
+    fn system_with_solid_bg0() -> OwnedSystem {
+        let mut system = system_with_backdrop(0x03e0);
+        system.video_unit.write_palette_ram(0x05000002, 0x001fu16);
+
+        // the map at 0 is all tile 0, the tiles start at 0x4000
+        let vram = &mut system.video_unit.vram;
+        vram.write_vramcnt(VramBank::A, 0x80);
+        for addr in 0..32 {
+            vram.write::<u8>(0x06804000 + addr, 0x11);
+        }
+        vram.write_vramcnt(VramBank::A, 0x81);
+        system.video_unit.ppu_a.write_bgcnt(0, 1 << 2, 0xffff);
+        system
+    }
+
+    /// Renders `line` and ends it the way the video unit does, returning whether bg0 was drawn
+    fn bg0_shown(ppu: &mut Ppu, line_number: u16) -> bool {
+        ppu.render_scanline(line_number);
+        ppu.on_scanline_end();
+        line(ppu, line_number as usize)[0] == rgb555_to_rgb666(0x001f)
+    }
+
+    #[test]
+    fn newly_enabled_bgs_stay_hidden_for_2_scanlines() {
+        let mut system = system_with_solid_bg0();
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.bg_enable_delay = true;
+        ppu.write_dispcnt(GRAPHICS_DISPLAY, 0xffffffff);
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 0x100, 0xffffffff);
+        assert!(!bg0_shown(ppu, 0));
+        assert!(!bg0_shown(ppu, 1));
+        assert!(bg0_shown(ppu, 2));
+
+        // writing the bit again while it's set doesn't restart the delay, clearing and setting it does
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 0x100, 0xffffffff);
+        assert!(bg0_shown(ppu, 3));
+        ppu.write_dispcnt(GRAPHICS_DISPLAY, 0xffffffff);
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 0x100, 0xffffffff);
+        assert!(!bg0_shown(ppu, 4));
+        assert!(!bg0_shown(ppu, 5));
+        assert!(bg0_shown(ppu, 6));
+    }
+
+    #[test]
+    fn bgs_show_right_away_without_the_enable_delay() {
+        let mut system = system_with_solid_bg0();
+        let ppu = &mut system.video_unit.ppu_a;
+        ppu.bg_enable_delay = false;
+        ppu.write_dispcnt(GRAPHICS_DISPLAY, 0xffffffff);
+        ppu.write_dispcnt(GRAPHICS_DISPLAY | 0x100, 0xffffffff);
+        assert!(bg0_shown(ppu, 0));
+    }
+}
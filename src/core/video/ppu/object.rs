@@ -1,13 +1,13 @@
-use std::mem::transmute;
-
-use log::error;
-
+use crate::bitfield;
 use crate::core::video::ppu::{COLOR_TRANSPARENT, Ppu};
-use crate::util::{bit, get_field};
+use crate::unimplemented_feature;
+
+// object rendering cycles available per scanline, fewer when the hblank period is left free for vram access
+const OBJ_CYCLES: u32 = 2130;
+const OBJ_CYCLES_HBLANK_FREE: u32 = 1536;
 
 const OBJECT_DIMENSIONS: [[[u32; 2]; 4]; 4] = [[[8, 8], [16, 16], [32, 32], [64, 64]], [[16, 8], [32, 8], [32, 16], [64, 32]], [[8, 16], [8, 32], [16, 32], [32, 64]], [[0, 0], [0, 0], [0, 0], [0, 0]]];
 
-#[repr(u32)]
 #[derive(Copy, Clone, PartialEq)]
 enum ObjectMode {
     Normal = 0,
@@ -16,63 +16,104 @@ enum ObjectMode {
     Bitmap = 3,
 }
 
-impl From<u32> for ObjectMode {
-    fn from(value: u32) -> Self {
-        unsafe { transmute(value) }
+// bit 9 means double size on affine objects and disable on the rest, so it has to be read through
+// `ObjAttr0::disabled` or `ObjAttr0::double_size`
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct ObjAttr0(u16) {
+        y: u32 => 0 | 7,
+        affine: bool => 8,
+        bit9: bool => 9,
+        mode: u8 [ObjectMode] => 10 | 11,
+        mosaic: bool => 12,
+        is_8bpp: bool => 13,
+        shape: usize => 14 | 15
+    }
+}
+
+impl ObjAttr0 {
+    const fn disabled(&self) -> bool {
+        !self.affine() && self.bit9()
+    }
+
+    const fn double_size(&self) -> bool {
+        self.affine() && self.bit9()
+    }
+}
+
+// the flip bits overlap the affine parameter group, they only apply to non-affine objects
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct ObjAttr1(u16) {
+        x: u32 => 0 | 8,
+        affine_group: u32 => 9 | 13,
+        horizontal_flip: bool => 12,
+        vertical_flip: bool => 13,
+        size: usize => 14 | 15
+    }
+}
+
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct ObjAttr2(u16) {
+        tile_number: u32 => 0 | 9,
+        priority: u32 => 10 | 11,
+        palette_number: u32 => 12 | 15
     }
 }
 
 impl Ppu {
     pub(super) fn render_objects(&mut self, line: u16) {
-        let oam = unsafe { self.oam.as_ref() };
+        let mut cycles_left = if self.dispcnt.obj_during_hblank() { OBJ_CYCLES_HBLANK_FREE } else { OBJ_CYCLES };
+
         for i in 0..128 {
-            if (oam[(i * 8) + 1] & 0x3) == 0x2 {
+            let attr0 = ObjAttr0(self.oam.read::<u16>(self.engine, i * 8));
+            if attr0.disabled() {
                 continue;
             }
 
-            // todo: remove the casts
-            let attributes = [
-                read::<u16>(oam, i * 8) as u32,
-                read::<u16>(oam, (i * 8) + 2) as u32,
-                read::<u16>(oam, (i * 8) + 4) as u32,
-            ];
+            let attr1 = ObjAttr1(self.oam.read::<u16>(self.engine, (i * 8) + 2));
+            let attr2 = ObjAttr2(self.oam.read::<u16>(self.engine, (i * 8) + 4));
             let mut affine_parameters = [0; 4];
 
-            let mut y = get_field::<0, 8>(attributes[0]);
-            let affine = bit::<8>(attributes[0]);
-            let mode: ObjectMode = (get_field::<10, 2>(attributes[0])).into();
-            let mosaic = bit::<12>(attributes[0]);
-            let is_8bpp = bit::<13>(attributes[0]);
-            let shape = get_field::<14, 2>(attributes[0]);
-            let mut x = get_field::<0, 9>(attributes[1]);
-            let horizontal_flip = !affine & bit::<12>(attributes[1]);
-            let vertical_flip = !affine & bit::<13>(attributes[1]);
-            let size = get_field::<14, 2>(attributes[1]);
-            let tile_number = get_field::<0, 10>(attributes[2]);
-            let priority = get_field::<10, 2>(attributes[2]);
-            let palette_number = get_field::<12, 4>(attributes[2]);
+            let affine = attr0.affine();
+            let mode = attr0.mode();
+            let horizontal_flip = !affine && attr1.horizontal_flip();
+            let vertical_flip = !affine && attr1.vertical_flip();
+            let priority = attr2.priority();
 
+            let mut x = attr1.x();
+            let mut y = attr0.y();
+            // both wrap, the signed value is recovered by the i32 casts below
             if x >= 256 {
-                x -= 512;
+                x = x.wrapping_sub(512);
             }
             if y >= 192 {
-                y -= 256
+                y = y.wrapping_sub(256);
             }
 
-            let width = OBJECT_DIMENSIONS[shape as usize][size as usize][0];
-            let height = OBJECT_DIMENSIONS[shape as usize][size as usize][1];
+            let [width, height] = OBJECT_DIMENSIONS[attr0.shape()][attr1.size()];
             let half_width = (width / 2) as i32;
             let half_height = (height / 2) as i32;
 
-            x += half_width as u32;
-            y += half_height as u32;
+            // double size affine objects are drawn in a box twice as big so the rotated image isn't clipped
+            let (bounds_width, bounds_height) = if attr0.double_size() { (width * 2, height * 2) } else { (width, height) };
+            let half_bounds_width = (bounds_width / 2) as i32;
+            let half_bounds_height = (bounds_height / 2) as i32;
 
-            if mosaic {
-                error!("PPU: handle object mosaic");
+            x = x.wrapping_add(half_bounds_width as u32);
+            y = y.wrapping_add(half_bounds_height as u32);
+
+            if attr0.mosaic() {
+                unimplemented_feature!("PPU: handle object mosaic");
             }
 
             if affine {
-                todo!()
+                // the parameters are spread over attribute 3 of 4 consecutive oam entries, 32 bytes per group
+                let group = attr1.affine_group() * 32;
+                for (index, parameter) in affine_parameters.iter_mut().enumerate() {
+                    *parameter = self.oam.read::<u16>(self.engine, group + 6 + (index as u32 * 8)) as i16 as i32;
+                }
             } else {
                 // for non-affine sprites, we can still use the general affine formula,
                 // but instead use the parameters 0x100, 0, 0 and 0x100
@@ -85,29 +126,35 @@ impl Ppu {
             }
 
             if mode == ObjectMode::SemiTransparent {
-                error!("PPU: handle semi transparent mode")
-            }
-
-            if mode == ObjectMode::ObjectWindow {
-                error!("PPU: handle object window mode")
+                unimplemented_feature!("PPU: handle semi transparent mode")
             }
 
             let local_y = line as i32 - y as i32;
-            if local_y < -half_height || local_y >= half_height {
+            if local_y < -half_bounds_height || local_y >= half_bounds_height {
                 continue;
             }
 
-            for local_x in -half_width..=half_width {
-                let mut global_x = (x as i32 + local_x);
+            // objects are fetched in oam order, each costs a cycle per pixel (affine ones twice that, plus setup)
+            if self.obj_cycle_limit {
+                let cost = if affine { 10 + 2 * bounds_width } else { width };
+                if cost > cycles_left {
+                    break;
+                }
+                cycles_left -= cost;
+            }
+
+            for local_x in -half_bounds_width..half_bounds_width {
+                let global_x = x as i32 + local_x;
                 if global_x < 0 || global_x >= 256 {
                     continue;
                 }
 
+                // negative results wrap to large values and fail the bounds check below
                 let mut transformed_x = ((((affine_parameters[0] * local_x) + (affine_parameters[1] * local_y)) >> 8) + half_width) as u32;
                 let mut transformed_y = ((((affine_parameters[2] * local_x) + (affine_parameters[3] * local_y)) >> 8) + half_height) as u32;
 
                 // make sure the transformed coordinates are still in bounds
-                if /*transformed_x < 0 || transformed_y < 0 ||*/ transformed_x >= width || transformed_y >= height {
+                if transformed_x >= width || transformed_y >= height {
                     continue;
                 }
 
@@ -119,37 +166,18 @@ impl Ppu {
                     transformed_y = height - transformed_y - 1;
                 }
 
-                let inner_tile_x = transformed_x % 8;
-                let inner_tile_y = transformed_y % 8;
-                let tile_x = transformed_x / 8;
-                let tile_y = transformed_y / 8;
-                let mut tile_addr = 0;
-
                 let color = if mode == ObjectMode::Bitmap {
                     todo!()
-                } else if is_8bpp {
-                    if self.dispcnt.tile_obj_mapping() {
-                        tile_addr = (tile_number * (32 << self.dispcnt.tile_obj_1d_boundary())) + (tile_y * width * 8);
-                    } else {
-                        error!("PPU: handle 2d mapping 8bb")
-                    }
-
-                    tile_addr += tile_x * 64;
-                    self.decode_obj_pixel_8bpp(tile_addr, palette_number, inner_tile_x, inner_tile_y)
                 } else {
-                    if self.dispcnt.tile_obj_mapping() {
-                        tile_addr = (tile_number * (32 << self.dispcnt.tile_obj_1d_boundary())) + (tile_y * width * 4) as u32;
-                    } else {
-                        error!("PPU: handle 2d mapping 8bpp");
-                    }
-
-                    tile_addr += (tile_x * 32) as u32;
-                    self.decode_obj_pixel_4bpp(tile_addr, palette_number, inner_tile_x, inner_tile_y)
+                    self.decode_obj_pixel(attr0, attr2, width, transformed_x, transformed_y)
                 };
 
+                // object window objects aren't drawn, their opaque pixels make up the object window
                 let target_obj = &mut self.obj_buffer[global_x as usize];
                 if color != COLOR_TRANSPARENT {
-                    if priority < target_obj.priority {
+                    if mode == ObjectMode::ObjectWindow {
+                        self.obj_window[global_x as usize] = true;
+                    } else if priority < target_obj.priority {
                         target_obj.color = color;
                         target_obj.priority = priority;
                     }
@@ -158,13 +186,66 @@ impl Ppu {
         }
     }
 
+    /// Object `id` without its affine transform or flips, drawn into the top left of a 64x64 box.
+    /// Returns the size of the object, bitmap objects come out transparent
+    pub fn decode_object(&mut self, id: u32, pixels: &mut [u16; 64 * 64]) -> [u32; 2] {
+        pixels.fill(COLOR_TRANSPARENT);
+
+        let attr0 = ObjAttr0(self.oam.read::<u16>(self.engine, id * 8));
+        let attr1 = ObjAttr1(self.oam.read::<u16>(self.engine, (id * 8) + 2));
+        let attr2 = ObjAttr2(self.oam.read::<u16>(self.engine, (id * 8) + 4));
+        let [width, height] = OBJECT_DIMENSIONS[attr0.shape()][attr1.size()];
+        if attr0.mode() == ObjectMode::Bitmap {
+            return [width, height];
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                pixels[(y * 64 + x) as usize] = self.decode_obj_pixel(attr0, attr2, width, x, y);
+            }
+        }
+
+        [width, height]
+    }
+
+    /// Pixel at `x`, `y` of a tiled object
+    fn decode_obj_pixel(&mut self, attr0: ObjAttr0, attr2: ObjAttr2, width: u32, x: u32, y: u32) -> u16 {
+        let tile_number = attr2.tile_number();
+        let palette_number = attr2.palette_number();
+        let inner_tile_x = x % 8;
+        let inner_tile_y = y % 8;
+        let tile_x = x / 8;
+        let tile_y = y / 8;
+
+        // 1d mapping stores the tiles of an object one after another, 2d mapping places them in
+        // a 32x32 matrix of 32 byte tiles where each object row starts 1024 bytes after the last
+        if attr0.is_8bpp() {
+            let tile_addr = if self.dispcnt.tile_obj_mapping() {
+                (tile_number * (32 << self.dispcnt.tile_obj_1d_boundary())) + (tile_y * width * 8)
+            } else {
+                // 8bpp tiles take two entries, the low bit of the tile number is ignored
+                ((tile_number & !0x1) * 32) + (tile_y * 1024)
+            };
+
+            self.decode_obj_pixel_8bpp(tile_addr + tile_x * 64, palette_number, inner_tile_x, inner_tile_y)
+        } else {
+            let tile_addr = if self.dispcnt.tile_obj_mapping() {
+                (tile_number * (32 << self.dispcnt.tile_obj_1d_boundary())) + (tile_y * width * 4)
+            } else {
+                (tile_number * 32) + (tile_y * 1024)
+            };
+
+            self.decode_obj_pixel_4bpp(tile_addr + tile_x * 32, palette_number, inner_tile_x, inner_tile_y)
+        }
+    }
+
     fn decode_obj_pixel_4bpp(&mut self, base: u32, number: u32, x: u32, y: u32) -> u16 {
         let indices = self.obj.read::<u8>(base + (y * 4) + (x / 2));
         let index = (indices >> (4 * (x & 0x1))) & 0xf;
         if index == 0 {
             COLOR_TRANSPARENT
         } else {
-            unsafe { read(self.palette_ram.as_ref(), ((0x200 + (number * 32) + (index as u32 * 2)) & 0x3ff) as usize) }
+            self.palette_color(0x200 + (number * 32) + (index as u32 * 2))
         }
     }
 
@@ -174,15 +255,104 @@ impl Ppu {
         if index == 0 {
             COLOR_TRANSPARENT
         } else if self.dispcnt.obj_extended_palette() {
-            self.obj_extended_palette.read((number * 0xff + index as u32) * 2)
+            self.obj_extended_palette.read::<u16>((number * 256 + index as u32) * 2) & 0x7fff
         } else {
-            unsafe { read(self.palette_ram.as_ref(), (0x200 + (index as usize * 2)) & 0x3ff) }
+            self.palette_color(0x200 + (index as u32 * 2))
         }
     }
 }
 
-fn read<T: Copy>(ptr: &[u8], offset: usize) -> T {
-    unsafe {
-        *ptr.as_ptr().add(offset).cast()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::video::vram::VramBank;
+    use crate::core::System;
+
+    const BIT9: u16 = 1 << 9;
+    const AFFINE: u16 = 1 << 8;
+
+    #[test]
+    fn attr0_bit_9_disables_regular_objects_and_doubles_affine_ones() {
+        let regular = ObjAttr0(BIT9);
+        assert!(regular.disabled());
+        assert!(!regular.double_size());
+
+        let affine = ObjAttr0(AFFINE | BIT9);
+        assert!(!affine.disabled());
+        assert!(affine.double_size());
+
+        assert!(!ObjAttr0(0).disabled());
+        assert!(!ObjAttr0(AFFINE).double_size());
+    }
+
+    #[test]
+    fn attr0_fields() {
+        let attr0 = ObjAttr0((2 << 14) | (1 << 13) | (1 << 12) | (2 << 10) | 0xa5);
+        assert_eq!(attr0.y(), 0xa5);
+        assert!(attr0.mode() == ObjectMode::ObjectWindow);
+        assert!(attr0.mosaic());
+        assert!(attr0.is_8bpp());
+        assert_eq!(attr0.shape(), 2);
+        assert!(!attr0.affine());
+    }
+
+    #[test]
+    fn attr1_flip_bits_are_the_top_of_the_affine_group() {
+        let attr1 = ObjAttr1((3 << 14) | (0x1f << 9) | 0x1ff);
+        assert_eq!(attr1.x(), 0x1ff);
+        assert_eq!(attr1.affine_group(), 0x1f);
+        assert!(attr1.horizontal_flip());
+        assert!(attr1.vertical_flip());
+        assert_eq!(attr1.size(), 3);
+
+        let attr1 = ObjAttr1(0x7 << 9);
+        assert_eq!(attr1.affine_group(), 0x7);
+        assert!(!attr1.horizontal_flip());
+        assert!(!attr1.vertical_flip());
+    }
+
+    #[test]
+    fn attr2_fields() {
+        let attr2 = ObjAttr2((0xc << 12) | (2 << 10) | 0x3ff);
+        assert_eq!(attr2.tile_number(), 0x3ff);
+        assert_eq!(attr2.priority(), 2);
+        assert_eq!(attr2.palette_number(), 0xc);
+    }
+
+    #[test]
+    fn disabled_objects_are_skipped_and_double_size_affine_objects_are_drawn() {
+        let mut system = System::new();
+        system.scheduler.reset();
+        system.video_unit.reset();
+
+        // tile 0 is solid color 1 of obj palette 0
+        let vram = &mut system.video_unit.vram;
+        vram.write_vramcnt(VramBank::A, 0x80);
+        for addr in 0..32 {
+            vram.write::<u8>(0x06800000 + addr, 0x11);
+        }
+        vram.write_vramcnt(VramBank::A, 0x82);
+        system.video_unit.write_palette_ram(0x05000202, 0x001fu16);
+
+        // every object but 1 is an 8x8 object at 0, 0 with the disable bit set. Object 1 is a double size
+        // affine object at 16, 0 whose identity parameters come from the attribute 3 of objects 0-3
+        for id in 0..128 {
+            system.video_unit.write_oam(0x07000000 + id * 8, BIT9);
+        }
+        system.video_unit.write_oam(0x07000008, AFFINE | BIT9);
+        system.video_unit.write_oam(0x0700000a, 16u16);
+        for (entry, parameter) in [0x100u16, 0, 0, 0x100].into_iter().enumerate() {
+            system.video_unit.write_oam(0x07000006 + entry as u32 * 8, parameter);
+        }
+
+        // the 8x8 image sits in the middle of its 16x16 box
+        let ppu = &mut system.video_unit.ppu_a;
+        for line in [0, 4, 11, 12] {
+            ppu.reset_layers();
+            ppu.render_objects(line);
+            let drawn: Vec<usize> = (0..256).filter(|&x| ppu.obj_buffer[x].color != COLOR_TRANSPARENT).collect();
+            let expected: Vec<usize> = if (4..12).contains(&line) { (20..28).collect() } else { vec![] };
+            assert_eq!(drawn, expected, "line {line}");
+        }
     }
-}
\ No newline at end of file
+}
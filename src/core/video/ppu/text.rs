@@ -12,7 +12,11 @@ impl Ppu {
         let y = ((line + self.bgvofs[id]) % 512) as u32;
         let mut screen_base = (self.dispcnt.screen_base() * 65536) + (self.bgcnt[id].screen_base() * 2048) + ((y / 8) % 32) * 64;
         let character_base = (self.dispcnt.character_base() * 65536) + (self.bgcnt[id].character_base() * 16384);
-        let extended_palette_slot = id as u32 | (self.bgcnt[id].wraparound_ext_palette_slot() as u32 * 2);
+        // bg0/bg1 can move to slot 2/3 with bgcnt bit 13, for bg2/bg3 that bit is wraparound and they always use slot 2/3
+        let extended_palette_slot = match id {
+            0 | 1 if self.bgcnt[id].wraparound_ext_palette_slot() => id as u32 + 2,
+            _ => id as u32,
+        };
         let screen_width = TEXT_DIMENSIONS[self.bgcnt[id].size()][0];
         let screen_height = TEXT_DIMENSIONS[self.bgcnt[id].size()][1];
 
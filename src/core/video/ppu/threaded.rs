@@ -0,0 +1,95 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use crate::core::video::ppu::Ppu;
+
+/// A ppu on its way to or from a render thread
+struct SentPpu(Box<Ppu>);
+
+// SAFETY: a ppu isn't Send because of its `Shared` handles to vram, palette ram and oam. Only `ThreadedPpu` makes
+// these, and its `start` is always followed by `wait` before the emulation thread touches the system again. While
+// it's away the ppu only reads that memory through shared references, from both engines at the same time at
+// most, and never clones or drops the handles, so neither the memory nor the non atomic counts change under it
+unsafe impl Send for SentPpu {}
+
+#[derive(Copy, Clone)]
+pub enum RenderJob {
+    Scanline(u16),
+    FinishFrame,
+}
+
+/// A ppu with a render thread of its own, started the first time it's needed. The ppu moves to the
+/// thread for a job and comes back in `wait`, between jobs it's used like any other `Ppu`
+pub struct ThreadedPpu {
+    ppu: Option<Box<Ppu>>,
+    thread: Option<RenderThread>,
+}
+
+struct RenderThread {
+    jobs: Sender<(SentPpu, RenderJob)>,
+    done: Receiver<SentPpu>,
+}
+
+impl RenderThread {
+    fn spawn(name: String) -> Self {
+        let (jobs, job_receiver) = channel::<(SentPpu, RenderJob)>();
+        let (done_sender, done) = channel();
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                // ends once the ppu that owns the thread is dropped
+                for (SentPpu(mut ppu), job) in job_receiver {
+                    match job {
+                        RenderJob::Scanline(line) => ppu.render_scanline(line),
+                        RenderJob::FinishFrame => ppu.on_finish_frame(),
+                    }
+                    if done_sender.send(SentPpu(ppu)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Ppu: failed to start render thread");
+
+        Self { jobs, done }
+    }
+}
+
+impl ThreadedPpu {
+    pub fn new(ppu: Ppu) -> Self {
+        Self {
+            ppu: Some(Box::new(ppu)),
+            thread: None,
+        }
+    }
+
+    /// Hands the ppu to its render thread, it can't be used until `wait` takes it back
+    pub fn start(&mut self, job: RenderJob) {
+        let ppu = self.ppu.take().expect("Ppu: already rendering");
+        let thread = self.thread.get_or_insert_with(|| RenderThread::spawn(format!("ppu {:?}", ppu.engine)));
+        thread.jobs.send((SentPpu(ppu), job)).expect("Ppu: render thread stopped");
+    }
+
+    /// Blocks until the render thread is done with the job `start` gave it
+    pub fn wait(&mut self) {
+        if self.ppu.is_none() {
+            let thread = self.thread.as_ref().expect("Ppu: no render thread");
+            let SentPpu(ppu) = thread.done.recv().expect("Ppu: render thread panicked");
+            self.ppu = Some(ppu);
+        }
+    }
+}
+
+impl Deref for ThreadedPpu {
+    type Target = Ppu;
+
+    fn deref(&self) -> &Self::Target {
+        self.ppu.as_deref().expect("Ppu: used while rendering")
+    }
+}
+
+impl DerefMut for ThreadedPpu {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.ppu.as_deref_mut().expect("Ppu: used while rendering")
+    }
+}
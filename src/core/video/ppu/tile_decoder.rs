@@ -1,5 +1,3 @@
-use std::ptr::NonNull;
-
 use crate::core::video::ppu::{COLOR_TRANSPARENT, Ppu};
 
 impl Ppu {
@@ -14,7 +12,7 @@ impl Ppu {
             let palette_index = palette_indices & 0xf;
             let palette_addr = (palette_number * 32) + (palette_index * 2);
 
-            let color = if palette_index == 0 { COLOR_TRANSPARENT } else { read(&self.palette_ram, palette_addr & 0x3fff) };
+            let color = if palette_index == 0 { COLOR_TRANSPARENT } else { self.palette_color(palette_addr) };
             pixels[column] = color;
             palette_indices >>= 4;
         }
@@ -35,9 +33,9 @@ impl Ppu {
             let color = if palette_index == 0 {
                 COLOR_TRANSPARENT
             } else if self.dispcnt.bg_extended_palette() {
-                self.bg_extended_palette.read(extended_palette_slot * 0x2000 + (palette_number * 0xff + palette_index) * 2)
+                self.bg_extended_palette.read::<u16>(extended_palette_slot * 0x2000 + (palette_number * 256 + palette_index) * 2) & 0x7fff
             } else {
-                read(&self.palette_ram, (palette_index * 2) & 0x3ff)
+                self.palette_color(palette_index * 2)
             };
             pixels[column] = color;
             palette_indices >>= 8;
@@ -46,9 +44,3 @@ impl Ppu {
         pixels
     }
 }
-
-fn read<T: Copy>(ptr: &NonNull<[u8]>, offset: u32) -> T {
-    unsafe {
-        *ptr.as_ref().as_ptr().add(offset as usize).cast()
-    }
-}
\ No newline at end of file
@@ -1,10 +1,13 @@
 use crate::bitfield;
-use crate::util::Shared;
+use crate::util::{Shared, StateReader, StateWriter};
 
 use std::fmt::Debug;
 
 use std::ops::BitOrAssign;
 
+use log::warn;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum VramBank {
     A,
     B,
@@ -17,6 +20,50 @@ pub enum VramBank {
     I,
 }
 
+impl VramBank {
+    pub const ALL: [VramBank; 9] = [
+        VramBank::A,
+        VramBank::B,
+        VramBank::C,
+        VramBank::D,
+        VramBank::E,
+        VramBank::F,
+        VramBank::G,
+        VramBank::H,
+        VramBank::I,
+    ];
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum VramTarget {
+    Lcdc,
+    Bga,
+    Bgb,
+    Obja,
+    Objb,
+    Arm7,
+    TextureData,
+    TexturePalette,
+    BgaExtendedPalette,
+    BgbExtendedPalette,
+    ObjaExtendedPalette,
+    ObjbExtendedPalette,
+}
+
+impl VramTarget {
+    /// Texture and extended palette slots are only visible to the gpu/ppu, not the cpu
+    pub const fn cpu_accessible(self) -> bool {
+        matches!(self, Self::Lcdc | Self::Bga | Self::Bgb | Self::Obja | Self::Objb | Self::Arm7)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BankMapping {
+    pub target: VramTarget,
+    pub offset: usize,
+    pub length: usize,
+}
+
 bitfield! {
     #[derive(Clone, Copy, Default)]
     struct VramCnt(u8) {
@@ -44,6 +91,8 @@ pub struct Vram {
     vramstat: u8,
 
     vramcnt: [VramCnt; 9],
+    /// Where each bank is mapped in the regions right now, so a vramcnt write only has to undo that bank
+    mappings: [Option<BankMapping>; 9],
 
     bank_a: Box<[u8; 0x20000]>,
     bank_b: Box<[u8; 0x20000]>,
@@ -73,6 +122,7 @@ impl Vram {
             objb_extended_palette: Default::default(),
             vramstat: 0,
             vramcnt: [VramCnt(0); 9],
+            mappings: [None; 9],
             bank_a: Box::new([0; 0x20000]),
             bank_b: Box::new([0; 0x20000]),
             bank_c: Box::new([0; 0x20000]),
@@ -113,6 +163,9 @@ impl Vram {
         self.texture_palette.reset();
         self.bga_extended_palette.reset();
         self.bgb_extended_palette.reset();
+        self.obja_extended_palette.reset();
+        self.objb_extended_palette.reset();
+        self.mappings = [None; 9];
     }
 
     pub fn read<T: Default + BitOrAssign + Copy>(&mut self, addr: u32) -> T {
@@ -137,6 +190,44 @@ impl Vram {
         }
     }
 
+    /// Bank contents and control registers, the mappings are rebuilt from the loaded vramcnt values
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"VRAM");
+        for cnt in &self.vramcnt {
+            state.write("vramcnt", cnt.0);
+        }
+        state.write_bytes("bank_a", &*self.bank_a);
+        state.write_bytes("bank_b", &*self.bank_b);
+        state.write_bytes("bank_c", &*self.bank_c);
+        state.write_bytes("bank_d", &*self.bank_d);
+        state.write_bytes("bank_e", &*self.bank_e);
+        state.write_bytes("bank_f", &*self.bank_f);
+        state.write_bytes("bank_g", &*self.bank_g);
+        state.write_bytes("bank_h", &*self.bank_h);
+        state.write_bytes("bank_i", &*self.bank_i);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.section(b"VRAM");
+        let mut vramcnt = [0; 9];
+        state.read_bytes(&mut vramcnt);
+        state.read_bytes(&mut *self.bank_a);
+        state.read_bytes(&mut *self.bank_b);
+        state.read_bytes(&mut *self.bank_c);
+        state.read_bytes(&mut *self.bank_d);
+        state.read_bytes(&mut *self.bank_e);
+        state.read_bytes(&mut *self.bank_f);
+        state.read_bytes(&mut *self.bank_g);
+        state.read_bytes(&mut *self.bank_h);
+        state.read_bytes(&mut *self.bank_i);
+
+        self.reset_regions();
+        self.vramcnt = [VramCnt(0); 9];
+        for (bank, val) in VramBank::ALL.into_iter().zip(vramcnt) {
+            self.write_vramcnt(bank, val);
+        }
+    }
+
     pub const fn read_vramstat(&self) -> u8 {
         self.vramstat
     }
@@ -155,129 +246,139 @@ impl Vram {
         }
 
         self.vramcnt[index].0 = val;
-        self.reset_regions();
 
-        if self.vramcnt[0].enable() {
-            let ptr = self.bank_a.as_mut_ptr();
-            let offset = self.vramcnt[0].offset() as usize;
-            match self.vramcnt[0].mst() {
-                0 => self.lcdc.map(ptr, 0, 0x20000),
-                1 => self.bga.map(ptr, offset * 0x20000, 0x20000),
-                2 => self.obja.map(ptr, (offset & 1) * 0x20000, 0x20000),
-                3 => self.texture_data.map(ptr, offset * 0x20000, 0x20000),
-                _ => unreachable!(),
-            }
+        let ptr = self.bank_ptr(bank);
+        if let Some(old) = self.mappings[index].take() {
+            self.region_mut(old.target).unmap(ptr, old.offset, old.length);
         }
 
-        if self.vramcnt[1].enable() {
-            let ptr = self.bank_b.as_mut_ptr();
-            let offset = self.vramcnt[1].offset() as usize;
-            match self.vramcnt[1].mst() {
-                0 => self.lcdc.map(ptr, 0x20000, 0x20000),
-                1 => self.bga.map(ptr, offset * 0x20000, 0x20000),
-                2 => self.obja.map(ptr, (offset & 1) * 0x20000, 0x20000),
-                3 => self.texture_data.map(ptr, offset * 0x20000, 0x20000),
-                _ => unreachable!(),
+        let cnt = self.vramcnt[index];
+        if cnt.enable() {
+            match self.bank_mapping(bank) {
+                Some(mapping) => {
+                    self.region_mut(mapping.target).map(ptr, mapping.offset, mapping.length);
+                    self.mappings[index] = Some(mapping);
+                }
+                None => warn!("VRAM: bank {bank:?} enabled with invalid mst {}", cnt.mst()),
             }
         }
 
-        if self.vramcnt[2].enable() {
-            let ptr = self.bank_c.as_mut_ptr();
-            let offset = self.vramcnt[2].offset() as usize;
-            match self.vramcnt[2].mst() {
-                0 => self.lcdc.map(ptr, 0x40000, 0x20000),
-                1 => self.bga.map(ptr, offset * 0x20000, 0x20000),
-                2 => self.arm7_vram.map(ptr, (offset & 1) * 0x20000, 0x20000),
-                3 => self.texture_data.map(ptr, offset * 0x20000, 0x20000),
-                4 => self.bgb.map(ptr, 0, 0x20000),
-                _ => unreachable!(),
+        for bank in [VramBank::C, VramBank::D] {
+            let bit = 1 << (bank as usize - 2);
+            if self.bank_mapping(bank).map(|mapping| mapping.target) == Some(VramTarget::Arm7) {
+                self.vramstat |= bit;
+            } else {
+                self.vramstat &= !bit;
             }
         }
 
-        if self.vramcnt[2].enable() && self.vramcnt[2].mst() == 2 {
-            self.vramstat |= 1;
-        } else {
-            self.vramstat &= !1;
-        }
-
-        if self.vramcnt[3].enable() {
-            let ptr = self.bank_d.as_mut_ptr();
-            let offset = self.vramcnt[3].offset() as usize;
-            match self.vramcnt[3].mst() {
-                0 => self.lcdc.map(ptr, 0x60000, 0x20000),
-                1 => self.bga.map(ptr, offset * 0x20000, 0x20000),
-                2 => self.arm7_vram.map(ptr, (offset & 1) * 0x20000, 0x20000),
-                3 => self.texture_data.map(ptr, offset * 0x20000, 0x20000),
-                4 => self.objb.map(ptr, 0, 0x20000),
-                _ => unreachable!(),
-            }
+        for (a, b) in self.conflicts() {
+            warn!("VRAM: banks {a:?} and {b:?} overlap in {:?}", self.bank_mapping(a).unwrap().target);
         }
+    }
 
-        if self.vramcnt[3].enable() && self.vramcnt[3].mst() == 2 {
-            self.vramstat |= 1 << 1
-        } else {
-            self.vramstat &= !(1 << 1);
+    /// Where the bank is currently mapped, or None if it is disabled or the mst is invalid
+    ///
+    /// Extended palette slots are 8KB each. E covers all four bg slots with its first 32KB,
+    /// F/G cover slots 0-1 or 2-3 depending on the offset, H covers all four of engine B's slots,
+    /// and only the first 8KB of F/G/I is visible as obj extended palette
+    pub fn bank_mapping(&self, bank: VramBank) -> Option<BankMapping> {
+        use VramTarget::*;
+
+        let cnt = self.vramcnt[bank as usize];
+        if !cnt.enable() {
+            return None;
         }
 
-        if self.vramcnt[4].enable() {
-            let ptr = self.bank_e.as_mut_ptr();
-            match self.vramcnt[4].mst() {
-                0 => self.lcdc.map(ptr, 0x80000, 0x10000),
-                1 => self.bga.map(ptr, 0, 0x10000),
-                2 => self.obja.map(ptr, 0, 0x10000),
-                3 => self.texture_palette.map(ptr, 0, 0x10000),
-                4 => self.bga_extended_palette.map(ptr, 0, 0x8000),
-                _ => unreachable!(),
-            }
-        }
+        let offset = cnt.offset() as usize;
+        let (target, offset, length) = match (bank, cnt.mst()) {
+            (VramBank::A, 0) => (Lcdc, 0, 0x20000),
+            (VramBank::B, 0) => (Lcdc, 0x20000, 0x20000),
+            (VramBank::C, 0) => (Lcdc, 0x40000, 0x20000),
+            (VramBank::D, 0) => (Lcdc, 0x60000, 0x20000),
+            (VramBank::A | VramBank::B | VramBank::C | VramBank::D, 1) => (Bga, offset * 0x20000, 0x20000),
+            (VramBank::A | VramBank::B, 2) => (Obja, (offset & 1) * 0x20000, 0x20000),
+            (VramBank::C | VramBank::D, 2) => (Arm7, (offset & 1) * 0x20000, 0x20000),
+            (VramBank::A | VramBank::B | VramBank::C | VramBank::D, 3) => (TextureData, offset * 0x20000, 0x20000),
+            (VramBank::C, 4) => (Bgb, 0, 0x20000),
+            (VramBank::D, 4) => (Objb, 0, 0x20000),
+
+            (VramBank::E, 0) => (Lcdc, 0x80000, 0x10000),
+            (VramBank::E, 1) => (Bga, 0, 0x10000),
+            (VramBank::E, 2) => (Obja, 0, 0x10000),
+            (VramBank::E, 3) => (TexturePalette, 0, 0x10000),
+            (VramBank::E, 4) => (BgaExtendedPalette, 0, 0x8000),
+
+            (VramBank::F, 0) => (Lcdc, 0x90000, 0x4000),
+            (VramBank::G, 0) => (Lcdc, 0x94000, 0x4000),
+            (VramBank::F | VramBank::G, 1) => (Bga, (offset & 1) * 0x4000 + (offset & 2) * 0x10000, 0x4000),
+            (VramBank::F | VramBank::G, 2) => (Obja, (offset & 1) * 0x4000 + (offset & 2) * 0x10000, 0x4000),
+            (VramBank::F | VramBank::G, 3) => (TexturePalette, ((offset & 1) + (offset & 2) * 4) * 0x4000, 0x4000),
+            (VramBank::F | VramBank::G, 4) => (BgaExtendedPalette, (offset & 1) * 0x4000, 0x4000),
+            (VramBank::F | VramBank::G, 5) => (ObjaExtendedPalette, 0, 0x2000),
+
+            (VramBank::H, 0) => (Lcdc, 0x98000, 0x8000),
+            (VramBank::H, 1) => (Bgb, 0, 0x8000),
+            (VramBank::H, 2) => (BgbExtendedPalette, 0, 0x8000),
+
+            (VramBank::I, 0) => (Lcdc, 0xa0000, 0x4000),
+            (VramBank::I, 1) => (Bgb, 0x8000, 0x4000),
+            (VramBank::I, 2) => (Objb, 0, 0x4000),
+            (VramBank::I, 3) => (ObjbExtendedPalette, 0, 0x2000),
+            _ => return None,
+        };
+
+        Some(BankMapping { target, offset, length })
+    }
 
-        if self.vramcnt[5].enable() {
-            let ptr = self.bank_f.as_mut_ptr();
-            let offset = self.vramcnt[5].offset() as usize;
-            match self.vramcnt[5].mst() {
-                0 => self.lcdc.map(ptr, 0x90000, 0x4000),
-                1 => self.bga.map(ptr, (offset & 1) * 0x4000 + (offset & 2) * 0x10000, 0x4000),
-                2 => self.obja.map(ptr, (offset & 1) * 0x4000 + (offset & 2) * 0x10000, 0x4000),
-                3 => self.texture_palette.map(ptr, ((offset & 1) + (offset & 2) * 4) * 0x4000, 0x4000),
-                4 => self.bga_extended_palette.map(ptr, (offset & 1) * 0x4000, 0x4000),
-                5 => self.obja_extended_palette.map(ptr, 0, 0x2000),
-                _ => unreachable!(),
-            }
-        }
+    pub const fn bank_control(&self, bank: VramBank) -> (bool, u8, u8) {
+        let cnt = self.vramcnt[bank as usize];
+        (cnt.enable(), cnt.mst(), cnt.offset())
+    }
 
-        if self.vramcnt[6].enable() {
-            let ptr = self.bank_g.as_mut_ptr();
-            let offset = self.vramcnt[6].offset() as usize;
-            match self.vramcnt[6].mst() {
-                0 => self.lcdc.map(ptr, 0x94000, 0x4000),
-                1 => self.bga.map(ptr, (offset & 1) * 0x4000 + (offset & 2) * 0x10000, 0x4000),
-                2 => self.obja.map(ptr, (offset & 1) * 0x4000 + (offset & 2) * 0x10000, 0x4000),
-                3 => self.texture_palette.map(ptr, ((offset & 1) + (offset & 2) * 4) * 0x4000, 0x4000),
-                4 => self.bga_extended_palette.map(ptr, (offset & 1) * 0x4000, 0x4000),
-                5 => self.obja_extended_palette.map(ptr, 0, 0x2000),
-                _ => unreachable!(),
+    /// Pairs of enabled banks whose mappings overlap, which get OR-composed on reads
+    pub fn conflicts(&self) -> Vec<(VramBank, VramBank)> {
+        let mut conflicts = vec![];
+        for (i, &a) in VramBank::ALL.iter().enumerate() {
+            for &b in &VramBank::ALL[i + 1..] {
+                if let (Some(x), Some(y)) = (self.bank_mapping(a), self.bank_mapping(b)) {
+                    if x.target == y.target && x.offset < y.offset + y.length && y.offset < x.offset + x.length {
+                        conflicts.push((a, b));
+                    }
+                }
             }
         }
+        conflicts
+    }
 
-        if self.vramcnt[7].enable() {
-            let ptr = self.bank_h.as_mut_ptr();
-            match self.vramcnt[7].mst() {
-                0 => self.lcdc.map(ptr, 0x98000, 0x8000),
-                1 => self.bgb.map(ptr, 0, 0x8000),
-                2 => self.bgb_extended_palette.map(ptr, 0, 0x8000),
-                _ => unreachable!(),
-            }
+    fn region_mut(&mut self, target: VramTarget) -> &mut VramRegion {
+        match target {
+            VramTarget::Lcdc => &mut *self.lcdc,
+            VramTarget::Bga => &mut *self.bga,
+            VramTarget::Bgb => &mut *self.bgb,
+            VramTarget::Obja => &mut *self.obja,
+            VramTarget::Objb => &mut *self.objb,
+            VramTarget::Arm7 => &mut self.arm7_vram,
+            VramTarget::TextureData => &mut self.texture_data,
+            VramTarget::TexturePalette => &mut self.texture_palette,
+            VramTarget::BgaExtendedPalette => &mut *self.bga_extended_palette,
+            VramTarget::BgbExtendedPalette => &mut *self.bgb_extended_palette,
+            VramTarget::ObjaExtendedPalette => &mut *self.obja_extended_palette,
+            VramTarget::ObjbExtendedPalette => &mut *self.objb_extended_palette,
         }
+    }
 
-        if self.vramcnt[8].enable() {
-            let ptr = self.bank_i.as_mut_ptr();
-            match self.vramcnt[8].mst() {
-                0 => self.lcdc.map(ptr, 0xa0000, 0x4000),
-                1 => self.bgb.map(ptr, 0x8000, 0x4000),
-                2 => self.objb.map(ptr, 0, 0x4000),
-                3 => self.objb_extended_palette.map(ptr, 0, 0x2000),
-                _ => unreachable!(),
-            }
+    fn bank_ptr(&mut self, bank: VramBank) -> *mut u8 {
+        match bank {
+            VramBank::A => self.bank_a.as_mut_ptr(),
+            VramBank::B => self.bank_b.as_mut_ptr(),
+            VramBank::C => self.bank_c.as_mut_ptr(),
+            VramBank::D => self.bank_d.as_mut_ptr(),
+            VramBank::E => self.bank_e.as_mut_ptr(),
+            VramBank::F => self.bank_f.as_mut_ptr(),
+            VramBank::G => self.bank_g.as_mut_ptr(),
+            VramBank::H => self.bank_h.as_mut_ptr(),
+            VramBank::I => self.bank_i.as_mut_ptr(),
         }
     }
 }
@@ -299,6 +400,10 @@ impl VramPage {
         self.banks.push(ptr);
     }
 
+    pub fn remove_bank(&mut self, ptr: *mut u8) {
+        self.banks.retain(|&bank| bank != ptr);
+    }
+
     pub fn read<T: Default + BitOrAssign + Copy>(&self, addr: u32) -> T {
         unsafe {
             let mut data = T::default();
@@ -322,9 +427,14 @@ impl VramPage {
     }
 }
 
+/// A view of the banks mapped to one vram target. Every page remembers the generation it was last
+/// written or remapped in, so caches built on top (tile caches, the texture cache, debugger viewers)
+/// can each keep their own checkpoint and only redo the pages that changed since
 #[derive(Default)]
 pub struct VramRegion {
     pages: Vec<VramPage>,
+    page_generations: Vec<u64>,
+    generation: u64,
 }
 
 impl VramRegion {
@@ -334,14 +444,17 @@ impl VramRegion {
         for page in &mut self.pages {
             page.reset();
         }
+        self.touch_all();
     }
 
-    pub fn read<T: Default + BitOrAssign + Copy>(&mut self, addr: u32) -> T {
-        self.get_page(addr).read(addr)
+    pub fn read<T: Default + BitOrAssign + Copy>(&self, addr: u32) -> T {
+        self.pages[Self::page_index(addr)].read(addr)
     }
 
     pub fn write<T: Copy>(&mut self, addr: u32, val: T) {
-        self.get_page(addr).write(addr, val)
+        let index = Self::page_index(addr);
+        self.page_generations[index] = self.generation;
+        self.pages[index].write(addr, val)
     }
 
     pub fn allocate(&mut self, size: usize) {
@@ -350,25 +463,109 @@ impl VramRegion {
         for _ in 0..pages_to_allocate {
             self.pages.push(VramPage::default())
         }
+        self.page_generations = vec![0; pages_to_allocate];
+        self.touch_all();
     }
 
-    pub fn map(&mut self, ptr: *mut u8, offset: usize, length: usize) {
+    fn map(&mut self, ptr: *mut u8, offset: usize, length: usize) {
         let pages_to_map = length / Self::PAGE_SIZE;
         for i in 0..pages_to_map {
             let index = (offset / Self::PAGE_SIZE) + i;
-            self.pages[index].add_bank(unsafe { ptr.add(i * Self::PAGE_SIZE) })
+            self.pages[index].add_bank(unsafe { ptr.add(i * Self::PAGE_SIZE) });
+            self.page_generations[index] = self.generation;
         }
     }
 
-    fn get_page(&mut self, mut addr: u32) -> &mut VramPage {
+    fn unmap(&mut self, ptr: *mut u8, offset: usize, length: usize) {
+        let pages_to_unmap = length / Self::PAGE_SIZE;
+        for i in 0..pages_to_unmap {
+            let index = (offset / Self::PAGE_SIZE) + i;
+            self.pages[index].remove_bank(unsafe { ptr.add(i * Self::PAGE_SIZE) });
+            self.page_generations[index] = self.generation;
+        }
+    }
+
+    /// Starts a new generation and returns the one that just ended. A consumer keeps the returned value
+    /// and later asks what changed since, every change after this call counts as newer
+    pub fn checkpoint(&mut self) -> u64 {
+        let generation = self.generation;
+        self.generation += 1;
+        generation
+    }
+
+    /// Whether any page overlapping `addr..addr + length` changed after `checkpoint`.
+    /// A consumer that never took a checkpoint can pass 0 to treat everything as changed
+    pub fn is_dirty_since(&self, checkpoint: u64, addr: u32, length: u32) -> bool {
+        let first = Self::page_index(addr);
+        let last = Self::page_index(addr + length.max(1) - 1).max(first);
+        self.page_generations
+            .get(first..=last.min(self.page_generations.len().saturating_sub(1)))
+            .is_some_and(|pages| pages.iter().any(|&generation| generation > checkpoint))
+    }
+
+    /// Marks every page as changed in the current generation
+    fn touch_all(&mut self) {
+        self.generation = self.generation.max(1);
+        self.page_generations.fill(self.generation);
+    }
+
+    fn page_index(mut addr: u32) -> usize {
         addr &= 0xffffff;
         let region = (addr >> 20) & 0xf;
         let offset = addr - (region * 0x100000);
-        let index = offset >> 12;
-        // println!("{addr:x}, {region}, {offset:x}, {index}");
-        // unsafe {
-        //     &mut * self.pages.as_mut_ptr().add(index as usize)
-        // }
-        &mut self.pages[index as usize]
+        (offset >> 12) as usize
+    }
+
+    fn get_page(&mut self, addr: u32) -> &mut VramPage {
+        &mut self.pages[Self::page_index(addr)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vram() -> Vram {
+        let mut vram = Vram::new();
+        vram.reset();
+        vram
+    }
+
+    #[test]
+    fn vramcnt_write_only_remaps_its_bank() {
+        let mut vram = vram();
+        vram.write_vramcnt(VramBank::A, 0x80);
+        vram.write_vramcnt(VramBank::B, 0x80);
+        vram.write::<u16>(0x06800000, 0x1111);
+        vram.write::<u16>(0x06820000, 0x2222);
+
+        // a moves to engine a's bg vram and leaves the lcdc, b stays where it was
+        vram.write_vramcnt(VramBank::A, 0x81);
+        assert_eq!(vram.read::<u16>(0x06000000), 0x1111);
+        assert_eq!(vram.read::<u16>(0x06800000), 0);
+        assert_eq!(vram.read::<u16>(0x06820000), 0x2222);
+
+        // disabling a unmaps it without touching b either
+        vram.write_vramcnt(VramBank::A, 0x01);
+        assert_eq!(vram.read::<u16>(0x06000000), 0);
+        assert_eq!(vram.read::<u16>(0x06820000), 0x2222);
+    }
+
+    #[test]
+    fn overlapping_banks_both_stay_mapped() {
+        let mut vram = vram();
+        vram.write_vramcnt(VramBank::A, 0x80);
+        vram.write_vramcnt(VramBank::B, 0x80);
+        vram.write::<u8>(0x06800000, 0x01);
+        vram.write::<u8>(0x06820000, 0x02);
+
+        // both at bg offset 0 read as the or of the two
+        vram.write_vramcnt(VramBank::A, 0x81);
+        vram.write_vramcnt(VramBank::B, 0x81);
+        assert_eq!(vram.read::<u8>(0x06000000), 0x03);
+
+        vram.write_vramcnt(VramBank::B, 0x89);
+        assert_eq!(vram.read::<u8>(0x06000000), 0x01);
+        assert_eq!(vram.read::<u8>(0x06020000), 0x02);
     }
 }
@@ -1,6 +1,19 @@
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
+use log::error;
+
+use crate::core::System;
+
 const REFRESH_RATE: f64 = 60.0;
+pub const DS_REFRESH_RATE: f64 = crate::core::timing::REFRESH_RATE;
+
+/// Frames between rewind snapshots, rewinding steps back one snapshot per frame
+const REWIND_INTERVAL: u32 = 6;
+/// Memory the rewind deltas may take before the oldest are dropped
+const REWIND_BUDGET: usize = 64 * 1024 * 1024;
+/// Bytes that have to match before a run of differing bytes ends, shorter matches are cheaper to copy
+const DELTA_BLOCK: usize = 16;
 
 pub struct FrameHelper {
     accumulated: Duration,
@@ -44,9 +57,9 @@ impl FrameHelper {
         self.queue_reset = true;
     }
 
-    // pub const fn get_fast_forward(&self) -> f64 {
-    //     self.fast_forward
-    // }
+    pub const fn get_fast_forward(&self) -> f64 {
+        self.fast_forward
+    }
 
     pub fn set_fast_forward(&mut self, val: f64) {
         self.fast_forward = val;
@@ -92,3 +105,125 @@ impl FrameHelper {
         self
     }
 }
+
+/// Recent states for stepping back in time. The newest snapshot is kept whole, every older one only as
+/// the bytes that differ from the snapshot after it, which is usually a small part of main memory and vram
+pub struct Rewind {
+    frames: u32,
+    newest: Vec<u8>,
+    /// Deltas that turn a snapshot into the one before it, oldest first
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+    /// The state that was replaced last, its allocation is reused for the next snapshot
+    scratch: Vec<u8>,
+}
+
+impl Rewind {
+    pub fn new() -> Self {
+        Self { frames: 0, newest: vec![], deltas: VecDeque::new(), delta_bytes: 0, scratch: vec![] }
+    }
+
+    /// Forgets all snapshots, for when the game changes or the state jumps
+    pub fn clear(&mut self) {
+        self.frames = 0;
+        self.newest.clear();
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    /// Takes a snapshot every `REWIND_INTERVAL` frames, call after each frame that ran forward
+    pub fn on_frame(&mut self, system: &mut System) {
+        self.frames += 1;
+        if self.frames < REWIND_INTERVAL {
+            return;
+        }
+
+        self.frames = 0;
+        let state = system.save_state_into(std::mem::take(&mut self.scratch));
+        if !self.newest.is_empty() {
+            let delta = encode_delta(&state, &self.newest);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.scratch = std::mem::replace(&mut self.newest, state);
+
+        while self.delta_bytes > REWIND_BUDGET {
+            let Some(oldest) = self.deltas.pop_front() else {
+                break;
+            };
+            self.delta_bytes -= oldest.len();
+        }
+    }
+
+    /// Loads the newest snapshot and drops it, so the next step goes further back. Returns false
+    /// when there's nothing left to go back to
+    pub fn step_back(&mut self, system: &mut System) -> bool {
+        if self.newest.is_empty() {
+            return false;
+        }
+
+        if let Err(e) = system.load_state(&self.newest) {
+            error!("Rewind: failed to load a snapshot: {e}");
+            self.clear();
+            return false;
+        }
+
+        self.frames = 0;
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                self.delta_bytes -= delta.len();
+                apply_delta(&mut self.newest, &delta);
+            }
+            None => self.newest.clear(),
+        }
+        true
+    }
+}
+
+/// Records what turns `newer` back into `older`: the length of `older`, then runs of a matching byte count,
+/// a differing byte count and `older`'s bytes for that run
+fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let mut delta = (older.len() as u32).to_le_bytes().to_vec();
+    let common = newer.len().min(older.len());
+    let mut offset = 0;
+    while offset < older.len() {
+        let mut start = offset;
+        if offset < common {
+            // whole blocks are compared first, which is a lot faster than going byte by byte
+            let blocks = newer[offset..common].chunks(DELTA_BLOCK).zip(older[offset..common].chunks(DELTA_BLOCK));
+            start = (offset + blocks.take_while(|(a, b)| a == b).count() * DELTA_BLOCK).min(common);
+            while start < common && newer[start] == older[start] {
+                start += 1;
+            }
+        }
+        if start == older.len() {
+            break;
+        }
+
+        let mut end = start + 1;
+        while end < older.len() && !(end + DELTA_BLOCK <= common && newer[end..end + DELTA_BLOCK] == older[end..end + DELTA_BLOCK]) {
+            end = (end + DELTA_BLOCK).min(older.len());
+        }
+
+        delta.extend_from_slice(&((start - offset) as u32).to_le_bytes());
+        delta.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        delta.extend_from_slice(&older[start..end]);
+        offset = end;
+    }
+
+    delta
+}
+
+fn apply_delta(state: &mut Vec<u8>, delta: &[u8]) {
+    let word = |pos: usize| u32::from_le_bytes(delta[pos..pos + 4].try_into().unwrap()) as usize;
+    state.resize(word(0), 0);
+
+    let (mut pos, mut offset) = (4, 0);
+    while pos < delta.len() {
+        let (skip, len) = (word(pos), word(pos + 4));
+        offset += skip;
+        state[offset..offset + len].copy_from_slice(&delta[pos + 8..pos + 8 + len]);
+        offset += len;
+        pos += 8 + len;
+    }
+}
@@ -0,0 +1,69 @@
+use gilrs::{Axis, Button, EventType, Gilrs};
+use log::{error, info};
+
+use crate::core::hardware::input::InputEvent;
+
+/// How far a stick has to be pushed to count as a d-pad press
+const STICK_THRESHOLD: f32 = 0.5;
+
+pub enum GamepadInput {
+    /// Goes through the input map
+    Button(Button),
+    /// The left stick, always the d-pad
+    Stick(InputEvent),
+}
+
+/// Every connected gamepad, their inputs are merged
+pub struct Gamepads {
+    /// `None` when the platform backend failed to start, the keyboard still works
+    gilrs: Option<Gilrs>,
+    /// Left, right, up and down as pushed by the left stick
+    stick: [bool; 4],
+}
+
+impl Gamepads {
+    pub fn new() -> Self {
+        let gilrs = Gilrs::new().map_err(|e| error!("Gamepad: no gamepad support: {e}")).ok();
+        Self { gilrs, stick: [false; 4] }
+    }
+
+    /// Presses and releases since the last poll
+    pub fn poll(&mut self) -> Vec<(GamepadInput, bool)> {
+        let mut inputs = vec![];
+        let Some(gilrs) = &mut self.gilrs else {
+            return inputs;
+        };
+
+        while let Some(event) = gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(button, _) => inputs.push((GamepadInput::Button(button), true)),
+                EventType::ButtonReleased(button, _) => inputs.push((GamepadInput::Button(button), false)),
+                EventType::AxisChanged(Axis::LeftStickX, value, _) => {
+                    let pushed = [value < -STICK_THRESHOLD, value > STICK_THRESHOLD];
+                    update_stick(&mut self.stick, 0, pushed, &mut inputs);
+                }
+                // y points up
+                EventType::AxisChanged(Axis::LeftStickY, value, _) => {
+                    let pushed = [value > STICK_THRESHOLD, value < -STICK_THRESHOLD];
+                    update_stick(&mut self.stick, 2, pushed, &mut inputs);
+                }
+                EventType::Connected => info!("Gamepad: {} connected", gilrs.gamepad(event.id).name()),
+                EventType::Disconnected => info!("Gamepad: {} disconnected", gilrs.gamepad(event.id).name()),
+                _ => {}
+            }
+        }
+
+        inputs
+    }
+}
+
+/// Reports the directions of one axis that changed
+fn update_stick(stick: &mut [bool; 4], first: usize, pushed: [bool; 2], inputs: &mut Vec<(GamepadInput, bool)>) {
+    const DIRECTIONS: [InputEvent; 4] = [InputEvent::Left, InputEvent::Right, InputEvent::Up, InputEvent::Down];
+    for (i, pushed) in pushed.into_iter().enumerate() {
+        if stick[first + i] != pushed {
+            stick[first + i] = pushed;
+            inputs.push((GamepadInput::Stick(DIRECTIONS[first + i]), pushed));
+        }
+    }
+}
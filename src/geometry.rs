@@ -0,0 +1,243 @@
+use crate::core::video::Screen;
+
+const WIDTH: f32 = 256.0;
+const HEIGHT: f32 = 192.0;
+
+#[derive(Default, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Layout {
+    #[default]
+    Vertical,
+    Horizontal,
+    TopOnly,
+    BottomOnly,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Vertical, Layout::Horizontal, Layout::TopOnly, Layout::BottomOnly];
+}
+
+/// Clockwise rotation of the whole layout
+#[derive(Default, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl Rotation {
+    pub const ALL: [Rotation; 4] = [Rotation::None, Rotation::Cw90, Rotation::Cw180, Rotation::Cw270];
+}
+
+#[derive(Default, Copy, Clone, Debug)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// Where the screens end up inside the window, shared by the renderer and the touch mapping
+/// so letterboxing, rotation and the gap between screens are only worked out in one place
+#[derive(Default, Copy, Clone, Debug)]
+pub struct ScreenGeometry {
+    pub layout: Layout,
+    pub rotation: Rotation,
+    pub gap: u32,
+    pub integer_scale: bool,
+    pub viewport: Viewport,
+}
+
+impl ScreenGeometry {
+    /// Size of the unrotated layout in ds pixels
+    fn content_size(&self) -> (f32, f32) {
+        let gap = self.gap as f32;
+        match self.layout {
+            Layout::Vertical => (WIDTH, HEIGHT * 2.0 + gap),
+            Layout::Horizontal => (WIDTH * 2.0 + gap, HEIGHT),
+            Layout::TopOnly | Layout::BottomOnly => (WIDTH, HEIGHT),
+        }
+    }
+
+    /// Origin of a screen inside the unrotated layout, if it is visible
+    fn screen_origin(&self, screen: Screen) -> Option<(f32, f32)> {
+        let gap = self.gap as f32;
+        match (self.layout, screen) {
+            (Layout::Vertical | Layout::Horizontal | Layout::TopOnly, Screen::Top) => Some((0.0, 0.0)),
+            (Layout::Vertical, Screen::Bottom) => Some((0.0, HEIGHT + gap)),
+            (Layout::Horizontal, Screen::Bottom) => Some((WIDTH + gap, 0.0)),
+            (Layout::BottomOnly, Screen::Bottom) => Some((0.0, 0.0)),
+            _ => None,
+        }
+    }
+
+    /// Scale from ds pixels to window pixels and the offset of the letterboxed layout
+    fn transform(&self) -> (f32, f32, f32) {
+        let (width, height) = self.rotated_size();
+        let mut scale = (self.viewport.width / width).min(self.viewport.height / height);
+        if self.integer_scale && scale >= 1.0 {
+            scale = scale.floor();
+        }
+
+        let x = self.viewport.x + (self.viewport.width - width * scale) / 2.0;
+        let y = self.viewport.y + (self.viewport.height - height * scale) / 2.0;
+        (scale, x, y)
+    }
+
+    fn rotated_size(&self) -> (f32, f32) {
+        let (width, height) = self.content_size();
+        match self.rotation {
+            Rotation::None | Rotation::Cw180 => (width, height),
+            Rotation::Cw90 | Rotation::Cw270 => (height, width),
+        }
+    }
+
+    fn rotate(&self, x: f32, y: f32) -> (f32, f32) {
+        let (width, height) = self.content_size();
+        match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Cw90 => (height - y, x),
+            Rotation::Cw180 => (width - x, height - y),
+            Rotation::Cw270 => (y, width - x),
+        }
+    }
+
+    fn unrotate(&self, x: f32, y: f32) -> (f32, f32) {
+        let (width, height) = self.content_size();
+        match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Cw90 => (y, height - x),
+            Rotation::Cw180 => (width - x, height - y),
+            Rotation::Cw270 => (width - y, x),
+        }
+    }
+
+    /// Converts a position in window pixels to a pixel on the given screen
+    pub fn map_touch(&self, x: f32, y: f32, screen: Screen) -> Option<(u32, u32)> {
+        let (scale, offset_x, offset_y) = self.transform();
+        let (x, y) = self.unrotate((x - offset_x) / scale, (y - offset_y) / scale);
+        let (origin_x, origin_y) = self.screen_origin(screen)?;
+        let (x, y) = (x - origin_x, y - origin_y);
+
+        if (0.0..WIDTH).contains(&x) && (0.0..HEIGHT).contains(&y) {
+            Some((x as u32, y as u32))
+        } else {
+            None
+        }
+    }
+
+    /// Two quads as (x, y, u, v) in normalized device coordinates, sampling the top and bottom
+    /// halves of the screen texture. Hidden screens get a degenerate quad
+    pub fn vertices(&self, window_width: f32, window_height: f32) -> [[f32; 4]; 12] {
+        let (scale, offset_x, offset_y) = self.transform();
+        let mut vertices = [[0.0; 4]; 12];
+
+        for (i, screen) in [Screen::Top, Screen::Bottom].into_iter().enumerate() {
+            let Some((origin_x, origin_y)) = self.screen_origin(screen) else {
+                continue;
+            };
+
+            let v = i as f32 * 0.5;
+            let corners = [
+                (origin_x, origin_y, 0.0, v),
+                (origin_x + WIDTH, origin_y, 1.0, v),
+                (origin_x + WIDTH, origin_y + HEIGHT, 1.0, v + 0.5),
+                (origin_x, origin_y + HEIGHT, 0.0, v + 0.5),
+            ];
+
+            let corners = corners.map(|(x, y, u, v)| {
+                let (x, y) = self.rotate(x, y);
+                let x = offset_x + x * scale;
+                let y = offset_y + y * scale;
+                [x / window_width * 2.0 - 1.0, 1.0 - y / window_height * 2.0, u, v]
+            });
+
+            for (j, corner) in [0, 1, 2, 0, 2, 3].into_iter().enumerate() {
+                vertices[i * 6 + j] = corners[corner];
+            }
+        }
+
+        vertices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screens(layout: Layout, rotation: Rotation, gap: u32, width: f32, height: f32) -> ScreenGeometry {
+        ScreenGeometry {
+            layout,
+            rotation,
+            gap,
+            integer_scale: false,
+            viewport: Viewport { x: 0.0, y: 0.0, width, height },
+        }
+    }
+
+    #[test]
+    fn vertical_maps_each_screen() {
+        let geometry = screens(Layout::Vertical, Rotation::None, 0, 256.0, 384.0);
+        assert_eq!(geometry.map_touch(10.0, 20.0, Screen::Top), Some((10, 20)));
+        assert_eq!(geometry.map_touch(10.0, 20.0, Screen::Bottom), None);
+        assert_eq!(geometry.map_touch(10.0, 212.0, Screen::Bottom), Some((10, 20)));
+    }
+
+    #[test]
+    fn vertical_skips_the_gap() {
+        let geometry = screens(Layout::Vertical, Rotation::None, 32, 256.0, 416.0);
+        assert_eq!(geometry.map_touch(10.0, 200.0, Screen::Bottom), None);
+        assert_eq!(geometry.map_touch(10.0, 224.0, Screen::Bottom), Some((10, 0)));
+    }
+
+    #[test]
+    fn horizontal_puts_the_bottom_screen_on_the_right() {
+        let geometry = screens(Layout::Horizontal, Rotation::None, 0, 512.0, 192.0);
+        assert_eq!(geometry.map_touch(300.0, 50.0, Screen::Bottom), Some((44, 50)));
+        assert_eq!(geometry.map_touch(100.0, 50.0, Screen::Bottom), None);
+    }
+
+    #[test]
+    fn single_screen_layouts_hide_the_other_screen() {
+        let geometry = screens(Layout::BottomOnly, Rotation::None, 0, 256.0, 192.0);
+        assert_eq!(geometry.map_touch(10.0, 20.0, Screen::Bottom), Some((10, 20)));
+        assert_eq!(geometry.map_touch(10.0, 20.0, Screen::Top), None);
+        assert!(geometry.vertices(256.0, 192.0)[..6].iter().all(|vertex| *vertex == [0.0; 4]));
+
+        let geometry = ScreenGeometry { layout: Layout::TopOnly, ..geometry };
+        assert_eq!(geometry.map_touch(10.0, 20.0, Screen::Top), Some((10, 20)));
+        assert_eq!(geometry.map_touch(10.0, 20.0, Screen::Bottom), None);
+    }
+
+    #[test]
+    fn letterboxing_is_taken_off_before_scaling() {
+        // twice the size with 100 pixels of bars on each side
+        let geometry = screens(Layout::Vertical, Rotation::None, 0, 712.0, 768.0);
+        assert_eq!(geometry.map_touch(50.0, 20.0, Screen::Top), None);
+        assert_eq!(geometry.map_touch(120.0, 40.0, Screen::Top), Some((10, 20)));
+        assert_eq!(geometry.map_touch(120.0, 424.0, Screen::Bottom), Some((10, 20)));
+    }
+
+    #[test]
+    fn integer_scale_rounds_down() {
+        let mut geometry = screens(Layout::TopOnly, Rotation::None, 0, 600.0, 450.0);
+        geometry.integer_scale = true;
+        // scale 2 centered leaves 44 pixels on the left and 33 on top
+        assert_eq!(geometry.map_touch(44.0 + 20.0, 33.0 + 40.0, Screen::Top), Some((10, 20)));
+    }
+
+    #[test]
+    fn rotation_turns_the_layout_clockwise() {
+        // turned right the top screen ends up on the right, with its top left corner at the top right
+        let geometry = screens(Layout::Vertical, Rotation::Cw90, 0, 384.0, 256.0);
+        assert_eq!(geometry.map_touch(383.5, 0.5, Screen::Top), Some((0, 0)));
+        assert_eq!(geometry.map_touch(170.5, 10.5, Screen::Bottom), Some((10, 21)));
+
+        let geometry = screens(Layout::Vertical, Rotation::Cw180, 0, 256.0, 384.0);
+        assert_eq!(geometry.map_touch(245.5, 363.5, Screen::Top), Some((10, 20)));
+
+        let geometry = screens(Layout::Vertical, Rotation::Cw270, 0, 384.0, 256.0);
+        assert_eq!(geometry.map_touch(20.5, 245.5, Screen::Top), Some((10, 20)));
+    }
+}
@@ -0,0 +1,389 @@
+use std::cell::RefCell;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+
+use crate::arm::trace::TraceFormat;
+use crate::core::config::{BootMode, ClockScale};
+use crate::core::video::Screen;
+use crate::core::{OwnedSystem, StopReason, System};
+use crate::util::{alloc_counter, diff_states, paths, remove_lock_files, unimplemented_hits};
+
+/// `--headless [--frames N] [--screenshot out.png] [--expect-hash HASH] [--hash-file FILE] [--count-allocs]
+/// [--trace-format native|reference] [--arm9-clock PERCENT] [--arm7-clock PERCENT] rom.nds`
+pub struct HeadlessOptions {
+    pub rom: String,
+    pub frames: u32,
+    pub screenshot: Option<PathBuf>,
+    /// Exit with an error unless the last frame hashes to this
+    pub expect_hash: Option<u64>,
+    /// Compared like `expect_hash` when the file exists, otherwise the hash gets stored in it
+    pub hash_file: Option<PathBuf>,
+    /// Report how many allocations `frames` more frames make once the rom is running
+    pub count_allocs: bool,
+    /// Layout of the instruction traces, which are only written with the log_state feature
+    pub trace_format: TraceFormat,
+    pub arm9_clock: ClockScale,
+    pub arm7_clock: ClockScale,
+}
+
+impl HeadlessOptions {
+    /// Returns `None` when `--headless` wasn't passed
+    pub fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        if !args.iter().any(|arg| arg == "--headless") {
+            return Ok(None);
+        }
+
+        let mut rom = None;
+        let mut frames = 60;
+        let mut screenshot = None;
+        let mut expect_hash = None;
+        let mut hash_file = None;
+        let mut count_allocs = false;
+        let mut trace_format = TraceFormat::default();
+        let mut arm9_clock = ClockScale::default();
+        let mut arm7_clock = ClockScale::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => {}
+                "--frames" => {
+                    let value = args.next().ok_or("--frames needs a value")?;
+                    frames = value.parse().map_err(|_| format!("invalid frame count: {value}"))?;
+                }
+                "--screenshot" => screenshot = Some(PathBuf::from(args.next().ok_or("--screenshot needs a path")?)),
+                "--expect-hash" => {
+                    let value = args.next().ok_or("--expect-hash needs a value")?;
+                    expect_hash = Some(parse_hash(value)?);
+                }
+                "--hash-file" => hash_file = Some(PathBuf::from(args.next().ok_or("--hash-file needs a path")?)),
+                "--count-allocs" if !cfg!(feature = "count_allocs") => {
+                    return Err("--count-allocs needs a build with --features count_allocs".to_string())
+                }
+                "--count-allocs" => count_allocs = true,
+                "--trace-format" => trace_format = TraceFormat::parse(args.next().ok_or("--trace-format needs a value")?)?,
+                "--arm9-clock" => arm9_clock = ClockScale::parse(args.next().ok_or("--arm9-clock needs a value")?)?,
+                "--arm7-clock" => arm7_clock = ClockScale::parse(args.next().ok_or("--arm7-clock needs a value")?)?,
+                other if other.starts_with("--") => return Err(format!("unknown option: {other}")),
+                other => rom = Some(other.to_string()),
+            }
+        }
+
+        Ok(Some(Self {
+            rom: rom.ok_or("no rom given")?,
+            frames,
+            screenshot,
+            expect_hash,
+            hash_file,
+            count_allocs,
+            trace_format,
+            arm9_clock,
+            arm7_clock,
+        }))
+    }
+}
+
+fn parse_hash(value: &str) -> Result<u64, String> {
+    u64::from_str_radix(value.trim().trim_start_matches("0x"), 16).map_err(|_| format!("invalid hash: {value}"))
+}
+
+/// Boots `rom` without a window and runs it for `frames` frames
+pub fn boot_and_run(rom: &str, frames: u32) -> OwnedSystem {
+    let mut system = System::new();
+    run_rom(&mut system, rom, frames);
+    system
+}
+
+/// Resets `system` into `rom` and runs it for `frames` frames or until it stops
+fn run_rom(system: &mut System, rom: &str, frames: u32) {
+    system.set_game_path(rom);
+    let firmware = paths::firmware("firmware.bin");
+    if firmware.exists() {
+        system.set_firmware_path(firmware.to_str());
+    }
+    system.set_boot_mode(BootMode::Direct);
+    system.reset();
+
+    for _ in 0..frames {
+        if system.stop_reason().is_some() {
+            break;
+        }
+        system.run_frame();
+    }
+}
+
+pub fn run(options: HeadlessOptions) {
+    let mut system = System::new();
+    system.set_trace_format(options.trace_format);
+    system.set_arm9_clock(options.arm9_clock);
+    system.set_arm7_clock(options.arm7_clock);
+    run_rom(&mut system, &options.rom, options.frames);
+    info!("Headless: ran {} for {} frames", options.rom, options.frames);
+    for hit in unimplemented_hits() {
+        info!("Headless: unimplemented \"{}\" hit {} times at {}", hit.message, hit.count, hit.site);
+    }
+
+    if options.count_allocs {
+        // the first frames fill caches and grow buffers, so only count once the rom is warmed up
+        let start = alloc_counter::allocations();
+        for _ in 0..options.frames {
+            system.run_frame();
+        }
+        let count = alloc_counter::allocations() - start;
+        println!("allocations: {count} over {} frames ({:.1} per frame)", options.frames, count as f64 / options.frames.max(1) as f64);
+    }
+
+    if let Some(path) = options.screenshot {
+        // both screens stacked like the default window layout
+        let mut pixels = Vec::with_capacity(256 * 192 * 2 * 4);
+        pixels.extend_from_slice(system.video_unit.fetch_framebuffer(Screen::Top));
+        pixels.extend_from_slice(system.video_unit.fetch_framebuffer(Screen::Bottom));
+
+        match crate::png::write_rgba(&path, 256, 192 * 2, &pixels) {
+            Ok(_) => info!("Headless: saved screenshot to {}", path.display()),
+            Err(e) => error!("Headless: failed to save screenshot to {}: {e}", path.display()),
+        }
+    }
+
+    let hash = system.video_unit.frame_hash();
+    println!("{hash:016x}");
+    let mut expected = options.expect_hash;
+    if let Some(path) = &options.hash_file {
+        match std::fs::read_to_string(path) {
+            Ok(text) => match parse_hash(&text) {
+                Ok(stored) => expected = Some(stored),
+                Err(e) => {
+                    error!("Headless: {}: {e}", path.display());
+                    exit(1);
+                }
+            },
+            Err(_) => match std::fs::write(path, format!("{hash:016x}\n")) {
+                Ok(_) => info!("Headless: stored frame hash in {}", path.display()),
+                Err(e) => error!("Headless: failed to store frame hash in {}: {e}", path.display()),
+            },
+        }
+    }
+
+    if let Some(expected) = expected {
+        if hash != expected {
+            error!("Headless: frame hash {hash:016x} doesn't match the expected {expected:016x}");
+            exit(1);
+        }
+    }
+}
+
+/// `--scan-dir DIR [--frames N] [--report report]`, writes `report.md` and `report.json`
+pub struct ScanOptions {
+    pub dir: PathBuf,
+    pub frames: u32,
+    pub report: PathBuf,
+}
+
+impl ScanOptions {
+    /// Returns `None` when `--scan-dir` wasn't passed
+    pub fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        if !args.iter().any(|arg| arg == "--scan-dir") {
+            return Ok(None);
+        }
+
+        let mut dir = None;
+        let mut frames = 300;
+        let mut report = PathBuf::from("compatibility");
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scan-dir" => dir = Some(PathBuf::from(args.next().ok_or("--scan-dir needs a directory")?)),
+                "--frames" => {
+                    let value = args.next().ok_or("--frames needs a value")?;
+                    frames = value.parse().map_err(|_| format!("invalid frame count: {value}"))?;
+                }
+                "--report" => report = PathBuf::from(args.next().ok_or("--report needs a path")?),
+                other => return Err(format!("unknown option: {other}")),
+            }
+        }
+
+        Ok(Some(Self {
+            dir: dir.ok_or("no directory given")?,
+            frames,
+            report,
+        }))
+    }
+}
+
+enum Outcome {
+    Ok,
+    Stopped(StopReason),
+    Panicked { component: String, location: String, message: String },
+}
+
+struct ScanResult {
+    rom: String,
+    title: String,
+    outcome: Outcome,
+}
+
+thread_local! {
+    static LAST_PANIC: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+/// Boots every rom in a directory and reports which ones stop or panic, and in which component.
+/// Panics are caught with `catch_unwind`, so this needs a build that unwinds (the release profile aborts)
+pub fn scan(options: ScanOptions) {
+    let mut roms: Vec<PathBuf> = match std::fs::read_dir(&options.dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nds")))
+            .collect(),
+        Err(e) => return error!("Scan: failed to read {}: {e}", options.dir.display()),
+    };
+    roms.sort();
+
+    // record panics instead of printing them, the report has the details
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => info.payload().downcast_ref::<String>().cloned().unwrap_or_default(),
+        };
+        let location = info.location().map(|l| format!("{}:{}", l.file(), l.line())).unwrap_or_default();
+        LAST_PANIC.with(|last| *last.borrow_mut() = Some((location, message)));
+    }));
+
+    // one system is reused for every rom, reset puts it back into a known state even after a panic
+    let mut system = System::new();
+    let mut results = vec![];
+    for rom in roms {
+        let path = rom.display().to_string();
+        info!("Scan: {path}");
+
+        let run = std::panic::catch_unwind(AssertUnwindSafe(|| run_rom(&mut system, &path, options.frames)));
+        let outcome = match run {
+            Ok(_) => match system.stop_reason() {
+                Some(reason) => Outcome::Stopped(reason),
+                None => Outcome::Ok,
+            },
+            Err(_) => {
+                let (location, message) = LAST_PANIC.with(|last| last.borrow_mut().take()).unwrap_or_default();
+                Outcome::Panicked { component: component(&location), location, message }
+            }
+        };
+
+        let title = std::panic::catch_unwind(AssertUnwindSafe(|| system.game_title())).unwrap_or_default();
+        results.push(ScanResult { rom: path, title, outcome });
+    }
+
+    std::panic::set_hook(hook);
+    write_report(&options.report, &results);
+}
+
+/// `src/core/hardware/ipc.rs:56` -> `core/hardware/ipc`
+fn component(location: &str) -> String {
+    let file = location.split(':').next().unwrap_or_default();
+    let file = file.rsplit_once("src/").map_or(file, |(_, file)| file);
+    let file = file.strip_suffix(".rs").unwrap_or(file);
+    file.strip_suffix("/mod").unwrap_or(file).to_string()
+}
+
+fn write_report(path: &Path, results: &[ScanResult]) {
+    let passed = results.iter().filter(|result| matches!(result.outcome, Outcome::Ok)).count();
+    let mut markdown = format!("# Compatibility report\n\n{passed}/{} roms ran without stopping\n\n", results.len());
+    markdown.push_str("| rom | title | result | component | details |\n|---|---|---|---|---|\n");
+
+    let mut json = String::from("[\n");
+    for (i, result) in results.iter().enumerate() {
+        let (status, component, details) = match &result.outcome {
+            Outcome::Ok => ("ok", String::new(), String::new()),
+            Outcome::Stopped(reason) => ("stopped", String::new(), format!("{reason:?}")),
+            Outcome::Panicked { component, location, message } => ("panicked", component.clone(), format!("{message} ({location})")),
+        };
+
+        let rom = Path::new(&result.rom).file_name().map_or(result.rom.clone(), |name| name.to_string_lossy().to_string());
+        let cell = |s: &str| s.replace('|', "\\|").replace('\n', " ");
+        markdown.push_str(&format!("| {} | {} | {status} | {} | {} |\n", cell(&rom), cell(&result.title), cell(&component), cell(&details)));
+
+        json.push_str(&format!(
+            "  {{\"rom\": {}, \"title\": {}, \"status\": \"{status}\", \"component\": {}, \"details\": {}}}{}\n",
+            json_string(&result.rom),
+            json_string(&result.title),
+            json_string(&component),
+            json_string(&details),
+            if i + 1 < results.len() { "," } else { "" }
+        ));
+    }
+    json.push_str("]\n");
+
+    for (extension, contents) in [("md", markdown), ("json", json)] {
+        let path = path.with_extension(extension);
+        match std::fs::write(&path, contents) {
+            Ok(_) => info!("Scan: wrote {}", path.display()),
+            Err(e) => error!("Scan: failed to write {}: {e}", path.display()),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `--diff-states a.ss1 b.ss1`, prints where two savestates of the same build stop matching
+pub struct DiffOptions {
+    pub states: [PathBuf; 2],
+}
+
+impl DiffOptions {
+    /// Returns `None` when `--diff-states` wasn't passed
+    pub fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        let Some(index) = args.iter().position(|arg| arg == "--diff-states") else {
+            return Ok(None);
+        };
+
+        match &args[index + 1..] {
+            [a, b] => Ok(Some(Self { states: [PathBuf::from(a), PathBuf::from(b)] })),
+            _ => Err("--diff-states needs two savestates".to_string()),
+        }
+    }
+}
+
+/// Exits with an error when the states differ, so desync hunts can be scripted
+pub fn diff(options: DiffOptions) {
+    let [a, b] = options.states.map(|path| {
+        std::fs::read(&path).unwrap_or_else(|e| {
+            error!("Headless: can't read {}: {e}", path.display());
+            exit(2)
+        })
+    });
+
+    // a system that never ran saves fields where the states have them, apart from sections that
+    // grow with what was going on, which are compared without names
+    let layout = System::new().state_layout();
+    match diff_states(&a, &b, Some(&layout)) {
+        Ok(diff) => {
+            print!("{diff}");
+            if !diff.is_empty() {
+                exit(1);
+            }
+            println!();
+        }
+        Err(e) => {
+            error!("Headless: can't compare the states: {e}");
+            exit(2);
+        }
+    }
+}
+
+/// `process::exit` skips the cleanup at the end of main, so the lock files are removed first
+fn exit(code: i32) -> ! {
+    remove_lock_files();
+    std::process::exit(code)
+}
@@ -0,0 +1,203 @@
+use gilrs::Button;
+use log::{debug, error};
+use winit::event::VirtualKeyCode;
+
+use crate::core::hardware::input::InputEvent;
+use crate::util::paths;
+
+const CONFIG_NAME: &str = "input.cfg";
+
+/// Keys that can be bound, written to the config by their winit names
+const KEYS: [VirtualKeyCode; 85] = {
+    use VirtualKeyCode::*;
+    [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, Key0, Key1, Key2, Key3, Key4, Key5,
+        Key6, Key7, Key8, Key9, Up, Down, Left, Right, Space, Return, Tab, Back, Escape, LShift, RShift, LControl,
+        RControl, LAlt, RAlt, Minus, Equals, Comma, Period, Slash, Semicolon, Apostrophe, LBracket, RBracket, Backslash,
+        Grave, Insert, Delete, Home, End, PageUp, PageDown, F5, F6, F7, F8, F9, F10, F11, Numpad0, Numpad1, Numpad2, Numpad3,
+        Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    ]
+};
+
+const BUTTONS: [Button; 17] = {
+    use Button::*;
+    [
+        South, East, North, West, LeftTrigger, LeftTrigger2, RightTrigger, RightTrigger2, Select, Start, Mode, LeftThumb,
+        RightThumb, DPadUp, DPadDown, DPadLeft, DPadRight,
+    ]
+};
+
+/// What a key or gamepad button does
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    Button(InputEvent),
+    /// Double speed while held
+    FastForward,
+    /// Steps back through recent snapshots while held
+    Rewind,
+    SaveState,
+    LoadState,
+    NextStateSlot,
+}
+
+impl Action {
+    pub fn all() -> impl Iterator<Item = Action> {
+        let hotkeys = [Action::FastForward, Action::Rewind, Action::SaveState, Action::LoadState, Action::NextStateSlot];
+        InputEvent::ALL.into_iter().map(Action::Button).chain(hotkeys)
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Action::Button(event) => format!("{event:?}"),
+            action => format!("{action:?}"),
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::all().find(|action| action.name() == name)
+    }
+}
+
+/// Which keys and gamepad buttons trigger which actions, kept in `input.cfg` in the config
+/// directory. Each line is `key <name> = <action>` or `pad <name> = <action>`
+pub struct InputMap {
+    keys: Vec<(VirtualKeyCode, Action)>,
+    buttons: Vec<(Button, Action)>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        use InputEvent::*;
+        let keys = [
+            (VirtualKeyCode::A, A),
+            (VirtualKeyCode::B, B),
+            (VirtualKeyCode::X, X),
+            (VirtualKeyCode::Y, Y),
+            (VirtualKeyCode::W, L),
+            (VirtualKeyCode::E, R),
+            (VirtualKeyCode::Return, Start),
+            (VirtualKeyCode::Tab, Select),
+            (VirtualKeyCode::Up, Up),
+            (VirtualKeyCode::Down, Down),
+            (VirtualKeyCode::Left, Left),
+            (VirtualKeyCode::Right, Right),
+        ];
+        // the ds layout, nintendo style pads have the same labels in the same places
+        let buttons = [
+            (Button::East, A),
+            (Button::South, B),
+            (Button::North, X),
+            (Button::West, Y),
+            (Button::LeftTrigger, L),
+            (Button::RightTrigger, R),
+            (Button::Start, Start),
+            (Button::Select, Select),
+            (Button::DPadUp, Up),
+            (Button::DPadDown, Down),
+            (Button::DPadLeft, Left),
+            (Button::DPadRight, Right),
+        ];
+
+        let mut map = Self {
+            keys: keys.into_iter().map(|(key, event)| (key, Action::Button(event))).collect(),
+            buttons: buttons.into_iter().map(|(button, event)| (button, Action::Button(event))).collect(),
+        };
+        map.keys.extend([
+            (VirtualKeyCode::Space, Action::FastForward),
+            (VirtualKeyCode::Back, Action::Rewind),
+            (VirtualKeyCode::F5, Action::SaveState),
+            (VirtualKeyCode::F6, Action::NextStateSlot),
+            (VirtualKeyCode::F7, Action::LoadState),
+        ]);
+        map.buttons.extend([(Button::RightTrigger2, Action::FastForward), (Button::LeftTrigger2, Action::LoadState)]);
+        map
+    }
+}
+
+impl InputMap {
+    /// The saved bindings, the defaults if there aren't any
+    pub fn load() -> Self {
+        let path = paths::config().join(CONFIG_NAME);
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            return Self::default();
+        };
+
+        match Self::parse(&text) {
+            Ok(map) => {
+                debug!("Input: loaded bindings from {}", path.display());
+                map
+            }
+            Err(e) => {
+                error!("Input: ignoring {}: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let mut text = String::from("# key <winit key name> = <action> or pad <gilrs button name> = <action>\n");
+        for (key, action) in &self.keys {
+            text.push_str(&format!("key {key:?} = {}\n", action.name()));
+        }
+        for (button, action) in &self.buttons {
+            text.push_str(&format!("pad {button:?} = {}\n", action.name()));
+        }
+
+        let path = paths::config().join(CONFIG_NAME);
+        if let Err(e) = std::fs::write(&path, text) {
+            error!("Input: failed to save {}: {e}", path.display());
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut map = Self { keys: vec![], buttons: vec![] };
+        for (number, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (input, action) = line.split_once('=').ok_or(format!("line {number}: expected `input = action`"))?;
+            let action = Action::parse(action.trim()).ok_or(format!("line {number}: unknown action {}", action.trim()))?;
+            match input.trim().split_once(' ') {
+                Some(("key", name)) => {
+                    let key = KEYS.into_iter().find(|key| format!("{key:?}") == name.trim());
+                    map.keys.push((key.ok_or(format!("line {number}: unknown key {name}"))?, action));
+                }
+                Some(("pad", name)) => {
+                    let button = BUTTONS.into_iter().find(|button| format!("{button:?}") == name.trim());
+                    map.buttons.push((button.ok_or(format!("line {number}: unknown button {name}"))?, action));
+                }
+                _ => return Err(format!("line {number}: inputs start with `key` or `pad`")),
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn key(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.keys.iter().find(|&&(bound, _)| bound == key).map(|&(_, action)| action)
+    }
+
+    pub fn button(&self, button: Button) -> Option<Action> {
+        self.buttons.iter().find(|&&(bound, _)| bound == button).map(|&(_, action)| action)
+    }
+
+    /// Makes `key` the only key for `action`, taking it away from whatever it did before
+    pub fn bind_key(&mut self, key: VirtualKeyCode, action: Action) {
+        self.keys.retain(|&(bound, bound_action)| bound != key && bound_action != action);
+        self.keys.push((key, action));
+    }
+
+    /// Makes `button` the only gamepad button for `action`, taking it away from whatever it did before
+    pub fn bind_button(&mut self, button: Button, action: Action) {
+        self.buttons.retain(|&(bound, bound_action)| bound != button && bound_action != action);
+        self.buttons.push((button, action));
+    }
+
+    /// Names of the key and button bound to `action`, for the debugger
+    pub fn describe(&self, action: Action) -> (String, String) {
+        let key = self.keys.iter().find(|&&(_, bound)| bound == action).map(|(key, _)| format!("{key:?}"));
+        let button = self.buttons.iter().find(|&&(_, bound)| bound == action).map(|(button, _)| format!("{button:?}"));
+        (key.unwrap_or_else(|| "-".to_string()), button.unwrap_or_else(|| "-".to_string()))
+    }
+}
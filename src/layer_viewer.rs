@@ -0,0 +1,202 @@
+use crate::application::clicked;
+use crate::core::video::ppu::{rgb555_to_rgba, Ppu, COLOR_TRANSPARENT};
+use crate::core::System;
+
+/// Size of the square image the views are drawn into
+pub const IMAGE_SIZE: usize = 512;
+
+/// Bytes of bg or obj vram one page of the tile view covers, at 4bpp
+const TILE_PAGE: u32 = 32 * 32 * 32;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum View {
+    /// One of the captured layers, bg0-bg3 or the objects
+    Layer(usize),
+    Tiles,
+    /// 64 oam entries per page, each in a 64x64 cell
+    Objects,
+    Palettes,
+}
+
+/// Inspects what engine A or B draws from, like the vram viewers of other emulators. While a view
+/// is selected the debugger shows it in place of the screens
+pub struct LayerViewer {
+    engine_b: bool,
+    view: Option<View>,
+    tiles_obj: bool,
+    tiles_8bpp: bool,
+    tile_base: u32,
+    /// 16 color palette for 4bpp tiles and the extended palette shown
+    palette_number: u32,
+    /// Extended bg palette slot shown
+    slot: u32,
+    oam_page: u32,
+    image: Vec<u8>,
+}
+
+impl Default for LayerViewer {
+    fn default() -> Self {
+        Self {
+            engine_b: false,
+            view: None,
+            tiles_obj: false,
+            tiles_8bpp: false,
+            tile_base: 0,
+            palette_number: 0,
+            slot: 0,
+            oam_page: 0,
+            image: vec![0; IMAGE_SIZE * IMAGE_SIZE * 4],
+        }
+    }
+}
+
+impl LayerViewer {
+    pub const fn is_shown(&self) -> bool {
+        self.view.is_some()
+    }
+
+    fn ppu<'a>(&self, system: &'a mut System) -> &'a mut Ppu {
+        if self.engine_b {
+            &mut system.video_unit.ppu_b
+        } else {
+            &mut system.video_unit.ppu_a
+        }
+    }
+
+    pub fn render(&mut self, ui: &mut microui::Context, system: &mut System) {
+        ui.layout_row(&[475 / 4; 4], 0);
+        ui.label("Layers");
+        for (label, engine_b) in [("engine a", false), ("engine b", true)] {
+            let mut selected = self.engine_b == engine_b;
+            ui.checkbox(label, &mut selected);
+            if selected {
+                self.engine_b = engine_b;
+            }
+        }
+        if clicked(ui, "show screens") {
+            self.view = None;
+        }
+
+        ui.layout_row(&[475 / 8; 8], 0);
+        let views = [
+            ("bg0", View::Layer(0)),
+            ("bg1", View::Layer(1)),
+            ("bg2", View::Layer(2)),
+            ("bg3", View::Layer(3)),
+            ("obj", View::Layer(4)),
+            ("tiles", View::Tiles),
+            ("objects", View::Objects),
+            ("palettes", View::Palettes),
+        ];
+        for (label, view) in views {
+            let mut selected = self.view == Some(view);
+            ui.checkbox(label, &mut selected);
+            if selected {
+                self.view = Some(view);
+            }
+        }
+
+        ui.layout_row(&[475 / 7; 7], 0);
+        ui.checkbox("obj vram", &mut self.tiles_obj);
+        ui.checkbox("8bpp", &mut self.tiles_8bpp);
+        if clicked(ui, "tiles back") {
+            self.tile_base = self.tile_base.wrapping_sub(TILE_PAGE) & 0x7ffff;
+        }
+        if clicked(ui, "tiles next") {
+            self.tile_base = (self.tile_base + TILE_PAGE) & 0x7ffff;
+        }
+        if clicked(ui, "next palette") {
+            self.palette_number = (self.palette_number + 1) % 16;
+        }
+        if clicked(ui, "next slot") {
+            self.slot = (self.slot + 1) % 4;
+        }
+        if clicked(ui, "oam page") {
+            self.oam_page ^= 1;
+        }
+
+        ui.layout_row(&[-1], 0);
+        ui.label(&format!(
+            "tiles {:05x}, palette {}, ext slot {}, oam {}..{}",
+            self.tile_base,
+            self.palette_number,
+            self.slot,
+            self.oam_page * 64,
+            self.oam_page * 64 + 63
+        ));
+
+        // the capture costs a copy of every line, only pay for it while a layer is looked at
+        let capturing = matches!(self.view, Some(View::Layer(_)));
+        system.video_unit.ppu_a.set_layer_capture(capturing && !self.engine_b);
+        system.video_unit.ppu_b.set_layer_capture(capturing && self.engine_b);
+    }
+
+    /// Draws the selected view into a `IMAGE_SIZE` square of rgba pixels
+    pub fn update(&mut self, system: &mut System) -> &[u8] {
+        self.image.fill(0);
+        match self.view {
+            Some(View::Layer(layer)) => {
+                if let Some(pixels) = self.ppu(system).captured_layer(layer) {
+                    self.blit(pixels, 256, 0, 0, 2);
+                }
+            }
+            Some(View::Tiles) => {
+                let mut pixels = Box::new([0; 256 * 256]);
+                let (obj, base, palette_8bpp, palette_number) = (self.tiles_obj, self.tile_base, self.tiles_8bpp, self.palette_number);
+                self.ppu(system).decode_tiles(obj, base, palette_8bpp, palette_number, &mut pixels);
+                self.blit(&*pixels, 256, 0, 0, 2);
+            }
+            Some(View::Objects) => {
+                let mut pixels = Box::new([0; 64 * 64]);
+                for i in 0..64 {
+                    self.ppu(system).decode_object(self.oam_page * 64 + i, &mut pixels);
+                    self.blit(&*pixels, 64, (i as usize % 8) * 64, (i as usize / 8) * 64, 1);
+                }
+            }
+            Some(View::Palettes) => {
+                // standard bg and obj palettes on top, the selected extended palettes below
+                let (slot, palette_number) = (self.slot, self.palette_number);
+                let ppu = self.ppu(system);
+                let mut colors = [0; 4 * 256];
+                for index in 0..256 {
+                    colors[index] = ppu.palette_entry(index as u32);
+                    colors[256 + index] = ppu.palette_entry(256 + index as u32);
+                    colors[512 + index] = ppu.extended_palette_entry(false, slot, palette_number, index as u32);
+                    colors[768 + index] = ppu.extended_palette_entry(true, slot, palette_number, index as u32);
+                }
+
+                for (i, palette) in colors.chunks(256).enumerate() {
+                    self.blit(palette, 16, (i % 2) * 256, (i / 2) * 256, 16);
+                }
+            }
+            None => {}
+        }
+
+        &self.image
+    }
+
+    /// Copies `pixels`, `width` wide, to `x`, `y` of the image with every pixel drawn `scale` times as big.
+    /// Transparent pixels get a checkerboard so they stand out from black
+    fn blit(&mut self, pixels: &[u16], width: usize, x: usize, y: usize, scale: usize) {
+        for (i, &color) in pixels.iter().enumerate() {
+            for dy in 0..scale {
+                for dx in 0..scale {
+                    let image_x = x + (i % width) * scale + dx;
+                    let image_y = y + (i / width) * scale + dy;
+                    if image_x >= IMAGE_SIZE || image_y >= IMAGE_SIZE {
+                        continue;
+                    }
+
+                    let rgba = if color == COLOR_TRANSPARENT {
+                        let shade = if (image_x / 8 + image_y / 8) % 2 == 0 { 0x40 } else { 0x60 };
+                        [shade, shade, shade, 0xff]
+                    } else {
+                        rgb555_to_rgba(color)
+                    };
+                    let j = (image_y * IMAGE_SIZE + image_x) * 4;
+                    self.image[j..j + 4].copy_from_slice(&rgba);
+                }
+            }
+        }
+    }
+}
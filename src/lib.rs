@@ -0,0 +1,17 @@
+//! The emulator core without any of the frontend, so it can be driven from the binary, the tools in
+//! `src/bin` and integration tests alike. `headless::boot_and_run` boots a rom and runs it without a
+//! window or gl context, `system.video_unit.frame_hash()` then identifies what ended up on screen.
+
+#![allow(
+    clippy::upper_case_acronyms,
+    clippy::identity_op,
+    unused,
+    clippy::collapsible_else_if,
+    clippy::collapsible_if
+)]
+
+pub mod arm;
+pub mod core;
+pub mod headless;
+pub mod png;
+pub mod util;
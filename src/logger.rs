@@ -0,0 +1,195 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use color_backtrace::termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::util::{paths, FileLock};
+
+/// Logger settings, read from `ES_LOG_PATH`, `ES_LOG_LEVEL`, `ES_LOG_MAX_SIZE` and `ES_LOG_MAX_FILES`
+pub struct LogConfig {
+    /// Where the log file goes, `None` only logs to the terminal. Defaults to `out.log` in the logs directory
+    pub path: Option<PathBuf>,
+    pub level: LevelFilter,
+    /// Size in bytes after which the log file is rotated
+    pub max_size: u64,
+    /// How many rotated files (`out.log.1`, `out.log.2`, ...) are kept around
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            path: Some(paths::logs().join("out.log")),
+            level: LevelFilter::Info,
+            max_size: 64 * 1024 * 1024,
+            max_files: 2,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Ok(path) = std::env::var("ES_LOG_PATH") {
+            config.path = (!path.is_empty()).then(|| PathBuf::from(path));
+        }
+        if let Some(level) = std::env::var("ES_LOG_LEVEL").ok().and_then(|level| level.parse().ok()) {
+            config.level = level;
+        }
+        if let Some(size) = std::env::var("ES_LOG_MAX_SIZE").ok().and_then(|size| size.parse().ok()) {
+            config.max_size = size;
+        }
+        if let Some(files) = std::env::var("ES_LOG_MAX_FILES").ok().and_then(|files| files.parse().ok()) {
+            config.max_files = files;
+        }
+
+        config
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    _lock: Option<FileLock>,
+    writer: BufWriter<File>,
+    written: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        let (path, lock) = Self::lock_path(path);
+        Ok(Self {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            _lock: lock,
+            written: 0,
+            max_size,
+            max_files,
+        })
+    }
+
+    /// Picks the first of `out.log`, `out.2.log`, ... that isn't being written by another instance
+    fn lock_path(path: PathBuf) -> (PathBuf, Option<FileLock>) {
+        for n in 1..16 {
+            let candidate = match n {
+                1 => path.clone(),
+                _ => path.with_extension(format!("{n}.{}", path.extension().and_then(|e| e.to_str()).unwrap_or("log"))),
+            };
+            if let Some(lock) = FileLock::acquire(&candidate) {
+                if n != 1 {
+                    eprintln!("{} is used by another instance, logging to {}", path.display(), candidate.display());
+                }
+                return (candidate, Some(lock));
+            }
+        }
+
+        (path, None)
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.written + line.len() as u64 > self.max_size {
+            self.rotate();
+        }
+
+        if self.writer.write_all(line.as_bytes()).is_ok() {
+            self.written += line.len() as u64;
+        }
+    }
+
+    /// Shifts `out.log.N` to `out.log.N+1`, dropping the oldest, and starts a fresh file
+    fn rotate(&mut self) {
+        let _ = self.writer.flush();
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+
+        if self.max_files > 0 {
+            for n in (1..self.max_files).rev() {
+                let _ = std::fs::rename(rotated(n), rotated(n + 1));
+            }
+            let _ = std::fs::rename(&self.path, rotated(1));
+        }
+
+        if let Ok(file) = File::create(&self.path) {
+            self.writer = BufWriter::new(file);
+        }
+        self.written = 0;
+    }
+}
+
+pub struct Logger {
+    level: LevelFilter,
+    terminal: Mutex<StandardStream>,
+    file: Option<Mutex<LogFile>>,
+}
+
+impl Logger {
+    pub fn init(config: LogConfig) {
+        let file = config.path.and_then(|path| match LogFile::open(path.clone(), config.max_size, config.max_files) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                eprintln!("failed to open log file {}: {e}", path.display());
+                None
+            }
+        });
+
+        let logger = Self {
+            level: config.level,
+            terminal: Mutex::new(StandardStream::stderr(ColorChoice::Auto)),
+            file,
+        };
+
+        log::set_max_level(config.level);
+        log::set_boxed_logger(Box::new(logger)).unwrap();
+
+        // make sure the tail of the log makes it to disk before the process goes down
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            log::logger().flush();
+            hook(info);
+        }));
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if let Ok(mut terminal) = self.terminal.lock() {
+            let color = match record.level() {
+                Level::Error => Color::Red,
+                Level::Warn => Color::Yellow,
+                Level::Info => Color::Green,
+                Level::Debug => Color::Cyan,
+                Level::Trace => Color::White,
+            };
+            let _ = terminal.set_color(ColorSpec::new().set_fg(Some(color)));
+            let _ = write!(terminal, "[{:5}]", record.level());
+            let _ = terminal.reset();
+            let _ = writeln!(terminal, " {}", record.args());
+        }
+
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                file.write(&format!("[{:5}] {}\n", record.level(), record.args()));
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.writer.flush();
+            }
+        }
+    }
+}
@@ -6,30 +6,120 @@
     clippy::collapsible_if
 )]
 
-use std::fs::File;
-
-use color_backtrace::termcolor::ColorChoice;
-use log::LevelFilter;
-use tinylog::*;
 use winit::event_loop::EventLoop;
 
+// the emulator itself lives in the library, the frontend modules keep using it through `crate::`
+use emulation_station::{arm, core, headless, png, util};
+
 use crate::application::Application;
+use crate::arm::trace::TraceFormat;
+use crate::core::config::ClockScale;
+use crate::headless::{DiffOptions, HeadlessOptions, ScanOptions};
+use crate::logger::{LogConfig, Logger};
+#[cfg(feature = "count_allocs")]
+use crate::util::alloc_counter::CountingAllocator;
 
 mod application;
-mod arm;
-mod core;
+mod audio;
 mod framehelper;
-mod util;
+mod gamepad;
+mod geometry;
+mod input_map;
+mod layer_viewer;
+mod logger;
+mod memory_viewer;
+mod recorder;
 mod renderer;
 
+/// Only with the count_allocs feature, the counting costs an atomic add on every allocation
+#[cfg(feature = "count_allocs")]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
 fn main() {
     color_backtrace::install();
 
-    let config = ConfigBuilder::default().build();
-    TinyLogger::init(LevelFilter::Trace, config, Some(ColorChoice::Auto), Some("out.log")).unwrap();
+    // has to be picked before the logger opens its file
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let portable = args.iter().any(|arg| arg == "--portable");
+    args.retain(|arg| arg != "--portable");
+    util::paths::init(portable);
+
+    Logger::init(LogConfig::from_env());
+
+    start(&args);
+    util::remove_lock_files();
+}
+
+/// Runs the mode the arguments pick, the window unless one of the headless options is given
+fn start(args: &[String]) {
+    if let Some(options) = parse_or_exit(DiffOptions::parse(args)) {
+        return headless::diff(options);
+    }
+    if let Some(options) = parse_or_exit(ScanOptions::parse(args)) {
+        return headless::scan(options);
+    }
+    if let Some(options) = parse_or_exit(HeadlessOptions::parse(args)) {
+        return headless::run(options);
+    }
+
+    let gdb_port = parse_or_exit(parse_gdb_port(args));
+    let trace_format = parse_or_exit(parse_trace_format(args));
+    let arm9_clock = parse_or_exit(parse_clock(args, "--arm9-clock")).unwrap_or_default();
+    let arm7_clock = parse_or_exit(parse_clock(args, "--arm7-clock")).unwrap_or_default();
+    let local_wifi = args.iter().any(|arg| arg == "--local-wifi");
+    let hle_bios = args.iter().any(|arg| arg == "--hle-bios");
+    let record = args.iter().any(|arg| arg == "--record");
 
     let mut event_loop = EventLoop::new();
     let mut app = Application::new(&event_loop);
+    app.set_local_wifi(local_wifi);
+    app.set_hle_bios(hle_bios);
+    if let Some(format) = trace_format {
+        app.set_trace_format(format);
+    }
+    app.set_clocks(arm9_clock, arm7_clock);
     app.boot_game("roms/Pokemon Mystery Dungeon.nds");
+    if record {
+        app.start_recording();
+    }
+    if let Some(port) = gdb_port {
+        app.start_gdb(port);
+    }
     app.run(&mut event_loop);
 }
+
+/// `--gdb <port>` starts the remote debugging servers
+fn parse_gdb_port(args: &[String]) -> Result<Option<u16>, String> {
+    let Some(index) = args.iter().position(|arg| arg == "--gdb") else {
+        return Ok(None);
+    };
+    let value = args.get(index + 1).ok_or("--gdb needs a port")?;
+    value.parse().map(Some).map_err(|_| format!("invalid port: {value}"))
+}
+
+/// `--trace-format <native|reference>` picks the layout of the log_state instruction traces
+fn parse_trace_format(args: &[String]) -> Result<Option<TraceFormat>, String> {
+    let Some(index) = args.iter().position(|arg| arg == "--trace-format") else {
+        return Ok(None);
+    };
+    let value = args.get(index + 1).ok_or("--trace-format needs a value")?;
+    TraceFormat::parse(value).map(Some)
+}
+
+/// `--arm9-clock <percent>` and `--arm7-clock <percent>` under or overclock a cpu
+fn parse_clock(args: &[String], option: &str) -> Result<Option<ClockScale>, String> {
+    let Some(index) = args.iter().position(|arg| arg == option) else {
+        return Ok(None);
+    };
+    let value = args.get(index + 1).ok_or(format!("{option} needs a percentage"))?;
+    ClockScale::parse(value).map(Some)
+}
+
+fn parse_or_exit<T>(options: Result<Option<T>, String>) -> Option<T> {
+    options.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        util::remove_lock_files();
+        std::process::exit(1)
+    })
+}
@@ -0,0 +1,194 @@
+use winit::event::VirtualKeyCode;
+
+use crate::application::clicked;
+use crate::arm::cpu::Arch;
+use crate::core::System;
+
+const ROW_BYTES: u32 = 16;
+const ROWS: u32 = 16;
+const PAGE_BYTES: u32 = ROW_BYTES * ROWS;
+
+/// Places worth jumping to, palette ram and oam are only on the arm9 bus
+const PRESETS: [(&str, Arch, u32); 7] = [
+    ("main", Arch::ARMv5, 0x02000000),
+    ("wram9", Arch::ARMv5, 0x03000000),
+    ("wram7", Arch::ARMv4, 0x03800000),
+    ("palette", Arch::ARMv5, 0x05000000),
+    ("vram", Arch::ARMv5, 0x06000000),
+    ("vram7", Arch::ARMv4, 0x06000000),
+    ("oam", Arch::ARMv5, 0x07000000),
+];
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Typing {
+    /// The address to jump to
+    Goto,
+    /// Hex digits that replace the bytes from the cursor on
+    Edit,
+}
+
+/// Hex view of either bus for the debugger. Reads go through `Memory::peek_byte`, so looking at
+/// registers doesn't acknowledge irqs or pop fifos. Typing works like the nickname, the window
+/// sends the characters here while `is_typing` is set
+pub struct MemoryViewer {
+    arch: Arch,
+    /// First address shown, a multiple of the row size
+    address: u32,
+    /// Byte the next edit goes to, highlighted in the view
+    cursor: u32,
+    typing: Option<Typing>,
+    input: String,
+}
+
+impl Default for MemoryViewer {
+    fn default() -> Self {
+        Self {
+            arch: Arch::ARMv5,
+            address: 0x02000000,
+            cursor: 0x02000000,
+            typing: None,
+            input: String::new(),
+        }
+    }
+}
+
+impl MemoryViewer {
+    pub const fn is_typing(&self) -> bool {
+        self.typing.is_some()
+    }
+
+    pub fn input_char(&mut self, system: &mut System, c: char) {
+        match c {
+            '\u{8}' => {
+                self.input.pop();
+            }
+            '\r' => self.finish_typing(),
+            '\u{1b}' => {
+                self.typing = None;
+                self.input.clear();
+            }
+            c if c.is_ascii_hexdigit() => {
+                self.input.push(c.to_ascii_lowercase());
+                if self.typing == Some(Typing::Edit) && self.input.len() == 2 {
+                    let val = u8::from_str_radix(&self.input, 16).unwrap_or(0);
+                    system.get_memory(self.arch).poke_byte(self.cursor, val);
+                    self.input.clear();
+                    self.move_cursor(1);
+                } else if self.input.len() > 8 {
+                    self.input.remove(0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The arrow keys move the cursor while editing
+    pub fn input_key(&mut self, key: VirtualKeyCode) {
+        if self.typing != Some(Typing::Edit) {
+            return;
+        }
+
+        match key {
+            VirtualKeyCode::Left => self.move_cursor(-1),
+            VirtualKeyCode::Right => self.move_cursor(1),
+            VirtualKeyCode::Up => self.move_cursor(-(ROW_BYTES as i32)),
+            VirtualKeyCode::Down => self.move_cursor(ROW_BYTES as i32),
+            _ => {}
+        }
+    }
+
+    fn finish_typing(&mut self) {
+        if self.typing == Some(Typing::Goto) {
+            if let Ok(addr) = u32::from_str_radix(&self.input, 16) {
+                self.goto(addr);
+            }
+        }
+        self.typing = None;
+        self.input.clear();
+    }
+
+    fn goto(&mut self, addr: u32) {
+        self.cursor = addr;
+        self.address = addr & !(ROW_BYTES - 1);
+    }
+
+    /// Scrolls along when the cursor leaves the page
+    fn move_cursor(&mut self, delta: i32) {
+        self.cursor = self.cursor.wrapping_add_signed(delta);
+        if self.cursor.wrapping_sub(self.address) >= PAGE_BYTES {
+            self.address = self.address.wrapping_add_signed(delta.signum() * ROW_BYTES as i32);
+            if self.cursor.wrapping_sub(self.address) >= PAGE_BYTES {
+                self.address = self.cursor & !(ROW_BYTES - 1);
+            }
+        }
+    }
+
+    pub fn render(&mut self, ui: &mut microui::Context, system: &mut System) {
+        ui.layout_row(&[475 / 5; 5], 0);
+        ui.label("Memory");
+        for (label, arch) in [("arm7 bus", Arch::ARMv4), ("arm9 bus", Arch::ARMv5)] {
+            let mut selected = self.arch == arch;
+            ui.checkbox(label, &mut selected);
+            if selected {
+                self.arch = arch;
+            }
+        }
+        self.render_typing(ui, Typing::Goto, "goto");
+        self.render_typing(ui, Typing::Edit, "edit");
+
+        ui.layout_row(&[475 / 7; 7], 0);
+        for (name, arch, addr) in PRESETS {
+            if clicked(ui, name) {
+                self.arch = arch;
+                self.goto(addr);
+            }
+        }
+
+        ui.layout_row(&[475 / 5; 5], 0);
+        let status = match self.typing {
+            Some(Typing::Goto) => format!("goto: {}_", self.input),
+            Some(Typing::Edit) => format!("{:08x}: {}_", self.cursor, self.input),
+            None => format!("cursor {:08x}", self.cursor),
+        };
+        ui.label(&status);
+        for (label, delta) in [("page up", -(PAGE_BYTES as i32)), ("row up", -(ROW_BYTES as i32)), ("row down", ROW_BYTES as i32), ("page down", PAGE_BYTES as i32)] {
+            if clicked(ui, label) {
+                self.address = self.address.wrapping_add_signed(delta);
+            }
+        }
+
+        let memory = system.get_memory(self.arch);
+        ui.layout_row(&[-1], 300);
+        ui.panel("memory").show(ui, |ui| {
+            ui.layout_row(&[70, 330, -1], 0);
+            for row in 0..ROWS {
+                let start = self.address.wrapping_add(row * ROW_BYTES);
+                let bytes: [u8; ROW_BYTES as usize] = std::array::from_fn(|i| memory.peek_byte(start.wrapping_add(i as u32)));
+
+                let mut hex = String::new();
+                for (addr, byte) in (0..ROW_BYTES).map(|i| start.wrapping_add(i)).zip(&bytes) {
+                    if addr == self.cursor {
+                        hex.push_str(&format!("[{byte:02x}]"));
+                    } else {
+                        hex.push_str(&format!(" {byte:02x} "));
+                    }
+                }
+                let ascii = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect::<String>();
+
+                ui.label(&format!("{start:08x}"));
+                ui.label(&hex);
+                ui.label(&ascii);
+            }
+        });
+    }
+
+    /// Toggles between typing into `typing` and not typing
+    fn render_typing(&mut self, ui: &mut microui::Context, typing: Typing, label: &str) {
+        let mut selected = self.typing == Some(typing);
+        ui.checkbox(label, &mut selected);
+        if selected != (self.typing == Some(typing)) {
+            self.typing = selected.then_some(typing);
+            self.input.clear();
+        }
+    }
+}
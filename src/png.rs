@@ -0,0 +1,67 @@
+use std::io::Write;
+use std::path::Path;
+
+use crate::util::crc32;
+
+/// Writes an 8-bit rgba image. The image data goes into stored deflate blocks, screenshots are
+/// small enough that skipping compression doesn't matter
+pub fn write_rgba(path: &Path, width: u32, height: u32, pixels: &[u8]) -> std::io::Result<()> {
+    assert_eq!(pixels.len(), (width * height * 4) as usize);
+
+    // every scanline starts with its filter type, 0 is none
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for line in pixels.chunks_exact(width as usize * 4) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut file = Vec::new();
+    file.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+    write_chunk(&mut file, b"IHDR", &ihdr);
+    write_chunk(&mut file, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut file, b"IEND", &[]);
+
+    std::fs::File::create(path)?.write_all(&file)
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
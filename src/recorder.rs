@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+
+use crate::core::video::Screen;
+use crate::core::System;
+use crate::util::paths;
+
+/// Dumps every frame as a png of both screens, stacked like the default layout, and the audio as a
+/// 16 bit stereo wav into a directory of its own under `recordings/`. ffmpeg can mux them into a video:
+/// `ffmpeg -framerate 59.8261 -i frame%06d.png -i audio.wav video.mp4`
+pub struct Recorder {
+    dir: PathBuf,
+    frames: u32,
+    wav: BufWriter<File>,
+    sample_rate: u32,
+    /// Stereo samples written so far
+    samples: u32,
+    pixels: Vec<u8>,
+}
+
+impl Recorder {
+    /// `None` when the directory or the wav can't be created
+    pub fn start(system: &System) -> Option<Self> {
+        let title: String = system.game_title().chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        let dir = paths::recordings().join(format!("{title}-{time}"));
+
+        match Self::create(dir.clone(), system.audio_sample_rate()) {
+            Ok(recorder) => {
+                info!("Recorder: recording to {}", dir.display());
+                Some(recorder)
+            }
+            Err(e) => {
+                error!("Recorder: failed to start recording to {}: {e}", dir.display());
+                None
+            }
+        }
+    }
+
+    fn create(dir: PathBuf, sample_rate: u32) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut wav = BufWriter::new(File::create(dir.join("audio.wav"))?);
+        write_wav_header(&mut wav, sample_rate, 0)?;
+        Ok(Self {
+            dir,
+            frames: 0,
+            wav,
+            sample_rate,
+            samples: 0,
+            pixels: Vec::with_capacity(256 * 192 * 2 * 4),
+        })
+    }
+
+    /// Call after every frame with the interleaved left/right samples it produced
+    pub fn record_frame(&mut self, system: &System, samples: &[i16]) {
+        if let Err(e) = self.write_frame(system, samples) {
+            error!("Recorder: failed to write frame {}: {e}", self.frames);
+        }
+    }
+
+    fn write_frame(&mut self, system: &System, samples: &[i16]) -> std::io::Result<()> {
+        self.pixels.clear();
+        self.pixels.extend_from_slice(system.video_unit.fetch_framebuffer(Screen::Top));
+        self.pixels.extend_from_slice(system.video_unit.fetch_framebuffer(Screen::Bottom));
+        let path = self.dir.join(format!("frame{:06}.png", self.frames));
+        crate::png::write_rgba(&path, 256, 192 * 2, &self.pixels)?;
+        self.frames += 1;
+
+        for sample in samples {
+            self.wav.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32 / 2;
+        Ok(())
+    }
+
+    /// The wav header has the length of the data, which is only known at the end
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.wav.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.wav, self.sample_rate, self.samples * 4)?;
+        self.wav.flush()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        match self.finish() {
+            Ok(_) => info!("Recorder: wrote {} frames to {}", self.frames, self.dir.display()),
+            Err(e) => error!("Recorder: failed to finish {}: {e}", self.dir.display()),
+        }
+    }
+}
+
+fn write_wav_header(out: &mut impl Write, sample_rate: u32, data_len: u32) -> std::io::Result<()> {
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // pcm, 2 channels, 4 bytes per sample, 16 bits each
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 4).to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())
+}
@@ -36,6 +36,9 @@ const FRAGMENT_SHADER: &str = r#"#version 330
         gl_FragColor = vec4(color.rgb, col.a * color.a);
     }"#;
 
+/// Size of the vertex buffer, quads that don't fit in a frame are dropped
+const MAX_VERTICES: usize = 512000;
+
 #[repr(C)]
 #[derive(Default, Copy, Clone)]
 struct DebugVertex {
@@ -69,7 +72,7 @@ impl Renderer {
             width: ATLAS_WIDTH,
             height: ATLAS_HEIGHT,
         });
-        let vbo = ctx.new_buffer(BufferType::VertexBuffer, BufferUsage::Stream, BufferSource::empty::<DebugVertex>(512000));
+        let vbo = ctx.new_buffer(BufferType::VertexBuffer, BufferUsage::Stream, BufferSource::empty::<DebugVertex>(MAX_VERTICES));
 
         let bindings = Bindings {
             vertex_buffers: vec![vbo],
@@ -110,7 +113,7 @@ impl Renderer {
         );
 
         Self {
-            vertices: vec![],
+            vertices: Vec::with_capacity(MAX_VERTICES),
             bindings,
             pipeline,
             last_hash: 0,
@@ -122,6 +125,10 @@ impl Renderer {
     pub const fn get_font_height(_font: FontId) -> usize { 18 }
 
     fn push_rect(&mut self, dst: Rect, src: Rect, color: Color) {
+        if self.vertices.len() + 6 > MAX_VERTICES {
+            return;
+        }
+
         let x = src.x as f32 / ATLAS_WIDTH as f32;
         let y = src.y as f32 / ATLAS_HEIGHT as f32;
         let w = src.w as f32 / ATLAS_WIDTH as f32;
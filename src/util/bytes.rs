@@ -0,0 +1,48 @@
+/// Integers that can be read from and written to byte slices in little endian order
+pub trait LeBytes: Copy + Default {
+    const SIZE: usize;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+    fn to_le_slice(self, out: &mut [u8]);
+}
+
+macro_rules! impl_le_bytes {
+    ($($t:ty),+) => {
+        $(
+            impl LeBytes for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    <$t>::from_le_bytes(bytes.try_into().unwrap())
+                }
+
+                fn to_le_slice(self, out: &mut [u8]) {
+                    out.copy_from_slice(&self.to_le_bytes())
+                }
+            }
+        )+
+    };
+}
+
+impl_le_bytes!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Reads a little endian value at `offset`, or `None` if it doesn't fit in `data`
+pub fn read_le<T: LeBytes>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(T::SIZE)?;
+    data.get(offset..end).map(T::from_le_slice)
+}
+
+/// Writes a little endian value at `offset`, returns false and leaves `data` alone if it doesn't fit
+pub fn write_le<T: LeBytes>(data: &mut [u8], offset: usize, val: T) -> bool {
+    let Some(end) = offset.checked_add(T::SIZE) else {
+        return false;
+    };
+
+    match data.get_mut(offset..end) {
+        Some(out) => {
+            val.to_le_slice(out);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Lock files of the locks this process holds
+static HELD: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Exclusive lock on a path, released when dropped or when the process dies. The lock files live in
+/// the temp directory, keyed by the locked path, so nothing piles up next to roms and saves
+pub struct FileLock {
+    _file: File,
+    lock: PathBuf,
+}
+
+impl FileLock {
+    /// Returns `None` if another instance already holds the lock
+    pub fn acquire(path: &Path) -> Option<Self> {
+        // the file itself might not exist yet, so only its directory is canonicalized
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let full = std::fs::canonicalize(dir).map_or(path.to_path_buf(), |dir| dir.join(path.file_name().unwrap_or_default()));
+
+        let mut hasher = DefaultHasher::new();
+        full.hash(&mut hasher);
+        let lock = std::env::temp_dir().join(format!("emulation-station-{:016x}.lock", hasher.finish()));
+
+        // the previous holder deletes the file when it lets go, a lock taken on the file it just
+        // deleted wouldn't keep anyone else out, so try again on a fresh one
+        for _ in 0..4 {
+            let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock).ok()?;
+            file.try_lock().ok()?;
+            if lock.exists() {
+                HELD.lock().unwrap().push(lock.clone());
+                return Some(Self { _file: file, lock });
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        HELD.lock().unwrap().retain(|held| *held != self.lock);
+        let _ = std::fs::remove_file(&self.lock);
+    }
+}
+
+/// Deletes the lock files of the locks still held. The system and the logger are never dropped,
+/// so this has to be called on the way out, the locks themselves go with the process
+pub fn remove_lock_files() {
+    for lock in HELD.lock().unwrap().drain(..) {
+        let _ = std::fs::remove_file(lock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_file_is_removed_once_released() {
+        let path = std::env::temp_dir().join(format!("file-lock-test-{}.sav", std::process::id()));
+        let lock = FileLock::acquire(&path).unwrap();
+        let lock_file = lock.lock.clone();
+        assert!(lock_file.exists());
+        assert!(FileLock::acquire(&path).is_none());
+
+        drop(lock);
+        assert!(!lock_file.exists());
+
+        let _lock = FileLock::acquire(&path).unwrap();
+        remove_lock_files();
+        assert!(!lock_file.exists());
+    }
+}
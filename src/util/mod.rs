@@ -1,16 +1,30 @@
+pub mod alloc_counter;
 mod bits;
+mod bytes;
+mod crc;
+mod file_lock;
 mod page_table;
+pub mod paths;
 mod ringbuf;
 mod shared;
+mod state;
+mod state_diff;
+mod unimplemented;
 
 pub use bits::*;
+pub use bytes::*;
+pub use crc::*;
+pub use file_lock::*;
 pub use page_table::*;
 pub use ringbuf::*;
 pub use shared::*;
+pub use state::*;
+pub use state_diff::*;
+pub use unimplemented::*;
 
 /// Create a C-style bitfield
 ///
-/// ```
+/// ```ignore
 /// bitfield! {
 ///     #[derive(Default, Copy, Clone)]
 ///     pub struct StatusRegister(u32) {
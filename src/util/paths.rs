@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const APP_NAME: &str = "emulation-station";
+
+static DIRS: OnceLock<DataDirs> = OnceLock::new();
+
+// the subdirectories, each created the first time it's asked for
+static SAVES: OnceLock<PathBuf> = OnceLock::new();
+static STATES: OnceLock<PathBuf> = OnceLock::new();
+static SCREENSHOTS: OnceLock<PathBuf> = OnceLock::new();
+static RECORDINGS: OnceLock<PathBuf> = OnceLock::new();
+static LOGS: OnceLock<PathBuf> = OnceLock::new();
+static CONFIG: OnceLock<PathBuf> = OnceLock::new();
+static FIRMWARE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Where everything the emulator writes ends up. Data is the XDG data dir, `%APPDATA%` or
+/// `~/Library/Application Support`, in portable mode everything goes next to the executable
+pub struct DataDirs {
+    pub data: PathBuf,
+    pub config: PathBuf,
+    pub portable: bool,
+}
+
+impl DataDirs {
+    fn new(portable: bool) -> Self {
+        if !portable {
+            if let Some((data, config)) = platform_dirs() {
+                return Self { data, config, portable };
+            }
+        }
+
+        let root = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf))
+            .unwrap_or_else(|| PathBuf::from("."));
+        Self {
+            data: root.clone(),
+            config: root,
+            portable: true,
+        }
+    }
+}
+
+/// Picks the data directories, has to happen before anything asks for a path
+pub fn init(portable: bool) {
+    if DIRS.set(DataDirs::new(portable)).is_err() {
+        log::warn!("Paths: data directories were already initialized");
+    }
+}
+
+pub fn dirs() -> &'static DataDirs {
+    DIRS.get_or_init(|| DataDirs::new(false))
+}
+
+pub fn saves() -> PathBuf {
+    subdir(&SAVES, &dirs().data, "saves")
+}
+
+pub fn states() -> PathBuf {
+    subdir(&STATES, &dirs().data, "states")
+}
+
+pub fn screenshots() -> PathBuf {
+    subdir(&SCREENSHOTS, &dirs().data, "screenshots")
+}
+
+pub fn recordings() -> PathBuf {
+    subdir(&RECORDINGS, &dirs().data, "recordings")
+}
+
+pub fn logs() -> PathBuf {
+    subdir(&LOGS, &dirs().data, "logs")
+}
+
+pub fn config() -> PathBuf {
+    subdir(&CONFIG, &dirs().config, "")
+}
+
+/// Firmware and bios dumps live in `firmware/` under the data dir. A `firmware/` in the working
+/// directory is still picked up so existing setups keep working
+pub fn firmware(name: &str) -> PathBuf {
+    let path = subdir(&FIRMWARE, &dirs().data, "firmware").join(name);
+    let legacy = Path::new("firmware").join(name);
+    if !path.exists() && legacy.exists() {
+        return legacy;
+    }
+    path
+}
+
+/// Joins `name` onto `base` and creates the directory the first time, later calls return what's in
+/// `created`. Failing to create it isn't fatal, whoever uses the path reports the error when the actual
+/// read or write fails
+fn subdir(created: &OnceLock<PathBuf>, base: &Path, name: &str) -> PathBuf {
+    created
+        .get_or_init(|| {
+            let dir = base.join(name);
+            let _ = std::fs::create_dir_all(&dir);
+            dir
+        })
+        .clone()
+}
+
+fn home() -> Option<PathBuf> {
+    std::env::var_os("HOME").filter(|home| !home.is_empty()).map(PathBuf::from)
+}
+
+#[cfg(windows)]
+fn platform_dirs() -> Option<(PathBuf, PathBuf)> {
+    let appdata = PathBuf::from(std::env::var_os("APPDATA")?).join(APP_NAME);
+    Some((appdata.clone(), appdata))
+}
+
+#[cfg(target_os = "macos")]
+fn platform_dirs() -> Option<(PathBuf, PathBuf)> {
+    let support = home()?.join("Library/Application Support").join(APP_NAME);
+    Some((support.clone(), support))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn platform_dirs() -> Option<(PathBuf, PathBuf)> {
+    let xdg = |var: &str, fallback: &str| match std::env::var_os(var) {
+        // the spec says relative paths are invalid and should be ignored
+        Some(dir) if Path::new(&dir).is_absolute() => Some(PathBuf::from(dir)),
+        _ => Some(home()?.join(fallback)),
+    };
+    Some((xdg("XDG_DATA_HOME", ".local/share")?.join(APP_NAME), xdg("XDG_CONFIG_HOME", ".config")?.join(APP_NAME)))
+}
+
+#[cfg(not(any(windows, unix)))]
+fn platform_dirs() -> Option<(PathBuf, PathBuf)> {
+    None
+}
@@ -1,3 +1,5 @@
+use crate::util::{LeBytes, StateReader, StateWriter};
+
 pub struct RingBuffer<T, const N: usize> {
     head: usize,
     tail: usize,
@@ -50,6 +52,25 @@ impl<T: Copy, const N: usize> RingBuffer<T, N> {
     }
 }
 
+impl<T: LeBytes, const N: usize> RingBuffer<T, N> {
+    /// Saves the queued items oldest first
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write("items", self.items as u32);
+        for i in 0..self.items {
+            state.write("buffer", self.buffer[(self.head + i) % N]);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.clear();
+        let items = state.read::<u32>() as usize;
+        for _ in 0..items {
+            let item = state.read();
+            self.push(item);
+        }
+    }
+}
+
 impl<T: Default + Copy, const N: usize> Default for RingBuffer<T, N> {
     fn default() -> Self {
         Self {
@@ -3,9 +3,18 @@ use std::ops::{Deref, DerefMut};
 use std::ptr;
 use std::ptr::NonNull;
 
+#[cfg(feature = "debug_shared")]
+use std::panic::Location;
+#[cfg(feature = "debug_shared")]
+use std::sync::atomic::{AtomicIsize, Ordering};
+#[cfg(feature = "debug_shared")]
+use std::sync::Mutex;
+
 struct SharedBox<T> {
     inner: T,
     count: usize,
+    #[cfg(feature = "debug_shared")]
+    tracker: BorrowTracker,
 }
 
 pub struct Shared<T> {
@@ -15,7 +24,13 @@ pub struct Shared<T> {
 impl<T> Shared<T> {
     pub fn new(val: T) -> Self {
         Self {
-            ptr: Box::leak(Box::new(SharedBox { inner: val, count: 1 })).into(),
+            ptr: Box::leak(Box::new(SharedBox {
+                inner: val,
+                count: 1,
+                #[cfg(feature = "debug_shared")]
+                tracker: BorrowTracker::new(),
+            }))
+            .into(),
         }
     }
 
@@ -23,6 +38,8 @@ impl<T> Shared<T> {
         let uninit_ptr: NonNull<_> = Box::leak(Box::new(SharedBox {
             inner: MaybeUninit::<T>::uninit(),
             count: 1,
+            #[cfg(feature = "debug_shared")]
+            tracker: BorrowTracker::new(),
         }))
         .into();
 
@@ -39,6 +56,31 @@ impl<T> Shared<T> {
         shared
     }
 
+    /// Shared access which, with the `debug_shared` feature, panics if a `borrow_mut` is outstanding.
+    /// Until the guard is dropped, writing through any handle panics as well
+    #[track_caller]
+    pub fn borrow(&self) -> SharedRef<'_, T> {
+        #[cfg(feature = "debug_shared")]
+        self.tracker().acquire_shared(std::any::type_name::<T>());
+
+        SharedRef { shared: self }
+    }
+
+    /// Exclusive access which, with the `debug_shared` feature, panics if any other borrow is outstanding.
+    /// Until the guard is dropped, reading or writing through any handle panics as well
+    #[track_caller]
+    pub fn borrow_mut(&self) -> SharedRefMut<'_, T> {
+        #[cfg(feature = "debug_shared")]
+        self.tracker().acquire_exclusive(std::any::type_name::<T>());
+
+        SharedRefMut { shared: self }
+    }
+
+    #[cfg(feature = "debug_shared")]
+    fn tracker(&self) -> &BorrowTracker {
+        unsafe { &(*self.ptr.as_ptr()).tracker }
+    }
+
     fn inc_count(&self) {
         unsafe {
             (*self.ptr.as_ptr()).count += 1;
@@ -54,8 +96,11 @@ impl<T> Shared<T> {
     }
 }
 
-impl<T> Clone for Shared<T> {
-    fn clone(&self) -> Self {
+impl<T> Shared<T> {
+    /// Another handle to the same value. Only the core hands these out, so every handle to a system's
+    /// components stays inside that system, which is what makes `OwnedSystem` Send
+    #[allow(clippy::should_implement_trait)]
+    pub(crate) fn clone(&self) -> Self {
         self.inc_count();
         Self { ptr: self.ptr }
     }
@@ -64,13 +109,21 @@ impl<T> Clone for Shared<T> {
 impl<T> Deref for Shared<T> {
     type Target = T;
 
+    #[track_caller]
     fn deref(&self) -> &Self::Target {
+        #[cfg(feature = "debug_shared")]
+        self.tracker().check_read(std::any::type_name::<T>());
+
         unsafe { &(*self.ptr.as_ptr()).inner }
     }
 }
 
 impl<T> DerefMut for Shared<T> {
+    #[track_caller]
     fn deref_mut(&mut self) -> &mut Self::Target {
+        #[cfg(feature = "debug_shared")]
+        self.tracker().check_write(std::any::type_name::<T>());
+
         unsafe { &mut (*self.ptr.as_ptr()).inner }
     }
 }
@@ -78,6 +131,9 @@ impl<T> DerefMut for Shared<T> {
 impl<T> Drop for Shared<T> {
     fn drop(&mut self) {
         if self.dec_count() == 0 {
+            #[cfg(feature = "debug_shared")]
+            self.tracker().assert_unborrowed(std::any::type_name::<T>());
+
             unsafe { ptr::drop_in_place(self.ptr.as_ptr()) }
         }
     }
@@ -88,3 +144,189 @@ impl<T: Default> Default for Shared<T> {
         Shared::new(T::default())
     }
 }
+
+pub struct SharedRef<'a, T> {
+    shared: &'a Shared<T>,
+}
+
+impl<T> Deref for SharedRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &(*self.shared.ptr.as_ptr()).inner }
+    }
+}
+
+impl<T> Drop for SharedRef<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "debug_shared")]
+        self.shared.tracker().release_shared();
+    }
+}
+
+pub struct SharedRefMut<'a, T> {
+    shared: &'a Shared<T>,
+}
+
+impl<T> Deref for SharedRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &(*self.shared.ptr.as_ptr()).inner }
+    }
+}
+
+impl<T> DerefMut for SharedRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut (*self.shared.ptr.as_ptr()).inner }
+    }
+}
+
+impl<T> Drop for SharedRefMut<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "debug_shared")]
+        self.shared.tracker().release_exclusive();
+    }
+}
+
+/// Counts the outstanding `borrow` and `borrow_mut` guards like a `RefCell`, and remembers where the last one
+/// was taken. Plain derefs hand out references without a guard, so they aren't counted, they only check that no
+/// guard they would alias is outstanding. The counts are atomic since a ppu reads through its handles from a
+/// render thread
+#[cfg(feature = "debug_shared")]
+struct BorrowTracker {
+    // -1 while exclusively borrowed, otherwise the number of shared borrows
+    state: AtomicIsize,
+    location: Mutex<Option<&'static Location<'static>>>,
+}
+
+#[cfg(feature = "debug_shared")]
+impl BorrowTracker {
+    const fn new() -> Self {
+        Self {
+            state: AtomicIsize::new(0),
+            location: Mutex::new(None),
+        }
+    }
+
+    /// Where the last guard was taken, another thread can get here before it's recorded
+    fn location(&self) -> String {
+        self.location.lock().unwrap().map_or("an unknown location".into(), |location| location.to_string())
+    }
+
+    #[track_caller]
+    fn acquire_shared(&self, name: &str) {
+        let update = self.state.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n >= 0).then_some(n + 1));
+        if update.is_err() {
+            panic!("Shared<{name}>: already mutably borrowed at {}, cannot borrow at {}", self.location(), Location::caller());
+        }
+        *self.location.lock().unwrap() = Some(Location::caller());
+    }
+
+    #[track_caller]
+    fn acquire_exclusive(&self, name: &str) {
+        match self.state.compare_exchange(0, -1, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {}
+            Err(n) if n < 0 => panic!(
+                "Shared<{name}>: already mutably borrowed at {}, cannot borrow mutably at {}",
+                self.location(),
+                Location::caller()
+            ),
+            Err(n) => panic!(
+                "Shared<{name}>: {n} outstanding borrow(s), last taken at {}, cannot borrow mutably at {}",
+                self.location(),
+                Location::caller()
+            ),
+        }
+        *self.location.lock().unwrap() = Some(Location::caller());
+    }
+
+    fn release_shared(&self) {
+        self.state.fetch_sub(1, Ordering::Release);
+    }
+
+    fn release_exclusive(&self) {
+        self.state.store(0, Ordering::Release);
+    }
+
+    #[track_caller]
+    fn check_read(&self, name: &str) {
+        if self.state.load(Ordering::Acquire) < 0 {
+            panic!("Shared<{name}>: mutably borrowed at {}, cannot read at {}", self.location(), Location::caller());
+        }
+    }
+
+    #[track_caller]
+    fn check_write(&self, name: &str) {
+        match self.state.load(Ordering::Acquire) {
+            0 => {}
+            n if n < 0 => panic!("Shared<{name}>: mutably borrowed at {}, cannot write at {}", self.location(), Location::caller()),
+            n => panic!(
+                "Shared<{name}>: {n} outstanding borrow(s), last taken at {}, cannot write at {}",
+                self.location(),
+                Location::caller()
+            ),
+        }
+    }
+
+    fn assert_unborrowed(&self, name: &str) {
+        if self.state.load(Ordering::Acquire) != 0 {
+            panic!("Shared<{name}>: dropped while borrowed at {}", self.location());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guards_reach_the_shared_value() {
+        let shared = Shared::new(1);
+        let other = shared.clone();
+        *shared.borrow_mut() += 1;
+        assert_eq!(*other.borrow(), 2);
+        assert_eq!(*other, 2);
+    }
+
+    #[cfg(feature = "debug_shared")]
+    #[test]
+    fn dropped_guards_end_their_borrow() {
+        let shared = Shared::new(0);
+        let mut other = shared.clone();
+        drop((shared.borrow(), shared.borrow()));
+        drop(shared.borrow_mut());
+        *other += 1;
+        assert_eq!(*shared.borrow_mut(), 1);
+    }
+
+    #[cfg(feature = "debug_shared")]
+    #[test]
+    #[should_panic(expected = "2 outstanding borrow(s)")]
+    fn borrowing_mutably_while_borrowed_panics() {
+        let shared = Shared::new(0);
+        let _first = shared.borrow();
+        let _second = shared.borrow();
+        let _ = shared.borrow_mut();
+    }
+
+    #[cfg(feature = "debug_shared")]
+    #[test]
+    #[should_panic(expected = "cannot write")]
+    fn writing_through_another_handle_while_borrowed_panics() {
+        let shared = Shared::new(0);
+        let mut other = shared.clone();
+        let _borrow = shared.borrow();
+        *other += 1;
+    }
+
+    #[cfg(feature = "debug_shared")]
+    #[test]
+    #[should_panic(expected = "cannot read")]
+    fn reading_through_another_handle_while_mutably_borrowed_panics() {
+        let shared = Shared::new(0);
+        let other = shared.clone();
+        let _borrow = shared.borrow_mut();
+        assert_eq!(*other, 0);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::util::LeBytes;
+
+const MAGIC: [u8; 4] = *b"ESST";
+
+/// Bumped whenever a component adds, removes or reorders what it saves. States from other versions are refused
+pub const STATE_VERSION: u32 = 10;
+
+/// Serializes emulator state into the savestate format: a magic and version header followed by
+/// every component's fields as little endian values, each component starting with a 4 byte tag and
+/// the length of its data. Fields are named so tools can tell what an offset in a section holds,
+/// the names themselves aren't saved
+pub struct StateWriter {
+    data: Vec<u8>,
+    /// Offset of the length of the section being written, filled in once the section ends
+    open_section: Option<usize>,
+    layout: Option<StateLayout>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::with_buffer(Vec::with_capacity(0x800000))
+    }
+
+    /// Writes into `data` from the start, keeping its allocation. Saving often, like for rewind, doesn't
+    /// have to allocate a new state every time
+    pub fn with_buffer(mut data: Vec<u8>) -> Self {
+        data.clear();
+        let mut writer = Self { data, open_section: None, layout: None };
+        writer.data.extend_from_slice(&MAGIC);
+        writer.put(STATE_VERSION);
+        writer
+    }
+
+    /// Also keeps where every field went, for `finish_with_layout`
+    pub fn with_layout() -> Self {
+        let mut writer = Self::new();
+        writer.layout = Some(StateLayout::default());
+        writer
+    }
+
+    /// Marks the start of a component, so a state that's out of sync fails to load instead of loading garbage
+    pub fn section(&mut self, tag: &[u8; 4]) {
+        self.close_section();
+        self.data.extend_from_slice(tag);
+        self.open_section = Some(self.data.len());
+        self.put(0u32);
+
+        if let Some(layout) = &mut self.layout {
+            layout.sections.push(LayoutSection { tag: *tag, len: 0, fields: vec![] });
+        }
+    }
+
+    fn close_section(&mut self) {
+        if let Some(start) = self.open_section.take() {
+            let len = self.data.len() - start - 4;
+            self.data[start..start + 4].copy_from_slice(&(len as u32).to_le_bytes());
+
+            if let Some(section) = self.layout.as_mut().and_then(|layout| layout.sections.last_mut()) {
+                section.len = len;
+            }
+        }
+    }
+
+    pub fn write<T: LeBytes>(&mut self, name: &'static str, val: T) {
+        let start = self.data.len();
+        self.put(val);
+        self.record(name, start, None);
+    }
+
+    pub fn write_bool(&mut self, name: &'static str, val: bool) {
+        self.write(name, val as u8);
+    }
+
+    pub fn write_slice<T: LeBytes>(&mut self, name: &'static str, vals: &[T]) {
+        let start = self.data.len();
+        for &val in vals {
+            self.put(val);
+        }
+        self.record(name, start, Some(T::SIZE));
+    }
+
+    pub fn write_bytes(&mut self, name: &'static str, bytes: &[u8]) {
+        let start = self.data.len();
+        self.data.extend_from_slice(bytes);
+        self.record(name, start, None);
+    }
+
+    /// Writes the length before the data, for buffers whose size isn't fixed
+    pub fn write_vec(&mut self, name: &'static str, bytes: &[u8]) {
+        let start = self.data.len();
+        self.put(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+        self.record(name, start, None);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.close_section();
+        self.data
+    }
+
+    /// The state along with the fields of every section, empty unless made `with_layout`
+    pub fn finish_with_layout(mut self) -> (Vec<u8>, StateLayout) {
+        self.close_section();
+        let layout = self.layout.take().unwrap_or_default();
+        (self.data, layout)
+    }
+
+    fn put<T: LeBytes>(&mut self, val: T) {
+        let start = self.data.len();
+        self.data.resize(start + T::SIZE, 0);
+        val.to_le_slice(&mut self.data[start..]);
+    }
+
+    fn record(&mut self, name: &'static str, start: usize, element_size: Option<usize>) {
+        let (Some(layout), Some(section_start)) = (&mut self.layout, self.open_section) else {
+            return;
+        };
+
+        if let Some(section) = layout.sections.last_mut() {
+            let offset = start - section_start - 4;
+            section.fields.push(StateField { name, offset, len: self.data.len() - start, element_size });
+        }
+    }
+}
+
+/// The sections of a state and the fields each is made of
+#[derive(Default)]
+pub struct StateLayout {
+    pub sections: Vec<LayoutSection>,
+}
+
+pub struct LayoutSection {
+    pub tag: [u8; 4],
+    pub len: usize,
+    pub fields: Vec<StateField>,
+}
+
+/// A field of a section as it was written, components that save in a loop write the same name once per item
+pub struct StateField {
+    pub name: &'static str,
+    /// Offset from the start of the section
+    pub offset: usize,
+    pub len: usize,
+    /// Size of one element for slices, `None` for single values and byte buffers
+    pub element_size: Option<usize>,
+}
+
+impl LayoutSection {
+    /// Names the field holding byte `offset` of the section: the name followed by the element for slices,
+    /// the offset into byte buffers, and which one it is for names written more than once
+    pub fn field_name(&self, offset: usize) -> Option<String> {
+        let index = self.fields.iter().position(|field| (field.offset..field.offset + field.len).contains(&offset))?;
+        let field = &self.fields[index];
+        let inner = offset - field.offset;
+
+        let mut name = match field.element_size {
+            Some(size) => format!("{}[{}]", field.name, inner / size),
+            None if field.len > 8 => format!("{}+{inner:x}", field.name),
+            None => field.name.to_string(),
+        };
+
+        let occurrence = self.fields[..index].iter().filter(|other| other.name == field.name).count();
+        let repeated = occurrence != 0 || self.fields[index + 1..].iter().any(|other| other.name == field.name);
+        if repeated {
+            name += &format!(" #{}", occurrence + 1);
+        }
+        Some(name)
+    }
+}
+
+/// One component's data in a state, `offset` is where `data` starts in the whole state
+pub struct StateSection<'a> {
+    pub tag: [u8; 4],
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+/// Splits a state into its sections without loading it, for tools that look inside states
+pub fn state_sections(data: &[u8]) -> Result<Vec<StateSection<'_>>, String> {
+    StateReader::new(data)?;
+
+    let mut sections = vec![];
+    let mut offset = 8;
+    while offset < data.len() {
+        let header = data.get(offset..offset + 8).ok_or("savestate is truncated")?;
+        let tag: [u8; 4] = header[..4].try_into().unwrap();
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let body = data.get(offset + 8..offset + 8 + len).ok_or("savestate is truncated")?;
+        sections.push(StateSection { tag, offset: offset + 8, data: body });
+        offset += 8 + len;
+    }
+
+    Ok(sections)
+}
+
+/// Reads a state written by `StateWriter`. Reading past the end or a mismatched section doesn't
+/// panic, it returns zeroes and fails `finish` so a broken state can't be half applied unnoticed
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    /// Tag, start and end offset of the section being read
+    section: Option<([u8; 4], usize, usize)>,
+    error: Option<String>,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        if data.get(..4) != Some(&MAGIC) {
+            return Err("not a savestate".to_string());
+        }
+
+        let mut reader = Self { data, offset: 4, section: None, error: None };
+        let version = reader.read::<u32>();
+        if version != STATE_VERSION {
+            return Err(format!("savestate version {version} isn't supported, expected {STATE_VERSION}"));
+        }
+
+        Ok(reader)
+    }
+
+    pub fn section(&mut self, tag: &[u8; 4]) {
+        self.check_section_end();
+
+        let mut found = [0; 4];
+        self.read_bytes(&mut found);
+        if &found != tag && self.error.is_none() {
+            self.error = Some(format!("expected section {} at offset {:x}", String::from_utf8_lossy(tag), self.offset - 4));
+        }
+
+        let len = self.read::<u32>() as usize;
+        self.section = Some((*tag, self.offset, self.offset + len));
+    }
+
+    /// A component that reads less or more than it wrote points at the section, not at whatever
+    /// section happens to come next
+    fn check_section_end(&mut self) {
+        if let Some((tag, start, end)) = self.section.take() {
+            if self.offset != end && self.error.is_none() {
+                let tag = String::from_utf8_lossy(&tag);
+                self.error = Some(format!("section {tag} is {} bytes but {} were read", end - start, self.offset - start));
+            }
+        }
+    }
+
+    pub fn read<T: LeBytes>(&mut self) -> T {
+        match self.take(T::SIZE) {
+            Some(bytes) => T::from_le_slice(bytes),
+            None => T::default(),
+        }
+    }
+
+    pub fn read_bool(&mut self) -> bool {
+        self.read::<u8>() != 0
+    }
+
+    pub fn read_slice<T: LeBytes>(&mut self, out: &mut [T]) {
+        for val in out {
+            *val = self.read();
+        }
+    }
+
+    pub fn read_bytes(&mut self, out: &mut [u8]) {
+        match self.take(out.len()) {
+            Some(bytes) => out.copy_from_slice(bytes),
+            None => out.fill(0),
+        }
+    }
+
+    pub fn read_vec(&mut self) -> Vec<u8> {
+        let len = self.read::<u32>() as usize;
+        self.take(len).map(<[u8]>::to_vec).unwrap_or_default()
+    }
+
+    /// Rejects the state for a reason only the component reading it can tell, like an unknown event
+    pub fn fail(&mut self, error: String) {
+        self.error.get_or_insert(error);
+    }
+
+    /// Whether everything was read without running out of data or hitting the wrong section
+    pub fn finish(mut self) -> Result<(), String> {
+        self.check_section_end();
+        match self.error {
+            Some(error) => Err(error),
+            None if self.offset != self.data.len() => Err(format!("{} bytes left over", self.data.len() - self.offset)),
+            None => Ok(()),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.offset.checked_add(len).filter(|&end| end <= self.data.len());
+        match end {
+            Some(end) if self.error.is_none() => {
+                let bytes = &self.data[self.offset..end];
+                self.offset = end;
+                Some(bytes)
+            }
+            Some(_) => None,
+            None => {
+                self.error.get_or_insert_with(|| "savestate is truncated".to_string());
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt::{Display, Formatter};
+
+use crate::util::{state_sections, StateLayout};
+
+/// Granularity differences in large sections like main memory are reported at
+const PAGE_SIZE: usize = 0x1000;
+
+/// Where two states made by the same build stop matching, section by section. Sections are paired up
+/// in order, so components that write the same tag (the two dma controllers, the two ppus) are told
+/// apart by their position
+pub struct StateDiff {
+    pub sections: Vec<SectionDiff>,
+}
+
+pub struct SectionDiff {
+    /// Tag followed by the occurrence for tags that repeat, like `DMA #2`
+    pub name: String,
+    /// Offset of the first differing byte from the start of the section
+    pub first_offset: usize,
+    /// The field at `first_offset`, when the layout the states were compared with covers the section
+    pub first_field: Option<String>,
+    /// The 4 bytes around `first_offset` in each state, aligned so registers read as a word
+    pub first_values: [u32; 2],
+    pub differing_bytes: usize,
+    /// Offsets of the differing 4K pages from the start of the section
+    pub pages: Vec<usize>,
+    /// Section lengths when they don't match, only the common part is compared
+    pub lengths: Option<[usize; 2]>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// The section the states diverge in first, in save order
+    pub fn first(&self) -> Option<&SectionDiff> {
+        self.sections.first()
+    }
+}
+
+/// Compares two states. Fails when either isn't a valid state or the two don't have the same
+/// sections, which happens with states from different builds. With a `layout` from the same build,
+/// differences in sections of the same tag and length as the layout's are named by field
+pub fn diff_states(a: &[u8], b: &[u8], layout: Option<&StateLayout>) -> Result<StateDiff, String> {
+    let (left, right) = (state_sections(a)?, state_sections(b)?);
+    if left.len() != right.len() {
+        return Err(format!("the states have {} and {} sections", left.len(), right.len()));
+    }
+
+    let mut sections = vec![];
+    for (i, (a, b)) in left.iter().zip(&right).enumerate() {
+        if a.tag != b.tag {
+            return Err(format!(
+                "section {i} is {} in one state and {} in the other",
+                String::from_utf8_lossy(&a.tag),
+                String::from_utf8_lossy(&b.tag)
+            ));
+        }
+
+        let occurrence = left[..i].iter().filter(|section| section.tag == a.tag).count();
+        let lengths = (a.data.len() != b.data.len()).then_some([a.data.len(), b.data.len()]);
+        let len = a.data.len().min(b.data.len());
+        let differing: Vec<usize> = (0..len).filter(|&offset| a.data[offset] != b.data[offset]).collect();
+
+        let Some(&first_offset) = differing.first() else {
+            if let Some(lengths) = lengths {
+                sections.push(SectionDiff {
+                    name: section_name(&a.tag, occurrence),
+                    first_offset: len,
+                    first_field: None,
+                    first_values: [0; 2],
+                    differing_bytes: 0,
+                    pages: vec![],
+                    lengths: Some(lengths),
+                });
+            }
+            continue;
+        };
+
+        let mut pages: Vec<usize> = differing.iter().map(|offset| offset & !(PAGE_SIZE - 1)).collect();
+        pages.dedup();
+
+        // a section that's longer or shorter than the layout's has something variable sized in it, like
+        // pending events, and its fields after that are somewhere else
+        let layout_section = layout
+            .and_then(|layout| layout.sections.get(i))
+            .filter(|section| section.tag == a.tag && section.len == a.data.len() && section.len == b.data.len());
+
+        sections.push(SectionDiff {
+            name: section_name(&a.tag, occurrence),
+            first_offset,
+            first_field: layout_section.and_then(|section| section.field_name(first_offset)),
+            first_values: [word_at(a.data, first_offset), word_at(b.data, first_offset)],
+            differing_bytes: differing.len(),
+            pages,
+            lengths,
+        });
+    }
+
+    Ok(StateDiff { sections })
+}
+
+fn section_name(tag: &[u8; 4], occurrence: usize) -> String {
+    let tag = String::from_utf8_lossy(tag).trim_end().to_string();
+    if occurrence == 0 {
+        tag
+    } else {
+        format!("{tag} #{}", occurrence + 1)
+    }
+}
+
+/// The aligned little endian word containing `offset`, missing bytes past the end read as 0
+fn word_at(data: &[u8], offset: usize) -> u32 {
+    let start = offset & !0x3;
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = data.get(start + i).copied().unwrap_or(0);
+    }
+    u32::from_le_bytes(bytes)
+}
+
+impl Display for SectionDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.name)?;
+        if self.differing_bytes != 0 {
+            write!(f, "{} bytes differ in {} pages, first at +{:x}", self.differing_bytes, self.pages.len(), self.first_offset)?;
+            if let Some(field) = &self.first_field {
+                write!(f, " in {field}")?;
+            }
+            write!(f, " ({:08x} vs {:08x})", self.first_values[0], self.first_values[1])?;
+        }
+        if let Some([a, b]) = self.lengths {
+            if self.differing_bytes != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{a} vs {b} bytes long")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for StateDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "the states are identical");
+        }
+
+        for section in &self.sections {
+            writeln!(f, "{section}")?;
+            // memory sections can differ in hundreds of pages, a few are enough to know where to look
+            if section.pages.len() > 1 {
+                let pages: Vec<String> = section.pages.iter().take(8).map(|page| format!("+{page:x}")).collect();
+                let more = if section.pages.len() > 8 { " .." } else { "" };
+                writeln!(f, "    pages {}{more}", pages.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::cpu::Arch;
+    use crate::arm::memory::Memory;
+    use crate::core::System;
+    use crate::util::StateWriter;
+
+    /// A state with a register, two channels saved in a loop, a slice and a byte buffer. `change` can
+    /// make one of them differ
+    fn state(change: impl Fn(&mut [u32; 4], &mut [u16; 4], &mut [u8; 0x20])) -> (Vec<u8>, StateLayout) {
+        let (mut channels, mut gpr, mut memory) = ([0; 4], [0; 4], [0; 0x20]);
+        change(&mut channels, &mut gpr, &mut memory);
+
+        let mut state = StateWriter::with_layout();
+        state.section(b"TEST");
+        state.write("control", 0u32);
+        for channel in channels.chunks(2) {
+            state.write("channel.source", channel[0]);
+            state.write("channel.length", channel[1]);
+        }
+        state.write_slice("gpr", &gpr);
+        state.write_bytes("memory", &memory);
+        state.finish_with_layout()
+    }
+
+    fn first_field(change: impl Fn(&mut [u32; 4], &mut [u16; 4], &mut [u8; 0x20])) -> Option<String> {
+        let (a, layout) = state(|_, _, _| {});
+        let (b, _) = state(change);
+        let diff = diff_states(&a, &b, Some(&layout)).unwrap();
+        diff.first().unwrap().first_field.clone()
+    }
+
+    #[test]
+    fn differences_are_named_by_field() {
+        assert_eq!(first_field(|channels, _, _| channels[0] = 1).as_deref(), Some("channel.source #1"));
+        assert_eq!(first_field(|channels, _, _| channels[3] = 1).as_deref(), Some("channel.length #2"));
+        assert_eq!(first_field(|_, gpr, _| gpr[2] = 1).as_deref(), Some("gpr[2]"));
+        assert_eq!(first_field(|_, _, memory| memory[0x13] = 1).as_deref(), Some("memory+13"));
+    }
+
+    #[test]
+    fn sections_with_another_length_than_the_layout_are_not_named() {
+        let (a, _) = state(|_, _, _| {});
+        let (_, mut layout) = state(|_, _, _| {});
+        layout.sections[0].len += 4;
+        let mut b = a.clone();
+        *b.last_mut().unwrap() = 1;
+
+        let diff = diff_states(&a, &b, Some(&layout)).unwrap();
+        assert_eq!(diff.first().unwrap().first_offset, 0x3b);
+        assert_eq!(diff.first().unwrap().first_field, None);
+    }
+
+    #[test]
+    fn system_states_are_named_with_its_layout() {
+        let mut system = System::new();
+        let a = system.save_state();
+        system.get_memory(Arch::ARMv5).poke_byte(0x02001234, 1);
+        system.arm9.cpu.state.gpr[5] = 1;
+        let b = system.save_state();
+
+        let diff = diff_states(&a, &b, Some(&system.state_layout())).unwrap();
+        let fields: Vec<_> = diff.sections.iter().map(|section| (section.name.as_str(), section.first_field.as_deref())).collect();
+        assert_eq!(fields, [("SYS", Some("main_memory+1234")), ("ARM9", Some("gpr[5]"))]);
+    }
+}
@@ -0,0 +1,48 @@
+use std::cmp::Reverse;
+use std::sync::Mutex;
+
+/// A place in the emulator that ran into something it doesn't implement yet
+#[derive(Clone)]
+pub struct UnimplementedHit {
+    /// `file:line` of the report
+    pub site: &'static str,
+    /// Message of the first hit, later hits at the same site only bump the count
+    pub message: String,
+    pub count: u64,
+}
+
+static HITS: Mutex<Vec<UnimplementedHit>> = Mutex::new(Vec::new());
+
+/// Reports a missing feature, see `unimplemented_feature!`. Only the first hit of a site is logged,
+/// so paths that run every scanline don't bury the rest of the log
+pub fn report_unimplemented(site: &'static str, message: impl FnOnce() -> String) {
+    let mut hits = HITS.lock().unwrap();
+    match hits.iter_mut().find(|hit| hit.site == site) {
+        Some(hit) => hit.count += 1,
+        None => {
+            let message = message();
+            log::error!("{message}");
+            hits.push(UnimplementedHit { site, message, count: 1 });
+        }
+    }
+}
+
+/// Every site hit since the last clear, most frequent first
+pub fn unimplemented_hits() -> Vec<UnimplementedHit> {
+    let mut hits = HITS.lock().unwrap().clone();
+    hits.sort_by_key(|hit| Reverse(hit.count));
+    hits
+}
+
+pub fn clear_unimplemented_hits() {
+    HITS.lock().unwrap().clear();
+}
+
+/// Logs a missing feature the first time a site hits it and counts every hit after that. Takes the
+/// same arguments as `format!`, which are only formatted for the first hit
+#[macro_export]
+macro_rules! unimplemented_feature {
+    ($($arg:tt)+) => {
+        $crate::util::report_unimplemented(concat!(file!(), ":", line!()), || format!($($arg)+))
+    };
+}
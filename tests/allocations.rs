@@ -0,0 +1,24 @@
+//! Runs a rom with the counting allocator that `--features count_allocs` installs in the binary, and checks
+//! that once it's warmed up a frame doesn't allocate
+
+use emulation_station::headless::boot_and_run;
+use emulation_station::util::alloc_counter::{allocations, CountingAllocator};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn running_frames_doesnt_allocate() {
+    let start = allocations();
+    let buffer = std::hint::black_box(vec![0u8; 64]);
+    assert!(allocations() > start, "the counting allocator isn't installed");
+    drop(buffer);
+
+    let rom = concat!(env!("CARGO_MANIFEST_DIR"), "/roms/fire_and_sprites.nds");
+    let mut system = boot_and_run(rom, 30);
+    let start = allocations();
+    for _ in 0..60 {
+        system.run_frame();
+    }
+    assert_eq!(allocations() - start, 0);
+}
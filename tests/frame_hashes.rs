@@ -0,0 +1,31 @@
+//! Boots test roms headless and compares the last frame with a known good one. A change in rendering or
+//! timing that shows up on screen changes the hash, check the new output with `--headless --screenshot`
+//! and update the hash when it's right
+
+use emulation_station::core::video::Screen;
+use emulation_station::core::OwnedSystem;
+use emulation_station::headless::boot_and_run;
+
+fn run(rom: &str, frames: u32) -> OwnedSystem {
+    let path = format!("{}/roms/{rom}", env!("CARGO_MANIFEST_DIR"));
+    let system = boot_and_run(&path, frames);
+    assert_eq!(system.stop_reason(), None, "{rom} stopped early");
+    system
+}
+
+#[test]
+fn tinyfb_fills_its_framebuffer() {
+    let system = run("TinyFB.nds", 120);
+    let bottom = system.video_unit.fetch_framebuffer(Screen::Bottom);
+    assert!(bottom.chunks(4).all(|pixel| pixel == [250, 0, 0, 0xff]));
+    assert_eq!(system.video_unit.frame_hash(), 0x5cfaf10b9da23d82);
+}
+
+#[test]
+fn armwrestler_shows_its_first_test_page() {
+    // a hash of a blank screen would pass just as well with a rom that doesn't boot
+    let system = run("armwrestler.nds", 120);
+    let top = system.video_unit.fetch_framebuffer(Screen::Top);
+    assert!(top.chunks(4).any(|pixel| pixel != &top[..4]));
+    assert_eq!(system.video_unit.frame_hash(), 0x0000b97a4c9dc895);
+}
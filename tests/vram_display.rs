@@ -0,0 +1,69 @@
+//! Boots a homebrew built here that fills bank A through the lcdc and shows it with the vram display mode,
+//! then checks the frame hash. Display capture isn't emulated yet, once it is this is the place to add a
+//! second homebrew capturing engine A into a bank before showing it
+
+use emulation_station::core::video::Screen;
+use emulation_station::headless::boot_and_run;
+
+const ARM9_ADDRESS: u32 = 0x02000000;
+const ARM7_ADDRESS: u32 = 0x037f8000;
+
+/// Fills 256x192 halfwords of bank A with their own index as the color, then shows the bank on engine A
+const ARM9_CODE: [u32; 17] = [
+    0xe3a00301, // mov r0, #0x04000000
+    0xe3a01902, // mov r1, #0x8000
+    0xe3811003, // orr r1, r1, #3
+    0xe2802c03, // add r2, r0, #0x300
+    0xe1c210b4, // strh r1, [r2, #4]       POWCNT1, engine A on top
+    0xe3a01080, // mov r1, #0x80
+    0xe2802e24, // add r2, r0, #0x240
+    0xe5c21000, // strb r1, [r2]           VRAMCNT_A, lcdc
+    0xe3a02668, // mov r2, #0x06800000
+    0xe3a01000, // mov r1, #0
+    0xe0c210b2, // loop: strh r1, [r2], #2
+    0xe2811001, // add r1, r1, #1
+    0xe3510903, // cmp r1, #0xc000
+    0x1afffffb, // bne loop
+    0xe3a01802, // mov r1, #0x20000
+    0xe5801000, // str r1, [r0]            DISPCNT, vram display of bank A
+    0xeafffffe, // b .
+];
+
+const ARM7_CODE: [u32; 1] = [
+    0xeafffffe, // b .
+];
+
+/// A direct boot rom with the arm9 code at 0x200 and the arm7 code at 0x400
+fn build_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x800];
+    let mut write = |offset: usize, value: u32| rom[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    write(0x0c, u32::from_le_bytes(*b"ESVD"));
+    write(0x20, 0x200);
+    write(0x24, ARM9_ADDRESS);
+    write(0x28, ARM9_ADDRESS);
+    write(0x2c, ARM9_CODE.len() as u32 * 4);
+    write(0x30, 0x400);
+    write(0x34, ARM7_ADDRESS);
+    write(0x38, ARM7_ADDRESS);
+    write(0x3c, ARM7_CODE.len() as u32 * 4);
+    for (i, &word) in ARM9_CODE.iter().enumerate() {
+        write(0x200 + i * 4, word);
+    }
+    for (i, &word) in ARM7_CODE.iter().enumerate() {
+        write(0x400 + i * 4, word);
+    }
+    rom
+}
+
+#[test]
+fn vram_display_shows_lcdc_writes() {
+    let path = std::env::temp_dir().join("emulation-station-vram-display.nds");
+    std::fs::write(&path, build_rom()).unwrap();
+    let system = boot_and_run(path.to_str().unwrap(), 4);
+
+    // pixel 0x1f is pure red, pixel 0x3e0 pure green
+    let top = system.video_unit.fetch_framebuffer(Screen::Top);
+    assert_eq!(top[0x1f * 4..0x1f * 4 + 4], [250, 0, 0, 0xff]);
+    assert_eq!(top[0x3e0 * 4..0x3e0 * 4 + 4], [0, 250, 0, 0xff]);
+    assert_eq!(system.video_unit.frame_hash(), 0x1ef36bdaf0e3d9bb);
+}